@@ -0,0 +1,150 @@
+//! Functional automatic differentiation: vector-Jacobian, Jacobian-vector and Hessian-vector
+//! products.
+//!
+//! The returned derivatives are part of the computation graph so they can be differentiated
+//! again, e.g. to compute higher order derivatives.
+use crate::{bail, Result, Tensor, TensorId};
+use std::collections::HashSet;
+
+// Returns the tensors with respect to which the derivatives are computed, the tensors that do
+// not already track gradients are replaced by variables holding the same values.
+fn leaves(xs: &[&Tensor]) -> Result<Vec<Tensor>> {
+    xs.iter()
+        .map(|x| {
+            if x.track_op() {
+                Ok((*x).clone())
+            } else {
+                x.make_var()
+            }
+        })
+        .collect()
+}
+
+// Backpropagates grad from ys and returns the gradients of leaves, the gradient is zero for the
+// leaves that ys does not depend on.
+fn backward(ys: &Tensor, grad: Tensor, leaves: &[Tensor]) -> Result<Vec<Tensor>> {
+    let leaf_ids: HashSet<TensorId> = leaves.iter().map(|t| t.id()).collect();
+    let grads = ys.backward_with_leaves(grad, &|t| leaf_ids.contains(&t.id()), true)?;
+    leaves
+        .iter()
+        .map(|t| match grads.get(t) {
+            Some(grad) => Ok(grad.clone()),
+            None => t.zeros_like(),
+        })
+        .collect()
+}
+
+fn check_tangents(xs: &[&Tensor], vs: &[&Tensor], op: &'static str) -> Result<()> {
+    if xs.is_empty() {
+        bail!("{op}: no inputs")
+    }
+    if xs.len() != vs.len() {
+        bail!("{op}: {} tangents for {} inputs", vs.len(), xs.len())
+    }
+    for (x, v) in xs.iter().zip(vs.iter()) {
+        if x.shape() != v.shape() {
+            bail!(
+                "{op}: tangent shape {:?} does not match input shape {:?}",
+                v.shape(),
+                x.shape()
+            )
+        }
+    }
+    Ok(())
+}
+
+// Returns the sum of the elementwise products of xs and vs.
+fn dot(xs: &[Tensor], vs: &[&Tensor]) -> Result<Tensor> {
+    let mut sum = Tensor::zeros((), xs[0].dtype(), xs[0].device())?;
+    for (x, v) in xs.iter().zip(vs.iter()) {
+        sum = (sum + (x * *v)?.sum_all()?)?;
+    }
+    Ok(sum)
+}
+
+/// Computes `ys = f(xs)` and the vector-Jacobian product `vᵀ ∂ys/∂xs` for each of the inputs.
+///
+/// `v` must have the same shape as `ys`. The returned products have the shapes of the
+/// corresponding inputs.
+///
+/// ```rust
+/// use candle_core::{autograd, Tensor, Device};
+/// let x = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+/// let v = Tensor::new(&[1f32, -1.], &Device::Cpu)?;
+/// let (ys, vjp) = autograd::vjp(|xs| xs[0].sqr(), &[&x], &v)?;
+/// assert_eq!(ys.to_vec1::<f32>()?, &[1., 4.]);
+/// assert_eq!(vjp[0].to_vec1::<f32>()?, &[2., -4.]);
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub fn vjp<F>(f: F, xs: &[&Tensor], v: &Tensor) -> Result<(Tensor, Vec<Tensor>)>
+where
+    F: FnOnce(&[Tensor]) -> Result<Tensor>,
+{
+    let xs = leaves(xs)?;
+    let ys = f(&xs)?;
+    if ys.shape() != v.shape() {
+        bail!(
+            "vjp: cotangent shape {:?} does not match output shape {:?}",
+            v.shape(),
+            ys.shape()
+        )
+    }
+    let vjp = backward(&ys, v.clone(), &xs)?;
+    Ok((ys, vjp))
+}
+
+/// Computes `ys = f(xs)` and the Jacobian-vector product `∂ys/∂xs · vs`, i.e. the directional
+/// derivative of `f` at `xs` along `vs`.
+///
+/// `vs` holds one tangent per input with the same shape as this input. The returned product has
+/// the shape of `ys`. This uses two backward passes, the first one computes the vector-Jacobian
+/// product for some dummy vector `u` and the second one differentiates it with respect to `u`.
+pub fn jvp<F>(f: F, xs: &[&Tensor], vs: &[&Tensor]) -> Result<(Tensor, Tensor)>
+where
+    F: FnOnce(&[Tensor]) -> Result<Tensor>,
+{
+    check_tangents(xs, vs, "jvp")?;
+    let xs = leaves(xs)?;
+    let ys = f(&xs)?;
+    let u = ys.zeros_like()?.make_var()?;
+    // uᵀ J is linear in u, its derivative with respect to u along vs is J vs.
+    let vjp = backward(&ys, u.clone(), &xs)?;
+    let dot = dot(&vjp, vs)?;
+    let jvp = backward(&dot, dot.ones_like()?, &[u])?;
+    Ok((ys, jvp.into_iter().next().unwrap()))
+}
+
+/// Computes the scalar `y = f(xs)` and the Hessian-vector product `∂²y/∂xs² · vs` for each of
+/// the inputs.
+///
+/// `vs` holds one vector per input with the same shape as this input, the returned products
+/// have the shapes of the corresponding inputs.
+///
+/// ```rust
+/// use candle_core::{autograd, Tensor, Device};
+/// let x = Tensor::new(&[1f32, 2.], &Device::Cpu)?;
+/// let v = Tensor::new(&[1f32, -1.], &Device::Cpu)?;
+/// // The hessian of the sum of the x³ is diag(6x).
+/// let (y, hvp) = autograd::hvp(|xs| xs[0].powf(3.)?.sum_all(), &[&x], &[&v])?;
+/// assert_eq!(y.to_scalar::<f32>()?, 9.);
+/// assert_eq!(hvp[0].to_vec1::<f32>()?, &[6., -12.]);
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub fn hvp<F>(f: F, xs: &[&Tensor], vs: &[&Tensor]) -> Result<(Tensor, Vec<Tensor>)>
+where
+    F: FnOnce(&[Tensor]) -> Result<Tensor>,
+{
+    check_tangents(xs, vs, "hvp")?;
+    let xs = leaves(xs)?;
+    let y = f(&xs)?;
+    if y.rank() != 0 {
+        bail!(
+            "hvp: the function output should be a scalar, got {:?}",
+            y.shape()
+        )
+    }
+    let grads = backward(&y, y.ones_like()?, &xs)?;
+    let dot = dot(&grads, vs)?;
+    let hvp = backward(&dot, dot.ones_like()?, &xs)?;
+    Ok((y, hvp))
+}
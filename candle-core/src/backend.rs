@@ -21,6 +21,10 @@ pub trait BackendStorage: Sized {
 
     fn reduce_op(&self, _: ReduceOp, _: &Layout, _: &[usize]) -> Result<Self>;
 
+    /// Sorts along the last dimension in ascending order, or descending if the flag is false,
+    /// and returns the sorted values along with their u32 indexes.
+    fn sort_last_dim(&self, _: &Layout, _: bool) -> Result<(Self, Self)>;
+
     fn cmp(&self, _: CmpOp, _: &Self, _: &Layout, _: &Layout) -> Result<Self>;
 
     fn to_dtype(&self, _: &Layout, _: DType) -> Result<Self>;
@@ -63,10 +67,39 @@ pub trait BackendStorage: Sized {
         _params: &crate::conv::ParamsConvTranspose2D,
     ) -> Result<Self>;
 
+    fn conv3d(
+        &self,
+        _l: &Layout,
+        _kernel: &Self,
+        _kernel_l: &Layout,
+        _params: &crate::conv::ParamsConv3D,
+    ) -> Result<Self>;
+
+    fn conv_transpose3d(
+        &self,
+        _l: &Layout,
+        _kernel: &Self,
+        _kernel_l: &Layout,
+        _params: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self>;
+
     fn avg_pool2d(&self, _: &Layout, _: (usize, usize), _: (usize, usize)) -> Result<Self>;
     fn max_pool2d(&self, _: &Layout, _: (usize, usize), _: (usize, usize)) -> Result<Self>;
+    fn avg_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self>;
+    fn max_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self>;
     fn upsample_nearest1d(&self, _: &Layout, _: usize) -> Result<Self>;
     fn upsample_nearest2d(&self, _: &Layout, _: usize, _: usize) -> Result<Self>;
+    fn upsample_nearest3d(&self, _: &Layout, _: usize, _: usize, _: usize) -> Result<Self>;
 
     fn gather(&self, _: &Layout, _: &Self, _: &Layout, _: usize) -> Result<Self>;
     fn scatter_add(
@@ -78,6 +111,15 @@ pub trait BackendStorage: Sized {
         _: &Layout,
         _: usize,
     ) -> Result<Self>;
+    fn scatter(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: usize,
+    ) -> Result<Self>;
     fn index_select(&self, _: &Self, _: &Layout, _: &Layout, _: usize) -> Result<Self>;
     fn index_add(
         &self,
@@ -89,6 +131,25 @@ pub trait BackendStorage: Sized {
         _: usize,
     ) -> Result<Self>;
 
+    fn index_copy(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: usize,
+    ) -> Result<Self>;
+
+    /// Replaces the elements for which the mask is non-zero with the given value.
+    fn masked_fill(&self, _: &Layout, _: &Self, _: &Layout, _: f64) -> Result<Self>;
+    /// Returns the elements for which the mask is non-zero as a 1d storage, together with the
+    /// number of such elements.
+    fn masked_select(&self, _: &Layout, _: &Self, _: &Layout) -> Result<(Self, usize)>;
+    /// Returns the u32 coordinates of the non-zero elements as a `(n, rank)` storage, together
+    /// with `n`.
+    fn nonzero(&self, _: &Layout) -> Result<(Self, usize)>;
+
     fn matmul(
         &self,
         _: &Self,
@@ -15,6 +15,73 @@ fn broadcast_back(arg: &Tensor, node: &Tensor, reduced_dims: &[usize]) -> Result
     }
 }
 
+// The gradient of the nearest neighbor upsampling of arg, each element of grad is accumulated
+// back into its source element along the spatial dims, i.e. all the dims after the channels.
+fn upsample_nearest_back(arg: &Tensor, grad: &Tensor) -> Result<Tensor> {
+    let mut grad_arg = grad.clone();
+    for dim in 2..arg.rank() {
+        let src_size = arg.dim(dim)?;
+        let dst_size = grad_arg.dim(dim)?;
+        let scale = src_size as f64 / dst_size as f64;
+        let indexes: Vec<u32> = (0..dst_size)
+            .map(|idx| usize::min(src_size - 1, (idx as f64 * scale) as usize) as u32)
+            .collect();
+        let indexes = Tensor::new(indexes, grad_arg.device())?;
+        let mut dims = grad_arg.dims().to_vec();
+        dims[dim] = src_size;
+        let zeros = Tensor::zeros(dims, grad_arg.dtype(), grad_arg.device())?;
+        grad_arg = zeros.index_add(&indexes, &grad_arg.contiguous()?, dim)?;
+    }
+    Ok(grad_arg)
+}
+
+// The positions covered by the pooling windows along a dim, ordered by window.
+fn pool_indexes(out_size: usize, k: usize, stride: usize, arg: &Tensor) -> Result<Tensor> {
+    let indexes: Vec<u32> = (0..out_size)
+        .flat_map(|o| (0..k).map(move |i| (o * stride + i) as u32))
+        .collect();
+    Tensor::new(indexes, arg.device())
+}
+
+// Extracts the pooling windows of a (b, c, h, w) tensor as a (b, c, h_out, k_h, w_out, k_w)
+// tensor, the windows may overlap.
+fn pool2d_windows(
+    arg: &Tensor,
+    (k_h, k_w): (usize, usize),
+    (s_h, s_w): (usize, usize),
+) -> Result<Tensor> {
+    let (b, c, h, w) = arg.dims4()?;
+    let (h_out, w_out) = ((h - k_h) / s_h + 1, (w - k_w) / s_w + 1);
+    let h_idxs = pool_indexes(h_out, k_h, s_h, arg)?;
+    let w_idxs = pool_indexes(w_out, k_w, s_w, arg)?;
+    arg.contiguous()?
+        .index_select(&h_idxs, 2)?
+        .reshape((b, c, h_out, k_h, w))?
+        .index_select(&w_idxs, 4)?
+        .reshape((b, c, h_out, k_h, w_out, k_w))
+}
+
+// The adjoint of pool2d_windows, the elements of the windows are summed back into a
+// (b, c, h, w) tensor.
+fn pool2d_windows_back(
+    windows: &Tensor,
+    (h, w): (usize, usize),
+    (s_h, s_w): (usize, usize),
+) -> Result<Tensor> {
+    let &[b, c, h_out, k_h, w_out, k_w] = windows.dims() else {
+        crate::bail!("unexpected shape for pooling windows {:?}", windows.shape())
+    };
+    let h_idxs = pool_indexes(h_out, k_h, s_h, windows)?;
+    let w_idxs = pool_indexes(w_out, k_w, s_w, windows)?;
+    let windows = windows.reshape((b, c, h_out, k_h, w_out * k_w))?;
+    let zeros = Tensor::zeros((b, c, h_out, k_h, w), windows.dtype(), windows.device())?;
+    let xs = zeros
+        .index_add(&w_idxs, &windows, 4)?
+        .reshape((b, c, h_out * k_h, w))?;
+    let zeros = Tensor::zeros((b, c, h, w), xs.dtype(), xs.device())?;
+    zeros.index_add(&h_idxs, &xs, 2)
+}
+
 thread_local! {
     static CANDLE_GRAD_DO_NOT_DETACH: bool = {
         match std::env::var("CANDLE_GRAD_DO_NOT_DETACH") {
@@ -31,35 +98,45 @@ impl Tensor {
     /// elements having dependencies on the latter ones, e.g. the first element if any is the
     /// argument.
     /// This assumes that the op graph is a DAG.
-    fn sorted_nodes(&self) -> Vec<&Tensor> {
+    /// The graph is not walked past the nodes for which `is_leaf` returns true, these nodes are
+    /// not part of the returned vec.
+    pub(crate) fn sorted_nodes(&self, is_leaf: &dyn Fn(&Tensor) -> bool) -> Vec<&Tensor> {
         // The vec of sorted nodes is passed as an owned value rather than a mutable reference
         // to get around some lifetime limitations.
         fn walk<'a>(
             node: &'a Tensor,
             nodes: Vec<&'a Tensor>,
             already_seen: &mut HashMap<TensorId, bool>,
+            is_leaf: &dyn Fn(&Tensor) -> bool,
         ) -> (bool, Vec<&'a Tensor>) {
             if let Some(&tg) = already_seen.get(&node.id()) {
                 return (tg, nodes);
             }
+            if is_leaf(node) {
+                let track_grad = node.track_op();
+                already_seen.insert(node.id(), track_grad);
+                return (track_grad, nodes);
+            }
             let mut track_grad = false;
             let mut nodes = if node.is_variable() {
                 // Do not call recursively on the "leaf" nodes.
                 track_grad = true;
                 nodes
-            } else if node.dtype().is_int() {
+            } else if !node.dtype().is_float() {
                 nodes
             } else if let Some(op) = node.op() {
                 match op {
                     Op::IndexAdd(t1, t2, t3, _)
                     | Op::ScatterAdd(t1, t2, t3, _)
+                    | Op::IndexCopy(t1, t2, t3, _)
+                    | Op::Scatter(t1, t2, t3, _)
                     | Op::CustomOp3(t1, t2, t3, _)
                     | Op::WhereCond(t1, t2, t3) => {
-                        let (tg, nodes) = walk(t1, nodes, already_seen);
+                        let (tg, nodes) = walk(t1, nodes, already_seen, is_leaf);
                         track_grad |= tg;
-                        let (tg, nodes) = walk(t2, nodes, already_seen);
+                        let (tg, nodes) = walk(t2, nodes, already_seen, is_leaf);
                         track_grad |= tg;
-                        let (tg, nodes) = walk(t3, nodes, already_seen);
+                        let (tg, nodes) = walk(t3, nodes, already_seen, is_leaf);
                         track_grad |= tg;
                         nodes
                     }
@@ -83,28 +160,41 @@ impl Tensor {
                         kernel: rhs,
                         ..
                     }
+                    | Op::Conv3D {
+                        arg: lhs,
+                        kernel: rhs,
+                        ..
+                    }
+                    | Op::ConvTranspose3D {
+                        arg: lhs,
+                        kernel: rhs,
+                        ..
+                    }
                     | Op::CustomOp2(lhs, rhs, _)
                     | Op::Binary(lhs, rhs, _)
                     | Op::Gather(lhs, rhs, _)
                     | Op::IndexSelect(lhs, rhs, _)
                     | Op::Matmul(lhs, rhs)
+                    | Op::Solve(lhs, rhs)
                     | Op::SliceScatter0(lhs, rhs, _) => {
-                        let (tg, nodes) = walk(lhs, nodes, already_seen);
+                        let (tg, nodes) = walk(lhs, nodes, already_seen, is_leaf);
                         track_grad |= tg;
-                        let (tg, nodes) = walk(rhs, nodes, already_seen);
+                        let (tg, nodes) = walk(rhs, nodes, already_seen, is_leaf);
                         track_grad |= tg;
                         nodes
                     }
-                    Op::Cat(args, _) => args.iter().fold(nodes, |nodes, arg| {
-                        let (tg, nodes) = walk(arg, nodes, already_seen);
-                        track_grad |= tg;
-                        nodes
-                    }),
+                    Op::Cat(args, _) | Op::Checkpoint(args, _) => {
+                        args.iter().fold(nodes, |nodes, arg| {
+                            let (tg, nodes) = walk(arg, nodes, already_seen, is_leaf);
+                            track_grad |= tg;
+                            nodes
+                        })
+                    }
                     Op::Affine { arg, mul, .. } => {
                         if *mul == 0. {
                             nodes
                         } else {
-                            let (tg, nodes) = walk(arg, nodes, already_seen);
+                            let (tg, nodes) = walk(arg, nodes, already_seen, is_leaf);
                             track_grad |= tg;
                             nodes
                         }
@@ -117,10 +207,19 @@ impl Tensor {
                     | Op::UpsampleNearest2D(node)
                     | Op::AvgPool2D { arg: node, .. }
                     | Op::MaxPool2D { arg: node, .. }
+                    | Op::UpsampleNearest3D(node)
+                    | Op::AvgPool3D { arg: node, .. }
+                    | Op::MaxPool3D { arg: node, .. }
+                    | Op::MaskedFill(node, _)
+                    | Op::MaskedSelect(node, _)
                     | Op::Copy(node)
                     | Op::Broadcast(node)
                     | Op::Cmp(node, _)
-                    | Op::Reduce(node, ReduceOp::Min | ReduceOp::Sum | ReduceOp::Max, _)
+                    | Op::Reduce(
+                        node,
+                        ReduceOp::Min | ReduceOp::Sum | ReduceOp::Prod | ReduceOp::Max,
+                        _,
+                    )
                     | Op::ToDevice(node)
                     | Op::Transpose(node, _, _)
                     | Op::Permute(node, _)
@@ -128,14 +227,17 @@ impl Tensor {
                     | Op::Unary(node, _)
                     | Op::Elu(node, _)
                     | Op::Powf(node, _)
+                    | Op::Inverse(node)
+                    | Op::Cholesky(node)
+                    | Op::Det(node)
                     | Op::CustomOp1(node, _) => {
-                        let (tg, nodes) = walk(node, nodes, already_seen);
+                        let (tg, nodes) = walk(node, nodes, already_seen, is_leaf);
                         track_grad |= tg;
                         nodes
                     }
                     Op::ToDType(node) => {
                         if node.dtype().is_float() {
-                            let (tg, nodes) = walk(node, nodes, already_seen);
+                            let (tg, nodes) = walk(node, nodes, already_seen, is_leaf);
                             track_grad |= tg;
                             nodes
                         } else {
@@ -153,15 +255,45 @@ impl Tensor {
             }
             (track_grad, nodes)
         }
-        let (_tg, mut nodes) = walk(self, vec![], &mut HashMap::new());
+        let (_tg, mut nodes) = walk(self, vec![], &mut HashMap::new(), is_leaf);
         nodes.reverse();
         nodes
     }
 
     pub fn backward(&self) -> Result<GradStore> {
-        let sorted_nodes = self.sorted_nodes();
+        self.backward_with_leaves(self.ones_like()?.contiguous()?, &|_| false, false)
+    }
+
+    /// Same as `backward` but the returned gradients are part of the computation graph, so they
+    /// can themselves be backpropagated through to get higher order derivatives.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device, Var};
+    /// let x = Var::new(&[1f32, 2.], &Device::Cpu)?;
+    /// let grads = x.powf(3.)?.sum_all()?.backward_with_graph()?;
+    /// let dx = grads.get(&x).unwrap();
+    /// assert_eq!(dx.to_vec1::<f32>()?, &[3., 12.]);
+    /// let grads = dx.sum_all()?.backward()?;
+    /// assert_eq!(grads.get(&x).unwrap().to_vec1::<f32>()?, &[6., 12.]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn backward_with_graph(&self) -> Result<GradStore> {
+        self.backward_with_leaves(self.ones_like()?.contiguous()?, &|_| false, true)
+    }
+
+    /// Backpropagates `grad`, the gradient of some value with respect to this tensor. The nodes
+    /// for which `is_leaf` returns true are handled as variables, the graph is not walked past
+    /// them and their gradients are part of the returned store. When `create_graph` is set, the
+    /// gradients are not detached from the computation graph.
+    pub(crate) fn backward_with_leaves(
+        &self,
+        grad: Tensor,
+        is_leaf: &dyn Fn(&Tensor) -> bool,
+        create_graph: bool,
+    ) -> Result<GradStore> {
+        let sorted_nodes = self.sorted_nodes(is_leaf);
         let mut grads = GradStore::new();
-        grads.insert(self, self.ones_like()?.contiguous()?);
+        grads.insert(self, grad);
         for node in sorted_nodes.iter() {
             if node.is_variable() {
                 continue;
@@ -172,10 +304,14 @@ impl Tensor {
             // https://github.com/huggingface/candle/issues/1241
             // Ideally, we would make these operations in place where possible to ensure that we
             // do not have to allocate too often. Here we just call `.detach` to avoid computing
-            // the backprop graph of the backprop itself. This would be an issue for second order
-            // derivatives but these are out of scope at the moment.
+            // the backprop graph of the backprop itself. When `create_graph` is set, e.g. via
+            // `backward_with_graph` for second order derivatives, the graph is kept instead.
             let do_not_detach = CANDLE_GRAD_DO_NOT_DETACH.with(|b| *b);
-            let grad = if do_not_detach { grad } else { grad.detach()? };
+            let grad = if do_not_detach || create_graph {
+                grad
+            } else {
+                grad.detach()?
+            };
             if let Some(op) = node.op() {
                 match op {
                     Op::Binary(lhs, rhs, BinaryOp::Add) => {
@@ -306,53 +442,197 @@ impl Tensor {
                         };
                         *sum_grad = sum_grad.add(&grad_kernel)?;
                     }
-                    Op::ConvTranspose1D { .. } => Err(Error::BackwardNotSupported {
-                        op: "conv-transpose1d",
-                    })?,
-                    Op::ConvTranspose2D { .. } => Err(Error::BackwardNotSupported {
-                        op: "conv-transpose2d",
-                    })?,
+                    Op::ConvTranspose1D {
+                        arg,
+                        kernel,
+                        padding,
+                        output_padding: _,
+                        stride,
+                        dilation,
+                    } => {
+                        let grad_arg = grad.conv1d(kernel, *padding, *stride, *dilation, 1)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = grad
+                            .transpose(0, 1)?
+                            .conv1d(&arg.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0) = kernel.dims3()?;
+                        let grad_kernel = grad_kernel.narrow(2, 0, k0)?;
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
+                    Op::ConvTranspose2D {
+                        arg,
+                        kernel,
+                        padding,
+                        output_padding: _,
+                        stride,
+                        dilation,
+                    } => {
+                        let grad_arg = grad.conv2d(kernel, *padding, *stride, *dilation, 1)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = grad
+                            .transpose(0, 1)?
+                            .conv2d(&arg.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0, k1) = kernel.dims4()?;
+                        let grad_kernel = grad_kernel.narrow(2, 0, k0)?.narrow(3, 0, k1)?;
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
                     Op::AvgPool2D {
                         arg,
                         kernel_size,
                         stride,
+                    } => {
+                        // Each window element gets the window gradient scaled by the window size,
+                        // windows can overlap so the contributions are summed back.
+                        let (b, c, h, w) = arg.dims4()?;
+                        let (k_h, k_w) = *kernel_size;
+                        let (_, _, h_out, w_out) = grad.dims4()?;
+                        let grad = (grad * (1f64 / (k_h * k_w) as f64))?;
+                        let windows = grad
+                            .reshape((b, c, h_out, 1, w_out, 1))?
+                            .broadcast_as((b, c, h_out, k_h, w_out, k_w))?;
+                        let grad_arg = pool2d_windows_back(&windows, (h, w), *stride)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+                    }
+                    Op::MaxPool2D {
+                        arg,
+                        kernel_size,
+                        stride,
+                    } => {
+                        // For computing the max-pool gradient, we compute a mask over each window
+                        // where a 1 means that the element is the maximum, the window gradient is
+                        // then shared between the maximums of the window and the contributions
+                        // of the possibly overlapping windows are summed back.
+                        let (b, c, h, w) = arg.dims4()?;
+                        let (_, _, h_out, w_out) = grad.dims4()?;
+                        let windows = pool2d_windows(arg, *kernel_size, *stride)?;
+                        let node = node.reshape((b, c, h_out, 1, w_out, 1))?;
+                        let mask = windows.broadcast_eq(&node)?.to_dtype(arg.dtype())?;
+                        let count = mask.sum_keepdim(3)?.sum_keepdim(5)?;
+                        let grad = grad.reshape((b, c, h_out, 1, w_out, 1))?;
+                        let windows = mask.broadcast_mul(&(grad / count)?)?;
+                        let grad_arg = pool2d_windows_back(&windows, (h, w), *stride)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+                    }
+                    Op::UpsampleNearest1D(arg) | Op::UpsampleNearest2D(arg) => {
+                        let grad_arg = upsample_nearest_back(arg, &grad)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+                    }
+                    Op::Conv3D {
+                        arg,
+                        kernel,
+                        padding,
+                        stride,
+                        dilation,
+                    } => {
+                        // The output depth for conv_transpose3d is:
+                        // (i_d - 1) * stride - 2 * padding + dilation * (k_d - 1) + out_padding + 1
+                        let grad_d = grad.dim(2)?;
+                        let k_d = kernel.dim(2)?;
+                        let out_size =
+                            (grad_d - 1) * stride + dilation * (k_d - 1) + 1 - 2 * padding;
+                        let out_padding = arg.dim(2)? - out_size;
+                        let grad_arg = grad.conv_transpose3d(
+                            kernel,
+                            *padding,
+                            out_padding,
+                            *stride,
+                            *dilation,
+                        )?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = arg
+                            .transpose(0, 1)?
+                            .conv3d(&grad.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0, k1, k2) = kernel.dims5()?;
+                        let (_, _, g_k0, g_k1, g_k2) = grad_kernel.dims5()?;
+                        let grad_kernel = if g_k0 != k0 || g_k1 != k1 || g_k2 != k2 {
+                            grad_kernel
+                                .narrow(2, 0, k0)?
+                                .narrow(3, 0, k1)?
+                                .narrow(4, 0, k2)?
+                        } else {
+                            grad_kernel
+                        };
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
+                    Op::ConvTranspose3D {
+                        arg,
+                        kernel,
+                        padding,
+                        output_padding: _,
+                        stride,
+                        dilation,
+                    } => {
+                        let grad_arg = grad.conv3d(kernel, *padding, *stride, *dilation, 1)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+
+                        let grad_kernel = grad
+                            .transpose(0, 1)?
+                            .conv3d(&arg.transpose(0, 1)?, *padding, *dilation, *stride, 1)?
+                            .transpose(0, 1)?;
+                        let sum_grad = grads.or_insert(kernel)?;
+                        let (_, _, k0, k1, k2) = kernel.dims5()?;
+                        let grad_kernel = grad_kernel
+                            .narrow(2, 0, k0)?
+                            .narrow(3, 0, k1)?
+                            .narrow(4, 0, k2)?;
+                        *sum_grad = sum_grad.add(&grad_kernel)?;
+                    }
+                    Op::AvgPool3D {
+                        arg,
+                        kernel_size,
+                        stride,
                     } => {
                         if kernel_size != stride {
-                            crate::bail!("backward not supported for avgpool2d if ksize {kernel_size:?} != stride {stride:?}")
+                            crate::bail!("backward not supported for avgpool3d if ksize {kernel_size:?} != stride {stride:?}")
                         }
-                        let (_n, _c, h, w) = arg.dims4()?;
-                        let grad_arg = grad.upsample_nearest2d(h, w)?;
-                        let grad_arg =
-                            (grad_arg * (1f64 / (kernel_size.0 * kernel_size.1) as f64))?;
+                        let (_n, _c, d, h, w) = arg.dims5()?;
+                        let (k_d, k_h, k_w) = *kernel_size;
+                        let grad_arg = grad.upsample_nearest3d(d, h, w)?;
+                        let grad_arg = (grad_arg * (1f64 / (k_d * k_h * k_w) as f64))?;
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&grad_arg)?;
                     }
-                    Op::MaxPool2D {
+                    Op::MaxPool3D {
                         arg,
                         kernel_size,
                         stride,
                     } => {
                         if kernel_size != stride {
-                            crate::bail!("backward not supported for maxpool2d if ksize {kernel_size:?} != stride {stride:?}")
+                            crate::bail!("backward not supported for maxpool3d if ksize {kernel_size:?} != stride {stride:?}")
                         }
-                        let (_n, _c, h, w) = arg.dims4()?;
-                        // For computing the max-pool gradient, we compute a mask where a 1 means
-                        // that the element is the maximum, then we apply this mask to the
-                        // upsampled gradient (taking into account that multiple max may exist so
-                        // we scale the gradient for this case).
-                        let node_upsampled = node.upsample_nearest2d(h, w)?;
+                        let (_n, _c, d, h, w) = arg.dims5()?;
+                        // A mask selects the maximum elements and the upsampled gradient is
+                        // shared between them when there are multiple maximums in a window.
+                        let node_upsampled = node.upsample_nearest3d(d, h, w)?;
                         let mask = arg.eq(&node_upsampled)?.to_dtype(arg.dtype())?;
-                        let avg = mask.avg_pool2d_with_stride(*kernel_size, *stride)?;
-                        let grad_arg = ((grad * avg)?.upsample_nearest2d(h, w)? * mask)?;
+                        let (k_d, k_h, k_w) = *kernel_size;
+                        let count = (mask.avg_pool3d_with_stride(*kernel_size, *stride)?
+                            * (k_d * k_h * k_w) as f64)?;
+                        let grad_arg = ((grad / count)?.upsample_nearest3d(d, h, w)? * mask)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&grad_arg)?;
+                    }
+                    Op::UpsampleNearest3D(arg) => {
+                        let grad_arg = upsample_nearest_back(arg, &grad)?;
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&grad_arg)?;
                     }
-                    Op::UpsampleNearest1D { .. } => Err(Error::BackwardNotSupported {
-                        op: "upsample-nearest1d",
-                    })?,
-                    Op::UpsampleNearest2D { .. } => Err(Error::BackwardNotSupported {
-                        op: "upsample-nearest2d",
-                    })?,
                     Op::SliceScatter0(lhs, rhs, start_rhs) => {
                         let rhs_sum_grad = grads.or_insert(rhs)?;
                         let rhs_grad = grad.narrow(0, *start_rhs, rhs.dim(0)?)?;
@@ -386,6 +666,39 @@ impl Tensor {
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.index_add(indexes, &grad, *dim)?;
                     }
+                    Op::Scatter(init, indexes, src, dim) => {
+                        // The overwritten positions do not contribute to the result.
+                        let init_grad = grad.scatter(indexes, &src.zeros_like()?, *dim)?;
+                        let init_sum_grad = grads.or_insert(init)?;
+                        *init_sum_grad = init_sum_grad.add(&init_grad)?;
+
+                        let src_grad = grad.gather(indexes, *dim)?;
+                        let src_sum_grad = grads.or_insert(src)?;
+                        *src_sum_grad = src_sum_grad.add(&src_grad)?;
+                    }
+                    Op::IndexCopy(init, indexes, src, dim) => {
+                        let init_grad = grad.index_copy(indexes, &src.zeros_like()?, *dim)?;
+                        let init_sum_grad = grads.or_insert(init)?;
+                        *init_sum_grad = init_sum_grad.add(&init_grad)?;
+
+                        let src_grad = grad.index_select(indexes, *dim)?;
+                        let src_sum_grad = grads.or_insert(src)?;
+                        *src_sum_grad = src_sum_grad.add(&src_grad)?;
+                    }
+                    Op::MaskedFill(arg, mask) => {
+                        let arg_grad = grad.masked_fill(mask, 0.)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad)?;
+                    }
+                    Op::MaskedSelect(arg, mask) => {
+                        let ids = mask.flatten_all()?.nonzero()?.squeeze(1)?;
+                        let arg_grad =
+                            Tensor::zeros(arg.elem_count(), grad.dtype(), grad.device())?
+                                .index_add(&ids, &grad, 0)?
+                                .reshape(arg.shape())?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad)?;
+                    }
                     Op::Matmul(lhs, rhs) => {
                         // Skipping checks, the op went ok, we can skip
                         // the matmul size checks for now.
@@ -398,6 +711,15 @@ impl Tensor {
                         let rhs_sum_grad = grads.or_insert(rhs)?;
                         *rhs_sum_grad = rhs_sum_grad.add(&rhs_grad)?;
                     }
+                    Op::Checkpoint(args, checkpoint) => {
+                        let arg_grads = checkpoint.backward(args, grad, create_graph)?;
+                        for (arg, arg_grad) in args.iter().zip(arg_grads) {
+                            if let Some(arg_grad) = arg_grad {
+                                let sum_grad = grads.or_insert(arg)?;
+                                *sum_grad = sum_grad.add(&arg_grad)?;
+                            }
+                        }
+                    }
                     Op::Cat(args, dim) => {
                         let mut start_idx = 0;
                         for arg in args {
@@ -436,6 +758,29 @@ impl Tensor {
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&grad)?;
                     }
+                    Op::Reduce(arg, ReduceOp::Prod, reduced_dims) => {
+                        // The gradient of an element is the product of the other elements, this
+                        // is computed without dividing by zero so that zeros are supported.
+                        let dims: Vec<usize> = (0..reduced_dims.len())
+                            .filter(|&i| reduced_dims[i] == 1)
+                            .collect();
+                        let is_zero = arg.eq(0f64)?;
+                        let zeros = arg.zeros_like()?;
+                        let non_zeros = is_zero.where_cond(&arg.ones_like()?, arg)?;
+                        let n_zeros = is_zero
+                            .to_dtype(arg.dtype())?
+                            .sum_keepdim(dims.as_slice())?;
+                        let n_zeros = n_zeros.broadcast_as(arg.shape())?;
+                        let prod = non_zeros.prod_keepdim(dims)?.broadcast_as(arg.shape())?;
+                        let grad_zero = n_zeros.eq(1f64)?.where_cond(&prod, &zeros)?;
+                        let grad_non_zero = n_zeros
+                            .eq(0f64)?
+                            .where_cond(&(&prod / &non_zeros)?, &zeros)?;
+                        let grad = broadcast_back(arg, &grad, reduced_dims)?;
+                        let arg_grad = is_zero.where_cond(&grad_zero, &grad_non_zero)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad.mul(&grad)?)?;
+                    }
                     Op::Cmp(_args, _) => {}
                     Op::Reduce(arg, ReduceOp::Max, reduced_dims) => {
                         let node = broadcast_back(arg, node, reduced_dims)?;
@@ -586,6 +931,47 @@ impl Tensor {
                         let sum_grad = grads.or_insert(arg)?;
                         *sum_grad = sum_grad.add(&arg_grad)?
                     }
+                    Op::Inverse(arg) => {
+                        // d(A^-1) = -A^-1 dA A^-1
+                        let inv_t = node.t()?;
+                        let arg_grad = inv_t.matmul(&grad)?.matmul(&inv_t)?.neg()?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad)?
+                    }
+                    Op::Solve(lhs, rhs) => {
+                        // x = A^-1 b, the gradient for b is A^-T grad and the one for A is
+                        // -A^-T grad x^T.
+                        let rhs_grad = crate::linalg::solve(&lhs.t()?, &grad)?;
+                        let lhs_grad = rhs_grad.matmul(&node.t()?)?.neg()?;
+                        let lhs_sum_grad = grads.or_insert(lhs)?;
+                        *lhs_sum_grad = lhs_sum_grad.add(&lhs_grad)?;
+                        let rhs_sum_grad = grads.or_insert(rhs)?;
+                        *rhs_sum_grad = rhs_sum_grad.add(&rhs_grad)?;
+                    }
+                    Op::Cholesky(arg) => {
+                        // With A = L L^T and Φ taking the lower triangular part with a halved
+                        // diagonal, the symmetrized gradient is S + S^T over 2 with
+                        // S = L^-T Φ(L^T grad) L^-1.
+                        let n = node.dim(node.rank() - 1)?;
+                        let phi = (Tensor::tril2(n, node.dtype(), node.device())?
+                            - (Tensor::eye(n, node.dtype(), node.device())? * 0.5)?)?;
+                        let phi = node.t()?.matmul(&grad)?.broadcast_mul(&phi)?;
+                        let l_inv = crate::linalg::inverse(node)?;
+                        let s = l_inv.t()?.matmul(&phi)?.matmul(&l_inv)?;
+                        let arg_grad = ((&s + s.t()?)? * 0.5)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad)?
+                    }
+                    Op::Det(arg) => {
+                        // d det(A) = cof(A), which equals det(A) A^-T for invertible matrices
+                        // but is also defined for singular ones.
+                        let cofactors = crate::linalg::det_cofactors(arg)?;
+                        let rank = node.rank();
+                        let scale = grad.unsqueeze(rank)?.unsqueeze(rank + 1)?;
+                        let arg_grad = cofactors.broadcast_mul(&scale)?;
+                        let sum_grad = grads.or_insert(arg)?;
+                        *sum_grad = sum_grad.add(&arg_grad)?
+                    }
                     Op::CustomOp1(arg, c) => {
                         if let Some(arg_grad) = c.bwd(arg, node, &grad)? {
                             let sum_grad = grads.or_insert(arg)?;
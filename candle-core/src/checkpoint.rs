@@ -0,0 +1,81 @@
+//! Gradient checkpointing, the intermediate values of a computation are not kept around for
+//! backpropagation but are recomputed when needed.
+use crate::op::{BackpropOp, Op};
+use crate::{Result, Tensor, TensorId};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+type CheckpointFn = dyn Fn(&[Tensor]) -> Result<Tensor> + Send + Sync;
+
+/// The recomputation of a checkpointed closure, the leaves of the recomputed graph are the
+/// arguments of the `Op::Checkpoint` node.
+pub(crate) struct Checkpoint {
+    inputs: Vec<Tensor>,
+    f: Box<CheckpointFn>,
+}
+
+impl Checkpoint {
+    /// Recomputes the closure and returns the gradients of `leaves` for `grad`, the gradient
+    /// with respect to the closure output.
+    pub(crate) fn backward(
+        &self,
+        leaves: &[Tensor],
+        grad: Tensor,
+        create_graph: bool,
+    ) -> Result<Vec<Option<Tensor>>> {
+        let leaf_ids: HashSet<TensorId> = leaves.iter().map(|t| t.id()).collect();
+        let res = (self.f)(&self.inputs)?;
+        let is_leaf = |t: &Tensor| leaf_ids.contains(&t.id());
+        let grads = res.backward_with_leaves(grad, &is_leaf, create_graph)?;
+        Ok(leaves.iter().map(|t| grads.get(t).cloned()).collect())
+    }
+}
+
+/// Applies `f` to `xs` without keeping the intermediate values of the computation for
+/// backpropagation.
+///
+/// The returned tensor is a single node of the computation graph, its dependencies are the
+/// tensors from `xs` and the tensors captured by `f` that require some gradient. When
+/// backpropagating, `f` is called again to recompute the intermediate values so this trades
+/// some compute for a lower memory usage. `f` should be deterministic, e.g. it should not use
+/// dropout, as otherwise the gradients would not match the forward pass.
+///
+/// ```rust
+/// use candle_core::{Tensor, Device, Var};
+/// let w = Var::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+/// let x = Tensor::new(&[[1f32, 1.]], &Device::Cpu)?;
+/// let w_ = w.as_tensor().clone();
+/// let y = candle_core::checkpoint(move |xs| xs[0].matmul(&w_)?.relu()?.sqr(), &[&x])?;
+/// assert_eq!(y.to_vec2::<f32>()?, &[[16., 36.]]);
+/// let grads = y.sum_all()?.backward()?;
+/// assert_eq!(grads.get(&w).unwrap().to_vec2::<f32>()?, &[[8., 12.], [8., 12.]]);
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub fn checkpoint<F>(f: F, xs: &[&Tensor]) -> Result<Tensor>
+where
+    F: Fn(&[Tensor]) -> Result<Tensor> + Send + Sync + 'static,
+{
+    let inputs: Vec<Tensor> = xs.iter().map(|&x| x.clone()).collect();
+    // All the tensors created by f have a larger id than this one.
+    let first_id = TensorId::new();
+    let res = f(&inputs)?;
+    // The leaves are the tensors from outside of f that have to be backpropagated through, i.e.
+    // the inputs and the captured tensors.
+    let leaves = RefCell::new(vec![]);
+    let input_ids: HashSet<TensorId> = inputs.iter().map(|t| t.id()).collect();
+    let _ = res.sorted_nodes(&|t| {
+        let is_leaf = t.id() < first_id || input_ids.contains(&t.id());
+        if is_leaf && t.track_op() {
+            leaves.borrow_mut().push(t.clone())
+        }
+        is_leaf
+    });
+    let leaves = leaves.into_inner();
+    let checkpoint = Arc::new(Checkpoint {
+        inputs,
+        f: Box::new(f),
+    });
+    let op = BackpropOp::new(&leaves, |leaves| Op::Checkpoint(leaves, checkpoint.clone()));
+    Ok(res.with_op(op))
+}
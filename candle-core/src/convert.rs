@@ -1,5 +1,5 @@
 //! Implement conversion traits for tensors
-use crate::{DType, Device, Error, Tensor, WithDType};
+use crate::{Bool, DType, Device, Error, Tensor, WithDType, F8E4M3, F8E5M2};
 use half::{bf16, f16, slice::HalfFloatSliceExt};
 use std::convert::TryFrom;
 
@@ -93,8 +93,14 @@ from_tensor!(f32);
 from_tensor!(f16);
 from_tensor!(bf16);
 from_tensor!(i64);
+from_tensor!(i32);
+from_tensor!(i16);
+from_tensor!(i8);
 from_tensor!(u32);
 from_tensor!(u8);
+from_tensor!(Bool);
+from_tensor!(F8E4M3);
+from_tensor!(F8E5M2);
 
 impl Tensor {
     pub fn write_bytes<W: std::io::Write>(&self, f: &mut W) -> crate::Result<()> {
@@ -130,6 +136,21 @@ impl Tensor {
                     f.write_u32::<LittleEndian>(v)?
                 }
             }
+            DType::I8 => {
+                for v in vs.to_vec1::<i8>()? {
+                    f.write_i8(v)?
+                }
+            }
+            DType::I16 => {
+                for v in vs.to_vec1::<i16>()? {
+                    f.write_i16::<LittleEndian>(v)?
+                }
+            }
+            DType::I32 => {
+                for v in vs.to_vec1::<i32>()? {
+                    f.write_i32::<LittleEndian>(v)?
+                }
+            }
             DType::I64 => {
                 for v in vs.to_vec1::<i64>()? {
                     f.write_i64::<LittleEndian>(v)?
@@ -139,6 +160,21 @@ impl Tensor {
                 let vs = vs.to_vec1::<u8>()?;
                 f.write_all(&vs)?;
             }
+            DType::Bool => {
+                for v in vs.to_vec1::<Bool>()? {
+                    f.write_u8(u8::from(v.0))?
+                }
+            }
+            DType::F8E4M3 => {
+                for v in vs.to_vec1::<F8E4M3>()? {
+                    f.write_u8(v.to_bits())?
+                }
+            }
+            DType::F8E5M2 => {
+                for v in vs.to_vec1::<F8E5M2>()? {
+                    f.write_u8(v.to_bits())?
+                }
+            }
         }
         Ok(())
     }
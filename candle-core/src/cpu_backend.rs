@@ -1,5 +1,5 @@
 use crate::backend::{BackendDevice, BackendStorage};
-use crate::op::{BinaryOpT, CmpOp, ReduceOp, UnaryOpT};
+use crate::op::{BinaryOp, BinaryOpT, CmpOp, ReduceOp, UnaryOp, UnaryOpT};
 use crate::{DType, Error, IntDType, Layout, Result, Shape, WithDType};
 use half::{bf16, f16};
 use rayon::prelude::*;
@@ -13,7 +13,13 @@ const USE_IM2COL_CONV2D: bool = true;
 pub enum CpuStorage {
     U8(Vec<u8>),
     U32(Vec<u32>),
+    I8(Vec<i8>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
     I64(Vec<i64>),
+    Bool(Vec<crate::Bool>),
+    F8E4M3(Vec<crate::F8E4M3>),
+    F8E5M2(Vec<crate::F8E5M2>),
     BF16(Vec<bf16>),
     F16(Vec<f16>),
     F32(Vec<f32>),
@@ -30,7 +36,13 @@ pub trait Map1 {
         match vs {
             CpuStorage::U8(vs) => Ok(CpuStorage::U8(self.f(vs, layout)?)),
             CpuStorage::U32(vs) => Ok(CpuStorage::U32(self.f(vs, layout)?)),
+            CpuStorage::I8(vs) => Ok(CpuStorage::I8(self.f(vs, layout)?)),
+            CpuStorage::I16(vs) => Ok(CpuStorage::I16(self.f(vs, layout)?)),
+            CpuStorage::I32(vs) => Ok(CpuStorage::I32(self.f(vs, layout)?)),
             CpuStorage::I64(vs) => Ok(CpuStorage::I64(self.f(vs, layout)?)),
+            CpuStorage::Bool(vs) => Ok(CpuStorage::Bool(self.f(vs, layout)?)),
+            CpuStorage::F8E4M3(vs) => Ok(CpuStorage::F8E4M3(self.f(vs, layout)?)),
+            CpuStorage::F8E5M2(vs) => Ok(CpuStorage::F8E5M2(self.f(vs, layout)?)),
             CpuStorage::BF16(vs) => Ok(CpuStorage::BF16(self.f(vs, layout)?)),
             CpuStorage::F16(vs) => Ok(CpuStorage::F16(self.f(vs, layout)?)),
             CpuStorage::F32(vs) => Ok(CpuStorage::F32(self.f(vs, layout)?)),
@@ -51,7 +63,13 @@ pub trait Map1Any {
         match vs {
             CpuStorage::U8(vs) => Ok(self.f(vs, layout, CpuStorage::U8)?),
             CpuStorage::U32(vs) => Ok(self.f(vs, layout, CpuStorage::U32)?),
+            CpuStorage::I8(vs) => Ok(self.f(vs, layout, CpuStorage::I8)?),
+            CpuStorage::I16(vs) => Ok(self.f(vs, layout, CpuStorage::I16)?),
+            CpuStorage::I32(vs) => Ok(self.f(vs, layout, CpuStorage::I32)?),
             CpuStorage::I64(vs) => Ok(self.f(vs, layout, CpuStorage::I64)?),
+            CpuStorage::Bool(vs) => Ok(self.f(vs, layout, CpuStorage::Bool)?),
+            CpuStorage::F8E4M3(vs) => Ok(self.f(vs, layout, CpuStorage::F8E4M3)?),
+            CpuStorage::F8E5M2(vs) => Ok(self.f(vs, layout, CpuStorage::F8E5M2)?),
             CpuStorage::BF16(vs) => Ok(self.f(vs, layout, CpuStorage::BF16)?),
             CpuStorage::F16(vs) => Ok(self.f(vs, layout, CpuStorage::F16)?),
             CpuStorage::F32(vs) => Ok(self.f(vs, layout, CpuStorage::F32)?),
@@ -75,7 +93,13 @@ pub trait Map2 {
         match (v1, v2) {
             (C::U8(v1), C::U8(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
             (C::U32(v1), C::U32(v2)) => Ok(C::U32(self.f(v1, l1, v2, l2)?)),
+            (C::I8(v1), C::I8(v2)) => Ok(C::I8(self.f(v1, l1, v2, l2)?)),
+            (C::I16(v1), C::I16(v2)) => Ok(C::I16(self.f(v1, l1, v2, l2)?)),
+            (C::I32(v1), C::I32(v2)) => Ok(C::I32(self.f(v1, l1, v2, l2)?)),
             (C::I64(v1), C::I64(v2)) => Ok(C::I64(self.f(v1, l1, v2, l2)?)),
+            (C::Bool(v1), C::Bool(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::F8E4M3(v1), C::F8E4M3(v2)) => Ok(C::F8E4M3(self.f(v1, l1, v2, l2)?)),
+            (C::F8E5M2(v1), C::F8E5M2(v2)) => Ok(C::F8E5M2(self.f(v1, l1, v2, l2)?)),
             (C::BF16(v1), C::BF16(v2)) => Ok(C::BF16(self.f(v1, l1, v2, l2)?)),
             (C::F16(v1), C::F16(v2)) => Ok(C::F16(self.f(v1, l1, v2, l2)?)),
             (C::F32(v1), C::F32(v2)) => Ok(C::F32(self.f(v1, l1, v2, l2)?)),
@@ -90,9 +114,15 @@ pub trait Map2 {
     }
 }
 
-pub trait Map2U8 {
+pub trait Map2Bool {
     const OP: &'static str;
-    fn f<T: WithDType>(&self, v1: &[T], l1: &Layout, v2: &[T], l2: &Layout) -> Result<Vec<u8>>;
+    fn f<T: WithDType>(
+        &self,
+        v1: &[T],
+        l1: &Layout,
+        v2: &[T],
+        l2: &Layout,
+    ) -> Result<Vec<crate::Bool>>;
 
     fn map(
         &self,
@@ -102,13 +132,19 @@ pub trait Map2U8 {
         l2: &Layout,
     ) -> Result<CpuStorage> {
         match (v1, v2) {
-            (C::U8(v1), C::U8(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::U32(v1), C::U32(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::I64(v1), C::I64(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::BF16(v1), C::BF16(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::F16(v1), C::F16(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::F32(v1), C::F32(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
-            (C::F64(v1), C::F64(v2)) => Ok(C::U8(self.f(v1, l1, v2, l2)?)),
+            (C::U8(v1), C::U8(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::U32(v1), C::U32(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::I8(v1), C::I8(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::I16(v1), C::I16(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::I32(v1), C::I32(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::I64(v1), C::I64(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::Bool(v1), C::Bool(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::F8E4M3(v1), C::F8E4M3(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::F8E5M2(v1), C::F8E5M2(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::BF16(v1), C::BF16(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::F16(v1), C::F16(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::F32(v1), C::F32(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
+            (C::F64(v1), C::F64(v2)) => Ok(C::Bool(self.f(v1, l1, v2, l2)?)),
             _ => Err(Error::DTypeMismatchBinaryOp {
                 lhs: v1.dtype(),
                 rhs: v2.dtype(),
@@ -120,7 +156,7 @@ pub trait Map2U8 {
 }
 
 struct Cmp(CmpOp);
-impl Map2U8 for Cmp {
+impl Map2Bool for Cmp {
     const OP: &'static str = "cmp";
     #[inline(always)]
     fn f<T: WithDType>(
@@ -129,14 +165,14 @@ impl Map2U8 for Cmp {
         lhs_l: &Layout,
         rhs: &[T],
         rhs_l: &Layout,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<Vec<crate::Bool>> {
         let dst = match self.0 {
-            CmpOp::Eq => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| u8::from(x == y)),
-            CmpOp::Ne => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| u8::from(x != y)),
-            CmpOp::Lt => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| u8::from(x < y)),
-            CmpOp::Le => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| u8::from(x <= y)),
-            CmpOp::Gt => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| u8::from(x > y)),
-            CmpOp::Ge => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| u8::from(x >= y)),
+            CmpOp::Eq => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| crate::Bool(x == y)),
+            CmpOp::Ne => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| crate::Bool(x != y)),
+            CmpOp::Lt => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| crate::Bool(x < y)),
+            CmpOp::Le => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| crate::Bool(x <= y)),
+            CmpOp::Gt => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| crate::Bool(x > y)),
+            CmpOp::Ge => binary_map(lhs_l, rhs_l, lhs, rhs, |x, y| crate::Bool(x >= y)),
         };
         Ok(dst)
     }
@@ -287,6 +323,82 @@ impl Map1Any for ReduceIndex {
     }
 }
 
+// Orders the values for sorting, NaNs come last for both the ascending and descending orders.
+fn sort_cmp<T: PartialOrd>(a: &T, b: &T, asc: bool) -> std::cmp::Ordering {
+    match a.partial_cmp(b) {
+        Some(ord) if asc => ord,
+        Some(ord) => ord.reverse(),
+        None => {
+            let a_nan = a.partial_cmp(a).is_none();
+            let b_nan = b.partial_cmp(b).is_none();
+            a_nan.cmp(&b_nan)
+        }
+    }
+}
+
+struct SortLastDim {
+    asc: bool,
+}
+
+impl SortLastDim {
+    fn f<T: WithDType>(&self, src: &[T], src_l: &Layout) -> Result<(Vec<T>, Vec<u32>)> {
+        let last_dim = match src_l.dims().last() {
+            None => crate::bail!("sort requires at least one dimension"),
+            Some(&last_dim) => last_dim,
+        };
+        let src = match src_l.contiguous_offsets() {
+            Some((o1, o2)) => std::borrow::Cow::Borrowed(&src[o1..o2]),
+            None => std::borrow::Cow::Owned(unary_map(src, src_l, |v| v)),
+        };
+        if last_dim == 0 {
+            return Ok((vec![], vec![]));
+        }
+        let mut indexes = vec![0u32; src.len()];
+        // The sort is stable so ties are ordered by index.
+        indexes
+            .par_chunks_mut(last_dim)
+            .zip(src.par_chunks(last_dim))
+            .for_each(|(indexes, src)| {
+                indexes
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(i, v)| *v = i as u32);
+                indexes.sort_by(|&i, &j| sort_cmp(&src[i as usize], &src[j as usize], self.asc))
+            });
+        let values = src
+            .chunks(last_dim)
+            .zip(indexes.chunks(last_dim))
+            .flat_map(|(src, indexes)| indexes.iter().map(|&i| src[i as usize]))
+            .collect();
+        Ok((values, indexes))
+    }
+
+    fn map(&self, vs: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, CpuStorage)> {
+        fn wrap<T>(
+            (values, indexes): (Vec<T>, Vec<u32>),
+            f: fn(Vec<T>) -> CpuStorage,
+        ) -> (CpuStorage, CpuStorage) {
+            (f(values), CpuStorage::U32(indexes))
+        }
+        let sorted = match vs {
+            CpuStorage::U8(vs) => wrap(self.f(vs, layout)?, CpuStorage::U8),
+            CpuStorage::U32(vs) => wrap(self.f(vs, layout)?, CpuStorage::U32),
+            CpuStorage::I8(vs) => wrap(self.f(vs, layout)?, CpuStorage::I8),
+            CpuStorage::I16(vs) => wrap(self.f(vs, layout)?, CpuStorage::I16),
+            CpuStorage::I32(vs) => wrap(self.f(vs, layout)?, CpuStorage::I32),
+            CpuStorage::I64(vs) => wrap(self.f(vs, layout)?, CpuStorage::I64),
+            CpuStorage::Bool(vs) => wrap(self.f(vs, layout)?, CpuStorage::Bool),
+            CpuStorage::F8E4M3(vs) => wrap(self.f(vs, layout)?, CpuStorage::F8E4M3),
+            CpuStorage::F8E5M2(vs) => wrap(self.f(vs, layout)?, CpuStorage::F8E5M2),
+            CpuStorage::BF16(vs) => wrap(self.f(vs, layout)?, CpuStorage::BF16),
+            CpuStorage::F16(vs) => wrap(self.f(vs, layout)?, CpuStorage::F16),
+            CpuStorage::F32(vs) => wrap(self.f(vs, layout)?, CpuStorage::F32),
+            CpuStorage::F64(vs) => wrap(self.f(vs, layout)?, CpuStorage::F64),
+        };
+        Ok(sorted)
+    }
+}
+
 struct ReduceSum<'a> {
     dst_shape: &'a Shape,
     reduce_dims: &'a [usize],
@@ -294,10 +406,21 @@ struct ReduceSum<'a> {
 }
 
 impl<'a> ReduceSum<'a> {
+    // `fold_slice` reduces a contiguous slice into the destination value and `fold` accumulates a
+    // single element into it.
     #[inline(always)]
-    fn fold_impl<T>(&self, src: &[T], src_l: &Layout, start_elt: T) -> Result<Vec<T>>
+    fn fold_impl<T, F1, F2>(
+        &self,
+        src: &[T],
+        src_l: &Layout,
+        start_elt: T,
+        fold_slice: F1,
+        fold: F2,
+    ) -> Result<Vec<T>>
     where
         T: WithDType,
+        F1: Fn(&[T], &mut T),
+        F2: Fn(&mut T, T),
     {
         let mut dst = vec![start_elt; self.dst_shape.elem_count()];
         match src_l.contiguous_offsets() {
@@ -320,13 +443,7 @@ impl<'a> ReduceSum<'a> {
                         .product::<usize>();
                     for (dst_i, dst_v) in dst.iter_mut().enumerate() {
                         let src_i = dst_i * reduce_sz;
-                        unsafe {
-                            T::vec_reduce_sum(
-                                src[src_i..src_i + reduce_sz].as_ptr(),
-                                dst_v,
-                                reduce_sz,
-                            )
-                        };
+                        fold_slice(&src[src_i..src_i + reduce_sz], dst_v);
                     }
                     return Ok(dst);
                 };
@@ -338,7 +455,7 @@ impl<'a> ReduceSum<'a> {
                         let (pre, post) = (dst_index / stride, dst_index % stride);
                         dst_index = (pre / dim) * stride + post;
                     }
-                    dst[dst_index] += src;
+                    fold(&mut dst[dst_index], src);
                 }
             }
             None => {
@@ -350,7 +467,7 @@ impl<'a> ReduceSum<'a> {
                         let (pre, post) = (dst_index / stride, dst_index % stride);
                         dst_index = (pre / dim) * stride + post;
                     }
-                    dst[dst_index] += src[src_index];
+                    fold(&mut dst[dst_index], src[src_index]);
                 }
             }
         }
@@ -361,7 +478,21 @@ impl<'a> ReduceSum<'a> {
 impl<'a> Map1 for ReduceSum<'a> {
     #[inline(always)]
     fn f<T: WithDType>(&self, src: &[T], src_l: &Layout) -> Result<Vec<T>> {
-        self.fold_impl(src, src_l, T::zero())
+        let fold_slice =
+            |src: &[T], dst: &mut T| unsafe { T::vec_reduce_sum(src.as_ptr(), dst, src.len()) };
+        self.fold_impl(src, src_l, T::zero(), fold_slice, |dst, src| *dst += src)
+    }
+}
+
+struct ReduceProd<'a>(ReduceSum<'a>);
+
+impl<'a> Map1 for ReduceProd<'a> {
+    #[inline(always)]
+    fn f<T: WithDType>(&self, src: &[T], src_l: &Layout) -> Result<Vec<T>> {
+        let fold_slice =
+            |src: &[T], dst: &mut T| *dst = src.iter().fold(T::one(), |acc, &v| acc * v);
+        self.0
+            .fold_impl(src, src_l, T::one(), fold_slice, |dst, src| *dst *= src)
     }
 }
 
@@ -727,6 +858,108 @@ impl Map1 for MaxPool2D {
     }
 }
 
+struct AvgPool3D((usize, usize, usize), (usize, usize, usize));
+
+impl Map1 for AvgPool3D {
+    fn f<T: WithDType>(&self, src: &[T], layout: &Layout) -> Result<Vec<T>> {
+        // https://pytorch.org/docs/stable/generated/torch.nn.AvgPool3d.html
+        let (k_d, k_h, k_w) = self.0;
+        let (s_d, s_h, s_w) = self.1;
+        let (b_sz, c, d, h, w) = layout.shape().dims5()?;
+        let stride = layout.stride();
+        let (stride_d, stride_h, stride_w) = (stride[2], stride[3], stride[4]);
+        let d_out = (d - k_d) / s_d + 1;
+        let h_out = (h - k_h) / s_h + 1;
+        let w_out = (w - k_w) / s_w + 1;
+        let src_index = layout.start_offset();
+        let mut dst = vec![T::zero(); b_sz * c * d_out * h_out * w_out];
+        let scale = 1f64 / (k_d * k_h * k_w) as f64;
+        let scale = T::from_f64(scale);
+        for b_idx in 0..b_sz {
+            let dst = &mut dst[b_idx * c * d_out * h_out * w_out..];
+            let src_index = src_index + b_idx * stride[0];
+            for c_idx in 0..c {
+                let dst = &mut dst[c_idx * d_out * h_out * w_out..];
+                let src_index = src_index + c_idx * stride[1];
+                for d_idx in 0..d_out {
+                    for h_idx in 0..h_out {
+                        for w_idx in 0..w_out {
+                            let mut sum = T::zero();
+                            for l in 0..k_d {
+                                for m in 0..k_h {
+                                    for n in 0..k_w {
+                                        let l = s_d * d_idx + l;
+                                        let m = s_h * h_idx + m;
+                                        let n = s_w * w_idx + n;
+                                        sum += src
+                                            [src_index + l * stride_d + m * stride_h + n * stride_w]
+                                    }
+                                }
+                            }
+                            dst[(d_idx * h_out + h_idx) * w_out + w_idx] = sum * scale;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
+struct MaxPool3D((usize, usize, usize), (usize, usize, usize));
+
+impl Map1 for MaxPool3D {
+    fn f<T: WithDType>(&self, src: &[T], layout: &Layout) -> Result<Vec<T>> {
+        // https://pytorch.org/docs/stable/generated/torch.nn.MaxPool3d.html
+        let (k_d, k_h, k_w) = self.0;
+        let (s_d, s_h, s_w) = self.1;
+        let (b_sz, c, d, h, w) = layout.shape().dims5()?;
+        let stride = layout.stride();
+        let (stride_d, stride_h, stride_w) = (stride[2], stride[3], stride[4]);
+        let d_out = (d - k_d) / s_d + 1;
+        let h_out = (h - k_h) / s_h + 1;
+        let w_out = (w - k_w) / s_w + 1;
+        let src_index = layout.start_offset();
+        let mut dst = vec![T::zero(); b_sz * c * d_out * h_out * w_out];
+        for b_idx in 0..b_sz {
+            let dst = &mut dst[b_idx * c * d_out * h_out * w_out..];
+            let src_index = src_index + b_idx * stride[0];
+            for c_idx in 0..c {
+                let dst = &mut dst[c_idx * d_out * h_out * w_out..];
+                let src_index = src_index + c_idx * stride[1];
+                for d_idx in 0..d_out {
+                    for h_idx in 0..h_out {
+                        for w_idx in 0..w_out {
+                            let mut largest = src[src_index
+                                + s_d * d_idx * stride_d
+                                + s_h * h_idx * stride_h
+                                + s_w * w_idx * stride_w];
+                            for l in 0..k_d {
+                                for m in 0..k_h {
+                                    for n in 0..k_w {
+                                        let l = s_d * d_idx + l;
+                                        let m = s_h * h_idx + m;
+                                        let n = s_w * w_idx + n;
+                                        let v = src[src_index
+                                            + l * stride_d
+                                            + m * stride_h
+                                            + n * stride_w];
+                                        if largest < v {
+                                            largest = v
+                                        }
+                                    }
+                                }
+                            }
+                            dst[(d_idx * h_out + h_idx) * w_out + w_idx] = largest;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
 struct UpsampleNearest1D(usize);
 
 impl Map1 for UpsampleNearest1D {
@@ -794,6 +1027,49 @@ impl Map1 for UpsampleNearest2D {
     }
 }
 
+struct UpsampleNearest3D(usize, usize, usize);
+
+impl Map1 for UpsampleNearest3D {
+    fn f<T: WithDType>(&self, src: &[T], layout: &Layout) -> Result<Vec<T>> {
+        let (dst_d, dst_h, dst_w) = (self.0, self.1, self.2);
+        let (b_sz, c, src_d, src_h, src_w) = layout.shape().dims5()?;
+        let stride = layout.stride();
+        let (stride_d, stride_h, stride_w) = (stride[2], stride[3], stride[4]);
+        let src_index = layout.start_offset();
+        let src_idxs = |dst_sz: usize, src_sz: usize| {
+            let scale = src_sz as f64 / dst_sz as f64;
+            (0..dst_sz)
+                .map(|idx| usize::min(src_sz - 1, (idx as f64 * scale) as usize))
+                .collect::<Vec<_>>()
+        };
+        let src_d_idxs = src_idxs(dst_d, src_d);
+        let src_h_idxs = src_idxs(dst_h, src_h);
+        let src_w_idxs = src_idxs(dst_w, src_w);
+        let dst_sz = dst_d * dst_h * dst_w;
+        let mut dst = vec![T::zero(); b_sz * c * dst_sz];
+        for b_idx in 0..b_sz {
+            let dst = &mut dst[b_idx * c * dst_sz..];
+            let src_index = src_index + b_idx * stride[0];
+            for c_idx in 0..c {
+                let dst = &mut dst[c_idx * dst_sz..];
+                let src_index = src_index + c_idx * stride[1];
+                for (d_idx, src_d_idx) in src_d_idxs.iter().enumerate() {
+                    for (h_idx, src_h_idx) in src_h_idxs.iter().enumerate() {
+                        for (w_idx, src_w_idx) in src_w_idxs.iter().enumerate() {
+                            let src_index = src_index
+                                + src_d_idx * stride_d
+                                + src_h_idx * stride_h
+                                + src_w_idx * stride_w;
+                            dst[(d_idx * dst_h + h_idx) * dst_w + w_idx] = src[src_index]
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
 struct Gather<'a, I: IntDType> {
     ids: &'a [I],
     ids_l: &'a Layout,
@@ -1022,6 +1298,161 @@ impl<'a, I: IntDType> Map2 for IndexAdd<'a, I> {
     }
 }
 
+struct Scatter<'a, I: IntDType> {
+    ids: &'a [I],
+    ids_l: &'a Layout,
+    dim: usize,
+}
+
+impl<'a, I: IntDType> Map2 for Scatter<'a, I> {
+    const OP: &'static str = "scatter";
+    fn f<T: WithDType>(&self, v1: &[T], l1: &Layout, src: &[T], src_l: &Layout) -> Result<Vec<T>> {
+        let dst_len = l1.shape().elem_count();
+        let mut dst = vec![T::zero(); dst_len];
+        copy_strided_src_(v1, &mut dst, 0, l1);
+        let src = match src_l.contiguous_offsets() {
+            None => Err(Error::RequiresContiguous { op: "scatter" }.bt())?,
+            Some((o1, o2)) => &src[o1..o2],
+        };
+
+        let dim = self.dim;
+        let ids_dims = self.ids_l.dims();
+        let dst_dims = l1.dims();
+        let dst_dim_len = dst_dims[dim];
+        let dst_right_len: usize = dst_dims[dim + 1..].iter().product();
+
+        let ids_left_len: usize = ids_dims[..dim].iter().product();
+        let ids_dim_len = ids_dims[dim];
+        let ids_right_len: usize = ids_dims[dim + 1..].iter().product();
+
+        let ids = match self.ids_l.contiguous_offsets() {
+            Some((a, b)) => &self.ids[a..b],
+            None => Err(Error::RequiresContiguous { op: "scatter" }.bt())?,
+        };
+        for left_i in 0..ids_left_len {
+            let start_ids_idx = left_i * ids_right_len * ids_dim_len;
+            let start_dst_idx = left_i * dst_right_len * dst_dim_len;
+            for i in 0..ids_dim_len {
+                let start_ids_idx = start_ids_idx + i * ids_right_len;
+                for right_i in 0..dst_right_len {
+                    let ids_idx = start_ids_idx + right_i;
+                    let index = ids[ids_idx].as_usize();
+                    if index >= dst_dim_len {
+                        Err(Error::InvalidIndex {
+                            index,
+                            size: dst_dim_len,
+                            op: "scatter",
+                        }
+                        .bt())?
+                    }
+                    let dst_idx = start_dst_idx + index * dst_right_len + right_i;
+                    dst[dst_idx] = src[ids_idx]
+                }
+            }
+        }
+
+        Ok(dst)
+    }
+}
+
+struct IndexCopy<'a, I: IntDType> {
+    ids: &'a [I],
+    ids_l: &'a Layout,
+    dim: usize,
+}
+
+impl<'a, I: IntDType> Map2 for IndexCopy<'a, I> {
+    const OP: &'static str = "index-copy";
+    // https://pytorch.org/docs/stable/generated/torch.Tensor.index_copy_.html
+    fn f<T: WithDType>(&self, v1: &[T], l1: &Layout, src: &[T], src_l: &Layout) -> Result<Vec<T>> {
+        let dst_len = l1.shape().elem_count();
+        let mut dst = vec![T::zero(); dst_len];
+        copy_strided_src_(v1, &mut dst, 0, l1);
+        let src = match src_l.contiguous_offsets() {
+            None => Err(Error::RequiresContiguous { op: "index-copy" }.bt())?,
+            Some((o1, o2)) => &src[o1..o2],
+        };
+        let ids = match self.ids_l.contiguous_offsets() {
+            Some((a, b)) => &self.ids[a..b],
+            None => Err(Error::RequiresContiguous { op: "index-copy" }.bt())?,
+        };
+        let dim = self.dim;
+        let max_idx = l1.dims()[dim];
+        let pre_dim = src_l.dims()[..dim].iter().product::<usize>();
+        let src_dim_sz = src_l.dims()[dim];
+        let post_dim = src_l.dims()[dim + 1..].iter().product::<usize>();
+        for (src_idx, dst_idx) in ids.iter().enumerate() {
+            let dst_idx = dst_idx.as_usize();
+            if dst_idx >= max_idx {
+                Err(Error::InvalidIndex {
+                    index: dst_idx,
+                    op: "index-copy",
+                    size: max_idx,
+                })?
+            }
+            for pre_i in 0..pre_dim {
+                let pre_src_i = (pre_i * src_dim_sz + src_idx) * post_dim;
+                let pre_dst_i = (pre_i * max_idx + dst_idx) * post_dim;
+                dst[pre_dst_i..pre_dst_i + post_dim]
+                    .copy_from_slice(&src[pre_src_i..pre_src_i + post_dim])
+            }
+        }
+        Ok(dst)
+    }
+}
+
+/// Returns for each element, in the logical order of the layout, whether it is non-zero.
+fn nonzero_mask(vs: &CpuStorage, layout: &Layout) -> Vec<bool> {
+    fn f<T: WithDType>(vs: &[T], layout: &Layout) -> Vec<bool> {
+        layout.strided_index().map(|i| vs[i] != T::zero()).collect()
+    }
+    match vs {
+        CpuStorage::U8(vs) => f(vs, layout),
+        CpuStorage::U32(vs) => f(vs, layout),
+        CpuStorage::I8(vs) => f(vs, layout),
+        CpuStorage::I16(vs) => f(vs, layout),
+        CpuStorage::I32(vs) => f(vs, layout),
+        CpuStorage::I64(vs) => f(vs, layout),
+        CpuStorage::Bool(vs) => f(vs, layout),
+        CpuStorage::F8E4M3(vs) => f(vs, layout),
+        CpuStorage::F8E5M2(vs) => f(vs, layout),
+        CpuStorage::BF16(vs) => f(vs, layout),
+        CpuStorage::F16(vs) => f(vs, layout),
+        CpuStorage::F32(vs) => f(vs, layout),
+        CpuStorage::F64(vs) => f(vs, layout),
+    }
+}
+
+struct MaskedFill<'a> {
+    mask: &'a [bool],
+    value: f64,
+}
+
+impl<'a> Map1 for MaskedFill<'a> {
+    fn f<T: WithDType>(&self, vs: &[T], layout: &Layout) -> Result<Vec<T>> {
+        let value = T::from_f64(self.value);
+        let dst = layout
+            .strided_index()
+            .zip(self.mask.iter())
+            .map(|(i, &m)| if m { value } else { vs[i] })
+            .collect();
+        Ok(dst)
+    }
+}
+
+struct MaskedSelect<'a>(&'a [bool]);
+
+impl<'a> Map1 for MaskedSelect<'a> {
+    fn f<T: WithDType>(&self, vs: &[T], layout: &Layout) -> Result<Vec<T>> {
+        let dst = layout
+            .strided_index()
+            .zip(self.0.iter())
+            .filter_map(|(i, &m)| if m { Some(vs[i]) } else { None })
+            .collect();
+        Ok(dst)
+    }
+}
+
 fn copy_strided_src_<T: Copy>(src: &[T], dst: &mut [T], dst_offset: usize, src_l: &Layout) {
     match src_l.strided_blocks() {
         crate::StridedBlocks::SingleBlock { start_offset, len } => {
@@ -1502,6 +1933,206 @@ impl<'a> Map2 for ConvTranspose2D<'a> {
     }
 }
 
+struct Im2Col3D {
+    d_k: usize,
+    h_k: usize,
+    w_k: usize,
+    stride: usize,
+    dilation: usize,
+    padding: usize,
+}
+
+impl Im2Col3D {
+    fn out_size(&self, size: usize, k: usize) -> usize {
+        (size + 2 * self.padding - self.dilation * (k - 1) - 1) / self.stride + 1
+    }
+}
+
+impl Map1 for Im2Col3D {
+    fn f<T: WithDType>(&self, vs: &[T], layout: &Layout) -> Result<Vec<T>> {
+        let &Self {
+            d_k,
+            h_k,
+            w_k,
+            stride,
+            dilation,
+            padding,
+        } = self;
+        let (b, c, d, h, w) = layout.shape().dims5()?;
+        let d_out = self.out_size(d, d_k);
+        let h_out = self.out_size(h, h_k);
+        let w_out = self.out_size(w, w_k);
+        let k_sz = c * d_k * h_k * w_k;
+        let src = &vs[layout.start_offset()..];
+        let mut dst = vec![T::zero(); b * d_out * h_out * w_out * k_sz];
+        let (src_s0, src_s1, src_s2, src_s3, src_s4) = crate::shape::dims5(layout.stride())?;
+        // Returns the source index for the output position `idx` and kernel position `k_idx`, or
+        // `None` if this falls in the padding.
+        let src_pos = |idx: usize, k_idx: usize, size: usize| {
+            let pos = idx * stride + k_idx * dilation;
+            if pos < padding || pos >= size + padding {
+                None
+            } else {
+                Some(pos - padding)
+            }
+        };
+        for b_idx in 0..b {
+            let src_idx = b_idx * src_s0;
+            for d_idx in 0..d_out {
+                for h_idx in 0..h_out {
+                    for w_idx in 0..w_out {
+                        let dst_idx =
+                            (((b_idx * d_out + d_idx) * h_out + h_idx) * w_out + w_idx) * k_sz;
+                        for c_idx in 0..c {
+                            let dst_idx = dst_idx + c_idx * d_k * h_k * w_k;
+                            let src_idx = src_idx + c_idx * src_s1;
+                            for d_k_idx in 0..d_k {
+                                let src_d = match src_pos(d_idx, d_k_idx, d) {
+                                    None => continue,
+                                    Some(src_d) => src_d,
+                                };
+                                let src_idx = src_idx + src_d * src_s2;
+                                let dst_idx = dst_idx + d_k_idx * h_k * w_k;
+                                for h_k_idx in 0..h_k {
+                                    let src_h = match src_pos(h_idx, h_k_idx, h) {
+                                        None => continue,
+                                        Some(src_h) => src_h,
+                                    };
+                                    let src_idx = src_idx + src_h * src_s3;
+                                    let dst_idx = dst_idx + h_k_idx * w_k;
+                                    for w_k_idx in 0..w_k {
+                                        let src_w = match src_pos(w_idx, w_k_idx, w) {
+                                            None => continue,
+                                            Some(src_w) => src_w,
+                                        };
+                                        dst[dst_idx + w_k_idx] = src[src_idx + src_w * src_s4]
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
+struct ConvTranspose3D<'a>(&'a crate::conv::ParamsConvTranspose3D);
+
+impl<'a> Map2 for ConvTranspose3D<'a> {
+    const OP: &'static str = "conv_transpose3d";
+    fn f<T: WithDType>(&self, inp: &[T], inp_l: &Layout, k: &[T], k_l: &Layout) -> Result<Vec<T>> {
+        let p = self.0;
+        let inp = &inp[inp_l.start_offset()..];
+        let (inp_s0, inp_s1, inp_s2, inp_s3, inp_s4) = crate::shape::dims5(inp_l.stride())?;
+        let k = &k[k_l.start_offset()..];
+        let (k_s0, k_s1, k_s2, k_s3, k_s4) = crate::shape::dims5(k_l.stride())?;
+        let (out_d, out_h, out_w) = (p.out_d(), p.out_h(), p.out_w());
+
+        // Output shape: [b_size, c_out, out_d, out_h, out_w].
+        let dst = vec![T::zero(); p.b_size * p.c_out * out_d * out_h * out_w];
+        let dst_s0 = p.c_out * out_d * out_h * out_w;
+        let dst_s1 = out_d * out_h * out_w;
+
+        // The input with the channels as the last dimension: [b_size, i_d, i_h, i_w, c_in].
+        let mut inp_cont = vec![T::zero(); p.b_size * p.c_in * p.i_d * p.i_h * p.i_w];
+        for b_idx in 0..p.b_size {
+            for d_idx in 0..p.i_d {
+                for h_idx in 0..p.i_h {
+                    for w_idx in 0..p.i_w {
+                        let dst_idx =
+                            (((b_idx * p.i_d + d_idx) * p.i_h + h_idx) * p.i_w + w_idx) * p.c_in;
+                        for c_idx in 0..p.c_in {
+                            let src_idx = b_idx * inp_s0
+                                + c_idx * inp_s1
+                                + d_idx * inp_s2
+                                + h_idx * inp_s3
+                                + w_idx * inp_s4;
+                            inp_cont[dst_idx + c_idx] = inp[src_idx]
+                        }
+                    }
+                }
+            }
+        }
+
+        // Returns the output position for the input position `idx` and kernel position `k_idx`,
+        // or `None` if this falls outside of the output.
+        let out_pos = |idx: usize, k_idx: usize, size: usize| {
+            let pos = idx * p.stride + k_idx * p.dilation;
+            if pos < p.padding || pos - p.padding >= size {
+                None
+            } else {
+                Some(pos - p.padding)
+            }
+        };
+        for k_z in 0..p.k_d {
+            for k_y in 0..p.k_h {
+                for k_x in 0..p.k_w {
+                    (0..p.c_out).into_par_iter().for_each(|dst_c_idx| {
+                        let k_cont = (0..p.c_in)
+                            .map(|c_in_idx| {
+                                k[c_in_idx * k_s0
+                                    + dst_c_idx * k_s1
+                                    + k_z * k_s2
+                                    + k_y * k_s3
+                                    + k_x * k_s4]
+                            })
+                            .collect::<Vec<_>>();
+                        for b_idx in 0..p.b_size {
+                            for inp_z in 0..p.i_d {
+                                let out_z = match out_pos(inp_z, k_z, out_d) {
+                                    None => continue,
+                                    Some(out_z) => out_z,
+                                };
+                                for inp_y in 0..p.i_h {
+                                    let out_y = match out_pos(inp_y, k_y, out_h) {
+                                        None => continue,
+                                        Some(out_y) => out_y,
+                                    };
+                                    for inp_x in 0..p.i_w {
+                                        let out_x = match out_pos(inp_x, k_x, out_w) {
+                                            None => continue,
+                                            Some(out_x) => out_x,
+                                        };
+                                        let inp_idx = (((b_idx * p.i_d + inp_z) * p.i_h + inp_y)
+                                            * p.i_w
+                                            + inp_x)
+                                            * p.c_in;
+                                        let inp_cont = &inp_cont[inp_idx..];
+                                        let dst_idx = b_idx * dst_s0
+                                            + dst_c_idx * dst_s1
+                                            + (out_z * out_h + out_y) * out_w
+                                            + out_x;
+                                        let mut d = T::zero();
+                                        unsafe {
+                                            T::vec_dot(
+                                                inp_cont.as_ptr(),
+                                                k_cont.as_ptr(),
+                                                &mut d,
+                                                p.c_in,
+                                            )
+                                        }
+                                        let dst_p = dst.as_ptr();
+                                        // Safety: dst_idx are uniques per dst_c_idx which is used
+                                        // to parallelise the different tasks so no two threads can
+                                        // try to write at the same location.
+                                        unsafe {
+                                            let ptr = dst_p.add(dst_idx) as *mut T;
+                                            *ptr += d
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    })
+                }
+            }
+        }
+        Ok(dst)
+    }
+}
+
 struct MatMul((usize, usize, usize, usize));
 
 impl MatMul {
@@ -1846,11 +2477,215 @@ fn elu<T: num_traits::Float>(v: T, alpha: T) -> T {
     }
 }
 
+/// An instruction of a fused elementwise kernel, see [`CpuStorage::fused_elementwise`]. The
+/// operands are the indexes of previous instructions, the kernel result is the value of the last
+/// instruction.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FusedInstr {
+    /// Loads an input of the kernel, each input must be loaded by a single instruction.
+    Input(usize),
+    Unary(usize, UnaryOp),
+    Binary(usize, usize, BinaryOp),
+    Affine(usize, f64, f64),
+}
+
+// The instructions of a fused kernel are applied to blocks of this many elements so that all the
+// intermediary values stay in cache.
+const FUSED_BLOCK_LEN: usize = 1024;
+
+trait FusedDType: WithDType {
+    fn unary<B: UnaryOpT>(xs: &[Self], ys: &mut [Self]);
+    fn binary<B: BinaryOpT>(xs1: &[Self], xs2: &[Self], ys: &mut [Self]);
+}
+
+macro_rules! fused_dtype {
+    ($ty:ty, $fn:ident, $vec_fn:ident, $vec:ident) => {
+        impl FusedDType for $ty {
+            fn unary<B: UnaryOpT>(xs: &[Self], ys: &mut [Self]) {
+                if B::$vec {
+                    B::$vec_fn(xs, ys)
+                } else {
+                    for (y, &x) in ys.iter_mut().zip(xs) {
+                        *y = B::$fn(x)
+                    }
+                }
+            }
+
+            fn binary<B: BinaryOpT>(xs1: &[Self], xs2: &[Self], ys: &mut [Self]) {
+                if B::$vec {
+                    B::$vec_fn(xs1, xs2, ys)
+                } else {
+                    for ((y, &x1), &x2) in ys.iter_mut().zip(xs1).zip(xs2) {
+                        *y = B::$fn(x1, x2)
+                    }
+                }
+            }
+        }
+    };
+}
+
+fused_dtype!(bf16, bf16, bf16_vec, BF16_VEC);
+fused_dtype!(f16, f16, f16_vec, F16_VEC);
+fused_dtype!(f32, f32, f32_vec, F32_VEC);
+fused_dtype!(f64, f64, f64_vec, F64_VEC);
+
+fn fused_unary<T: FusedDType>(op: UnaryOp, xs: &[T], ys: &mut [T]) {
+    use crate::op;
+    match op {
+        UnaryOp::Exp => T::unary::<op::Exp>(xs, ys),
+        UnaryOp::Log => T::unary::<op::Log>(xs, ys),
+        UnaryOp::Sin => T::unary::<op::Sin>(xs, ys),
+        UnaryOp::Cos => T::unary::<op::Cos>(xs, ys),
+        UnaryOp::Abs => T::unary::<op::Abs>(xs, ys),
+        UnaryOp::Neg => T::unary::<op::Neg>(xs, ys),
+        UnaryOp::Recip => T::unary::<op::Recip>(xs, ys),
+        UnaryOp::Sqr => T::unary::<op::Sqr>(xs, ys),
+        UnaryOp::Sqrt => T::unary::<op::Sqrt>(xs, ys),
+        UnaryOp::Gelu => T::unary::<op::Gelu>(xs, ys),
+        UnaryOp::GeluErf => T::unary::<op::GeluErf>(xs, ys),
+        UnaryOp::Erf => T::unary::<op::Erf>(xs, ys),
+        UnaryOp::Relu => T::unary::<op::Relu>(xs, ys),
+        UnaryOp::Tanh => T::unary::<op::Tanh>(xs, ys),
+        UnaryOp::Floor => T::unary::<op::Floor>(xs, ys),
+        UnaryOp::Ceil => T::unary::<op::Ceil>(xs, ys),
+        UnaryOp::Round => T::unary::<op::Round>(xs, ys),
+    }
+}
+
+fn fused_binary<T: FusedDType>(op: BinaryOp, xs1: &[T], xs2: &[T], ys: &mut [T]) {
+    use crate::op;
+    match op {
+        BinaryOp::Add => T::binary::<op::Add>(xs1, xs2, ys),
+        BinaryOp::Mul => T::binary::<op::Mul>(xs1, xs2, ys),
+        BinaryOp::Sub => T::binary::<op::Sub>(xs1, xs2, ys),
+        BinaryOp::Div => T::binary::<op::Div>(xs1, xs2, ys),
+        BinaryOp::Maximum => T::binary::<op::Maximum>(xs1, xs2, ys),
+        BinaryOp::Minimum => T::binary::<op::Minimum>(xs1, xs2, ys),
+    }
+}
+
+// An input of a fused kernel, read one block after the other.
+struct FusedSrc<'a, T> {
+    vs: &'a [T],
+    block_start_index: crate::StridedIndex<'a>,
+    block_len: usize,
+    // The position of the next value to read, as the start of the current block and an offset
+    // in this block.
+    index: usize,
+    offset: usize,
+}
+
+impl<'a, T: Copy> FusedSrc<'a, T> {
+    fn new(vs: &'a [T], layout: &'a Layout) -> Self {
+        let (mut block_start_index, block_len) = match layout.strided_blocks() {
+            crate::StridedBlocks::SingleBlock { start_offset, len } => {
+                (crate::StridedIndex::new(&[], &[], start_offset), len)
+            }
+            crate::StridedBlocks::MultipleBlocks {
+                block_start_index,
+                block_len,
+            } => (block_start_index, block_len),
+        };
+        let index = block_start_index.next().unwrap_or(0);
+        Self {
+            vs,
+            block_start_index,
+            block_len,
+            index,
+            offset: 0,
+        }
+    }
+
+    fn read(&mut self, ys: &mut [T]) {
+        let mut ys = ys;
+        while !ys.is_empty() {
+            if self.offset == self.block_len {
+                self.index = self.block_start_index.next().unwrap_or(0);
+                self.offset = 0;
+            }
+            let len = usize::min(ys.len(), self.block_len - self.offset);
+            let start = self.index + self.offset;
+            let (dst, rest) = ys.split_at_mut(len);
+            dst.copy_from_slice(&self.vs[start..start + len]);
+            self.offset += len;
+            ys = rest
+        }
+    }
+}
+
+fn fused_map<T: FusedDType>(
+    inputs: &[(&[T], &Layout)],
+    instrs: &[FusedInstr],
+    el_count: usize,
+) -> Vec<T> {
+    let mut srcs: Vec<_> = inputs
+        .iter()
+        .map(|&(vs, layout)| FusedSrc::new(vs, layout))
+        .collect();
+    // One block of values per instruction.
+    let mut values = vec![T::zero(); instrs.len() * FUSED_BLOCK_LEN];
+    let mut dst = Vec::with_capacity(el_count);
+    for start in (0..el_count).step_by(FUSED_BLOCK_LEN) {
+        let len = usize::min(FUSED_BLOCK_LEN, el_count - start);
+        for (i, instr) in instrs.iter().enumerate() {
+            let (prev, ys) = values.split_at_mut(i * FUSED_BLOCK_LEN);
+            let ys = &mut ys[..len];
+            let value = |j: usize| &prev[j * FUSED_BLOCK_LEN..j * FUSED_BLOCK_LEN + len];
+            match *instr {
+                FusedInstr::Input(j) => srcs[j].read(ys),
+                FusedInstr::Unary(j, op) => fused_unary(op, value(j), ys),
+                FusedInstr::Binary(j1, j2, op) => fused_binary(op, value(j1), value(j2), ys),
+                FusedInstr::Affine(j, mul, add) => {
+                    let (mul, add) = (T::from_f64(mul), T::from_f64(add));
+                    for (y, &x) in ys.iter_mut().zip(value(j)) {
+                        *y = x * mul + add
+                    }
+                }
+            }
+        }
+        let last = (instrs.len() - 1) * FUSED_BLOCK_LEN;
+        dst.extend_from_slice(&values[last..last + len])
+    }
+    dst
+}
+
 impl CpuStorage {
     pub fn as_slice<D: WithDType>(&self) -> Result<&[D]> {
         D::cpu_storage_as_slice(self)
     }
 
+    /// Evaluates a fused elementwise kernel in a single pass, the input layouts must all have the
+    /// shape of the output which has `el_count` elements.
+    pub(crate) fn fused_elementwise(
+        inputs: &[(&Self, &Layout)],
+        instrs: &[FusedInstr],
+        el_count: usize,
+    ) -> Result<Self> {
+        fn map<T: FusedDType>(
+            inputs: &[(&CpuStorage, &Layout)],
+            instrs: &[FusedInstr],
+            el_count: usize,
+        ) -> Result<Vec<T>> {
+            let inputs = inputs
+                .iter()
+                .map(|&(storage, layout)| Ok((storage.as_slice::<T>()?, layout)))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(fused_map(&inputs, instrs, el_count))
+        }
+        if instrs.is_empty() {
+            crate::bail!("fused kernel without instructions")
+        }
+        let storage = match inputs.first().map(|(storage, _)| storage.dtype()) {
+            Some(DType::BF16) => Self::BF16(map(inputs, instrs, el_count)?),
+            Some(DType::F16) => Self::F16(map(inputs, instrs, el_count)?),
+            Some(DType::F32) => Self::F32(map(inputs, instrs, el_count)?),
+            Some(DType::F64) => Self::F64(map(inputs, instrs, el_count)?),
+            Some(dtype) => Err(Error::UnsupportedDTypeForOp(dtype, "fused").bt())?,
+            None => crate::bail!("fused kernel without inputs"),
+        };
+        Ok(storage)
+    }
+
     pub fn concat(storages: &[CpuStorage]) -> Result<CpuStorage> {
         let storage0 = &storages[0];
         let s = match storage0 {
@@ -1876,6 +2711,39 @@ impl CpuStorage {
                     .concat();
                 Self::U32(storages)
             }
+            Self::I8(_) => {
+                let storages = storages
+                    .iter()
+                    .map(|s| match s {
+                        Self::I8(s) => Ok(s.as_slice()),
+                        _ => crate::bail!("dtype mismatch"),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                Self::I8(storages)
+            }
+            Self::I16(_) => {
+                let storages = storages
+                    .iter()
+                    .map(|s| match s {
+                        Self::I16(s) => Ok(s.as_slice()),
+                        _ => crate::bail!("dtype mismatch"),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                Self::I16(storages)
+            }
+            Self::I32(_) => {
+                let storages = storages
+                    .iter()
+                    .map(|s| match s {
+                        Self::I32(s) => Ok(s.as_slice()),
+                        _ => crate::bail!("dtype mismatch"),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                Self::I32(storages)
+            }
             Self::I64(_) => {
                 let storages = storages
                     .iter()
@@ -1887,6 +2755,39 @@ impl CpuStorage {
                     .concat();
                 Self::I64(storages)
             }
+            Self::Bool(_) => {
+                let storages = storages
+                    .iter()
+                    .map(|s| match s {
+                        Self::Bool(s) => Ok(s.as_slice()),
+                        _ => crate::bail!("dtype mismatch"),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                Self::Bool(storages)
+            }
+            Self::F8E4M3(_) => {
+                let storages = storages
+                    .iter()
+                    .map(|s| match s {
+                        Self::F8E4M3(s) => Ok(s.as_slice()),
+                        _ => crate::bail!("dtype mismatch"),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                Self::F8E4M3(storages)
+            }
+            Self::F8E5M2(_) => {
+                let storages = storages
+                    .iter()
+                    .map(|s| match s {
+                        Self::F8E5M2(s) => Ok(s.as_slice()),
+                        _ => crate::bail!("dtype mismatch"),
+                    })
+                    .collect::<Result<Vec<_>>>()?
+                    .concat();
+                Self::F8E5M2(storages)
+            }
             Self::BF16(_) => {
                 let storages = storages
                     .iter()
@@ -1936,6 +2837,37 @@ impl CpuStorage {
     }
 }
 
+struct Cast(DType);
+
+impl Map1Any for Cast {
+    fn f<T: WithDType, W: Fn(Vec<T>) -> CpuStorage>(
+        &self,
+        vs: &[T],
+        layout: &Layout,
+        _: W,
+    ) -> Result<CpuStorage> {
+        fn cast<T: WithDType, U: WithDType>(vs: &[T], layout: &Layout) -> CpuStorage {
+            U::to_cpu_storage_owned(unary_map(vs, layout, |v| U::from_f64(v.to_f64())))
+        }
+        let storage = match self.0 {
+            DType::U8 => cast::<T, u8>(vs, layout),
+            DType::U32 => cast::<T, u32>(vs, layout),
+            DType::I8 => cast::<T, i8>(vs, layout),
+            DType::I16 => cast::<T, i16>(vs, layout),
+            DType::I32 => cast::<T, i32>(vs, layout),
+            DType::I64 => cast::<T, i64>(vs, layout),
+            DType::Bool => cast::<T, crate::Bool>(vs, layout),
+            DType::F8E4M3 => cast::<T, crate::F8E4M3>(vs, layout),
+            DType::F8E5M2 => cast::<T, crate::F8E5M2>(vs, layout),
+            DType::BF16 => cast::<T, bf16>(vs, layout),
+            DType::F16 => cast::<T, f16>(vs, layout),
+            DType::F32 => cast::<T, f32>(vs, layout),
+            DType::F64 => cast::<T, f64>(vs, layout),
+        };
+        Ok(storage)
+    }
+}
+
 impl BackendStorage for CpuStorage {
     type Device = CpuDevice;
 
@@ -1943,7 +2875,13 @@ impl BackendStorage for CpuStorage {
         match self {
             Self::U8(_) => DType::U8,
             Self::U32(_) => DType::U32,
+            Self::I8(_) => DType::I8,
+            Self::I16(_) => DType::I16,
+            Self::I32(_) => DType::I32,
             Self::I64(_) => DType::I64,
+            Self::Bool(_) => DType::Bool,
+            Self::F8E4M3(_) => DType::F8E4M3,
+            Self::F8E5M2(_) => DType::F8E5M2,
             Self::BF16(_) => DType::BF16,
             Self::F16(_) => DType::F16,
             Self::F32(_) => DType::F32,
@@ -2150,12 +3088,15 @@ impl BackendStorage for CpuStorage {
                 let data = unary_map(storage, layout, |v| v);
                 Ok(Self::F64(data))
             }
+            // The conversions from or to the i8, i16, i32 and bool dtypes go through f64 which is
+            // exact for all of them.
+            (storage, dtype) => Cast(dtype).map(storage, layout),
         }
     }
 
     fn reduce_op(&self, op: ReduceOp, layout: &Layout, reduce_dims: &[usize]) -> Result<Self> {
         match op {
-            ReduceOp::Sum => {
+            ReduceOp::Sum | ReduceOp::Prod => {
                 let src_dims = layout.dims();
                 let mut dst_dims = src_dims.to_vec();
                 for &dim in reduce_dims.iter() {
@@ -2170,12 +3111,16 @@ impl BackendStorage for CpuStorage {
                     .iter()
                     .map(|&d| (src_dims[d], src_dims[d + 1..].iter().product::<usize>()))
                     .collect();
-                ReduceSum {
+                let reduce = ReduceSum {
                     dst_shape: &dst_shape,
                     reduce_dims: &reduce_dims,
                     reduce_dims_and_stride,
+                };
+                if op == ReduceOp::Prod {
+                    ReduceProd(reduce).map(self, layout)
+                } else {
+                    reduce.map(self, layout)
                 }
-                .map(self, layout)
             }
             ReduceOp::Min | ReduceOp::ArgMin | ReduceOp::Max | ReduceOp::ArgMax => {
                 let reduce_dim_index = match reduce_dims {
@@ -2209,6 +3154,10 @@ impl BackendStorage for CpuStorage {
         }
     }
 
+    fn sort_last_dim(&self, layout: &Layout, asc: bool) -> Result<(Self, Self)> {
+        SortLastDim { asc }.map(self, layout)
+    }
+
     fn cmp(&self, op: CmpOp, rhs: &Self, lhs_l: &Layout, rhs_l: &Layout) -> Result<Self> {
         Cmp(op).map(self, lhs_l, rhs, rhs_l)
     }
@@ -2235,6 +3184,24 @@ impl BackendStorage for CpuStorage {
         MaxPool2D(kernel_size, stride).map(self, layout)
     }
 
+    fn avg_pool3d(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    ) -> Result<Self> {
+        AvgPool3D(kernel_size, stride).map(self, layout)
+    }
+
+    fn max_pool3d(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    ) -> Result<Self> {
+        MaxPool3D(kernel_size, stride).map(self, layout)
+    }
+
     fn upsample_nearest1d(&self, layout: &Layout, sz: usize) -> Result<Self> {
         UpsampleNearest1D(sz).map(self, layout)
     }
@@ -2243,6 +3210,10 @@ impl BackendStorage for CpuStorage {
         UpsampleNearest2D(h, w).map(self, layout)
     }
 
+    fn upsample_nearest3d(&self, layout: &Layout, d: usize, h: usize, w: usize) -> Result<Self> {
+        UpsampleNearest3D(d, h, w).map(self, layout)
+    }
+
     fn powf(&self, layout: &Layout, e: f64) -> Result<Self> {
         use num_traits::Float;
         // TODO: Have some generic map for functions that apply on num_traits::Float elements.
@@ -2263,9 +3234,7 @@ impl BackendStorage for CpuStorage {
                 let data = unary_map(storage, layout, |v| v.powf(e));
                 Ok(Self::F64(data))
             }
-            Self::U8(_) => Err(Error::UnsupportedDTypeForOp(DType::U8, "elu").bt()),
-            Self::U32(_) => Err(Error::UnsupportedDTypeForOp(DType::U32, "elu").bt()),
-            Self::I64(_) => Err(Error::UnsupportedDTypeForOp(DType::I64, "elu").bt()),
+            _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "powf").bt()),
         }
     }
 
@@ -2288,13 +3257,14 @@ impl BackendStorage for CpuStorage {
                 let data = unary_map(storage, layout, |v| elu(v, alpha));
                 Ok(Self::F64(data))
             }
-            Self::U8(_) => Err(Error::UnsupportedDTypeForOp(DType::U8, "elu").bt()),
-            Self::U32(_) => Err(Error::UnsupportedDTypeForOp(DType::U32, "elu").bt()),
-            Self::I64(_) => Err(Error::UnsupportedDTypeForOp(DType::I64, "elu").bt()),
+            _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "elu").bt()),
         }
     }
 
     fn unary_impl<B: UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
+        if !B::INT && self.dtype().is_int() {
+            return Err(Error::UnsupportedDTypeForOp(self.dtype(), B::NAME).bt());
+        }
         match self {
             Self::BF16(storage) => {
                 if B::BF16_VEC {
@@ -2340,10 +3310,25 @@ impl BackendStorage for CpuStorage {
                 let data = unary_map(storage, layout, B::u32);
                 Ok(Self::U32(data))
             }
+            Self::I8(storage) => {
+                let data = unary_map(storage, layout, B::i8);
+                Ok(Self::I8(data))
+            }
+            Self::I16(storage) => {
+                let data = unary_map(storage, layout, B::i16);
+                Ok(Self::I16(data))
+            }
+            Self::I32(storage) => {
+                let data = unary_map(storage, layout, B::i32);
+                Ok(Self::I32(data))
+            }
             Self::I64(storage) => {
                 let data = unary_map(storage, layout, B::i64);
                 Ok(Self::I64(data))
             }
+            Self::Bool(_) | Self::F8E4M3(_) | Self::F8E5M2(_) => {
+                Err(Error::UnsupportedDTypeForOp(self.dtype(), B::NAME).bt())
+            }
         }
     }
 
@@ -2410,6 +3395,25 @@ impl BackendStorage for CpuStorage {
                 };
                 Ok(Self::U8(data))
             }
+            (Self::I8(lhs), Self::I8(rhs)) => {
+                let data = binary_map(lhs_l, rhs_l, lhs, rhs, B::i8);
+                Ok(Self::I8(data))
+            }
+            (Self::I16(lhs), Self::I16(rhs)) => {
+                let data = binary_map(lhs_l, rhs_l, lhs, rhs, B::i16);
+                Ok(Self::I16(data))
+            }
+            (Self::I32(lhs), Self::I32(rhs)) => {
+                let data = binary_map(lhs_l, rhs_l, lhs, rhs, B::i32);
+                Ok(Self::I32(data))
+            }
+            (Self::Bool(lhs), Self::Bool(rhs)) => {
+                let data = binary_map(lhs_l, rhs_l, lhs, rhs, B::bool);
+                Ok(Self::Bool(data))
+            }
+            (Self::F8E4M3(_), Self::F8E4M3(_)) | (Self::F8E5M2(_), Self::F8E5M2(_)) => {
+                Err(Error::UnsupportedDTypeForOp(self.dtype(), B::NAME).bt())
+            }
             _ => {
                 // This should be covered by the dtype check above.
                 Err(Error::DTypeMismatchBinaryOp {
@@ -2426,7 +3430,17 @@ impl BackendStorage for CpuStorage {
         match (self, dst) {
             (Self::U8(src), Self::U8(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
             (Self::U32(src), Self::U32(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
+            (Self::I8(src), Self::I8(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
+            (Self::I16(src), Self::I16(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
+            (Self::I32(src), Self::I32(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
             (Self::I64(src), Self::I64(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
+            (Self::Bool(src), Self::Bool(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
+            (Self::F8E4M3(src), Self::F8E4M3(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
+            (Self::F8E5M2(src), Self::F8E5M2(dst)) => {
+                copy_strided_src_(src, dst, dst_offset, src_l)
+            }
             (Self::BF16(src), Self::BF16(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
             (Self::F16(src), Self::F16(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
             (Self::F32(src), Self::F32(dst)) => copy_strided_src_(src, dst, dst_offset, src_l),
@@ -2455,7 +3469,11 @@ impl BackendStorage for CpuStorage {
         match self {
             Self::U8(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
             Self::U32(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+            Self::I8(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+            Self::I16(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+            Self::I32(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
             Self::I64(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
+            Self::Bool(pred) => WCond(pred, layout).map(t, t_l, f, f_l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "where-cond")),
         }
     }
@@ -2492,10 +3510,10 @@ impl BackendStorage for CpuStorage {
             // Make the kernel contiguous if not already the case.
             let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, l_out, params.c_out)).transpose(1, 2)?;
         let mut res_t = self.device().zeros_impl(res_l.shape(), res.dtype())?;
@@ -2546,10 +3564,10 @@ impl BackendStorage for CpuStorage {
             // Make the kernel contiguous if not already the case.
             let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, h_out, w_out, params.c_out))
             .transpose(1, 2)?
@@ -2569,10 +3587,65 @@ impl BackendStorage for CpuStorage {
         ConvTranspose2D(params).map(self, l, kernel, kernel_l)
     }
 
+    fn conv3d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv3D,
+    ) -> Result<Self> {
+        let op = Im2Col3D {
+            d_k: params.k_d,
+            h_k: params.k_h,
+            w_k: params.k_w,
+            padding: params.padding,
+            stride: params.stride,
+            dilation: params.dilation,
+        };
+        let col = op.map(self, l)?;
+        let b = params.b_size;
+        let n = params.c_out;
+        let k = op.d_k * op.h_k * op.w_k * params.c_in;
+        let m = params.out_d() * params.out_h() * params.out_w();
+        let col_l = Layout::contiguous((b, m, k));
+        let res = if kernel_l.is_contiguous() {
+            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+                .transpose(1, 2)?
+                .broadcast_as((b, k, n))?;
+            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+        } else {
+            // Make the kernel contiguous if not already the case.
+            let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
+            kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
+            let kernel_l = Layout::contiguous((1, n, k))
+                .transpose(1, 2)?
+                .broadcast_as((b, k, n))?;
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
+        };
+        // The result has shape (b, d_out * h_out * w_out, c_out), move the channels first.
+        let res_l = Layout::contiguous((b, m, n)).transpose(1, 2)?;
+        let mut res_t = self.device().zeros_impl(res_l.shape(), res.dtype())?;
+        res.copy_strided_src(&mut res_t, 0, &res_l)?;
+        Ok(res_t)
+    }
+
+    fn conv_transpose3d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self> {
+        ConvTranspose3D(params).map(self, l, kernel, kernel_l)
+    }
+
     fn index_select(&self, ids: &Self, l: &Layout, ids_l: &Layout, dim: usize) -> Result<Self> {
         match ids {
             Self::U8(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
             Self::U32(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
+            Self::I8(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
+            Self::I16(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
+            Self::I32(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
             Self::I64(ids) => IndexSelect { ids, ids_l, dim }.map(self, l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "index-select")),
         }
@@ -2582,6 +3655,9 @@ impl BackendStorage for CpuStorage {
         match ids {
             Self::U8(ids) => Gather { ids, ids_l, dim }.map(self, l),
             Self::U32(ids) => Gather { ids, ids_l, dim }.map(self, l),
+            Self::I8(ids) => Gather { ids, ids_l, dim }.map(self, l),
+            Self::I16(ids) => Gather { ids, ids_l, dim }.map(self, l),
+            Self::I32(ids) => Gather { ids, ids_l, dim }.map(self, l),
             Self::I64(ids) => Gather { ids, ids_l, dim }.map(self, l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "gather")),
         }
@@ -2599,6 +3675,9 @@ impl BackendStorage for CpuStorage {
         match ids {
             Self::U8(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
             Self::U32(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I8(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I16(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I32(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
             Self::I64(ids) => ScatterAdd { ids, ids_l, dim }.map(self, l, src, src_l),
             _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "scatter-add")),
         }
@@ -2628,6 +3707,27 @@ impl BackendStorage for CpuStorage {
                 };
                 IndexAdd { ids, dim }.map(self, l, src, src_l)
             }
+            Self::I8(ids) => {
+                let ids = match ids_l.contiguous_offsets() {
+                    Some((a, b)) => &ids[a..b],
+                    None => Err(Error::RequiresContiguous { op: "index-add" }.bt())?,
+                };
+                IndexAdd { ids, dim }.map(self, l, src, src_l)
+            }
+            Self::I16(ids) => {
+                let ids = match ids_l.contiguous_offsets() {
+                    Some((a, b)) => &ids[a..b],
+                    None => Err(Error::RequiresContiguous { op: "index-add" }.bt())?,
+                };
+                IndexAdd { ids, dim }.map(self, l, src, src_l)
+            }
+            Self::I32(ids) => {
+                let ids = match ids_l.contiguous_offsets() {
+                    Some((a, b)) => &ids[a..b],
+                    None => Err(Error::RequiresContiguous { op: "index-add" }.bt())?,
+                };
+                IndexAdd { ids, dim }.map(self, l, src, src_l)
+            }
             Self::I64(ids) => {
                 let ids = match ids_l.contiguous_offsets() {
                     Some((a, b)) => &ids[a..b],
@@ -2639,6 +3739,75 @@ impl BackendStorage for CpuStorage {
         }
     }
 
+    fn scatter(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        match ids {
+            Self::U8(ids) => Scatter { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::U32(ids) => Scatter { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I8(ids) => Scatter { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I16(ids) => Scatter { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I32(ids) => Scatter { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I64(ids) => Scatter { ids, ids_l, dim }.map(self, l, src, src_l),
+            _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "scatter")),
+        }
+    }
+
+    fn index_copy(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        match ids {
+            Self::U8(ids) => IndexCopy { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::U32(ids) => IndexCopy { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I8(ids) => IndexCopy { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I16(ids) => IndexCopy { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I32(ids) => IndexCopy { ids, ids_l, dim }.map(self, l, src, src_l),
+            Self::I64(ids) => IndexCopy { ids, ids_l, dim }.map(self, l, src, src_l),
+            _ => Err(Error::UnsupportedDTypeForOp(self.dtype(), "index-copy").bt()),
+        }
+    }
+
+    fn masked_fill(&self, l: &Layout, mask: &Self, mask_l: &Layout, value: f64) -> Result<Self> {
+        let mask = nonzero_mask(mask, mask_l);
+        MaskedFill { mask: &mask, value }.map(self, l)
+    }
+
+    fn masked_select(&self, l: &Layout, mask: &Self, mask_l: &Layout) -> Result<(Self, usize)> {
+        let mask = nonzero_mask(mask, mask_l);
+        let len = mask.iter().filter(|&&m| m).count();
+        let storage = MaskedSelect(&mask).map(self, l)?;
+        Ok((storage, len))
+    }
+
+    fn nonzero(&self, l: &Layout) -> Result<(Self, usize)> {
+        let dims = l.dims();
+        let mut coords = vec![];
+        let mut len = 0;
+        for (index, _) in nonzero_mask(self, l).iter().enumerate().filter(|(_, &m)| m) {
+            let start = coords.len();
+            coords.resize(start + dims.len(), 0u32);
+            let mut index = index;
+            for (c, &d) in coords[start..].iter_mut().zip(dims.iter()).rev() {
+                *c = (index % d) as u32;
+                index /= d;
+            }
+            len += 1;
+        }
+        Ok((Self::U32(coords), len))
+    }
+
     fn matmul(
         &self,
         rhs: &Self,
@@ -2691,9 +3860,15 @@ impl BackendDevice for CpuDevice {
         let elem_count = shape.elem_count();
         let mut rng = rand::thread_rng();
         match dtype {
-            DType::U8 | DType::U32 | DType::I64 => {
-                Err(Error::UnsupportedDTypeForOp(dtype, "rand_uniform").bt())
-            }
+            DType::U8
+            | DType::U32
+            | DType::I8
+            | DType::I16
+            | DType::I32
+            | DType::I64
+            | DType::Bool
+            | DType::F8E4M3
+            | DType::F8E5M2 => Err(Error::UnsupportedDTypeForOp(dtype, "rand_uniform").bt()),
             DType::BF16 => {
                 let mut data = Vec::with_capacity(elem_count);
                 let uniform =
@@ -2737,9 +3912,15 @@ impl BackendDevice for CpuDevice {
         let elem_count = shape.elem_count();
         let mut rng = rand::thread_rng();
         match dtype {
-            DType::U8 | DType::U32 | DType::I64 => {
-                Err(Error::UnsupportedDTypeForOp(dtype, "rand_normal").bt())
-            }
+            DType::U8
+            | DType::U32
+            | DType::I8
+            | DType::I16
+            | DType::I32
+            | DType::I64
+            | DType::Bool
+            | DType::F8E4M3
+            | DType::F8E5M2 => Err(Error::UnsupportedDTypeForOp(dtype, "rand_normal").bt()),
             DType::BF16 => {
                 let mut data = Vec::with_capacity(elem_count);
                 let normal = rand_distr::Normal::new(bf16::from_f64(mean), bf16::from_f64(std))
@@ -2783,11 +3964,17 @@ impl BackendDevice for CpuDevice {
         let storage = match dtype {
             DType::U8 => CpuStorage::U8(vec![1u8; elem_count]),
             DType::U32 => CpuStorage::U32(vec![1u32; elem_count]),
+            DType::I8 => CpuStorage::I8(vec![1i8; elem_count]),
+            DType::I16 => CpuStorage::I16(vec![1i16; elem_count]),
+            DType::I32 => CpuStorage::I32(vec![1i32; elem_count]),
             DType::I64 => CpuStorage::I64(vec![1i64; elem_count]),
+            DType::Bool => CpuStorage::Bool(vec![crate::Bool(true); elem_count]),
             DType::BF16 => CpuStorage::BF16(vec![bf16::ONE; elem_count]),
             DType::F16 => CpuStorage::F16(vec![f16::ONE; elem_count]),
             DType::F32 => CpuStorage::F32(vec![1f32; elem_count]),
             DType::F64 => CpuStorage::F64(vec![1f64; elem_count]),
+            DType::F8E4M3 => CpuStorage::F8E4M3(vec![crate::F8E4M3::from_f32(1.); elem_count]),
+            DType::F8E5M2 => CpuStorage::F8E5M2(vec![crate::F8E5M2::from_f32(1.); elem_count]),
         };
         Ok(storage)
     }
@@ -2797,11 +3984,17 @@ impl BackendDevice for CpuDevice {
         let storage = match dtype {
             DType::U8 => CpuStorage::U8(vec![0u8; elem_count]),
             DType::U32 => CpuStorage::U32(vec![0u32; elem_count]),
+            DType::I8 => CpuStorage::I8(vec![0i8; elem_count]),
+            DType::I16 => CpuStorage::I16(vec![0i16; elem_count]),
+            DType::I32 => CpuStorage::I32(vec![0i32; elem_count]),
             DType::I64 => CpuStorage::I64(vec![0i64; elem_count]),
+            DType::Bool => CpuStorage::Bool(vec![crate::Bool(false); elem_count]),
             DType::BF16 => CpuStorage::BF16(vec![bf16::ZERO; elem_count]),
             DType::F16 => CpuStorage::F16(vec![f16::ZERO; elem_count]),
             DType::F32 => CpuStorage::F32(vec![0f32; elem_count]),
             DType::F64 => CpuStorage::F64(vec![0f64; elem_count]),
+            DType::F8E4M3 => CpuStorage::F8E4M3(vec![crate::F8E4M3::from_bits(0); elem_count]),
+            DType::F8E5M2 => CpuStorage::F8E5M2(vec![crate::F8E5M2::from_bits(0); elem_count]),
         };
         Ok(storage)
     }
@@ -139,6 +139,9 @@ impl CudaDevice {
                 unsafe { func.launch(cfg, params) }.w()?;
                 CudaStorageSlice::U32(data)
             }
+            DType::I8 | DType::I16 | DType::I32 | DType::Bool | DType::F8E4M3 | DType::F8E5M2 => {
+                Err(crate::Error::UnsupportedDTypeForOp(dtype, "const").bt())?
+            }
             DType::I64 => {
                 // SAFETY: Set later by running the fill kernel.
                 let data = unsafe { self.alloc::<i64>(elem_count) }.w()?;
@@ -252,6 +255,9 @@ impl BackendDevice for CudaDevice {
                 let data = self.alloc_zeros::<u32>(elem_count).w()?;
                 CudaStorageSlice::U32(data)
             }
+            DType::I8 | DType::I16 | DType::I32 | DType::Bool | DType::F8E4M3 | DType::F8E5M2 => {
+                Err(crate::Error::UnsupportedDTypeForOp(dtype, "zeros").bt())?
+            }
             DType::I64 => {
                 let data = self.alloc_zeros::<i64>(elem_count).w()?;
                 CudaStorageSlice::I64(data)
@@ -285,13 +291,21 @@ impl BackendDevice for CudaDevice {
         let slice = match dtype {
             // TODO: Add support for F16 and BF16 though this is likely to require some upstream
             // cudarc changes.
-            DType::U8 | DType::U32 | DType::I64 | DType::F16 | DType::BF16 => {
-                Err(CudaError::UnsupportedDtype {
-                    dtype,
-                    op: "rand_uniform",
-                })
-                .w()?
-            }
+            DType::U8
+            | DType::U32
+            | DType::I8
+            | DType::I16
+            | DType::I32
+            | DType::I64
+            | DType::Bool
+            | DType::F8E4M3
+            | DType::F8E5M2
+            | DType::F16
+            | DType::BF16 => Err(CudaError::UnsupportedDtype {
+                dtype,
+                op: "rand_uniform",
+            })
+            .w()?,
             DType::F32 => {
                 let mut data = unsafe { self.alloc::<f32>(elem_count) }.w()?;
                 curand.0.fill_with_uniform(&mut data).w()?;
@@ -328,13 +342,21 @@ impl BackendDevice for CudaDevice {
             elem_count
         };
         let slice = match dtype {
-            DType::U8 | DType::U32 | DType::I64 | DType::F16 | DType::BF16 => {
-                Err(CudaError::UnsupportedDtype {
-                    dtype,
-                    op: "rand_normal",
-                })
-                .w()?
-            }
+            DType::U8
+            | DType::U32
+            | DType::I8
+            | DType::I16
+            | DType::I32
+            | DType::I64
+            | DType::Bool
+            | DType::F8E4M3
+            | DType::F8E5M2
+            | DType::F16
+            | DType::BF16 => Err(CudaError::UnsupportedDtype {
+                dtype,
+                op: "rand_normal",
+            })
+            .w()?,
             DType::F32 => {
                 let mut data = unsafe { self.alloc::<f32>(elem_count_round) }.w()?;
                 curand
@@ -373,6 +395,14 @@ impl BackendDevice for CudaDevice {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::I64(data)
             }
+            CpuStorage::I8(_)
+            | CpuStorage::I16(_)
+            | CpuStorage::I32(_)
+            | CpuStorage::Bool(_)
+            | CpuStorage::F8E4M3(_)
+            | CpuStorage::F8E5M2(_) => {
+                Err(crate::Error::UnsupportedDTypeForOp(storage.dtype(), "to-device").bt())?
+            }
             CpuStorage::BF16(storage) => {
                 let data = self.htod_sync_copy(storage).w()?;
                 CudaStorageSlice::BF16(data)
@@ -814,6 +844,7 @@ impl<'a> Map1Any for FastReduce<'a> {
         let src = &src.slice(layout.start_offset()..);
         let (name, check_empty, return_index) = match self.1 {
             ReduceOp::Sum => ("fast_sum", false, false),
+            ReduceOp::Prod => crate::bail!("prod is not supported on cuda"),
             ReduceOp::Min => ("fast_min", true, false),
             ReduceOp::Max => ("fast_max", true, false),
             ReduceOp::ArgMin => ("fast_argmin", true, true),
@@ -924,6 +955,48 @@ impl<'a> Map1 for IndexSelect<'a> {
     }
 }
 
+struct ArgSort {
+    asc: bool,
+    last_dim: usize,
+}
+impl Map1Any for ArgSort {
+    fn f<T: DeviceRepr + WithDType + ValidAsZeroBits, W: Fn(CudaSlice<T>) -> S>(
+        &self,
+        src: &CudaSlice<T>,
+        dev: &CudaDevice,
+        layout: &Layout,
+        _wrap: W,
+    ) -> Result<S> {
+        let src = match layout.contiguous_offsets() {
+            Some((o1, o2)) => src.slice(o1..o2),
+            None => Err(crate::Error::RequiresContiguous { op: "sort" }.bt())?,
+        };
+        let el = layout.shape().elem_count();
+        if el == 0 {
+            return Ok(S::U32(dev.alloc_zeros::<u32>(0).w()?));
+        }
+        let nrows = el / self.last_dim;
+        // Bitonic sort operates on a power of two elements, the padding is sorted at the end of
+        // each row in a scratch buffer.
+        let ncols_pad = self.last_dim.next_power_of_two();
+        let cfg = LaunchConfig {
+            grid_dim: (nrows as u32, 1, 1),
+            block_dim: (usize::min(1024, ncols_pad) as u32, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let name = if self.asc { "asort_asc" } else { "asort_desc" };
+        let func = dev.get_or_load_func(&kernel_name::<T>(name), kernels::SORT)?;
+        // SAFETY: Set later by running the kernel.
+        let idx = unsafe { dev.alloc::<u32>(nrows * ncols_pad) }.w()?;
+        // SAFETY: Set later by running the kernel.
+        let dst = unsafe { dev.alloc::<u32>(el) }.w()?;
+        let params = (&src, &idx, &dst, self.last_dim, ncols_pad);
+        // SAFETY: ffi.
+        unsafe { func.launch(cfg, params) }.w()?;
+        Ok(S::U32(dst))
+    }
+}
+
 struct Gather<'a>(&'a CudaStorage, &'a Layout, usize);
 impl<'a> Map1 for Gather<'a> {
     fn f<T: DeviceRepr + WithDType + ValidAsZeroBits>(
@@ -1622,6 +1695,9 @@ impl BackendStorage for CudaStorage {
                 unsafe { func.launch(cfg, params) }.w()?;
                 CudaStorageSlice::U32(out)
             }
+            DType::I8 | DType::I16 | DType::I32 | DType::Bool | DType::F8E4M3 | DType::F8E5M2 => {
+                Err(crate::Error::UnsupportedDTypeForOp(dtype, "to_dtype").bt())?
+            }
             DType::I64 => {
                 let out = unsafe { dev.alloc::<i64>(el) }.w()?;
                 let params = (el, dims.len(), &ds, *inp, &out);
@@ -1683,6 +1759,19 @@ impl BackendStorage for CudaStorage {
         Ok(Self { slice, device })
     }
 
+    fn sort_last_dim(&self, layout: &Layout, asc: bool) -> Result<(Self, Self)> {
+        let device = self.device().clone();
+        let last_dim = match layout.dims().last() {
+            None => crate::bail!("sort requires at least one dimension"),
+            Some(&last_dim) => last_dim,
+        };
+        let slice = ArgSort { asc, last_dim }.map(&self.slice, &device, layout)?;
+        let indexes = Self { slice, device };
+        let indexes_l = Layout::contiguous(layout.shape());
+        let values = self.gather(layout, &indexes, &indexes_l, layout.dims().len() - 1)?;
+        Ok((values, indexes))
+    }
+
     fn cmp(&self, op: CmpOp, rhs: &Self, lhs_l: &Layout, rhs_l: &Layout) -> Result<Self> {
         let device = self.device().clone();
         let slice = Cmp(op).map(&self.slice, lhs_l, &rhs.slice, rhs_l, &device)?;
@@ -1984,6 +2073,48 @@ impl BackendStorage for CudaStorage {
         Ok(Self { slice, device })
     }
 
+    fn upsample_nearest3d(&self, _: &Layout, _: usize, _: usize, _: usize) -> Result<Self> {
+        crate::bail!("upsample-nearest3d is not supported on cuda")
+    }
+
+    fn avg_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self> {
+        crate::bail!("avg-pool3d is not supported on cuda")
+    }
+
+    fn max_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self> {
+        crate::bail!("max-pool3d is not supported on cuda")
+    }
+
+    fn conv3d(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &crate::conv::ParamsConv3D,
+    ) -> Result<Self> {
+        crate::bail!("conv3d is not supported on cuda")
+    }
+
+    fn conv_transpose3d(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self> {
+        crate::bail!("conv-transpose3d is not supported on cuda")
+    }
+
     fn index_select(&self, ids: &Self, l: &Layout, ids_l: &Layout, dim: usize) -> Result<Self> {
         let device = self.device().clone();
         let slice = IndexSelect(ids, ids_l, dim).map(&self.slice, &device, l)?;
@@ -2009,6 +2140,63 @@ impl BackendStorage for CudaStorage {
         ScatterAdd(ids, ids_l, dim).map(&mut acc.slice, l.shape(), &src.slice, src_l, &device)?;
         Ok(acc)
     }
+    fn scatter(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        // There is no cuda kernel for this op yet, it runs on the cpu instead.
+        let (ids, src) = (ids.to_cpu_storage()?, src.to_cpu_storage()?);
+        let storage = self
+            .to_cpu_storage()?
+            .scatter(l, &ids, ids_l, &src, src_l, dim)?;
+        self.device().storage_from_cpu_storage(&storage)
+    }
+
+    fn index_copy(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        // There is no cuda kernel for this op yet, it runs on the cpu instead.
+        let (ids, src) = (ids.to_cpu_storage()?, src.to_cpu_storage()?);
+        let storage = self
+            .to_cpu_storage()?
+            .index_copy(l, &ids, ids_l, &src, src_l, dim)?;
+        self.device().storage_from_cpu_storage(&storage)
+    }
+
+    fn masked_select(&self, l: &Layout, mask: &Self, mask_l: &Layout) -> Result<(Self, usize)> {
+        // There is no cuda kernel for this op yet, it runs on the cpu instead.
+        let mask = mask.to_cpu_storage()?;
+        let (storage, len) = self.to_cpu_storage()?.masked_select(l, &mask, mask_l)?;
+        Ok((self.device().storage_from_cpu_storage(&storage)?, len))
+    }
+
+    fn nonzero(&self, l: &Layout) -> Result<(Self, usize)> {
+        // There is no cuda kernel for this op yet, it runs on the cpu instead.
+        let (storage, len) = self.to_cpu_storage()?.nonzero(l)?;
+        Ok((self.device().storage_from_cpu_storage(&storage)?, len))
+    }
+
+    fn masked_fill(&self, l: &Layout, mask: &Self, mask_l: &Layout, value: f64) -> Result<Self> {
+        let shape = l.shape();
+        let fill_l = Layout::contiguous(shape);
+        let fill = self
+            .device()
+            .zeros_impl(shape, self.dtype())?
+            .affine(&fill_l, 0., value)?;
+        mask.where_cond(mask_l, &fill, &fill_l, self, l)
+    }
+
     fn index_add(
         &self,
         l: &Layout,
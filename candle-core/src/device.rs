@@ -306,6 +306,14 @@ impl Device {
         }
     }
 
+    pub(crate) fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<Storage> {
+        match self {
+            Device::Cpu => Ok(Storage::Cpu(storage.clone())),
+            Device::Cuda(device) => Ok(Storage::Cuda(device.storage_from_cpu_storage(storage)?)),
+            Device::Metal(device) => Ok(Storage::Metal(device.storage_from_cpu_storage(storage)?)),
+        }
+    }
+
     pub(crate) fn storage_owned<S: WithDType>(&self, data: Vec<S>) -> Result<Storage> {
         match self {
             Device::Cpu => Ok(Storage::Cpu(S::to_cpu_storage_owned(data))),
@@ -1,7 +1,7 @@
 //! Types for elements that can be stored and manipulated using tensors.
 #![allow(clippy::redundant_closure_call)]
 use crate::backend::BackendStorage;
-use crate::{CpuStorage, Error, Result};
+use crate::{CpuStorage, Error, Result, F8E4M3, F8E5M2};
 
 /// The different types of elements allowed in tensors.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -10,8 +10,16 @@ pub enum DType {
     U8,
     // Unsigned 32 bits integer.
     U32,
+    // Signed 8 bits integer.
+    I8,
+    // Signed 16 bits integer.
+    I16,
+    // Signed 32 bits integer.
+    I32,
     // Signed 64 bits integer.
     I64,
+    // Boolean.
+    Bool,
     // Brain floating-point using half precision (16 bits).
     BF16,
     // Floating-point using half precision (16 bits).
@@ -20,6 +28,10 @@ pub enum DType {
     F32,
     // Floating-point using double precision (64 bits).
     F64,
+    // 8 bits floating-point with 4 exponent bits and 3 mantissa bits.
+    F8E4M3,
+    // 8 bits floating-point with 5 exponent bits and 2 mantissa bits.
+    F8E5M2,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -31,11 +43,17 @@ impl std::str::FromStr for DType {
         match s {
             "u8" => Ok(Self::U8),
             "u32" => Ok(Self::U32),
+            "i8" => Ok(Self::I8),
+            "i16" => Ok(Self::I16),
+            "i32" => Ok(Self::I32),
             "i64" => Ok(Self::I64),
+            "bool" => Ok(Self::Bool),
             "bf16" => Ok(Self::BF16),
             "f16" => Ok(Self::F16),
             "f32" => Ok(Self::F32),
             "f64" => Ok(Self::F64),
+            "f8e4m3" => Ok(Self::F8E4M3),
+            "f8e5m2" => Ok(Self::F8E5M2),
             _ => Err(DTypeParseError),
         }
     }
@@ -47,11 +65,17 @@ impl DType {
         match self {
             Self::U8 => "u8",
             Self::U32 => "u32",
+            Self::I8 => "i8",
+            Self::I16 => "i16",
+            Self::I32 => "i32",
             Self::I64 => "i64",
+            Self::Bool => "bool",
             Self::BF16 => "bf16",
             Self::F16 => "f16",
             Self::F32 => "f32",
             Self::F64 => "f64",
+            Self::F8E4M3 => "f8e4m3",
+            Self::F8E5M2 => "f8e5m2",
         }
     }
 
@@ -60,27 +84,45 @@ impl DType {
         match self {
             Self::U8 => 1,
             Self::U32 => 4,
+            Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I32 => 4,
             Self::I64 => 8,
+            Self::Bool => 1,
             Self::BF16 => 2,
             Self::F16 => 2,
             Self::F32 => 4,
             Self::F64 => 8,
+            Self::F8E4M3 => 1,
+            Self::F8E5M2 => 1,
         }
     }
 
     pub fn is_int(&self) -> bool {
         match self {
-            Self::U8 | Self::U32 | Self::I64 => true,
-            Self::BF16 | Self::F16 | Self::F32 | Self::F64 => false,
+            Self::U8 | Self::U32 | Self::I8 | Self::I16 | Self::I32 | Self::I64 => true,
+            Self::Bool
+            | Self::BF16
+            | Self::F16
+            | Self::F32
+            | Self::F64
+            | Self::F8E4M3
+            | Self::F8E5M2 => false,
         }
     }
 
     pub fn is_float(&self) -> bool {
         match self {
-            Self::U8 | Self::U32 | Self::I64 => false,
-            Self::BF16 | Self::F16 | Self::F32 | Self::F64 => true,
+            Self::U8 | Self::U32 | Self::I8 | Self::I16 | Self::I32 | Self::I64 | Self::Bool => {
+                false
+            }
+            Self::BF16 | Self::F16 | Self::F32 | Self::F64 | Self::F8E4M3 | Self::F8E5M2 => true,
         }
     }
+
+    pub fn is_signed_int(&self) -> bool {
+        matches!(self, Self::I8 | Self::I16 | Self::I32 | Self::I64)
+    }
 }
 
 pub trait WithDType:
@@ -155,23 +197,48 @@ use half::{bf16, f16};
 
 with_dtype!(u8, U8, |v: f64| v as u8, |v: u8| v as f64);
 with_dtype!(u32, U32, |v: f64| v as u32, |v: u32| v as f64);
+with_dtype!(i8, I8, |v: f64| v as i8, |v: i8| v as f64);
+with_dtype!(i16, I16, |v: f64| v as i16, |v: i16| v as f64);
+with_dtype!(i32, I32, |v: f64| v as i32, |v: i32| v as f64);
 with_dtype!(i64, I64, |v: f64| v as i64, |v: i64| v as f64);
+with_dtype!(Bool, Bool, |v: f64| Bool(v != 0.), |v: Bool| u8::from(v.0)
+    as f64);
 with_dtype!(f16, F16, f16::from_f64, f16::to_f64);
 with_dtype!(bf16, BF16, bf16::from_f64, bf16::to_f64);
 with_dtype!(f32, F32, |v: f64| v as f32, |v: f32| v as f64);
 with_dtype!(f64, F64, |v: f64| v, |v: f64| v);
+with_dtype!(F8E4M3, F8E4M3, F8E4M3::from_f64, F8E4M3::to_f64);
+with_dtype!(F8E5M2, F8E5M2, F8E5M2::from_f64, F8E5M2::to_f64);
 
 pub trait IntDType: WithDType {
     fn is_true(&self) -> bool;
     fn as_usize(&self) -> usize;
 }
 
-impl IntDType for i64 {
+macro_rules! int_dtype {
+    ($ty:ty) => {
+        impl IntDType for $ty {
+            fn is_true(&self) -> bool {
+                *self != 0
+            }
+            fn as_usize(&self) -> usize {
+                *self as usize
+            }
+        }
+    };
+}
+
+int_dtype!(i8);
+int_dtype!(i16);
+int_dtype!(i32);
+int_dtype!(i64);
+
+impl IntDType for Bool {
     fn is_true(&self) -> bool {
-        *self != 0
+        self.0
     }
     fn as_usize(&self) -> usize {
-        *self as usize
+        usize::from(self.0)
     }
 }
 
@@ -199,3 +266,108 @@ impl FloatDType for f16 {}
 impl FloatDType for bf16 {}
 impl FloatDType for f32 {}
 impl FloatDType for f64 {}
+
+/// The element type of [`DType::Bool`] tensors.
+///
+/// The arithmetic treats `false` as 0 and `true` as 1 and saturates, so that addition is a
+/// logical or and multiplication is a logical and. Dividing by `false` does not panic and returns
+/// `false`, as does the remainder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Bool(pub bool);
+
+impl From<bool> for Bool {
+    fn from(v: bool) -> Self {
+        Self(v)
+    }
+}
+
+impl From<Bool> for bool {
+    fn from(v: Bool) -> Self {
+        v.0
+    }
+}
+
+impl std::fmt::Display for Bool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::ops::Add for Bool {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self(self.0 || rhs.0)
+    }
+}
+
+impl std::ops::Sub for Bool {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 & !rhs.0)
+    }
+}
+
+impl std::ops::Mul for Bool {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self(self.0 && rhs.0)
+    }
+}
+
+impl std::ops::Div for Bool {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        Self(self.0 && rhs.0)
+    }
+}
+
+impl std::ops::Rem for Bool {
+    type Output = Self;
+    fn rem(self, _rhs: Self) -> Self {
+        Self(false)
+    }
+}
+
+macro_rules! bool_assign_op {
+    ($trait:ident, $fn:ident, $op:tt) => {
+        impl std::ops::$trait for Bool {
+            fn $fn(&mut self, rhs: Self) {
+                *self = *self $op rhs
+            }
+        }
+    };
+}
+
+bool_assign_op!(AddAssign, add_assign, +);
+bool_assign_op!(SubAssign, sub_assign, -);
+bool_assign_op!(MulAssign, mul_assign, *);
+bool_assign_op!(DivAssign, div_assign, /);
+bool_assign_op!(RemAssign, rem_assign, %);
+
+impl num_traits::Zero for Bool {
+    fn zero() -> Self {
+        Self(false)
+    }
+
+    fn is_zero(&self) -> bool {
+        !self.0
+    }
+}
+
+impl num_traits::One for Bool {
+    fn one() -> Self {
+        Self(true)
+    }
+}
+
+impl num_traits::Num for Bool {
+    type FromStrRadixErr = std::str::ParseBoolError;
+
+    fn from_str_radix(s: &str, _radix: u32) -> std::result::Result<Self, Self::FromStrRadixErr> {
+        match s {
+            "0" => Ok(Self(false)),
+            "1" => Ok(Self(true)),
+            s => Ok(Self(s.parse()?)),
+        }
+    }
+}
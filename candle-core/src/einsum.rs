@@ -0,0 +1,299 @@
+//! Einstein summation over tensors.
+use crate::{bail, Result, Tensor};
+use std::collections::HashMap;
+
+// The letters of an equation are labelled by their char code, the dimensions covered by an
+// ellipsis are labelled from `ELLIPSIS` onwards, right-aligned as in numpy broadcasting.
+type Label = usize;
+const ELLIPSIS: Label = 256;
+
+// A parsed term, `ellipsis` holds the position of the ellipsis within the labels.
+struct Term {
+    labels: Vec<Label>,
+    ellipsis: Option<usize>,
+}
+
+impl Term {
+    fn parse(term: &str, spec: &str) -> Result<Self> {
+        let mut labels = vec![];
+        let mut ellipsis = None;
+        let mut rest = term.trim();
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix("...") {
+                if ellipsis.is_some() {
+                    bail!("einsum: multiple ellipsis in term '{term}' of '{spec}'")
+                }
+                ellipsis = Some(labels.len());
+                rest = r;
+                continue;
+            }
+            let c = rest.chars().next().unwrap();
+            if !c.is_ascii_alphabetic() {
+                bail!("einsum: invalid character '{c}' in '{spec}'")
+            }
+            labels.push(c as Label);
+            rest = &rest[1..];
+        }
+        Ok(Self { labels, ellipsis })
+    }
+
+    // Returns the labels of each dimension of a tensor with `rank` dimensions.
+    fn expand(&self, rank: usize, spec: &str) -> Result<Vec<Label>> {
+        match self.ellipsis {
+            None if rank != self.labels.len() => {
+                bail!(
+                    "einsum: operand of rank {rank} does not match {} labels in '{spec}'",
+                    self.labels.len()
+                )
+            }
+            None => Ok(self.labels.clone()),
+            Some(_) if rank < self.labels.len() => {
+                bail!(
+                    "einsum: operand of rank {rank} has less than {} dims in '{spec}'",
+                    self.labels.len()
+                )
+            }
+            Some(_) => Ok(self.expand_ellipsis(rank - self.labels.len())),
+        }
+    }
+
+    fn expand_ellipsis(&self, ellipsis_rank: usize) -> Vec<Label> {
+        match self.ellipsis {
+            None => self.labels.clone(),
+            Some(pos) => {
+                let mut labels = self.labels[..pos].to_vec();
+                labels.extend((0..ellipsis_rank).map(|i| ELLIPSIS + i));
+                labels.extend_from_slice(&self.labels[pos..]);
+                labels
+            }
+        }
+    }
+}
+
+// A tensor together with the label of each of its dimensions, the labels are distinct.
+struct Operand {
+    tensor: Tensor,
+    labels: Vec<Label>,
+}
+
+impl Operand {
+    // Takes the diagonal over the dimensions sharing the same label.
+    fn diagonals(mut tensor: Tensor, mut labels: Vec<Label>) -> Result<Self> {
+        while let Some((i, j)) = (0..labels.len()).find_map(|i| {
+            let j = (i + 1..labels.len()).find(|&j| labels[j] == labels[i])?;
+            Some((i, j))
+        }) {
+            let (n, m) = (tensor.dims()[i], tensor.dims()[j]);
+            if n != m {
+                bail!("einsum: repeated label with different sizes {n} and {m}")
+            }
+            // Move the two dimensions last and pick the elements with the same index on both.
+            let mut perm: Vec<usize> = (0..labels.len()).filter(|&d| d != i && d != j).collect();
+            perm.extend([i, j]);
+            let mut dims: Vec<usize> = perm[..perm.len() - 2]
+                .iter()
+                .map(|&d| tensor.dims()[d])
+                .collect();
+            dims.push(n * n);
+            let flat = tensor.permute(perm.as_slice())?.reshape(dims)?;
+            let indexes: Vec<u32> = (0..n).map(|k| (k * (n + 1)) as u32).collect();
+            let indexes = Tensor::new(indexes, tensor.device())?;
+            tensor = flat.index_select(&indexes, flat.rank() - 1)?;
+            let label = labels[i];
+            labels = perm[..perm.len() - 2].iter().map(|&d| labels[d]).collect();
+            labels.push(label);
+        }
+        Ok(Self { tensor, labels })
+    }
+
+    // Sums over the dimensions whose label is not in `keep`.
+    fn sum_except(&self, keep: &[Label]) -> Result<Self> {
+        let (sum_dims, labels): (Vec<_>, Vec<_>) =
+            (0..self.labels.len()).partition(|&d| !keep.contains(&self.labels[d]));
+        let tensor = if sum_dims.is_empty() {
+            self.tensor.clone()
+        } else {
+            self.tensor.sum(sum_dims)?
+        };
+        let labels = labels.into_iter().map(|d| self.labels[d]).collect();
+        Ok(Self { tensor, labels })
+    }
+
+    // Permutes the dimensions to follow `labels` and broadcasts them to the sizes in `sizes`.
+    fn arrange(&self, labels: &[Label], sizes: &HashMap<Label, usize>) -> Result<Tensor> {
+        let perm: Vec<usize> = labels
+            .iter()
+            .map(|l| self.labels.iter().position(|s| s == l).unwrap())
+            .collect();
+        let dims: Vec<usize> = labels.iter().map(|l| sizes[l]).collect();
+        self.tensor.permute(perm)?.broadcast_as(dims)
+    }
+
+    // Multiplies two operands and sums over the shared labels that are not in `keep`, this
+    // lowers to a single batched matmul.
+    fn contract(&self, rhs: &Self, keep: &[Label]) -> Result<Self> {
+        let mut sizes = HashMap::new();
+        for op in [self, rhs] {
+            for (&label, &size) in op.labels.iter().zip(op.tensor.dims()) {
+                let s = sizes.entry(label).or_insert(size);
+                if *s == 1 {
+                    *s = size
+                } else if size != 1 && size != *s {
+                    bail!("einsum: incompatible sizes {s} and {size} for the same label")
+                }
+            }
+        }
+        let in_rhs = |l: &Label| rhs.labels.contains(l);
+        let batch: Vec<Label> = self
+            .labels
+            .iter()
+            .filter(|l| in_rhs(l) && keep.contains(l))
+            .copied()
+            .collect();
+        let contracted: Vec<Label> = self
+            .labels
+            .iter()
+            .filter(|l| in_rhs(l) && !keep.contains(l))
+            .copied()
+            .collect();
+        let lhs_only: Vec<Label> = self.labels.iter().filter(|l| !in_rhs(l)).copied().collect();
+        let rhs_only: Vec<Label> = rhs
+            .labels
+            .iter()
+            .filter(|l| !self.labels.contains(l))
+            .copied()
+            .collect();
+        let numel = |labels: &[Label]| labels.iter().map(|l| sizes[l]).product::<usize>();
+        let (b, m, k, n) = (
+            numel(&batch),
+            numel(&lhs_only),
+            numel(&contracted),
+            numel(&rhs_only),
+        );
+        let lhs = self
+            .arrange(&[batch.as_slice(), &lhs_only, &contracted].concat(), &sizes)?
+            .reshape((b, m, k))?;
+        let rhs = rhs
+            .arrange(&[batch.as_slice(), &contracted, &rhs_only].concat(), &sizes)?
+            .reshape((b, k, n))?;
+        let labels = [batch, lhs_only, rhs_only].concat();
+        let dims: Vec<usize> = labels.iter().map(|l| sizes[l]).collect();
+        let tensor = lhs.matmul(&rhs)?.reshape(dims)?;
+        Ok(Self { tensor, labels })
+    }
+}
+
+impl Tensor {
+    /// Evaluates the Einstein summation convention on the operands, with the same semantics as
+    /// `numpy.einsum` and `torch.einsum`.
+    ///
+    /// The equation has one comma separated term per operand, each letter labelling a dimension
+    /// of the operand, optionally followed by `->` and the labels of the output. Without an
+    /// explicit output, the output labels are the ones appearing only once, in alphabetical
+    /// order. The labels missing from the output are summed over.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::arange(0f32, 6., &Device::Cpu)?.reshape((2, 3))?;
+    /// let b = Tensor::arange(0f32, 12., &Device::Cpu)?.reshape((3, 4))?;
+    /// let c = Tensor::einsum("ij,jk->ik", &[&a, &b])?;
+    /// assert_eq!(c.to_vec2::<f32>()?, a.matmul(&b)?.to_vec2::<f32>()?);
+    /// let trace = Tensor::einsum("ii", &[&c.narrow(1, 0, 2)?])?;
+    /// assert_eq!(trace.to_vec0::<f32>()?, 20. + 68.);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    ///
+    /// An ellipsis `...` stands for the dimensions that are not labelled, these are broadcast
+    /// between the operands. A label repeated within a term takes the diagonal of the
+    /// corresponding dimensions. The operands are contracted from left to right, each
+    /// contraction using a single batched matmul, so the result is differentiable.
+    pub fn einsum(spec: &str, operands: &[&Tensor]) -> Result<Self> {
+        let (inputs, output) = match spec.split_once("->") {
+            Some((inputs, output)) => (inputs, Some(output)),
+            None => (spec, None),
+        };
+        let terms = inputs
+            .split(',')
+            .map(|term| Term::parse(term, spec))
+            .collect::<Result<Vec<_>>>()?;
+        if terms.len() != operands.len() {
+            bail!(
+                "einsum: '{spec}' has {} terms but got {} operands",
+                terms.len(),
+                operands.len()
+            )
+        }
+        let mut ellipsis_rank = 0;
+        let mut labels = Vec::with_capacity(operands.len());
+        for (term, operand) in terms.iter().zip(operands.iter()) {
+            let l = term.expand(operand.rank(), spec)?;
+            if term.ellipsis.is_some() {
+                ellipsis_rank = usize::max(ellipsis_rank, l.len() - term.labels.len())
+            }
+            labels.push(l)
+        }
+        let output = match output {
+            Some(output) => {
+                let term = Term::parse(output, spec)?;
+                for (i, l) in term.labels.iter().enumerate() {
+                    if term.labels[i + 1..].contains(l) {
+                        bail!("einsum: repeated output label in '{spec}'")
+                    }
+                    if !terms.iter().any(|t| t.labels.contains(l)) {
+                        bail!("einsum: output label not in the inputs of '{spec}'")
+                    }
+                }
+                term.expand_ellipsis(ellipsis_rank)
+            }
+            None => {
+                let mut counts = HashMap::new();
+                for l in terms.iter().flat_map(|t| t.labels.iter()) {
+                    *counts.entry(*l).or_insert(0) += 1
+                }
+                let mut letters: Vec<Label> = counts
+                    .into_iter()
+                    .filter_map(|(l, c)| (c == 1).then_some(l))
+                    .collect();
+                letters.sort();
+                let mut output: Vec<Label> = (0..ellipsis_rank).map(|i| ELLIPSIS + i).collect();
+                output.extend(letters);
+                output
+            }
+        };
+
+        // Ellipsis dims are right-aligned over the operands.
+        let mut ops = Vec::with_capacity(operands.len());
+        for ((term, operand), labels) in terms.iter().zip(operands.iter()).zip(labels) {
+            let labels = match term.ellipsis {
+                None => labels,
+                Some(_) => {
+                    let rank = labels.len() - term.labels.len();
+                    let shift = ellipsis_rank - rank;
+                    labels
+                        .into_iter()
+                        .map(|l| if l >= ELLIPSIS { l + shift } else { l })
+                        .collect()
+                }
+            };
+            ops.push(Operand::diagonals((*operand).clone(), labels)?)
+        }
+        // The labels still needed once the operands before `ops[i]` have been contracted.
+        let needed = |i: usize| -> Vec<Label> {
+            let mut needed = output.clone();
+            needed.extend(ops[i..].iter().flat_map(|op| op.labels.iter()));
+            needed
+        };
+        let mut acc = ops[0].sum_except(&needed(1))?;
+        for (i, op) in ops.iter().enumerate().skip(1) {
+            let keep = needed(i + 1);
+            let op = op.sum_except(&[keep.as_slice(), &acc.labels].concat())?;
+            acc = acc.contract(&op, &keep)?;
+        }
+        let acc = acc.sum_except(&output)?;
+        let perm: Vec<usize> = output
+            .iter()
+            .map(|l| acc.labels.iter().position(|s| s == l).unwrap())
+            .collect();
+        acc.tensor.permute(perm)
+    }
+}
@@ -0,0 +1,429 @@
+//! Discrete Fourier transforms.
+//!
+//! Complex tensors are represented as real tensors with a trailing dimension of size 2 holding
+//! the real and imaginary parts, e.g. the `fft` of a complex tensor of shape `(b, n, 2)` is
+//! another complex tensor of shape `(b, n, 2)`. The transforms use a radix-2 FFT, or Bluestein's
+//! algorithm when the size is not a power of two, so they run in `O(n log n)`. They are only
+//! implemented on the cpu, tensors on other devices are copied to the cpu and back.
+use crate::cpu_backend::Map1;
+use crate::{bail, CpuStorage, DType, Device, Error, Layout, Result, Shape, Tensor, WithDType, D};
+
+// In place radix-2 transform of a buffer which size is a power of two. The inverse transform
+// is not normalized.
+fn fft_radix2(re: &mut [f64], im: &mut [f64], inverse: bool) {
+    let n = re.len();
+    if n <= 1 {
+        return;
+    }
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1. } else { -1. };
+    let mut len = 2;
+    while len <= n {
+        let half = len / 2;
+        let twiddles: Vec<(f64, f64)> = (0..half)
+            .map(|k| {
+                let theta = sign * 2. * std::f64::consts::PI * k as f64 / len as f64;
+                (theta.cos(), theta.sin())
+            })
+            .collect();
+        for start in (0..n).step_by(len) {
+            for (k, &(w_re, w_im)) in twiddles.iter().enumerate() {
+                let (i, j) = (start + k, start + k + half);
+                let t_re = re[j] * w_re - im[j] * w_im;
+                let t_im = re[j] * w_im + im[j] * w_re;
+                re[j] = re[i] - t_re;
+                im[j] = im[i] - t_im;
+                re[i] += t_re;
+                im[i] += t_im;
+            }
+        }
+        len *= 2;
+    }
+}
+
+// In place transform of a buffer of any size, the sizes that are not powers of two use
+// Bluestein's algorithm, i.e. the transform is written as a convolution with a chirp which is
+// computed with radix-2 transforms. The inverse transform is not normalized.
+fn fft_any(re: &mut [f64], im: &mut [f64], inverse: bool) {
+    let n = re.len();
+    if n.is_power_of_two() || n == 0 {
+        return fft_radix2(re, im, inverse);
+    }
+    let m = (2 * n - 1).next_power_of_two();
+    let sign = if inverse { 1. } else { -1. };
+    // The chirp exp(sign * iπk²/n), reducing k² modulo 2n keeps the angles accurate.
+    let chirp: Vec<(f64, f64)> = (0..n)
+        .map(|k| {
+            let theta = sign * std::f64::consts::PI * ((k * k) % (2 * n)) as f64 / n as f64;
+            (theta.cos(), theta.sin())
+        })
+        .collect();
+    let (mut a_re, mut a_im) = (vec![0f64; m], vec![0f64; m]);
+    for (k, &(c_re, c_im)) in chirp.iter().enumerate() {
+        a_re[k] = re[k] * c_re - im[k] * c_im;
+        a_im[k] = re[k] * c_im + im[k] * c_re;
+    }
+    let (mut b_re, mut b_im) = (vec![0f64; m], vec![0f64; m]);
+    for (k, &(c_re, c_im)) in chirp.iter().enumerate() {
+        b_re[k] = c_re;
+        b_im[k] = -c_im;
+        if k > 0 {
+            b_re[m - k] = c_re;
+            b_im[m - k] = -c_im;
+        }
+    }
+    fft_radix2(&mut a_re, &mut a_im, false);
+    fft_radix2(&mut b_re, &mut b_im, false);
+    for k in 0..m {
+        let p_re = a_re[k] * b_re[k] - a_im[k] * b_im[k];
+        let p_im = a_re[k] * b_im[k] + a_im[k] * b_re[k];
+        a_re[k] = p_re;
+        a_im[k] = p_im;
+    }
+    fft_radix2(&mut a_re, &mut a_im, true);
+    for (k, &(c_re, c_im)) in chirp.iter().enumerate() {
+        let (p_re, p_im) = (a_re[k] / m as f64, a_im[k] / m as f64);
+        re[k] = p_re * c_re - p_im * c_im;
+        im[k] = p_re * c_im + p_im * c_re;
+    }
+}
+
+// The transform over the second to last dimension of a complex tensor, the inverse transform is
+// normalized by `1/n`.
+struct Fft {
+    inverse: bool,
+}
+
+impl Map1 for Fft {
+    fn f<T: WithDType>(&self, vs: &[T], layout: &Layout) -> Result<Vec<T>> {
+        let vs = match layout.contiguous_offsets() {
+            Some((o1, o2)) => &vs[o1..o2],
+            None => bail!("fft: the input has to be contiguous"),
+        };
+        let n = layout.dims()[layout.dims().len() - 2];
+        let mut dst = Vec::with_capacity(vs.len());
+        let (mut re, mut im) = (vec![0f64; n], vec![0f64; n]);
+        let scale = if self.inverse { 1. / n as f64 } else { 1. };
+        for src in vs.chunks_exact(2 * n) {
+            for k in 0..n {
+                re[k] = src[2 * k].to_f64();
+                im[k] = src[2 * k + 1].to_f64();
+            }
+            fft_any(&mut re, &mut im, self.inverse);
+            for k in 0..n {
+                dst.push(T::from_f64(re[k] * scale));
+                dst.push(T::from_f64(im[k] * scale));
+            }
+        }
+        Ok(dst)
+    }
+}
+
+impl crate::CustomOp1 for Fft {
+    fn name(&self) -> &'static str {
+        if self.inverse {
+            "ifft"
+        } else {
+            "fft"
+        }
+    }
+
+    fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)> {
+        Ok((self.map(storage, layout)?, layout.shape().clone()))
+    }
+
+    fn bwd(&self, _arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        // The dft matrix is symmetric so the gradient is the transform using its conjugate, i.e.
+        // the unnormalized inverse for the forward transform and conversely.
+        let n = grad_res.dim(D::Minus2)? as f64;
+        let grad = if self.inverse {
+            grad_res.fft()?.affine(1. / n, 0.)?
+        } else {
+            grad_res.ifft()?.affine(n, 0.)?
+        };
+        Ok(Some(grad))
+    }
+}
+
+fn check_float(xs: &Tensor, op: &'static str) -> Result<()> {
+    if !xs.dtype().is_float() {
+        Err(Error::UnsupportedDTypeForOp(xs.dtype(), op).bt())?
+    }
+    Ok(())
+}
+
+// Splits a complex tensor into its real and imaginary parts.
+fn complex_parts(xs: &Tensor, op: &'static str) -> Result<(Tensor, Tensor)> {
+    check_float(xs, op)?;
+    if xs.rank() < 2 || xs.dim(D::Minus1)? != 2 {
+        bail!(
+            "{op} expects a complex tensor with a trailing dimension of size 2, got {:?}",
+            xs.shape()
+        )
+    }
+    let re = xs.narrow(D::Minus1, 0, 1)?.squeeze(D::Minus1)?;
+    let im = xs.narrow(D::Minus1, 1, 1)?.squeeze(D::Minus1)?;
+    Ok((re, im))
+}
+
+// The indexes of the frames of size `n_fft` starting every `hop_length` elements.
+fn frame_indexes(
+    n_frames: usize,
+    n_fft: usize,
+    hop_length: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let ids: Vec<u32> = (0..n_frames)
+        .flat_map(|t| (0..n_fft).map(move |j| (t * hop_length + j) as u32))
+        .collect();
+    Tensor::from_vec(ids, n_frames * n_fft, device)
+}
+
+fn window_values(window: Option<&Tensor>, n_fft: usize) -> Result<Option<Vec<f64>>> {
+    match window {
+        None => Ok(None),
+        Some(window) => {
+            let len = window.dims1()?;
+            if len != n_fft {
+                bail!("the window length {len} does not match n_fft {n_fft}")
+            }
+            Ok(Some(window.to_dtype(DType::F64)?.to_vec1::<f64>()?))
+        }
+    }
+}
+
+impl Tensor {
+    fn fft_(&self, inverse: bool, op: &'static str) -> Result<Self> {
+        complex_parts(self, op)?;
+        if self.dim(D::Minus2)? == 0 {
+            return Ok(self.clone());
+        }
+        let xs = self.contiguous()?;
+        let fft = Fft { inverse };
+        if xs.device().is_cpu() {
+            xs.apply_op1(fft)
+        } else {
+            xs.to_device(&Device::Cpu)?
+                .apply_op1(fft)?
+                .to_device(self.device())
+        }
+    }
+
+    /// The discrete Fourier transform over the last dimension of a complex tensor, the input
+    /// shape `(..., n, 2)` is preserved.
+    pub fn fft(&self) -> Result<Self> {
+        self.fft_(false, "fft")
+    }
+
+    /// The inverse of `fft`, the result is normalized by `1/n`.
+    pub fn ifft(&self) -> Result<Self> {
+        self.fft_(true, "ifft")
+    }
+
+    /// The discrete Fourier transform over the last dimension of a real tensor. Only the
+    /// non-negative frequencies are returned so an input of shape `(..., n)` results in a
+    /// complex tensor of shape `(..., n / 2 + 1, 2)`.
+    pub fn rfft(&self) -> Result<Self> {
+        check_float(self, "rfft")?;
+        let n = self.dim(D::Minus1)?;
+        if n == 0 {
+            bail!(
+                "rfft expects a non-empty last dimension, got {:?}",
+                self.shape()
+            )
+        }
+        Tensor::stack(&[self, &self.zeros_like()?], D::Minus1)?
+            .fft()?
+            .narrow(D::Minus2, 0, n / 2 + 1)
+    }
+
+    /// The inverse of `rfft`, the input of shape `(..., n / 2 + 1, 2)` is mapped to a real tensor
+    /// of shape `(..., n)`. The imaginary parts of the zero and Nyquist frequencies are ignored.
+    pub fn irfft(&self, n: usize) -> Result<Self> {
+        let (re, im) = complex_parts(self, "irfft")?;
+        let n_bins = re.dim(D::Minus1)?;
+        if n == 0 || n_bins != n / 2 + 1 {
+            bail!(
+                "irfft expects {} frequency bins for n {n}, got {n_bins}",
+                n / 2 + 1
+            )
+        }
+        // The negative frequencies are the conjugates of the positive ones.
+        let ids: Vec<u32> = (0..n)
+            .map(|k| if k < n_bins { k } else { n - k } as u32)
+            .collect();
+        let signs: Vec<f64> = (0..n)
+            .map(|k| {
+                if k == 0 || 2 * k == n {
+                    0.
+                } else if k < n_bins {
+                    1.
+                } else {
+                    -1.
+                }
+            })
+            .collect();
+        let ids = Tensor::from_vec(ids, n, self.device())?;
+        let signs = Tensor::from_vec(signs, n, self.device())?.to_dtype(self.dtype())?;
+        let re = re.contiguous()?.index_select(&ids, D::Minus1)?;
+        let im = im
+            .contiguous()?
+            .index_select(&ids, D::Minus1)?
+            .broadcast_mul(&signs)?;
+        Tensor::stack(&[re, im], D::Minus1)?
+            .ifft()?
+            .narrow(D::Minus1, 0, 1)?
+            .squeeze(D::Minus1)
+    }
+
+    /// Pads the last dimension by reflecting the values around the first and last elements.
+    fn reflect_pad_last_dim(&self, pad: usize) -> Result<Self> {
+        let len = self.dim(D::Minus1)?;
+        if pad >= len {
+            bail!("reflect padding {pad} should be smaller than the dimension size {len}")
+        }
+        let ids: Vec<u32> = (0..pad)
+            .map(|i| (pad - i) as u32)
+            .chain((0..len).map(|i| i as u32))
+            .chain((0..pad).map(|i| (len - 2 - i) as u32))
+            .collect();
+        let ids = Tensor::from_vec(ids, len + 2 * pad, self.device())?;
+        self.contiguous()?.index_select(&ids, D::Minus1)
+    }
+
+    /// The short-time Fourier transform of a real signal of shape `(..., len)`.
+    ///
+    /// The signal is split into frames of size `n_fft` every `hop_length` samples, the frames
+    /// are multiplied by `window` when provided and transformed with `rfft`. The result is a
+    /// complex tensor of shape `(..., n_fft / 2 + 1, n_frames, 2)`. When `center` is set, the
+    /// signal is reflect padded by `n_fft / 2` on both sides so that frame `t` is centered on
+    /// sample `t * hop_length`.
+    pub fn stft(
+        &self,
+        n_fft: usize,
+        hop_length: usize,
+        window: Option<&Tensor>,
+        center: bool,
+    ) -> Result<Self> {
+        check_float(self, "stft")?;
+        if n_fft == 0 || hop_length == 0 {
+            bail!("stft expects non-zero n_fft and hop_length, got {n_fft} {hop_length}")
+        }
+        let xs = if center {
+            self.reflect_pad_last_dim(n_fft / 2)?
+        } else {
+            self.clone()
+        };
+        let len = xs.dim(D::Minus1)?;
+        if len < n_fft {
+            bail!("stft input of length {len} is smaller than n_fft {n_fft}")
+        }
+        let n_frames = 1 + (len - n_fft) / hop_length;
+        let ids = frame_indexes(n_frames, n_fft, hop_length, xs.device())?;
+        let mut dims = xs.dims().to_vec();
+        dims.pop();
+        dims.push(n_frames);
+        dims.push(n_fft);
+        let frames = xs
+            .contiguous()?
+            .index_select(&ids, D::Minus1)?
+            .reshape(dims)?;
+        let frames = match window {
+            None => frames,
+            Some(window) => {
+                let len = window.dims1()?;
+                if len != n_fft {
+                    bail!("the window length {len} does not match n_fft {n_fft}")
+                }
+                frames.broadcast_mul(window)?
+            }
+        };
+        let spec = frames.rfft()?;
+        let rank = spec.rank();
+        spec.transpose(rank - 3, rank - 2)
+    }
+
+    /// The inverse of `stft`, the input has shape `(..., n_fft / 2 + 1, n_frames, 2)`.
+    ///
+    /// The frames are recovered with `irfft` and overlap-added, the result is normalized by the
+    /// overlap-added squared window. `length` can be used to trim the output, by default its
+    /// size is the size of the signal passed to `stft` rounded down to the last full frame.
+    pub fn istft(
+        &self,
+        n_fft: usize,
+        hop_length: usize,
+        window: Option<&Tensor>,
+        center: bool,
+        length: Option<usize>,
+    ) -> Result<Self> {
+        if hop_length == 0 {
+            bail!("istft expects a non-zero hop_length")
+        }
+        let rank = self.rank();
+        if rank < 3 {
+            bail!("istft expects a complex tensor of shape (..., n_bins, n_frames, 2)")
+        }
+        let window_vs = window_values(window, n_fft)?;
+        let frames = self.transpose(rank - 3, rank - 2)?.irfft(n_fft)?;
+        let frames = match window {
+            None => frames,
+            Some(window) => frames.broadcast_mul(window)?,
+        };
+        let n_frames = frames.dim(D::Minus2)?;
+        if n_frames == 0 {
+            bail!("istft expects at least one frame")
+        }
+        let total = n_fft + hop_length * (n_frames - 1);
+        let ids = frame_indexes(n_frames, n_fft, hop_length, frames.device())?;
+        let frames = frames.flatten_from(D::Minus2)?;
+        let mut dims = frames.dims().to_vec();
+        dims.pop();
+        dims.push(total);
+        let out = Tensor::zeros(dims, frames.dtype(), frames.device())?.index_add(
+            &ids,
+            &frames,
+            D::Minus1,
+        )?;
+
+        let start = if center { n_fft / 2 } else { 0 };
+        let length = match length {
+            Some(length) => length,
+            None => total.saturating_sub(2 * start),
+        };
+        if start + length > total {
+            bail!("istft length {length} exceeds the reconstructed signal size {total}")
+        }
+        let mut envelope = vec![0f64; total];
+        for t in 0..n_frames {
+            for j in 0..n_fft {
+                let w = window_vs.as_ref().map_or(1., |w| w[j]);
+                envelope[t * hop_length + j] += w * w;
+            }
+        }
+        let envelope = &envelope[start..start + length];
+        if envelope.iter().any(|&v| v < 1e-11) {
+            bail!("istft window overlap-add is zero, the signal cannot be reconstructed")
+        }
+        let envelope = Tensor::from_slice(envelope, length, out.device())?.to_dtype(out.dtype())?;
+        out.narrow(D::Minus1, start, length)?
+            .broadcast_div(&envelope)
+    }
+
+    /// A periodic Hann window of size `size`, as used for spectrograms.
+    pub fn hann_window(size: usize, dtype: DType, device: &Device) -> Result<Self> {
+        let vs: Vec<f64> = (0..size)
+            .map(|i| {
+                let theta = 2. * std::f64::consts::PI * i as f64 / size as f64;
+                0.5 * (1. - theta.cos())
+            })
+            .collect();
+        Tensor::from_vec(vs, size, device)?.to_dtype(dtype)
+    }
+}
@@ -0,0 +1,206 @@
+//! 8 bits floating-point types.
+//!
+//! These follow the OCP FP8 specification: `F8E4M3` has 4 exponent bits and 3 mantissa bits, no
+//! infinities and a maximum value of 448, `F8E5M2` has 5 exponent bits and 2 mantissa bits and
+//! follows the IEEE conventions for infinities and NaNs. Conversions from `f32` round to the
+//! nearest value, ties to even. Out of range values saturate to the maximum value for `F8E4M3`
+//! and become infinities for `F8E5M2`.
+//!
+//! These are storage types, arithmetic goes through `f32` and is mostly useful for conversions.
+
+// Decodes an fp8 value with `exp_bits` exponent bits and `man_bits` mantissa bits.
+fn decode(bits: u8, exp_bits: u32, man_bits: u32, has_inf: bool) -> f32 {
+    let sign = if bits & 0x80 != 0 { -1f32 } else { 1f32 };
+    let exp_mask = (1u8 << exp_bits) - 1;
+    let man_mask = (1u8 << man_bits) - 1;
+    let exp = (bits >> man_bits) & exp_mask;
+    let man = bits & man_mask;
+    let bias = (1i32 << (exp_bits - 1)) - 1;
+    if exp == exp_mask {
+        if has_inf {
+            return if man == 0 {
+                sign * f32::INFINITY
+            } else {
+                f32::NAN
+            };
+        } else if man == man_mask {
+            return f32::NAN;
+        }
+    }
+    let man = man as f32 / (1u32 << man_bits) as f32;
+    if exp == 0 {
+        sign * man * 2f32.powi(1 - bias)
+    } else {
+        sign * (1. + man) * 2f32.powi(exp as i32 - bias)
+    }
+}
+
+// Encodes `v` with `exp_bits` exponent bits and `man_bits` mantissa bits, `max` is the largest
+// finite value and `overflow` the encoding used for the positive values above it.
+fn encode(v: f32, exp_bits: u32, man_bits: u32, max: f64, overflow: u8, nan: u8) -> u8 {
+    if v.is_nan() {
+        return nan;
+    }
+    let sign = if v.is_sign_negative() { 0x80u8 } else { 0 };
+    let v = (v as f64).abs();
+    let bias = (1i32 << (exp_bits - 1)) - 1;
+    let min_exp = 1 - bias;
+    let exponent = |v: f64| ((v.to_bits() >> 52) & 0x7ff) as i32 - 1023;
+    let overflow = sign | overflow;
+    if v.is_infinite() {
+        return overflow;
+    }
+    if v == 0. {
+        return sign;
+    }
+    // Round to the nearest multiple of the spacing between representable values.
+    let quantum = 2f64.powi(exponent(v).max(min_exp) - man_bits as i32);
+    let v = (v / quantum).round_ties_even() * quantum;
+    if v > max {
+        return overflow;
+    }
+    if v == 0. {
+        return sign;
+    }
+    let exp = exponent(v);
+    if exp < min_exp {
+        let man = v / 2f64.powi(min_exp - man_bits as i32);
+        sign | man as u8
+    } else {
+        let man = v / 2f64.powi(exp - man_bits as i32) - (1u32 << man_bits) as f64;
+        sign | (((exp + bias) as u8) << man_bits) | man as u8
+    }
+}
+
+macro_rules! fp8_type {
+    ($ty:ident, $exp_bits:expr, $man_bits:expr, $max:expr, $overflow:expr, $has_inf:expr, $doc:expr) => {
+        #[doc = $doc]
+        #[derive(Debug, Clone, Copy, Default)]
+        #[repr(transparent)]
+        pub struct $ty(u8);
+
+        impl $ty {
+            /// The largest finite value.
+            pub const MAX: f32 = $max;
+
+            pub const fn from_bits(bits: u8) -> Self {
+                Self(bits)
+            }
+
+            pub const fn to_bits(self) -> u8 {
+                self.0
+            }
+
+            pub fn from_f32(v: f32) -> Self {
+                Self(encode(v, $exp_bits, $man_bits, $max as f64, $overflow, 0x7f))
+            }
+
+            pub fn from_f64(v: f64) -> Self {
+                Self::from_f32(v as f32)
+            }
+
+            pub fn to_f32(self) -> f32 {
+                decode(self.0, $exp_bits, $man_bits, $has_inf)
+            }
+
+            pub fn to_f64(self) -> f64 {
+                self.to_f32() as f64
+            }
+        }
+
+        impl From<$ty> for f32 {
+            fn from(v: $ty) -> Self {
+                v.to_f32()
+            }
+        }
+
+        impl PartialEq for $ty {
+            fn eq(&self, other: &Self) -> bool {
+                self.to_f32() == other.to_f32()
+            }
+        }
+
+        impl PartialOrd for $ty {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                self.to_f32().partial_cmp(&other.to_f32())
+            }
+        }
+
+        impl std::fmt::Display for $ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                self.to_f32().fmt(f)
+            }
+        }
+
+        fp8_binary_op!($ty, Add, add, AddAssign, add_assign, +);
+        fp8_binary_op!($ty, Sub, sub, SubAssign, sub_assign, -);
+        fp8_binary_op!($ty, Mul, mul, MulAssign, mul_assign, *);
+        fp8_binary_op!($ty, Div, div, DivAssign, div_assign, /);
+        fp8_binary_op!($ty, Rem, rem, RemAssign, rem_assign, %);
+
+        impl std::ops::Neg for $ty {
+            type Output = Self;
+            fn neg(self) -> Self {
+                Self(self.0 ^ 0x80)
+            }
+        }
+
+        impl num_traits::Zero for $ty {
+            fn zero() -> Self {
+                Self(0)
+            }
+            fn is_zero(&self) -> bool {
+                self.0 & 0x7f == 0
+            }
+        }
+
+        impl num_traits::One for $ty {
+            fn one() -> Self {
+                Self::from_f32(1.)
+            }
+        }
+
+        impl num_traits::Num for $ty {
+            type FromStrRadixErr = <f32 as num_traits::Num>::FromStrRadixErr;
+            fn from_str_radix(s: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+                <f32 as num_traits::Num>::from_str_radix(s, radix).map(Self::from_f32)
+            }
+        }
+    };
+}
+
+macro_rules! fp8_binary_op {
+    ($ty:ident, $trait:ident, $fn:ident, $assign_trait:ident, $assign_fn:ident, $op:tt) => {
+        impl std::ops::$trait for $ty {
+            type Output = Self;
+            fn $fn(self, rhs: Self) -> Self {
+                Self::from_f32(self.to_f32() $op rhs.to_f32())
+            }
+        }
+
+        impl std::ops::$assign_trait for $ty {
+            fn $assign_fn(&mut self, rhs: Self) {
+                *self = *self $op rhs
+            }
+        }
+    };
+}
+
+fp8_type!(
+    F8E4M3,
+    4,
+    3,
+    448.,
+    0x7e,
+    false,
+    "An 8 bits floating-point value with 4 exponent bits and 3 mantissa bits."
+);
+fp8_type!(
+    F8E5M2,
+    5,
+    2,
+    57344.,
+    0x7c,
+    true,
+    "An 8 bits floating-point value with 5 exponent bits and 2 mantissa bits."
+);
@@ -26,9 +26,8 @@ impl Tensor {
     /// ```
     fn index(&self, indexers: &[TensorIndexer]) -> Result<Self, Error> {
         let mut x = self.clone();
-        let dims = self.shape().dims();
         let mut current_dim = 0;
-        for (i, indexer) in indexers.iter().enumerate() {
+        for indexer in indexers.iter() {
             x = match indexer {
                 TensorIndexer::Select(n) => x.narrow(current_dim, *n, 1)?.squeeze(current_dim)?,
                 TensorIndexer::Narrow(left_bound, right_bound) => {
@@ -40,17 +39,40 @@ impl Tensor {
                     let stop = match right_bound {
                         Bound::Included(n) => *n + 1,
                         Bound::Excluded(n) => *n,
-                        Bound::Unbounded => dims[i],
+                        Bound::Unbounded => x.dim(current_dim)?,
                     };
                     let out = x.narrow(current_dim, start, stop.saturating_sub(start))?;
                     current_dim += 1;
                     out
                 }
                 TensorIndexer::IndexSelect(indexes) => {
-                    if indexes.rank() != 1 {
-                        crate::bail!("multi-dimensional tensor indexing is not supported")
+                    // The indexed dimension gets replaced by the dimensions of the indexes.
+                    let dims = x.dims();
+                    let mut out_dims = dims[..current_dim].to_vec();
+                    out_dims.extend_from_slice(indexes.dims());
+                    out_dims.extend_from_slice(&dims[current_dim + 1..]);
+                    let ids = indexes.flatten_all()?.to_device(x.device())?;
+                    let out = x.index_select(&ids, current_dim)?.reshape(out_dims)?;
+                    current_dim += indexes.rank();
+                    out
+                }
+                TensorIndexer::Mask(mask) => {
+                    // The dimensions covered by the mask get flattened into a single one, only
+                    // the elements for which the mask is non-zero are kept.
+                    let dims = x.dims();
+                    let end_dim = current_dim + mask.rank();
+                    if end_dim > dims.len() || &dims[current_dim..end_dim] != mask.dims() {
+                        crate::bail!(
+                            "mask with shape {:?} does not match the indexed dims {dims:?}",
+                            mask.shape()
+                        )
                     }
-                    let out = x.index_select(&indexes.to_device(x.device())?, current_dim)?;
+                    let mut out_dims = dims[..current_dim].to_vec();
+                    out_dims.push(mask.elem_count());
+                    out_dims.extend_from_slice(&dims[end_dim..]);
+                    let indexes = mask.flatten_all()?.nonzero()?.squeeze(1)?;
+                    let indexes = indexes.to_device(x.device())?;
+                    let out = x.reshape(out_dims)?.index_select(&indexes, current_dim)?;
                     current_dim += 1;
                     out
                 }
@@ -68,8 +90,12 @@ pub enum TensorIndexer {
     Select(usize),
     /// This is a regular slice, purely indexing a chunk of the tensor
     Narrow(Bound<usize>, Bound<usize>),
-    /// Indexing via a 1d tensor
+    /// Indexing via an integer tensor, the indexed dimension is replaced by the dimensions of
+    /// this tensor.
     IndexSelect(Tensor),
+    /// Indexing via a boolean tensor, the elements for which the mask is true are selected over
+    /// the dimensions that it covers.
+    Mask(Tensor),
     Err(Error),
 }
 
@@ -98,9 +124,22 @@ impl From<Vec<u32>> for TensorIndexer {
     }
 }
 
+impl From<&[crate::Bool]> for TensorIndexer {
+    fn from(mask: &[crate::Bool]) -> Self {
+        match Tensor::new(mask, &crate::Device::Cpu) {
+            Ok(tensor) => TensorIndexer::Mask(tensor),
+            Err(e) => TensorIndexer::Err(e),
+        }
+    }
+}
+
 impl From<&Tensor> for TensorIndexer {
     fn from(tensor: &Tensor) -> Self {
-        TensorIndexer::IndexSelect(tensor.clone())
+        if tensor.dtype() == crate::DType::Bool {
+            TensorIndexer::Mask(tensor.clone())
+        } else {
+            TensorIndexer::IndexSelect(tensor.clone())
+        }
     }
 }
 
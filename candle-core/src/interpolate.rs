@@ -0,0 +1,406 @@
+//! Bilinear and bicubic resizing, grid sampling and RoI align.
+//!
+//! These are expressed with `index_select`, `gather` and `matmul` so that the gradients come from
+//! the backward passes of these ops.
+use crate::{bail, DType, IndexOp, Result, Tensor};
+
+/// How the input values are combined by [`Tensor::grid_sample`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridSampleMode {
+    /// Bilinear interpolation of the four closest input values.
+    Bilinear,
+    /// The value of the closest input element.
+    Nearest,
+}
+
+/// How [`Tensor::grid_sample`] handles the locations that fall outside of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridSamplePadding {
+    /// The out of bounds values are zeros.
+    Zeros,
+    /// The locations are clamped to the input borders.
+    Border,
+}
+
+// Returns the position in the input of the output element `dst`, this follows the PyTorch
+// conventions for `align_corners`.
+fn source_index(dst: usize, in_size: usize, out_size: usize, align_corners: bool) -> f64 {
+    if align_corners {
+        if out_size > 1 {
+            dst as f64 * (in_size - 1) as f64 / (out_size - 1) as f64
+        } else {
+            0.
+        }
+    } else {
+        (dst as f64 + 0.5) * in_size as f64 / out_size as f64 - 0.5
+    }
+}
+
+// The interpolation taps along a single dimension, the output element `i` is the weighted sum
+// over the taps `k` of `weights[k * out_size + i] * input[ids[k * out_size + i]]`.
+struct Taps {
+    n_taps: usize,
+    ids: Vec<u32>,
+    weights: Vec<f64>,
+}
+
+impl Taps {
+    fn bilinear(in_size: usize, out_size: usize, align_corners: bool) -> Self {
+        let mut ids = vec![0u32; 2 * out_size];
+        let mut weights = vec![0f64; 2 * out_size];
+        for dst in 0..out_size {
+            // Negative positions are clamped when the corners are not aligned, as in PyTorch.
+            let src = source_index(dst, in_size, out_size, align_corners).max(0.);
+            let i0 = usize::min(src as usize, in_size - 1);
+            let i1 = usize::min(i0 + 1, in_size - 1);
+            let l1 = src - i0 as f64;
+            ids[dst] = i0 as u32;
+            ids[out_size + dst] = i1 as u32;
+            weights[dst] = 1. - l1;
+            weights[out_size + dst] = l1;
+        }
+        Self {
+            n_taps: 2,
+            ids,
+            weights,
+        }
+    }
+
+    fn bicubic(in_size: usize, out_size: usize, align_corners: bool) -> Self {
+        // The cubic convolution coefficients, using the same constant as PyTorch.
+        const A: f64 = -0.75;
+        let cc1 = |x: f64| ((A + 2.) * x - (A + 3.)) * x * x + 1.;
+        let cc2 = |x: f64| ((A * x - 5. * A) * x + 8. * A) * x - 4. * A;
+        let mut ids = vec![0u32; 4 * out_size];
+        let mut weights = vec![0f64; 4 * out_size];
+        for dst in 0..out_size {
+            let src = source_index(dst, in_size, out_size, align_corners);
+            let i = src.floor();
+            let t = src - i;
+            let coeffs = [cc2(t + 1.), cc1(t), cc1(1. - t), cc2(2. - t)];
+            for (k, coeff) in coeffs.into_iter().enumerate() {
+                let idx = (i as i64 + k as i64 - 1).clamp(0, in_size as i64 - 1);
+                ids[k * out_size + dst] = idx as u32;
+                weights[k * out_size + dst] = coeff;
+            }
+        }
+        Self {
+            n_taps: 4,
+            ids,
+            weights,
+        }
+    }
+
+    // Resamples `xs` along dimension `dim`.
+    fn apply(&self, xs: &Tensor, dim: usize) -> Result<Tensor> {
+        let out_size = self.ids.len() / self.n_taps;
+        let ids = Tensor::new(self.ids.as_slice(), xs.device())?;
+        let mut w_dims = vec![self.n_taps, out_size];
+        w_dims.resize(xs.rank() - dim + 1, 1);
+        let weights =
+            Tensor::from_slice(&self.weights, w_dims, xs.device())?.to_dtype(xs.dtype())?;
+        let mut dims = xs.dims()[..dim].to_vec();
+        dims.extend_from_slice(&[self.n_taps, out_size]);
+        dims.extend_from_slice(&xs.dims()[dim + 1..]);
+        xs.contiguous()?
+            .index_select(&ids, dim)?
+            .reshape(dims)?
+            .broadcast_mul(&weights)?
+            .sum(dim)
+    }
+}
+
+// Maps the normalized grid coordinates in [-1, 1] to positions in an input of size `size`.
+fn unnormalize(
+    coords: &Tensor,
+    size: usize,
+    padding: GridSamplePadding,
+    align_corners: bool,
+) -> Result<Tensor> {
+    let size = size as f64;
+    let coords = if align_corners {
+        coords.affine((size - 1.) / 2., (size - 1.) / 2.)?
+    } else {
+        coords.affine(size / 2., (size - 1.) / 2.)?
+    };
+    match padding {
+        GridSamplePadding::Zeros => Ok(coords),
+        GridSamplePadding::Border => coords.clamp(0., size - 1.),
+    }
+}
+
+// Gathers the values of `xs`, with shape `(n, c, h * w)`, at the integer positions `x` and `y`,
+// with shapes `(n, l)` and a floating point dtype that can be different from the `xs` one. The
+// values at out of bounds positions are zeros.
+fn gather_2d(xs: &Tensor, x: &Tensor, y: &Tensor, h: usize, w: usize) -> Result<Tensor> {
+    let (n, c, _) = xs.dims3()?;
+    let l = x.dim(1)?;
+    let (h_max, w_max) = ((h - 1) as f64, (w - 1) as f64);
+    let valid = (x.ge(0.)? * x.le(w_max)?)?;
+    let valid = (valid * y.ge(0.)?)?;
+    let valid = (valid * y.le(h_max)?)?;
+    // The flat indexes are computed on integers so that they are exact for any input size.
+    let x = x.clamp(0., w_max)?.to_dtype(DType::U32)?;
+    let y = y.clamp(0., h_max)?.to_dtype(DType::U32)?;
+    let ids = (y.affine(w as f64, 0.)? + x)?
+        .unsqueeze(1)?
+        .broadcast_as((n, c, l))?
+        .contiguous()?;
+    let valid = valid.to_dtype(xs.dtype())?.unsqueeze(1)?;
+    xs.gather(&ids, 2)?.broadcast_mul(&valid)
+}
+
+// Returns the `(pooled, size)` matrix averaging the bilinear samples of RoI align along one
+// dimension of the input, following the torchvision implementation.
+fn roi_align_weights(
+    (start, end): (f64, f64),
+    size: usize,
+    pooled: usize,
+    sampling_ratio: usize,
+    aligned: bool,
+) -> Vec<f64> {
+    let mut len = end - start;
+    if !aligned {
+        len = len.max(1.)
+    }
+    let bin = len / pooled as f64;
+    let grid = if sampling_ratio > 0 {
+        sampling_ratio
+    } else {
+        bin.ceil().max(0.) as usize
+    };
+    let mut weights = vec![0f64; pooled * size];
+    for p in 0..pooled {
+        let weights = &mut weights[p * size..(p + 1) * size];
+        for i in 0..grid {
+            let pos = start + p as f64 * bin + (i as f64 + 0.5) * bin / grid as f64;
+            if pos < -1. || pos > size as f64 {
+                continue;
+            }
+            let pos = pos.max(0.);
+            let (lo, hi, pos) = if pos as usize >= size - 1 {
+                (size - 1, size - 1, (size - 1) as f64)
+            } else {
+                (pos as usize, pos as usize + 1, pos)
+            };
+            let l = pos - lo as f64;
+            weights[lo] += (1. - l) / grid as f64;
+            weights[hi] += l / grid as f64;
+        }
+    }
+    weights
+}
+
+impl Tensor {
+    /// Resizes the input tensor to `(target_h, target_w)` using bilinear interpolation.
+    ///
+    /// The input tensor should have four dimensions, `(batch, channels, h, w)`, the returned
+    /// tensor has dimensions `(batch, channels, target_h, target_w)`. When `align_corners` is
+    /// true, the corner elements of the input and output are aligned, otherwise the corners of
+    /// the corner elements are aligned, as in PyTorch.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let t = Tensor::new(&[[[[0f32, 1.], [2., 3.]]]], &Device::Cpu)?;
+    /// let t = t.upsample_bilinear2d(3, 3, true)?;
+    /// assert_eq!(
+    ///     t.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?,
+    ///     &[[0., 0.5, 1.], [1., 1.5, 2.], [2., 2.5, 3.]]
+    /// );
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn upsample_bilinear2d(
+        &self,
+        target_h: usize,
+        target_w: usize,
+        align_corners: bool,
+    ) -> Result<Self> {
+        let (_n, _c, h, w) = self.dims4()?;
+        if h == 0 || w == 0 {
+            bail!("upsample-bilinear2d: empty input {:?}", self.shape())
+        }
+        let xs = Taps::bilinear(w, target_w, align_corners).apply(self, 3)?;
+        Taps::bilinear(h, target_h, align_corners).apply(&xs, 2)
+    }
+
+    /// Resizes the input tensor to `(target_h, target_w)` using bicubic interpolation.
+    ///
+    /// This is similar to [`Tensor::upsample_bilinear2d`] but uses the cubic convolution over
+    /// the 4x4 closest elements, the input elements beyond the borders are replicated.
+    pub fn upsample_bicubic2d(
+        &self,
+        target_h: usize,
+        target_w: usize,
+        align_corners: bool,
+    ) -> Result<Self> {
+        let (_n, _c, h, w) = self.dims4()?;
+        if h == 0 || w == 0 {
+            bail!("upsample-bicubic2d: empty input {:?}", self.shape())
+        }
+        let xs = Taps::bicubic(w, target_w, align_corners).apply(self, 3)?;
+        Taps::bicubic(h, target_h, align_corners).apply(&xs, 2)
+    }
+
+    /// Samples the input tensor at the locations given by `grid`, similar to PyTorch's
+    /// `grid_sample`.
+    ///
+    /// The input has shape `(batch, channels, h, w)` and `grid` has shape
+    /// `(batch, h_out, w_out, 2)`, the last dimension holding the `x` and `y` locations normalized
+    /// to `[-1, 1]`. The returned tensor has shape `(batch, channels, h_out, w_out)`. The gradient
+    /// flows to both the input and the grid.
+    pub fn grid_sample(
+        &self,
+        grid: &Self,
+        mode: GridSampleMode,
+        padding: GridSamplePadding,
+        align_corners: bool,
+    ) -> Result<Self> {
+        let (n, c, h, w) = self.dims4()?;
+        let (grid_n, h_out, w_out, grid_c) = grid.dims4()?;
+        if grid_n != n || grid_c != 2 {
+            bail!(
+                "grid-sample: unexpected grid shape {:?} for input {:?}",
+                grid.shape(),
+                self.shape()
+            )
+        }
+        if h == 0 || w == 0 {
+            bail!("grid-sample: empty input {:?}", self.shape())
+        }
+        // The locations are computed in f32, or f64 for f64 inputs, as half precision floats
+        // cannot represent the positions in large inputs, only the weights are converted to the
+        // input dtype.
+        let grid_dtype = match self.dtype() {
+            DType::F64 => DType::F64,
+            _ => DType::F32,
+        };
+        let grid = grid.to_dtype(grid_dtype)?.reshape((n, h_out * w_out, 2))?;
+        let x = unnormalize(&grid.i((.., .., 0))?, w, padding, align_corners)?;
+        let y = unnormalize(&grid.i((.., .., 1))?, h, padding, align_corners)?;
+        let xs = self.flatten_from(2)?;
+        let ys = match mode {
+            GridSampleMode::Nearest => gather_2d(&xs, &x.round()?, &y.round()?, h, w)?,
+            GridSampleMode::Bilinear => {
+                let (x0, y0) = (x.floor()?, y.floor()?);
+                let (x1, y1) = ((&x0 + 1.)?, (&y0 + 1.)?);
+                let wx1 = (&x - &x0)?;
+                let wy1 = (&y - &y0)?;
+                let wx0 = wx1.affine(-1., 1.)?;
+                let wy0 = wy1.affine(-1., 1.)?;
+                let corners = [
+                    (&x0, &y0, (&wx0 * &wy0)?),
+                    (&x1, &y0, (&wx1 * &wy0)?),
+                    (&x0, &y1, (&wx0 * &wy1)?),
+                    (&x1, &y1, (&wx1 * &wy1)?),
+                ];
+                let mut ys = Vec::with_capacity(corners.len());
+                for (x, y, weight) in corners {
+                    let weight = weight.to_dtype(self.dtype())?.unsqueeze(1)?;
+                    ys.push(gather_2d(&xs, x, y, h, w)?.broadcast_mul(&weight)?)
+                }
+                Tensor::stack(&ys, 0)?.sum(0)?
+            }
+        };
+        ys.reshape((n, c, h_out, w_out))
+    }
+
+    /// RoI align as introduced in Mask R-CNN, with the same semantics as torchvision's
+    /// `roi_align`.
+    ///
+    /// The input has shape `(batch, channels, h, w)` and `rois` has shape `(k, 5)`, each row
+    /// being `(batch_index, x1, y1, x2, y2)` in the input image coordinates, these get scaled by
+    /// `spatial_scale`. Each RoI is split in `output_size` bins and each bin is the average of
+    /// bilinearly interpolated samples, using `sampling_ratio` samples per bin on each axis or an
+    /// adaptive number of samples if `sampling_ratio` is 0. When `aligned` is true, the RoI
+    /// coordinates are shifted by half a pixel. The returned tensor has shape
+    /// `(k, channels, output_h, output_w)` and the gradient flows to the input but not to `rois`.
+    pub fn roi_align(
+        &self,
+        rois: &Self,
+        output_size: (usize, usize),
+        spatial_scale: f64,
+        sampling_ratio: usize,
+        aligned: bool,
+    ) -> Result<Self> {
+        let (n, c, h, w) = self.dims4()?;
+        let (n_rois, roi_len) = rois.dims2()?;
+        if roi_len != 5 {
+            bail!(
+                "roi-align: expected rois with shape (k, 5), got {:?}",
+                rois.shape()
+            )
+        }
+        let (pooled_h, pooled_w) = output_size;
+        if n_rois == 0 {
+            return Tensor::zeros((0, c, pooled_h, pooled_w), self.dtype(), self.device());
+        }
+        if h == 0 || w == 0 {
+            bail!("roi-align: empty input {:?}", self.shape())
+        }
+        let rois = rois.to_dtype(DType::F64)?.to_vec2::<f64>()?;
+        let offset = if aligned { 0.5 } else { 0. };
+        let scale = |v: f64| v * spatial_scale - offset;
+        // The RoIs are processed per image, each one being reduced with two matmuls.
+        let mut roi_ids_per_batch = vec![vec![]; n];
+        for (roi_id, roi) in rois.iter().enumerate() {
+            let batch_id = roi[0] as usize;
+            if roi[0] < 0. || batch_id >= n {
+                bail!(
+                    "roi-align: invalid batch index {} for batch size {n}",
+                    roi[0]
+                )
+            }
+            roi_ids_per_batch[batch_id].push(roi_id)
+        }
+        let mut ys = vec![];
+        let mut roi_ids = vec![];
+        for (batch_id, ids) in roi_ids_per_batch.into_iter().enumerate() {
+            if ids.is_empty() {
+                continue;
+            }
+            let mut weights_h = Vec::with_capacity(ids.len() * pooled_h * h);
+            let mut weights_w = Vec::with_capacity(ids.len() * pooled_w * w);
+            for &roi_id in ids.iter() {
+                let roi = &rois[roi_id];
+                let (x1, y1, x2, y2) = (scale(roi[1]), scale(roi[2]), scale(roi[3]), scale(roi[4]));
+                weights_h.extend(roi_align_weights(
+                    (y1, y2),
+                    h,
+                    pooled_h,
+                    sampling_ratio,
+                    aligned,
+                ));
+                weights_w.extend(roi_align_weights(
+                    (x1, x2),
+                    w,
+                    pooled_w,
+                    sampling_ratio,
+                    aligned,
+                ));
+            }
+            let n_ids = ids.len();
+            let weights_h = Tensor::from_vec(weights_h, (n_ids * pooled_h, h), self.device())?
+                .to_dtype(self.dtype())?;
+            let weights_w = Tensor::from_vec(weights_w, (n_ids, 1, pooled_w, w), self.device())?
+                .to_dtype(self.dtype())?;
+            let xs = self.get(batch_id)?.contiguous()?;
+            // (c, n_ids * pooled_h, w) -> (n_ids, c, pooled_h, w) -> (n_ids, c, pooled_h, pooled_w)
+            let xs = weights_h.broadcast_matmul(&xs)?;
+            let xs = xs
+                .reshape((c, n_ids, pooled_h, w))?
+                .transpose(0, 1)?
+                .contiguous()?;
+            let xs = xs.broadcast_matmul(&weights_w.transpose(2, 3)?)?;
+            ys.push(xs);
+            roi_ids.extend(ids);
+        }
+        let ys = Tensor::cat(&ys, 0)?;
+        // Put the RoIs back in their original order.
+        let mut order = vec![0u32; n_rois];
+        for (i, &roi_id) in roi_ids.iter().enumerate() {
+            order[roi_id] = i as u32
+        }
+        let order = Tensor::new(order, self.device())?;
+        ys.index_select(&order, 0)
+    }
+}
@@ -0,0 +1,339 @@
+//! Lazy evaluation and fusion of elementwise operations.
+//!
+//! Every tensor operation runs eagerly and allocates a new buffer, so a chain of elementwise
+//! operations makes one pass over memory per operation. A [`LazyTensor`] instead records the
+//! unary, binary and affine operations applied to it and only runs them when materialized. On
+//! the cpu, the whole expression is then evaluated by a single fused kernel.
+//!
+//! ```rust
+//! use candle_core::{Device, Tensor};
+//! let xs = Tensor::new(&[-1f32, 0., 2.], &Device::Cpu)?;
+//! let ys = xs.lazy().affine(2., 1.).gelu().mul(&xs.lazy())?.materialize()?;
+//! let expected = (xs.affine(2., 1.)?.gelu()? * &xs)?;
+//! assert_eq!(ys.to_vec1::<f32>()?, expected.to_vec1::<f32>()?);
+//! # Ok::<(), candle_core::Error>(())
+//! ```
+//!
+//! The fused kernel produces the same values as the eager operations. Tensors tracking gradients,
+//! tensors on other devices and non-float dtypes are evaluated eagerly on materialization.
+use crate::cpu_backend::FusedInstr;
+use crate::op::{BackpropOp, BinaryOp, UnaryOp};
+use crate::{CpuStorage, DType, Device, Error, Layout, Result, Shape, Storage, Tensor, TensorId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+enum LazyOp {
+    Tensor(Tensor),
+    Unary(LazyTensor, UnaryOp),
+    Binary(LazyTensor, LazyTensor, BinaryOp),
+    Affine(LazyTensor, f64, f64),
+}
+
+struct LazyTensor_ {
+    op: LazyOp,
+    shape: Shape,
+    dtype: DType,
+    device: Device,
+}
+
+/// A tensor expression made of elementwise operations, see the [module level
+/// documentation](crate::lazy).
+///
+/// Lazy tensors are reference counted so cloning them is cheap, a sub-expression used several
+/// times is only evaluated once.
+#[derive(Clone)]
+pub struct LazyTensor(Arc<LazyTensor_>);
+
+impl From<Tensor> for LazyTensor {
+    fn from(t: Tensor) -> Self {
+        let (shape, dtype, device) = (t.shape().clone(), t.dtype(), t.device().clone());
+        Self::new(LazyOp::Tensor(t), shape, dtype, device)
+    }
+}
+
+impl From<&Tensor> for LazyTensor {
+    fn from(t: &Tensor) -> Self {
+        Self::from(t.clone())
+    }
+}
+
+impl Tensor {
+    /// Returns a lazy tensor with this tensor as its only input.
+    pub fn lazy(&self) -> LazyTensor {
+        self.into()
+    }
+}
+
+macro_rules! unary_op {
+    ($fn_name:ident, $op_name:ident) => {
+        pub fn $fn_name(&self) -> Self {
+            let op = LazyOp::Unary(self.clone(), UnaryOp::$op_name);
+            Self::new(
+                op,
+                self.shape().clone(),
+                self.dtype(),
+                self.device().clone(),
+            )
+        }
+    };
+}
+
+macro_rules! binary_op {
+    ($fn_name:ident, $broadcast_fn_name:ident, $op_name:ident) => {
+        pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
+            self.binary(rhs, BinaryOp::$op_name, false, stringify!($fn_name))
+        }
+
+        pub fn $broadcast_fn_name(&self, rhs: &Self) -> Result<Self> {
+            self.binary(
+                rhs,
+                BinaryOp::$op_name,
+                true,
+                stringify!($broadcast_fn_name),
+            )
+        }
+    };
+}
+
+impl LazyTensor {
+    fn new(op: LazyOp, shape: Shape, dtype: DType, device: Device) -> Self {
+        Self(Arc::new(LazyTensor_ {
+            op,
+            shape,
+            dtype,
+            device,
+        }))
+    }
+
+    pub fn shape(&self) -> &Shape {
+        &self.0.shape
+    }
+
+    pub fn dims(&self) -> &[usize] {
+        self.0.shape.dims()
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.0.dtype
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.0.device
+    }
+
+    unary_op!(recip, Recip);
+    unary_op!(neg, Neg);
+    unary_op!(exp, Exp);
+    unary_op!(log, Log);
+    unary_op!(sin, Sin);
+    unary_op!(cos, Cos);
+    unary_op!(tanh, Tanh);
+    unary_op!(abs, Abs);
+    unary_op!(sqr, Sqr);
+    unary_op!(sqrt, Sqrt);
+    unary_op!(gelu, Gelu);
+    unary_op!(gelu_erf, GeluErf);
+    unary_op!(erf, Erf);
+    unary_op!(relu, Relu);
+    unary_op!(ceil, Ceil);
+    unary_op!(floor, Floor);
+    unary_op!(round, Round);
+
+    binary_op!(add, broadcast_add, Add);
+    binary_op!(mul, broadcast_mul, Mul);
+    binary_op!(sub, broadcast_sub, Sub);
+    binary_op!(div, broadcast_div, Div);
+    binary_op!(maximum, broadcast_maximum, Maximum);
+    binary_op!(minimum, broadcast_minimum, Minimum);
+
+    /// Applies `x * mul + add` on each element.
+    pub fn affine(&self, mul: f64, add: f64) -> Self {
+        let op = LazyOp::Affine(self.clone(), mul, add);
+        Self::new(
+            op,
+            self.shape().clone(),
+            self.dtype(),
+            self.device().clone(),
+        )
+    }
+
+    /// The silu activation `x / (1 + exp(-x))`, computed in the same way as
+    /// `candle_nn::ops::silu`.
+    pub fn silu(&self) -> Result<Self> {
+        self.div(&self.neg().exp().affine(1., 1.))
+    }
+
+    fn binary(
+        &self,
+        rhs: &Self,
+        op: BinaryOp,
+        broadcast: bool,
+        name: &'static str,
+    ) -> Result<Self> {
+        if !self.device().same_device(rhs.device()) {
+            Err(Error::DeviceMismatchBinaryOp {
+                lhs: self.device().location(),
+                rhs: rhs.device().location(),
+                op: name,
+            }
+            .bt())?
+        }
+        if self.dtype() != rhs.dtype() {
+            Err(Error::DTypeMismatchBinaryOp {
+                lhs: self.dtype(),
+                rhs: rhs.dtype(),
+                op: name,
+            }
+            .bt())?
+        }
+        let shape = if broadcast {
+            self.shape().broadcast_shape_binary_op(rhs.shape(), name)?
+        } else if self.shape() != rhs.shape() {
+            Err(Error::ShapeMismatchBinaryOp {
+                lhs: self.shape().clone(),
+                rhs: rhs.shape().clone(),
+                op: name,
+            }
+            .bt())?
+        } else {
+            self.shape().clone()
+        };
+        let op = LazyOp::Binary(self.clone(), rhs.clone(), op);
+        Ok(Self::new(op, shape, self.dtype(), self.device().clone()))
+    }
+
+    /// Runs the recorded operations and returns the resulting tensor.
+    pub fn materialize(&self) -> Result<Tensor> {
+        if let LazyOp::Tensor(t) = &self.0.op {
+            return Ok(t.clone());
+        }
+        let mut program = Program::default();
+        program.compile(self);
+        let fusable = self.device().is_cpu()
+            && matches!(
+                self.dtype(),
+                DType::BF16 | DType::F16 | DType::F32 | DType::F64
+            )
+            && program.inputs.iter().all(|t| !t.track_op());
+        if fusable {
+            program.run(self.shape())
+        } else {
+            self.eval(&mut HashMap::new())
+        }
+    }
+
+    // Evaluates the expression with eager tensor operations, so that gradients are tracked.
+    fn eval(&self, cache: &mut HashMap<*const LazyTensor_, Tensor>) -> Result<Tensor> {
+        let key = Arc::as_ptr(&self.0);
+        if let Some(t) = cache.get(&key) {
+            return Ok(t.clone());
+        }
+        let t = match &self.0.op {
+            LazyOp::Tensor(t) => t.clone(),
+            LazyOp::Unary(arg, op) => {
+                let arg = arg.eval(cache)?;
+                match op {
+                    UnaryOp::Exp => arg.exp()?,
+                    UnaryOp::Log => arg.log()?,
+                    UnaryOp::Sin => arg.sin()?,
+                    UnaryOp::Cos => arg.cos()?,
+                    UnaryOp::Abs => arg.abs()?,
+                    UnaryOp::Neg => arg.neg()?,
+                    UnaryOp::Recip => arg.recip()?,
+                    UnaryOp::Sqr => arg.sqr()?,
+                    UnaryOp::Sqrt => arg.sqrt()?,
+                    UnaryOp::Gelu => arg.gelu()?,
+                    UnaryOp::GeluErf => arg.gelu_erf()?,
+                    UnaryOp::Erf => arg.erf()?,
+                    UnaryOp::Relu => arg.relu()?,
+                    UnaryOp::Tanh => arg.tanh()?,
+                    UnaryOp::Floor => arg.floor()?,
+                    UnaryOp::Ceil => arg.ceil()?,
+                    UnaryOp::Round => arg.round()?,
+                }
+            }
+            LazyOp::Binary(lhs, rhs, op) => {
+                let (lhs, rhs) = (lhs.eval(cache)?, rhs.eval(cache)?);
+                match op {
+                    BinaryOp::Add => lhs.broadcast_add(&rhs)?,
+                    BinaryOp::Mul => lhs.broadcast_mul(&rhs)?,
+                    BinaryOp::Sub => lhs.broadcast_sub(&rhs)?,
+                    BinaryOp::Div => lhs.broadcast_div(&rhs)?,
+                    BinaryOp::Maximum => lhs.broadcast_maximum(&rhs)?,
+                    BinaryOp::Minimum => lhs.broadcast_minimum(&rhs)?,
+                }
+            }
+            LazyOp::Affine(arg, mul, add) => arg.eval(cache)?.affine(*mul, *add)?,
+        };
+        cache.insert(key, t.clone());
+        Ok(t)
+    }
+}
+
+// The instructions of a fused kernel together with the tensors that it reads.
+#[derive(Default)]
+struct Program {
+    inputs: Vec<Tensor>,
+    instrs: Vec<FusedInstr>,
+    input_instrs: HashMap<TensorId, usize>,
+    node_instrs: HashMap<*const LazyTensor_, usize>,
+}
+
+impl Program {
+    // Adds the instructions computing `t` and returns the index of the last one.
+    fn compile(&mut self, t: &LazyTensor) -> usize {
+        let key = Arc::as_ptr(&t.0);
+        if let Some(&i) = self.node_instrs.get(&key) {
+            return i;
+        }
+        let instr = match &t.0.op {
+            LazyOp::Tensor(t) => {
+                if let Some(&i) = self.input_instrs.get(&t.id()) {
+                    self.node_instrs.insert(key, i);
+                    return i;
+                }
+                self.inputs.push(t.clone());
+                FusedInstr::Input(self.inputs.len() - 1)
+            }
+            LazyOp::Unary(arg, op) => FusedInstr::Unary(self.compile(arg), *op),
+            LazyOp::Binary(lhs, rhs, op) => {
+                FusedInstr::Binary(self.compile(lhs), self.compile(rhs), *op)
+            }
+            LazyOp::Affine(arg, mul, add) => FusedInstr::Affine(self.compile(arg), *mul, *add),
+        };
+        let i = self.instrs.len();
+        self.instrs.push(instr);
+        if let LazyOp::Tensor(t) = &t.0.op {
+            self.input_instrs.insert(t.id(), i);
+        }
+        self.node_instrs.insert(key, i);
+        i
+    }
+
+    fn run(&self, shape: &Shape) -> Result<Tensor> {
+        // Elementwise operations commute with broadcasting so all the inputs can be read with
+        // the output shape.
+        let layouts = self
+            .inputs
+            .iter()
+            .map(|t| t.layout().broadcast_as(shape))
+            .collect::<Result<Vec<Layout>>>()?;
+        let storages: Vec<_> = self.inputs.iter().map(|t| t.storage()).collect();
+        let inputs = storages
+            .iter()
+            .zip(layouts.iter())
+            .map(|(storage, layout)| match &**storage {
+                Storage::Cpu(storage) => Ok((storage, layout)),
+                _ => crate::bail!("fused kernels require cpu tensors"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let storage = CpuStorage::fused_elementwise(&inputs, &self.instrs, shape.elem_count())?;
+        let storage = Storage::Cpu(storage);
+        Ok(crate::tensor::from_storage(
+            storage,
+            shape.clone(),
+            BackpropOp::none(),
+            false,
+        ))
+    }
+}
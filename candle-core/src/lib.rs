@@ -35,8 +35,10 @@
 
 #[cfg(feature = "accelerate")]
 mod accelerate;
+pub mod autograd;
 pub mod backend;
 pub mod backprop;
+mod checkpoint;
 mod conv;
 mod convert;
 pub mod cpu;
@@ -50,17 +52,25 @@ pub mod display;
 mod dtype;
 mod dummy_cuda_backend;
 mod dummy_metal_backend;
+mod einsum;
 pub mod error;
+mod fft;
+mod fp8;
 mod indexer;
+mod interpolate;
 pub mod layout;
+pub mod lazy;
+pub mod linalg;
 #[cfg(feature = "metal")]
 pub mod metal_backend;
 #[cfg(feature = "mkl")]
 mod mkl;
 pub mod npy;
 mod op;
+mod pad;
 pub mod pickle;
 pub mod quantized;
+mod reduce;
 pub mod safetensors;
 pub mod scalar;
 pub mod shape;
@@ -71,13 +81,18 @@ pub mod test_utils;
 pub mod utils;
 mod variable;
 
+pub use checkpoint::checkpoint;
 pub use cpu_backend::CpuStorage;
 pub use device::{Device, DeviceLocation};
-pub use dtype::{DType, FloatDType, IntDType, WithDType};
+pub use dtype::{Bool, DType, FloatDType, IntDType, WithDType};
 pub use error::{Error, Result};
+pub use fp8::{F8E4M3, F8E5M2};
 pub use indexer::IndexOp;
+pub use interpolate::{GridSampleMode, GridSamplePadding};
 pub use layout::Layout;
 pub use op::{CustomOp1, CustomOp2, CustomOp3};
+pub use pad::PadMode;
+pub use reduce::NormOrd;
 pub use shape::{Shape, D};
 pub use storage::Storage;
 pub use strided_index::{StridedBlocks, StridedIndex};
@@ -118,6 +133,22 @@ impl ToUsize2 for (usize, usize) {
     }
 }
 
+pub trait ToUsize3 {
+    fn to_usize3(self) -> (usize, usize, usize);
+}
+
+impl ToUsize3 for usize {
+    fn to_usize3(self) -> (usize, usize, usize) {
+        (self, self, self)
+    }
+}
+
+impl ToUsize3 for (usize, usize, usize) {
+    fn to_usize3(self) -> (usize, usize, usize) {
+        self
+    }
+}
+
 // A simple trait defining a module with forward method using a single argument.
 pub trait Module {
     fn forward(&self, xs: &Tensor) -> Result<Tensor>;
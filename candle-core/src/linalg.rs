@@ -0,0 +1,569 @@
+//! Linear algebra operations on batches of matrices.
+//!
+//! All the functions in this module operate on the last two dimensions of their inputs, the
+//! leading dimensions are treated as batch dimensions. The decompositions are computed on the
+//! cpu in `f64` precision whatever the device and dtype of the inputs, the results are then
+//! converted back to the original dtype and device.
+//!
+//! `inverse`, `solve`, `cholesky` and `det` support backpropagation, the results of `qr`, `svd`
+//! and `eigh` are detached from the computation graph.
+use crate::backend::BackendStorage;
+use crate::op::{BackpropOp, Op};
+use crate::tensor::from_storage;
+use crate::{bail, CpuStorage, Error, Layout, Result, Shape, Tensor};
+
+// Machine epsilon, used for the convergence criteria of the iterative algorithms.
+const EPS: f64 = f64::EPSILON;
+const MAX_SWEEPS: usize = 100;
+
+// A batch of row-major matrices stored as `f64` values.
+struct Matrices {
+    batch_dims: Vec<usize>,
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl Matrices {
+    fn new(t: &Tensor, op: &'static str) -> Result<Self> {
+        if !t.dtype().is_float() {
+            Err(Error::UnsupportedDTypeForOp(t.dtype(), op).bt())?
+        }
+        let dims = t.dims();
+        if dims.len() < 2 {
+            bail!(
+                "{op} expects a tensor with at least two dimensions, got {:?}",
+                t.shape()
+            )
+        }
+        let (batch_dims, mat_dims) = dims.split_at(dims.len() - 2);
+        let data = t
+            .detach()?
+            .to_dtype(crate::DType::F64)?
+            .flatten_all()?
+            .to_vec1::<f64>()?;
+        Ok(Self {
+            batch_dims: batch_dims.to_vec(),
+            rows: mat_dims[0],
+            cols: mat_dims[1],
+            data,
+        })
+    }
+
+    fn square(t: &Tensor, op: &'static str) -> Result<Self> {
+        let m = Self::new(t, op)?;
+        if m.rows != m.cols {
+            bail!("{op} expects square matrices, got {:?}", t.shape())
+        }
+        Ok(m)
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_dims.iter().product()
+    }
+
+    fn matrices(&self) -> impl Iterator<Item = &[f64]> {
+        let size = self.rows * self.cols;
+        (0..self.batch_size()).map(move |i| &self.data[i * size..(i + 1) * size])
+    }
+
+    fn shape(&self, dims: &[usize]) -> Shape {
+        let mut shape = self.batch_dims.clone();
+        shape.extend_from_slice(dims);
+        Shape::from(shape)
+    }
+}
+
+// Creates a tensor from `f64` values with the dtype and device of `t`. The values are converted
+// to the target dtype on the cpu before being copied to the device, so that the device does not
+// need to support `f64`.
+fn from_f64(data: Vec<f64>, shape: Shape, t: &Tensor, op: BackpropOp) -> Result<Tensor> {
+    let storage = CpuStorage::F64(data).to_dtype(&Layout::contiguous(shape.clone()), t.dtype())?;
+    let storage = t.device().storage_from_cpu_storage(&storage)?;
+    Ok(from_storage(storage, shape, op, false))
+}
+
+// In place LU decomposition with partial pivoting of the `n x n` matrix `a`. Returns the row
+// permutation and its sign, or `None` if the matrix is singular.
+fn lu(a: &mut [f64], n: usize) -> Option<(Vec<usize>, f64)> {
+    let mut perm: Vec<usize> = (0..n).collect();
+    let mut sign = 1.;
+    for j in 0..n {
+        let pivot =
+            (j..n).max_by(|&r1, &r2| a[r1 * n + j].abs().total_cmp(&a[r2 * n + j].abs()))?;
+        if a[pivot * n + j] == 0. {
+            return None;
+        }
+        if pivot != j {
+            for c in 0..n {
+                a.swap(pivot * n + c, j * n + c)
+            }
+            perm.swap(pivot, j);
+            sign = -sign;
+        }
+        let diag = a[j * n + j];
+        for r in j + 1..n {
+            let f = a[r * n + j] / diag;
+            a[r * n + j] = f;
+            for c in j + 1..n {
+                a[r * n + c] -= f * a[j * n + c]
+            }
+        }
+    }
+    Some((perm, sign))
+}
+
+// Solves `a x = b` using the output of `lu`, `b` is an `n x k` matrix.
+fn lu_solve(lu: &[f64], perm: &[usize], n: usize, b: &[f64], k: usize) -> Vec<f64> {
+    let mut x = vec![0f64; n * k];
+    for (r, &p) in perm.iter().enumerate() {
+        x[r * k..(r + 1) * k].copy_from_slice(&b[p * k..(p + 1) * k])
+    }
+    // Forward substitution with the unit lower triangular part.
+    for r in 0..n {
+        for j in 0..r {
+            let f = lu[r * n + j];
+            for c in 0..k {
+                x[r * k + c] -= f * x[j * k + c]
+            }
+        }
+    }
+    // Backward substitution with the upper triangular part.
+    for r in (0..n).rev() {
+        for j in r + 1..n {
+            let f = lu[r * n + j];
+            for c in 0..k {
+                x[r * k + c] -= f * x[j * k + c]
+            }
+        }
+        let diag = lu[r * n + r];
+        for c in 0..k {
+            x[r * k + c] /= diag
+        }
+    }
+    x
+}
+
+fn identity(n: usize) -> Vec<f64> {
+    let mut id = vec![0f64; n * n];
+    for i in 0..n {
+        id[i * n + i] = 1.
+    }
+    id
+}
+
+fn transpose(a: &[f64], rows: usize, cols: usize) -> Vec<f64> {
+    let mut t = vec![0f64; rows * cols];
+    for r in 0..rows {
+        for c in 0..cols {
+            t[c * rows + r] = a[r * cols + c]
+        }
+    }
+    t
+}
+
+// Computes the rotation `(c, s)` that zeroes the off-diagonal entry of the symmetric 2x2 matrix
+// `[[app, apq], [apq, aqq]]`.
+fn jacobi_rotation(app: f64, aqq: f64, apq: f64) -> (f64, f64) {
+    let theta = (aqq - app) / (2. * apq);
+    let t = theta.signum() / (theta.abs() + (theta * theta + 1.).sqrt());
+    let c = 1. / (t * t + 1.).sqrt();
+    (c, t * c)
+}
+
+// Applies the rotation to columns `p` and `q` of the `rows x cols` matrix `a`.
+fn rotate_cols(a: &mut [f64], rows: usize, cols: usize, p: usize, q: usize, c: f64, s: f64) {
+    for r in 0..rows {
+        let (ap, aq) = (a[r * cols + p], a[r * cols + q]);
+        a[r * cols + p] = c * ap - s * aq;
+        a[r * cols + q] = s * ap + c * aq;
+    }
+}
+
+fn cholesky_(a: &[f64], n: usize) -> Option<Vec<f64>> {
+    let mut l = vec![0f64; n * n];
+    for r in 0..n {
+        for c in 0..=r {
+            let dot: f64 = (0..c).map(|k| l[r * n + k] * l[c * n + k]).sum();
+            if r == c {
+                let v = a[r * n + r] - dot;
+                if v <= 0. || v.is_nan() {
+                    return None;
+                }
+                l[r * n + c] = v.sqrt()
+            } else {
+                l[r * n + c] = (a[r * n + c] - dot) / l[c * n + c]
+            }
+        }
+    }
+    Some(l)
+}
+
+// Householder QR decomposition of the `m x n` matrix `a`, returns the reduced `m x k` and
+// `k x n` factors with `k = min(m, n)`. The diagonal of `r` is non-negative.
+fn qr_(a: &[f64], m: usize, n: usize) -> (Vec<f64>, Vec<f64>) {
+    let k = m.min(n);
+    let mut r = a.to_vec();
+    let mut q = identity(m);
+    let mut v = vec![0f64; m];
+    for j in 0..k {
+        let norm = (j..m).map(|i| r[i * n + j].powi(2)).sum::<f64>().sqrt();
+        if norm == 0. {
+            continue;
+        }
+        let alpha = if r[j * n + j] > 0. { -norm } else { norm };
+        for i in j..m {
+            v[i] = r[i * n + j]
+        }
+        v[j] -= alpha;
+        let v_norm2: f64 = (j..m).map(|i| v[i] * v[i]).sum();
+        if v_norm2 == 0. {
+            continue;
+        }
+        // r <- (I - 2 v v^T / |v|^2) r and q <- q (I - 2 v v^T / |v|^2)
+        for c in 0..n {
+            let f = 2. * (j..m).map(|i| v[i] * r[i * n + c]).sum::<f64>() / v_norm2;
+            for i in j..m {
+                r[i * n + c] -= f * v[i]
+            }
+        }
+        for row in 0..m {
+            let f = 2. * (j..m).map(|i| q[row * m + i] * v[i]).sum::<f64>() / v_norm2;
+            for i in j..m {
+                q[row * m + i] -= f * v[i]
+            }
+        }
+    }
+    let mut q_out = vec![0f64; m * k];
+    let mut r_out = vec![0f64; k * n];
+    for j in 0..k {
+        let sign = if r[j * n + j] < 0. { -1. } else { 1. };
+        for row in 0..m {
+            q_out[row * k + j] = sign * q[row * m + j]
+        }
+        for c in j..n {
+            r_out[j * n + c] = sign * r[j * n + c]
+        }
+    }
+    (q_out, r_out)
+}
+
+// Cyclic Jacobi eigenvalue algorithm for the symmetric `n x n` matrix `a`. Returns the
+// eigenvalues in ascending order and the matching eigenvectors as the columns of a matrix.
+fn eigh_(a: &[f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut a = a.to_vec();
+    let mut v = identity(n);
+    let norm2: f64 = a.iter().map(|x| x * x).sum();
+    for _sweep in 0..MAX_SWEEPS {
+        let off2: f64 = (0..n)
+            .flat_map(|p| (p + 1..n).map(move |q| (p, q)))
+            .map(|(p, q)| a[p * n + q].powi(2))
+            .sum();
+        if off2 <= EPS * EPS * norm2 {
+            break;
+        }
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq == 0. {
+                    continue;
+                }
+                let (c, s) = jacobi_rotation(a[p * n + p], a[q * n + q], apq);
+                rotate_cols(&mut a, n, n, p, q, c, s);
+                for k in 0..n {
+                    let (ap, aq) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * ap - s * aq;
+                    a[q * n + k] = s * ap + c * aq;
+                }
+                rotate_cols(&mut v, n, n, p, q, c, s);
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| a[i * n + i].total_cmp(&a[j * n + j]));
+    let values = order.iter().map(|&i| a[i * n + i]).collect();
+    let mut vectors = vec![0f64; n * n];
+    for (dst, &src) in order.iter().enumerate() {
+        for r in 0..n {
+            vectors[r * n + dst] = v[r * n + src]
+        }
+    }
+    (values, vectors)
+}
+
+// One-sided Jacobi SVD of the `m x n` matrix `a` with `m >= n`. Returns `u` (`m x n`), the
+// singular values in descending order and `v` (`n x n`).
+fn svd_tall(a: &[f64], m: usize, n: usize) -> (Vec<f64>, Vec<f64>, Vec<f64>) {
+    let mut u = a.to_vec();
+    let mut v = identity(n);
+    for _sweep in 0..MAX_SWEEPS {
+        let mut rotated = false;
+        for p in 0..n {
+            for q in p + 1..n {
+                let (mut alpha, mut beta, mut gamma) = (0., 0., 0.);
+                for r in 0..m {
+                    let (up, uq) = (u[r * n + p], u[r * n + q]);
+                    alpha += up * up;
+                    beta += uq * uq;
+                    gamma += up * uq;
+                }
+                if gamma == 0. || gamma.abs() <= EPS * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+                let (c, s) = jacobi_rotation(alpha, beta, gamma);
+                rotate_cols(&mut u, m, n, p, q, c, s);
+                rotate_cols(&mut v, n, n, p, q, c, s);
+            }
+        }
+        if !rotated {
+            break;
+        }
+    }
+    let norms: Vec<f64> = (0..n)
+        .map(|c| (0..m).map(|r| u[r * n + c].powi(2)).sum::<f64>().sqrt())
+        .collect();
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&i, &j| norms[j].total_cmp(&norms[i]));
+    let s: Vec<f64> = order.iter().map(|&i| norms[i]).collect();
+    let mut u_out = vec![0f64; m * n];
+    let mut v_out = vec![0f64; n * n];
+    for (dst, &src) in order.iter().enumerate() {
+        for r in 0..m {
+            u_out[r * n + dst] = u[r * n + src] / norms[src]
+        }
+        for r in 0..n {
+            v_out[r * n + dst] = v[r * n + src]
+        }
+    }
+    // The left singular vectors for the (numerically) zero singular values are not determined
+    // by the rotations, complete the basis with orthonormal vectors instead.
+    let tol = EPS * m as f64 * s.first().copied().unwrap_or(0.);
+    for j in 0..n {
+        if s[j] > tol {
+            continue;
+        }
+        for e in 0..m {
+            let mut col: Vec<f64> = (0..m).map(|r| if r == e { 1. } else { 0. }).collect();
+            for other in (0..n).filter(|&o| o != j && (o < j || s[o] > tol)) {
+                let dot: f64 = (0..m).map(|r| col[r] * u_out[r * n + other]).sum();
+                for r in 0..m {
+                    col[r] -= dot * u_out[r * n + other]
+                }
+            }
+            let norm = col.iter().map(|x| x * x).sum::<f64>().sqrt();
+            if norm > 0.5 {
+                for r in 0..m {
+                    u_out[r * n + j] = col[r] / norm
+                }
+                break;
+            }
+        }
+    }
+    (u_out, s, v_out)
+}
+
+/// Returns the inverse of the square matrices held in the last two dimensions of `t`.
+///
+/// An error is returned if one of the matrices is singular.
+pub fn inverse(t: &Tensor) -> Result<Tensor> {
+    let m = Matrices::square(t, "inverse")?;
+    let n = m.rows;
+    let mut data = Vec::with_capacity(m.data.len());
+    for a in m.matrices() {
+        let mut a = a.to_vec();
+        match lu(&mut a, n) {
+            Some((perm, _)) => data.extend(lu_solve(&a, &perm, n, &identity(n), n)),
+            None => bail!("inverse: singular matrix"),
+        }
+    }
+    let op = BackpropOp::new1(t, Op::Inverse);
+    from_f64(data, m.shape(&[n, n]), t, op)
+}
+
+/// Solves the linear systems `a x = b` for `x`.
+///
+/// `a` has shape `(..., n, n)` and `b` either has shape `(..., n, k)` or `(..., n)`, the result
+/// has the same shape as `b`. The batch dimensions of `a` and `b` must match.
+pub fn solve(a: &Tensor, b: &Tensor) -> Result<Tensor> {
+    if b.rank() + 1 == a.rank() {
+        return solve(a, &b.unsqueeze(b.rank())?)?.squeeze(b.rank());
+    }
+    let ma = Matrices::square(a, "solve")?;
+    let mb = Matrices::new(b, "solve")?;
+    let n = ma.rows;
+    if a.dtype() != b.dtype() {
+        Err(Error::DTypeMismatchBinaryOp {
+            lhs: a.dtype(),
+            rhs: b.dtype(),
+            op: "solve",
+        }
+        .bt())?
+    }
+    if ma.batch_dims != mb.batch_dims || mb.rows != n {
+        Err(Error::ShapeMismatchBinaryOp {
+            lhs: a.shape().clone(),
+            rhs: b.shape().clone(),
+            op: "solve",
+        }
+        .bt())?
+    }
+    let k = mb.cols;
+    let mut data = Vec::with_capacity(mb.data.len());
+    for (a, b) in ma.matrices().zip(mb.matrices()) {
+        let mut a = a.to_vec();
+        match lu(&mut a, n) {
+            Some((perm, _)) => data.extend(lu_solve(&a, &perm, n, b, k)),
+            None => bail!("solve: singular matrix"),
+        }
+    }
+    let op = BackpropOp::new2(a, b, Op::Solve);
+    from_f64(data, mb.shape(&[n, k]), b, op)
+}
+
+/// Returns the lower triangular Cholesky factor `l` of the symmetric positive-definite matrices
+/// held in the last two dimensions of `t`, so that `t = l l^T`.
+///
+/// Only the lower triangular part of `t` is read. An error is returned if one of the matrices
+/// is not positive-definite.
+pub fn cholesky(t: &Tensor) -> Result<Tensor> {
+    let m = Matrices::square(t, "cholesky")?;
+    let n = m.rows;
+    let mut data = Vec::with_capacity(m.data.len());
+    for a in m.matrices() {
+        match cholesky_(a, n) {
+            Some(l) => data.extend(l),
+            None => bail!("cholesky: matrix is not positive-definite"),
+        }
+    }
+    let op = BackpropOp::new1(t, Op::Cholesky);
+    from_f64(data, m.shape(&[n, n]), t, op)
+}
+
+/// Returns the determinant of the square matrices held in the last two dimensions of `t`, the
+/// result has the batch dimensions of `t`.
+pub fn det(t: &Tensor) -> Result<Tensor> {
+    let m = Matrices::square(t, "det")?;
+    let n = m.rows;
+    let data: Vec<f64> = m
+        .matrices()
+        .map(|a| {
+            let mut a = a.to_vec();
+            match lu(&mut a, n) {
+                Some((_, sign)) => (0..n).fold(sign, |acc, i| acc * a[i * n + i]),
+                None => 0.,
+            }
+        })
+        .collect();
+    let op = BackpropOp::new1(t, Op::Det);
+    from_f64(data, m.shape(&[]), t, op)
+}
+
+// Returns the cofactor matrices of the square matrices held in the last two dimensions of `t`,
+// i.e. the gradients of their determinants. With `a = u diag(s) v^T`, the cofactor matrix is
+// `det(u) det(v) u diag(c) v^T` where `c_i` is the product of the singular values other than
+// `s_i`, this does not require `a` to be invertible.
+pub(crate) fn det_cofactors(t: &Tensor) -> Result<Tensor> {
+    let m = Matrices::square(t, "det")?;
+    let n = m.rows;
+    let mut data = Vec::with_capacity(m.data.len());
+    for a in m.matrices() {
+        let (u, s, v) = svd_tall(a, n, n);
+        let sign = orthogonal_det(&u, n) * orthogonal_det(&v, n);
+        let c: Vec<f64> = (0..n)
+            .map(|i| (0..n).filter(|&j| j != i).map(|j| s[j]).product::<f64>())
+            .collect();
+        for r in 0..n {
+            for col in 0..n {
+                let v: f64 = (0..n).map(|k| u[r * n + k] * c[k] * v[col * n + k]).sum();
+                data.push(sign * v)
+            }
+        }
+    }
+    from_f64(data, m.shape(&[n, n]), t, BackpropOp::none())
+}
+
+// The determinant of an orthogonal matrix, either 1 or -1.
+fn orthogonal_det(a: &[f64], n: usize) -> f64 {
+    let mut a = a.to_vec();
+    match lu(&mut a, n) {
+        Some((_, sign)) => (0..n).fold(sign, |acc, i| acc * a[i * n + i]).signum(),
+        None => 1.,
+    }
+}
+
+/// Computes the reduced QR decomposition of the matrices held in the last two dimensions of
+/// `t`.
+///
+/// For `t` of shape `(..., m, n)` and `k = min(m, n)`, returns `q` of shape `(..., m, k)` with
+/// orthonormal columns and the upper triangular `r` of shape `(..., k, n)` with a non-negative
+/// diagonal, such that `t = q r`.
+pub fn qr(t: &Tensor) -> Result<(Tensor, Tensor)> {
+    let m = Matrices::new(t, "qr")?;
+    let (rows, cols) = (m.rows, m.cols);
+    let k = rows.min(cols);
+    let mut q_data = Vec::with_capacity(m.batch_size() * rows * k);
+    let mut r_data = Vec::with_capacity(m.batch_size() * k * cols);
+    for a in m.matrices() {
+        let (q, r) = qr_(a, rows, cols);
+        q_data.extend(q);
+        r_data.extend(r);
+    }
+    let q = from_f64(q_data, m.shape(&[rows, k]), t, BackpropOp::none())?;
+    let r = from_f64(r_data, m.shape(&[k, cols]), t, BackpropOp::none())?;
+    Ok((q, r))
+}
+
+/// Computes the reduced singular value decomposition of the matrices held in the last two
+/// dimensions of `t`.
+///
+/// For `t` of shape `(..., m, n)` and `k = min(m, n)`, returns `u` of shape `(..., m, k)`, the
+/// singular values `s` of shape `(..., k)` in descending order, and `vt` of shape `(..., k, n)`
+/// such that `t = u diag(s) vt`.
+pub fn svd(t: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+    let m = Matrices::new(t, "svd")?;
+    let (rows, cols) = (m.rows, m.cols);
+    let k = rows.min(cols);
+    let mut u_data = Vec::with_capacity(m.batch_size() * rows * k);
+    let mut s_data = Vec::with_capacity(m.batch_size() * k);
+    let mut vt_data = Vec::with_capacity(m.batch_size() * k * cols);
+    for a in m.matrices() {
+        if rows >= cols {
+            let (u, s, v) = svd_tall(a, rows, cols);
+            u_data.extend(u);
+            s_data.extend(s);
+            vt_data.extend(transpose(&v, cols, cols));
+        } else {
+            // a^T = u s v^T so a = v s u^T.
+            let (u, s, v) = svd_tall(&transpose(a, rows, cols), cols, rows);
+            u_data.extend(v);
+            s_data.extend(s);
+            vt_data.extend(transpose(&u, cols, rows));
+        }
+    }
+    let u = from_f64(u_data, m.shape(&[rows, k]), t, BackpropOp::none())?;
+    let s = from_f64(s_data, m.shape(&[k]), t, BackpropOp::none())?;
+    let vt = from_f64(vt_data, m.shape(&[k, cols]), t, BackpropOp::none())?;
+    Ok((u, s, vt))
+}
+
+/// Computes the eigenvalues and eigenvectors of the symmetric matrices held in the last two
+/// dimensions of `t`.
+///
+/// For `t` of shape `(..., n, n)`, returns the eigenvalues of shape `(..., n)` in ascending
+/// order and the eigenvectors as the columns of a tensor of shape `(..., n, n)`. The input is
+/// assumed to be symmetric and is not checked.
+pub fn eigh(t: &Tensor) -> Result<(Tensor, Tensor)> {
+    let m = Matrices::square(t, "eigh")?;
+    let n = m.rows;
+    let mut values = Vec::with_capacity(m.batch_size() * n);
+    let mut vectors = Vec::with_capacity(m.data.len());
+    for a in m.matrices() {
+        let (vals, vecs) = eigh_(a, n);
+        values.extend(vals);
+        vectors.extend(vecs);
+    }
+    let values = from_f64(values, m.shape(&[n]), t, BackpropOp::none())?;
+    let vectors = from_f64(vectors, m.shape(&[n, n]), t, BackpropOp::none())?;
+    Ok((values, vectors))
+}
@@ -116,6 +116,9 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => Ok(CpuStorage::BF16(self.buffer.read_to_vec(length / size))),
             DType::F32 => Ok(CpuStorage::F32(self.buffer.read_to_vec(length / size))),
             DType::F64 => Ok(CpuStorage::F64(self.buffer.read_to_vec(length / size))),
+            DType::I8 | DType::I16 | DType::I32 | DType::Bool | DType::F8E4M3 | DType::F8E5M2 => {
+                Err(crate::Error::UnsupportedDTypeForOp(self.dtype, "to_cpu_storage").bt())
+            }
         }
     }
 
@@ -192,6 +195,7 @@ impl BackendStorage for MetalStorage {
         // this we want the number of threads to be a power of two.
         let (name, check_empty, return_index) = match (op, self.dtype) {
             (ReduceOp::Sum, DType::F32) => ("fast_sum_float", false, false),
+            (ReduceOp::Prod, _) => crate::bail!("prod is not supported on metal"),
             (ReduceOp::Min, DType::F32) => ("fast_min_float", true, false),
             (ReduceOp::Max, DType::F32) => ("fast_max_float", true, false),
             (ReduceOp::ArgMin, DType::F32) => ("fast_argmin_float", true, true),
@@ -483,10 +487,56 @@ impl BackendStorage for MetalStorage {
         crate::bail!("upsample_nearest2d metal")
     }
 
+    fn upsample_nearest3d(&self, _: &Layout, _: usize, _: usize, _: usize) -> Result<Self> {
+        crate::bail!("upsample_nearest3d metal")
+    }
+
+    fn avg_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self> {
+        crate::bail!("avg_pool3d metal")
+    }
+
+    fn max_pool3d(
+        &self,
+        _: &Layout,
+        _: (usize, usize, usize),
+        _: (usize, usize, usize),
+    ) -> Result<Self> {
+        crate::bail!("max_pool3d metal")
+    }
+
+    fn conv3d(
+        &self,
+        _l: &Layout,
+        _kernel: &Self,
+        _kernel_l: &Layout,
+        _params: &crate::conv::ParamsConv3D,
+    ) -> Result<Self> {
+        crate::bail!("conv3d metal")
+    }
+
+    fn conv_transpose3d(
+        &self,
+        _l: &Layout,
+        _kernel: &Self,
+        _kernel_l: &Layout,
+        _params: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self> {
+        crate::bail!("conv_transpose3d metal")
+    }
+
     fn gather(&self, _: &Layout, _: &Self, _: &Layout, _: usize) -> Result<Self> {
         crate::bail!("gather metal")
     }
 
+    fn sort_last_dim(&self, _: &Layout, _: bool) -> Result<(Self, Self)> {
+        crate::bail!("sort metal")
+    }
+
     fn scatter_add(
         &self,
         _: &Layout,
@@ -541,6 +591,63 @@ impl BackendStorage for MetalStorage {
         })
     }
 
+    fn scatter(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        // There is no metal kernel for this op yet, it runs on the cpu instead.
+        let (ids, src) = (ids.to_cpu_storage()?, src.to_cpu_storage()?);
+        let storage = self
+            .to_cpu_storage()?
+            .scatter(l, &ids, ids_l, &src, src_l, dim)?;
+        self.device().storage_from_cpu_storage(&storage)
+    }
+
+    fn index_copy(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        // There is no metal kernel for this op yet, it runs on the cpu instead.
+        let (ids, src) = (ids.to_cpu_storage()?, src.to_cpu_storage()?);
+        let storage = self
+            .to_cpu_storage()?
+            .index_copy(l, &ids, ids_l, &src, src_l, dim)?;
+        self.device().storage_from_cpu_storage(&storage)
+    }
+
+    fn masked_select(&self, l: &Layout, mask: &Self, mask_l: &Layout) -> Result<(Self, usize)> {
+        // There is no metal kernel for this op yet, it runs on the cpu instead.
+        let mask = mask.to_cpu_storage()?;
+        let (storage, len) = self.to_cpu_storage()?.masked_select(l, &mask, mask_l)?;
+        Ok((self.device().storage_from_cpu_storage(&storage)?, len))
+    }
+
+    fn nonzero(&self, l: &Layout) -> Result<(Self, usize)> {
+        // There is no metal kernel for this op yet, it runs on the cpu instead.
+        let (storage, len) = self.to_cpu_storage()?.nonzero(l)?;
+        Ok((self.device().storage_from_cpu_storage(&storage)?, len))
+    }
+
+    fn masked_fill(&self, l: &Layout, mask: &Self, mask_l: &Layout, value: f64) -> Result<Self> {
+        let shape = l.shape();
+        let fill_l = Layout::contiguous(shape);
+        let fill = self
+            .device()
+            .zeros_impl(shape, self.dtype())?
+            .affine(&fill_l, 0., value)?;
+        mask.where_cond(mask_l, &fill, &fill_l, self, l)
+    }
+
     fn index_add(
         &self,
         _: &Layout,
@@ -792,6 +899,14 @@ impl BackendDevice for MetalDevice {
                 (storage.len() * mem::size_of::<f64>()) as NSUInteger,
                 option,
             ),
+            CpuStorage::I8(_)
+            | CpuStorage::I16(_)
+            | CpuStorage::I32(_)
+            | CpuStorage::Bool(_)
+            | CpuStorage::F8E4M3(_)
+            | CpuStorage::F8E5M2(_) => {
+                Err(crate::Error::UnsupportedDTypeForOp(storage.dtype(), "to-device").bt())?
+            }
         };
         Ok(Self::Storage {
             buffer,
@@ -86,8 +86,16 @@ impl Header {
             DType::F32 => "f4",
             DType::F64 => "f8",
             DType::I64 => "i8",
+            DType::I32 => "i4",
+            DType::I16 => "i2",
+            DType::I8 => "i1",
             DType::U32 => "u4",
             DType::U8 => "u1",
+            DType::Bool => "b1",
+            DType::F8E4M3 | DType::F8E5M2 => Err(Error::Npy(format!(
+                "{} is not supported",
+                self.descr.as_str()
+            )))?,
         };
         if !shape.is_empty() {
             shape.push(',')
@@ -160,13 +168,13 @@ impl Header {
                     "e" | "f2" => DType::F16,
                     "f" | "f4" => DType::F32,
                     "d" | "f8" => DType::F64,
-                    // "i" | "i4" => DType::S32,
+                    "i" | "i4" => DType::I32,
                     "q" | "i8" => DType::I64,
-                    // "h" | "i2" => DType::S16,
-                    // "b" | "i1" => DType::S8,
+                    "h" | "i2" => DType::I16,
+                    "b" | "i1" => DType::I8,
                     "B" | "u1" => DType::U8,
                     "I" | "u4" => DType::U32,
-                    "?" | "b1" => DType::U8,
+                    "?" | "b1" => DType::Bool,
                     // "F" | "F4" => DType::C64,
                     // "D" | "F8" => DType::C128,
                     descr => return Err(Error::Npy(format!("unrecognized descr {descr}"))),
@@ -234,11 +242,44 @@ impl Tensor {
                 reader.read_u32_into::<LittleEndian>(&mut data_t)?;
                 Tensor::from_vec(data_t, shape, &Device::Cpu)
             }
+            DType::I8 => {
+                let mut data_t = vec![0i8; elem_count];
+                reader.read_i8_into(&mut data_t)?;
+                Tensor::from_vec(data_t, shape, &Device::Cpu)
+            }
+            DType::I16 => {
+                let mut data_t = vec![0i16; elem_count];
+                reader.read_i16_into::<LittleEndian>(&mut data_t)?;
+                Tensor::from_vec(data_t, shape, &Device::Cpu)
+            }
+            DType::I32 => {
+                let mut data_t = vec![0i32; elem_count];
+                reader.read_i32_into::<LittleEndian>(&mut data_t)?;
+                Tensor::from_vec(data_t, shape, &Device::Cpu)
+            }
             DType::I64 => {
                 let mut data_t = vec![0i64; elem_count];
                 reader.read_i64_into::<LittleEndian>(&mut data_t)?;
                 Tensor::from_vec(data_t, shape, &Device::Cpu)
             }
+            DType::Bool => {
+                let mut data_t = vec![0u8; elem_count];
+                reader.read_exact(&mut data_t)?;
+                let data_t: Vec<_> = data_t.into_iter().map(|v| crate::Bool(v != 0)).collect();
+                Tensor::from_vec(data_t, shape, &Device::Cpu)
+            }
+            DType::F8E4M3 => {
+                let mut data_t = vec![0u8; elem_count];
+                reader.read_exact(&mut data_t)?;
+                let data_t: Vec<_> = data_t.into_iter().map(crate::F8E4M3::from_bits).collect();
+                Tensor::from_vec(data_t, shape, &Device::Cpu)
+            }
+            DType::F8E5M2 => {
+                let mut data_t = vec![0u8; elem_count];
+                reader.read_exact(&mut data_t)?;
+                let data_t: Vec<_> = data_t.into_iter().map(crate::F8E5M2::from_bits).collect();
+                Tensor::from_vec(data_t, shape, &Device::Cpu)
+            }
         }
     }
 
@@ -1,5 +1,5 @@
 #![allow(clippy::redundant_closure_call)]
-use crate::{CpuStorage, CudaStorage, Layout, MetalStorage, Result, Shape, Tensor};
+use crate::{Bool, CpuStorage, CudaStorage, Layout, MetalStorage, Result, Shape, Tensor};
 use half::{bf16, f16};
 use num_traits::float::Float;
 
@@ -16,6 +16,7 @@ pub enum CmpOp {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ReduceOp {
     Sum,
+    Prod,
     Min,
     Max,
     ArgMin,
@@ -30,6 +31,7 @@ impl ReduceOp {
             Self::Min => "min",
             Self::Max => "max",
             Self::Sum => "sum",
+            Self::Prod => "prod",
         }
     }
 }
@@ -79,6 +81,11 @@ pub enum Op {
     ScatterAdd(Tensor, Tensor, Tensor, usize),
     IndexSelect(Tensor, Tensor, usize),
     IndexAdd(Tensor, Tensor, Tensor, usize),
+    Scatter(Tensor, Tensor, Tensor, usize),
+    IndexCopy(Tensor, Tensor, Tensor, usize),
+    // The mask has been broadcasted to the shape of the first argument.
+    MaskedFill(Tensor, Tensor),
+    MaskedSelect(Tensor, Tensor),
     WhereCond(Tensor, Tensor, Tensor),
 
     #[allow(dead_code)]
@@ -119,6 +126,25 @@ pub enum Op {
         dilation: usize,
     },
 
+    #[allow(dead_code)]
+    Conv3D {
+        arg: Tensor,
+        kernel: Tensor,
+        padding: usize,
+        stride: usize,
+        dilation: usize,
+    },
+
+    #[allow(dead_code)]
+    ConvTranspose3D {
+        arg: Tensor,
+        kernel: Tensor,
+        padding: usize,
+        output_padding: usize,
+        stride: usize,
+        dilation: usize,
+    },
+
     AvgPool2D {
         arg: Tensor,
         kernel_size: (usize, usize),
@@ -131,8 +157,21 @@ pub enum Op {
         stride: (usize, usize),
     },
 
+    AvgPool3D {
+        arg: Tensor,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    },
+
+    MaxPool3D {
+        arg: Tensor,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    },
+
     UpsampleNearest1D(Tensor),
     UpsampleNearest2D(Tensor),
+    UpsampleNearest3D(Tensor),
 
     Cat(Vec<Tensor>, usize),
 
@@ -153,6 +192,11 @@ pub enum Op {
     Permute(Tensor, Vec<usize>),
     Elu(Tensor, f64),
     Powf(Tensor, f64),
+    Inverse(Tensor),
+    Solve(Tensor, Tensor),
+    Cholesky(Tensor),
+    Det(Tensor),
+    Checkpoint(Vec<Tensor>, std::sync::Arc<crate::checkpoint::Checkpoint>),
     CustomOp1(Tensor, std::sync::Arc<Box<dyn CustomOp1 + Send + Sync>>),
     CustomOp2(
         Tensor,
@@ -325,6 +369,9 @@ pub trait UnaryOpT {
     fn f64(v1: f64) -> f64;
     fn u8(v1: u8) -> u8;
     fn u32(v1: u32) -> u32;
+    fn i8(v1: i8) -> i8;
+    fn i16(v1: i16) -> i16;
+    fn i32(v1: i32) -> i32;
     fn i64(v1: i64) -> i64;
 
     // There is no very good way to represent optional function in traits so we go for an explicit
@@ -337,6 +384,9 @@ pub trait UnaryOpT {
     fn f32_vec(_xs: &[f32], _ys: &mut [f32]) {}
     const F64_VEC: bool = false;
     fn f64_vec(_xs: &[f64], _ys: &mut [f64]) {}
+    // Ops that are only defined on floats set this to false, applying them to an integer dtype
+    // then returns an error rather than calling the integer functions above.
+    const INT: bool = true;
 }
 
 pub trait BinaryOpT {
@@ -349,7 +399,11 @@ pub trait BinaryOpT {
     fn f64(v1: f64, v2: f64) -> f64;
     fn u8(v1: u8, v2: u8) -> u8;
     fn u32(v1: u32, v2: u32) -> u32;
+    fn i8(v1: i8, v2: i8) -> i8;
+    fn i16(v1: i16, v2: i16) -> i16;
+    fn i32(v1: i32, v2: i32) -> i32;
     fn i64(v1: i64, v2: i64) -> i64;
+    fn bool(v1: Bool, v2: Bool) -> Bool;
 
     const BF16_VEC: bool = false;
     fn bf16_vec(_xs1: &[bf16], _xs2: &[bf16], _ys: &mut [bf16]) {}
@@ -422,9 +476,25 @@ macro_rules! bin_op {
                 $e(v1, v2)
             }
             #[inline(always)]
+            fn i8(v1: i8, v2: i8) -> i8 {
+                $e(v1, v2)
+            }
+            #[inline(always)]
+            fn i16(v1: i16, v2: i16) -> i16 {
+                $e(v1, v2)
+            }
+            #[inline(always)]
+            fn i32(v1: i32, v2: i32) -> i32 {
+                $e(v1, v2)
+            }
+            #[inline(always)]
             fn i64(v1: i64, v2: i64) -> i64 {
                 $e(v1, v2)
             }
+            #[inline(always)]
+            fn bool(v1: Bool, v2: Bool) -> Bool {
+                $e(v1, v2)
+            }
 
             #[cfg(feature = "mkl")]
             const F32_VEC: bool = true;
@@ -485,6 +555,7 @@ macro_rules! unary_op {
             const NAME: &'static str = $name;
             const KERNEL: &'static str = concat!("u", $name);
             const V: Self = $op;
+            const INT: bool = false;
             #[inline(always)]
             fn bf16($a: bf16) -> bf16 {
                 $e
@@ -503,15 +574,27 @@ macro_rules! unary_op {
             }
             #[inline(always)]
             fn u8(_: u8) -> u8 {
-                todo!("no unary function for u8")
+                unreachable!("no unary function for u8")
             }
             #[inline(always)]
             fn u32(_: u32) -> u32 {
-                todo!("no unary function for u32")
+                unreachable!("no unary function for u32")
+            }
+            #[inline(always)]
+            fn i8(_: i8) -> i8 {
+                unreachable!("no unary function for i8")
+            }
+            #[inline(always)]
+            fn i16(_: i16) -> i16 {
+                unreachable!("no unary function for i16")
+            }
+            #[inline(always)]
+            fn i32(_: i32) -> i32 {
+                unreachable!("no unary function for i32")
             }
             #[inline(always)]
             fn i64(_: i64) -> i64 {
-                todo!("no unary function for i64")
+                unreachable!("no unary function for i64")
             }
         }
     };
@@ -521,6 +604,7 @@ macro_rules! unary_op {
             const NAME: &'static str = $name;
             const KERNEL: &'static str = concat!("u", $name);
             const V: Self = $op;
+            const INT: bool = false;
             #[inline(always)]
             fn bf16($a: bf16) -> bf16 {
                 $e
@@ -539,15 +623,27 @@ macro_rules! unary_op {
             }
             #[inline(always)]
             fn u8(_: u8) -> u8 {
-                todo!("no unary function for u8")
+                unreachable!("no unary function for u8")
             }
             #[inline(always)]
             fn u32(_: u32) -> u32 {
-                todo!("no unary function for u32")
+                unreachable!("no unary function for u32")
+            }
+            #[inline(always)]
+            fn i8(_: i8) -> i8 {
+                unreachable!("no unary function for i8")
+            }
+            #[inline(always)]
+            fn i16(_: i16) -> i16 {
+                unreachable!("no unary function for i16")
+            }
+            #[inline(always)]
+            fn i32(_: i32) -> i32 {
+                unreachable!("no unary function for i32")
             }
             #[inline(always)]
             fn i64(_: i64) -> i64 {
-                todo!("no unary function for i64")
+                unreachable!("no unary function for i64")
             }
 
             #[cfg(feature = "mkl")]
@@ -642,6 +738,18 @@ impl UnaryOpT for Gelu {
         0
     }
     #[inline(always)]
+    fn i8(_: i8) -> i8 {
+        0
+    }
+    #[inline(always)]
+    fn i16(_: i16) -> i16 {
+        0
+    }
+    #[inline(always)]
+    fn i32(_: i32) -> i32 {
+        0
+    }
+    #[inline(always)]
     fn i64(_: i64) -> i64 {
         0
     }
@@ -715,6 +823,18 @@ impl UnaryOpT for Erf {
         0
     }
     #[inline(always)]
+    fn i8(_: i8) -> i8 {
+        0
+    }
+    #[inline(always)]
+    fn i16(_: i16) -> i16 {
+        0
+    }
+    #[inline(always)]
+    fn i32(_: i32) -> i32 {
+        0
+    }
+    #[inline(always)]
     fn i64(_: i64) -> i64 {
         0
     }
@@ -749,6 +869,18 @@ impl UnaryOpT for Abs {
         v
     }
     #[inline(always)]
+    fn i8(v: i8) -> i8 {
+        v.abs()
+    }
+    #[inline(always)]
+    fn i16(v: i16) -> i16 {
+        v.abs()
+    }
+    #[inline(always)]
+    fn i32(v: i32) -> i32 {
+        v.abs()
+    }
+    #[inline(always)]
     fn i64(v: i64) -> i64 {
         v.abs()
     }
@@ -783,6 +915,18 @@ impl UnaryOpT for Ceil {
         v
     }
     #[inline(always)]
+    fn i8(v: i8) -> i8 {
+        v
+    }
+    #[inline(always)]
+    fn i16(v: i16) -> i16 {
+        v
+    }
+    #[inline(always)]
+    fn i32(v: i32) -> i32 {
+        v
+    }
+    #[inline(always)]
     fn i64(v: i64) -> i64 {
         v
     }
@@ -817,6 +961,18 @@ impl UnaryOpT for Floor {
         v
     }
     #[inline(always)]
+    fn i8(v: i8) -> i8 {
+        v
+    }
+    #[inline(always)]
+    fn i16(v: i16) -> i16 {
+        v
+    }
+    #[inline(always)]
+    fn i32(v: i32) -> i32 {
+        v
+    }
+    #[inline(always)]
     fn i64(v: i64) -> i64 {
         v
     }
@@ -851,6 +1007,18 @@ impl UnaryOpT for Round {
         v
     }
     #[inline(always)]
+    fn i8(v: i8) -> i8 {
+        v
+    }
+    #[inline(always)]
+    fn i16(v: i16) -> i16 {
+        v
+    }
+    #[inline(always)]
+    fn i32(v: i32) -> i32 {
+        v
+    }
+    #[inline(always)]
     fn i64(v: i64) -> i64 {
         v
     }
@@ -885,6 +1053,18 @@ impl UnaryOpT for GeluErf {
         0
     }
     #[inline(always)]
+    fn i8(_: i8) -> i8 {
+        0
+    }
+    #[inline(always)]
+    fn i16(_: i16) -> i16 {
+        0
+    }
+    #[inline(always)]
+    fn i32(_: i32) -> i32 {
+        0
+    }
+    #[inline(always)]
     fn i64(_: i64) -> i64 {
         0
     }
@@ -919,6 +1099,18 @@ impl UnaryOpT for Relu {
         v
     }
     #[inline(always)]
+    fn i8(v: i8) -> i8 {
+        v
+    }
+    #[inline(always)]
+    fn i16(v: i16) -> i16 {
+        v
+    }
+    #[inline(always)]
+    fn i32(v: i32) -> i32 {
+        v
+    }
+    #[inline(always)]
     fn i64(v: i64) -> i64 {
         v
     }
@@ -0,0 +1,118 @@
+//! Padding of tensors along multiple dimensions.
+use crate::{bail, Result, Tensor};
+
+/// How the values added by [`Tensor::pad`] are computed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadMode {
+    /// Pads with a constant value.
+    Constant(f64),
+    /// Pads with the reflection of the values along the padded dimension, excluding the edge
+    /// value, e.g. `[1, 2, 3]` padded by 2 on both sides gives `[3, 2, 1, 2, 3, 2, 1]`.
+    Reflect,
+    /// Pads by repeating the edge value, e.g. `[1, 2, 3]` gives `[1, 1, 1, 2, 3, 3, 3]`.
+    Replicate,
+    /// Pads by wrapping around the values, e.g. `[1, 2, 3]` gives `[2, 3, 1, 2, 3, 1, 2]`.
+    Circular,
+}
+
+impl PadMode {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Constant(_) => "constant",
+            Self::Reflect => "reflect",
+            Self::Replicate => "replicate",
+            Self::Circular => "circular",
+        }
+    }
+
+    // Returns the index in the input of the element at position `i` in the padded output.
+    fn source_index(&self, i: usize, left: usize, size: usize) -> usize {
+        let i = i as i64 - left as i64;
+        let size = size as i64;
+        let i = match self {
+            Self::Constant(_) | Self::Replicate => i.clamp(0, size - 1),
+            Self::Reflect => {
+                let i = i.rem_euclid(2 * size - 2);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 2 - i
+                }
+            }
+            Self::Circular => i.rem_euclid(size),
+        };
+        i as usize
+    }
+}
+
+impl Tensor {
+    /// Pads the trailing dimensions of the tensor.
+    ///
+    /// `pad` holds the `(left, right)` number of elements to add before and after the values for
+    /// each of the last `pad.len()` dimensions, so `pad[0]` applies to dimension
+    /// `rank - pad.len()` and the last pair applies to the last dimension.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device, PadMode};
+    /// let t = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &Device::Cpu)?;
+    /// let t = t.pad(&[(1, 0), (2, 1)], PadMode::Reflect)?;
+    /// assert_eq!(
+    ///     t.to_vec2::<f32>()?,
+    ///     &[[6., 5., 4., 5., 6., 5.], [3., 2., 1., 2., 3., 2.], [6., 5., 4., 5., 6., 5.]]
+    /// );
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    ///
+    /// The reflect mode requires the padding to be smaller than the dimension size, the
+    /// replicate and circular modes require the dimension to be non-empty.
+    pub fn pad(&self, pad: &[(usize, usize)], mode: PadMode) -> Result<Self> {
+        let rank = self.rank();
+        if pad.len() > rank {
+            bail!(
+                "pad: {} padding pairs for a tensor of shape {:?}",
+                pad.len(),
+                self.shape()
+            )
+        }
+        let mut xs = self.clone();
+        for (i, &(left, right)) in pad.iter().enumerate() {
+            let dim = rank - pad.len() + i;
+            xs = xs.pad_dim(dim, left, right, mode)?
+        }
+        Ok(xs)
+    }
+
+    fn pad_dim(&self, dim: usize, left: usize, right: usize, mode: PadMode) -> Result<Self> {
+        if left == 0 && right == 0 {
+            return Ok(self.clone());
+        }
+        let size = self.dim(dim)?;
+        match mode {
+            PadMode::Constant(0.) => return self.pad_with_zeros(dim, left, right),
+            PadMode::Constant(value) => {
+                let mut dims = self.dims().to_vec();
+                let mut pad = |len| {
+                    dims[dim] = len;
+                    Tensor::ones(dims.as_slice(), self.dtype(), self.device())?.affine(value, 0.)
+                };
+                let (left, right) = (pad(left)?, pad(right)?);
+                return Tensor::cat(&[&left, self, &right], dim);
+            }
+            PadMode::Reflect if left >= size || right >= size => bail!(
+                "pad: reflect padding ({left}, {right}) is too large for dim {dim} of {:?}",
+                self.shape()
+            ),
+            PadMode::Replicate | PadMode::Circular if size == 0 => bail!(
+                "pad: cannot use {} padding on dim {dim} of {:?}",
+                mode.name(),
+                self.shape()
+            ),
+            PadMode::Reflect | PadMode::Replicate | PadMode::Circular => {}
+        }
+        let indexes: Vec<u32> = (0..left + size + right)
+            .map(|i| mode.source_index(i, left, size) as u32)
+            .collect();
+        let indexes = Tensor::new(indexes, self.device())?;
+        self.contiguous()?.index_select(&indexes, dim)
+    }
+}
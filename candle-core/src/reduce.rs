@@ -0,0 +1,221 @@
+//! Reductions and scans built on top of the primitive tensor ops.
+use crate::shape::{Dim, Dims};
+use crate::{bail, Result, Tensor};
+
+/// The norms supported by [`Tensor::norm`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormOrd {
+    /// The sum of the absolute values.
+    L1,
+    /// The square root of the sum of the squared values.
+    L2,
+    /// The matrix norm equivalent to the L2 norm over exactly two dimensions.
+    Frobenius,
+    /// The maximum of the absolute values.
+    Inf,
+}
+
+impl Tensor {
+    /// Returns the log of the sum of the exponentials of the elements over the selected
+    /// dimensions, the maximum is subtracted before the exponentials so that large values do
+    /// not overflow.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device, test_utils::to_vec2_round};
+    /// let a = Tensor::new(&[[1000f32, 1000.], [0., f32::NEG_INFINITY]], &Device::Cpu)?;
+    /// let s = a.log_sum_exp_keepdim(1)?;
+    /// assert_eq!(to_vec2_round(&s, 3)?, &[[1000.693], [0.]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn log_sum_exp_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        let dims = dims.to_indexes(self.shape(), "log-sum-exp")?;
+        let (shifted, max) = self.shift_by_max(&dims)?;
+        shifted.exp()?.sum_keepdim(dims)?.log()?.add(&max)
+    }
+
+    /// Returns the log of the softmax over the selected dimensions, i.e. the elements minus the
+    /// log of the sum of the exponentials. The maximum is subtracted first so that the result
+    /// does not lose precision for large values.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device, test_utils::to_vec1_round};
+    /// let a = Tensor::new(&[1e6f32, 1e6, 1e6], &Device::Cpu)?;
+    /// let s = a.log_softmax(0)?;
+    /// assert_eq!(to_vec1_round(&s, 4)?, &[-1.0986, -1.0986, -1.0986]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn log_softmax<D: Dims>(&self, dims: D) -> Result<Self> {
+        let dims = dims.to_indexes(self.shape(), "log-softmax")?;
+        let (shifted, _max) = self.shift_by_max(&dims)?;
+        let log_sum_exp = shifted.exp()?.sum_keepdim(dims)?.log()?;
+        shifted.broadcast_sub(&log_sum_exp)
+    }
+
+    // Subtracts the maximum over `dims` from the elements, returns the shifted elements and the
+    // maximum.
+    fn shift_by_max(&self, dims: &[usize]) -> Result<(Self, Self)> {
+        // The gradient does not depend on the shift so it is not propagated through the max.
+        let max = self.detach()?.max_keepdim(dims)?;
+        // Infinite maximums would result in nans, these slices are not shifted.
+        let is_finite = max.abs()?.lt(f64::INFINITY)?;
+        let max = is_finite.where_cond(&max, &max.zeros_like()?)?;
+        Ok((self.broadcast_sub(&max)?, max))
+    }
+
+    /// Similar to `log_sum_exp_keepdim` but the target dimensions are squeezed.
+    pub fn log_sum_exp<D: Dims>(&self, dims: D) -> Result<Self> {
+        let dims = dims.to_indexes(self.shape(), "log-sum-exp")?;
+        self.log_sum_exp_keepdim(dims.as_slice())?
+            .squeeze_dims(&dims)
+    }
+
+    /// Returns the unbiased standard deviation over the selected dimensions.
+    pub fn std_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.var_keepdim(dims)?.sqrt()
+    }
+
+    /// Similar to `std_keepdim` but the target dimensions are squeezed.
+    pub fn std<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.var(dims)?.sqrt()
+    }
+
+    /// Returns the norm of the elements over the selected dimensions.
+    ///
+    /// The L2 norm is computed on the values rescaled by their maximum absolute value so that
+    /// it does not overflow or underflow.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device, NormOrd};
+    /// let a = Tensor::new(&[[3f32, -4.], [1e30, 1e30]], &Device::Cpu)?;
+    /// let n = a.norm_keepdim(NormOrd::L1, 1)?;
+    /// assert_eq!(n.to_vec2::<f32>()?, &[[7.], [2e30]]);
+    /// let n = a.norm_keepdim(NormOrd::L2, 1)?;
+    /// assert_eq!(n.to_vec2::<f32>()?, &[[5.], [1.4142135e30]]);
+    /// let n = a.norm_keepdim(NormOrd::Inf, 1)?;
+    /// assert_eq!(n.to_vec2::<f32>()?, &[[4.], [1e30]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn norm_keepdim<D: Dims>(&self, ord: NormOrd, dims: D) -> Result<Self> {
+        let dims = dims.to_indexes(self.shape(), "norm")?;
+        match ord {
+            NormOrd::L1 => self.abs()?.sum_keepdim(dims),
+            NormOrd::Inf => self.abs()?.max_keepdim(dims),
+            NormOrd::L2 | NormOrd::Frobenius => {
+                if ord == NormOrd::Frobenius && dims.len() != 2 {
+                    bail!("norm: the frobenius norm requires two dimensions, got {dims:?}")
+                }
+                let scale = self.detach()?.abs()?.max_keepdim(dims.as_slice())?;
+                let scale = scale.eq(0f64)?.where_cond(&scale.ones_like()?, &scale)?;
+                self.broadcast_div(&scale)?
+                    .sqr()?
+                    .sum_keepdim(dims)?
+                    .sqrt()?
+                    .mul(&scale)
+            }
+        }
+    }
+
+    /// Similar to `norm_keepdim` but the target dimensions are squeezed.
+    pub fn norm<D: Dims>(&self, ord: NormOrd, dims: D) -> Result<Self> {
+        let dims = dims.to_indexes(self.shape(), "norm")?;
+        self.norm_keepdim(ord, dims.as_slice())?.squeeze_dims(&dims)
+    }
+
+    /// Returns a boolean tensor, with the same dtype as the [`Tensor::cmp`] results, that is true
+    /// where all the elements over the selected dimensions are non-zero.
+    pub fn all_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.ne(0f64)?.min_keepdim(dims)
+    }
+
+    /// Similar to `all_keepdim` but the target dimensions are squeezed.
+    pub fn all<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.ne(0f64)?.min(dims)
+    }
+
+    /// Returns a boolean tensor, with the same dtype as the [`Tensor::cmp`] results, that is true
+    /// where any of the elements over the selected dimensions is non-zero.
+    ///
+    /// ```rust
+    /// use candle_core::{Bool, Tensor, Device};
+    /// let a = Tensor::new(&[[0f32, 2.], [0., 0.]], &Device::Cpu)?;
+    /// assert_eq!(a.any(1)?.to_vec1::<Bool>()?, &[Bool(true), Bool(false)]);
+    /// assert_eq!(a.all(0)?.to_vec1::<Bool>()?, &[Bool(false), Bool(false)]);
+    /// assert_eq!(a.any((0, 1))?.to_scalar::<Bool>()?, Bool(true));
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn any_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.ne(0f64)?.max_keepdim(dims)
+    }
+
+    /// Similar to `any_keepdim` but the target dimensions are squeezed.
+    pub fn any<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.ne(0f64)?.max(dims)
+    }
+
+    /// Returns the cumulative product of elements of the input tensor over the specified
+    /// dimension.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[1f32, 2., 0., 4.], [-1., 3., 2., 0.5]], &Device::Cpu)?;
+    /// let a = a.cumprod(1)?;
+    /// assert_eq!(a.to_vec2::<f32>()?, &[[1., 2., 0., 0.], [-1., -3., -6., -3.]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn cumprod<D: Dim>(&self, dim: D) -> Result<Self> {
+        let dim = dim.to_index(self.shape(), "cumprod")?;
+        let n = self.dim(dim)?;
+        // Inclusive scan in log2(n) steps, each element being multiplied by the partial product
+        // ending `offset` positions before it.
+        let mut xs = self.clone();
+        let mut offset = 1;
+        while offset < n {
+            let ones = xs.narrow(dim, 0, offset)?.ones_like()?;
+            let shifted = Tensor::cat(&[&ones, &xs.narrow(dim, 0, n - offset)?], dim)?;
+            xs = xs.mul(&shifted)?;
+            offset *= 2;
+        }
+        Ok(xs)
+    }
+
+    /// Returns the cumulative maximum of elements of the input tensor over the specified
+    /// dimension, as well as the `u32` indexes of these maximums. On ties, the last index is
+    /// returned.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[1f32, 3., 2., 3., 5.], &Device::Cpu)?;
+    /// let (values, indexes) = a.cummax(0)?;
+    /// assert_eq!(values.to_vec1::<f32>()?, &[1., 3., 3., 3., 5.]);
+    /// assert_eq!(indexes.to_vec1::<u32>()?, &[0, 1, 1, 3, 4]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn cummax<D: Dim>(&self, dim: D) -> Result<(Self, Self)> {
+        let dim = dim.to_index(self.shape(), "cummax")?;
+        let n = self.dim(dim)?;
+        let mut shape = vec![1; self.rank()];
+        shape[dim] = n;
+        let indexes = Tensor::arange(0u32, n as u32, self.device())?
+            .reshape(shape)?
+            .broadcast_as(self.shape())?
+            .contiguous()?;
+        // Same scan as in `cumprod` with the values and indexes updated together. The first
+        // elements are combined with themselves, this is a no-op for the maximum.
+        let (mut values, mut indexes) = (self.clone(), indexes);
+        let mut offset = 1;
+        while offset < n {
+            let shift = |xs: &Tensor| -> Result<Tensor> {
+                Tensor::cat(
+                    &[&xs.narrow(dim, 0, offset)?, &xs.narrow(dim, 0, n - offset)?],
+                    dim,
+                )
+            };
+            let shifted_values = shift(&values)?;
+            let take = shifted_values.gt(&values)?;
+            indexes = take.where_cond(&shift(&indexes)?, &indexes)?;
+            values = take.where_cond(&shifted_values, &values)?;
+            offset *= 2;
+        }
+        Ok((values, indexes))
+    }
+}
@@ -10,11 +10,17 @@ impl From<DType> for st::Dtype {
         match value {
             DType::U8 => st::Dtype::U8,
             DType::U32 => st::Dtype::U32,
+            DType::I8 => st::Dtype::I8,
+            DType::I16 => st::Dtype::I16,
+            DType::I32 => st::Dtype::I32,
             DType::I64 => st::Dtype::I64,
+            DType::Bool => st::Dtype::BOOL,
             DType::BF16 => st::Dtype::BF16,
             DType::F16 => st::Dtype::F16,
             DType::F32 => st::Dtype::F32,
             DType::F64 => st::Dtype::F64,
+            DType::F8E4M3 => st::Dtype::F8_E4M3,
+            DType::F8E5M2 => st::Dtype::F8_E5M2,
         }
     }
 }
@@ -25,11 +31,17 @@ impl TryFrom<st::Dtype> for DType {
         match value {
             st::Dtype::U8 => Ok(DType::U8),
             st::Dtype::U32 => Ok(DType::U32),
+            st::Dtype::I8 => Ok(DType::I8),
+            st::Dtype::I16 => Ok(DType::I16),
+            st::Dtype::I32 => Ok(DType::I32),
             st::Dtype::I64 => Ok(DType::I64),
+            st::Dtype::BOOL => Ok(DType::Bool),
             st::Dtype::BF16 => Ok(DType::BF16),
             st::Dtype::F16 => Ok(DType::F16),
             st::Dtype::F32 => Ok(DType::F32),
             st::Dtype::F64 => Ok(DType::F64),
+            st::Dtype::F8_E4M3 => Ok(DType::F8E4M3),
+            st::Dtype::F8_E5M2 => Ok(DType::F8E5M2),
             dtype => Err(Error::UnsupportedSafeTensorDtype(dtype)),
         }
     }
@@ -187,11 +199,21 @@ impl Tensor {
         match dtype {
             DType::U8 => convert_slice::<u8>(data, shape, device),
             DType::U32 => convert_slice::<u32>(data, shape, device),
+            DType::I8 => convert_slice::<i8>(data, shape, device),
+            DType::I16 => convert_slice::<i16>(data, shape, device),
+            DType::I32 => convert_slice::<i32>(data, shape, device),
             DType::I64 => convert_slice::<i64>(data, shape, device),
+            DType::Bool => {
+                convert_slice_with_cast::<u8, crate::Bool, _>(data, shape, device, |x| {
+                    Ok(crate::Bool(x != 0))
+                })
+            }
             DType::BF16 => convert_slice::<half::bf16>(data, shape, device),
             DType::F16 => convert_slice::<half::f16>(data, shape, device),
             DType::F32 => convert_slice::<f32>(data, shape, device),
             DType::F64 => convert_slice::<f64>(data, shape, device),
+            DType::F8E4M3 => convert_slice::<crate::F8E4M3>(data, shape, device),
+            DType::F8E5M2 => convert_slice::<crate::F8E5M2>(data, shape, device),
         }
     }
 }
@@ -204,15 +226,35 @@ fn convert(view: &st::TensorView<'_>, device: &Device) -> Result<Tensor> {
             convert_with_cast_::<u16, u32, _>(view, device, conv)
         }
         st::Dtype::U32 => convert_::<u32>(view, device),
-        st::Dtype::I32 => {
+        // Only the cpu backend supports the i8, i16, i32 and bool dtypes, these values are widened
+        // to i64 or u8 on the other devices.
+        st::Dtype::I8 if !device.is_cpu() => {
+            let conv = |x| Ok(i64::from(x));
+            convert_with_cast_::<i8, i64, _>(view, device, conv)
+        }
+        st::Dtype::I8 => convert_::<i8>(view, device),
+        st::Dtype::I16 if !device.is_cpu() => {
+            let conv = |x| Ok(i64::from(x));
+            convert_with_cast_::<i16, i64, _>(view, device, conv)
+        }
+        st::Dtype::I16 => convert_::<i16>(view, device),
+        st::Dtype::I32 if !device.is_cpu() => {
             let conv = |x| Ok(i64::from(x));
             convert_with_cast_::<i32, i64, _>(view, device, conv)
         }
+        st::Dtype::I32 => convert_::<i32>(view, device),
+        st::Dtype::BOOL if !device.is_cpu() => convert_::<u8>(view, device),
+        st::Dtype::BOOL => {
+            let conv = |x| Ok(crate::Bool(x != 0));
+            convert_with_cast_::<u8, crate::Bool, _>(view, device, conv)
+        }
         st::Dtype::I64 => convert_::<i64>(view, device),
         st::Dtype::BF16 => convert_::<half::bf16>(view, device),
         st::Dtype::F16 => convert_::<half::f16>(view, device),
         st::Dtype::F32 => convert_::<f32>(view, device),
         st::Dtype::F64 => convert_::<f64>(view, device),
+        st::Dtype::F8_E4M3 => convert_::<crate::F8E4M3>(view, device),
+        st::Dtype::F8_E5M2 => convert_::<crate::F8E5M2>(view, device),
         dtype => Err(Error::UnsupportedSafeTensorDtype(dtype)),
     }
 }
@@ -223,11 +265,20 @@ fn convert_back(tensor: &Tensor) -> Result<Vec<u8>> {
     match tensor.dtype() {
         DType::U8 => Ok(convert_back_::<u8>(tensor.to_vec1()?)),
         DType::U32 => Ok(convert_back_::<u32>(tensor.to_vec1()?)),
+        DType::I8 => Ok(convert_back_::<i8>(tensor.to_vec1()?)),
+        DType::I16 => Ok(convert_back_::<i16>(tensor.to_vec1()?)),
+        DType::I32 => Ok(convert_back_::<i32>(tensor.to_vec1()?)),
         DType::I64 => Ok(convert_back_::<i64>(tensor.to_vec1()?)),
+        DType::Bool => {
+            let vs = tensor.to_vec1::<crate::Bool>()?;
+            Ok(vs.into_iter().map(|v| u8::from(v.0)).collect())
+        }
         DType::F16 => Ok(convert_back_::<half::f16>(tensor.to_vec1()?)),
         DType::BF16 => Ok(convert_back_::<half::bf16>(tensor.to_vec1()?)),
         DType::F32 => Ok(convert_back_::<f32>(tensor.to_vec1()?)),
         DType::F64 => Ok(convert_back_::<f64>(tensor.to_vec1()?)),
+        DType::F8E4M3 => Ok(convert_back_::<crate::F8E4M3>(tensor.to_vec1()?)),
+        DType::F8E5M2 => Ok(convert_back_::<crate::F8E5M2>(tensor.to_vec1()?)),
     }
 }
 
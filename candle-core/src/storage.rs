@@ -165,6 +165,23 @@ impl Storage {
         }
     }
 
+    pub(crate) fn sort_last_dim(&self, layout: &Layout, asc: bool) -> Result<(Self, Self)> {
+        match self {
+            Storage::Cpu(storage) => {
+                let (values, indexes) = storage.sort_last_dim(layout, asc)?;
+                Ok((Self::Cpu(values), Self::Cpu(indexes)))
+            }
+            Self::Cuda(storage) => {
+                let (values, indexes) = storage.sort_last_dim(layout, asc)?;
+                Ok((Self::Cuda(values), Self::Cuda(indexes)))
+            }
+            Self::Metal(storage) => {
+                let (values, indexes) = storage.sort_last_dim(layout, asc)?;
+                Ok((Self::Metal(values), Self::Metal(indexes)))
+            }
+        }
+    }
+
     pub(crate) fn to_dtype(&self, layout: &Layout, dtype: DType) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
@@ -423,6 +440,68 @@ impl Storage {
         }
     }
 
+    pub(crate) fn conv3d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv3D,
+    ) -> Result<Self> {
+        self.same_device(kernel, "conv3d")?;
+        self.same_dtype(kernel, "conv3d")?;
+        match (self, &kernel) {
+            (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
+                let s = inp.conv3d(l, kernel, kernel_l, params)?;
+                Ok(Self::Cpu(s))
+            }
+            (Storage::Cuda(inp), Storage::Cuda(kernel)) => {
+                let s = inp.conv3d(l, kernel, kernel_l, params)?;
+                Ok(Self::Cuda(s))
+            }
+            (Storage::Metal(inp), Storage::Metal(kernel)) => {
+                let s = inp.conv3d(l, kernel, kernel_l, params)?;
+                Ok(Self::Metal(s))
+            }
+            (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
+                lhs: lhs.device().location(),
+                rhs: rhs.device().location(),
+                op: "conv3d",
+            }
+            .bt()),
+        }
+    }
+
+    pub(crate) fn conv_transpose3d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose3D,
+    ) -> Result<Self> {
+        self.same_device(kernel, "conv_transpose3d")?;
+        self.same_dtype(kernel, "conv_transpose3d")?;
+        match (self, &kernel) {
+            (Storage::Cpu(inp), Storage::Cpu(kernel)) => {
+                let s = inp.conv_transpose3d(l, kernel, kernel_l, params)?;
+                Ok(Self::Cpu(s))
+            }
+            (Storage::Cuda(inp), Storage::Cuda(kernel)) => {
+                let s = inp.conv_transpose3d(l, kernel, kernel_l, params)?;
+                Ok(Self::Cuda(s))
+            }
+            (Storage::Metal(inp), Storage::Metal(kernel)) => {
+                let s = inp.conv_transpose3d(l, kernel, kernel_l, params)?;
+                Ok(Self::Metal(s))
+            }
+            (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
+                lhs: lhs.device().location(),
+                rhs: rhs.device().location(),
+                op: "conv_transpose3d",
+            }
+            .bt()),
+        }
+    }
+
     pub(crate) fn avg_pool2d(
         &self,
         layout: &Layout,
@@ -467,6 +546,50 @@ impl Storage {
         }
     }
 
+    pub(crate) fn avg_pool3d(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    ) -> Result<Self> {
+        match self {
+            Storage::Cpu(storage) => {
+                let storage = storage.avg_pool3d(layout, kernel_size, stride)?;
+                Ok(Self::Cpu(storage))
+            }
+            Self::Cuda(storage) => {
+                let storage = storage.avg_pool3d(layout, kernel_size, stride)?;
+                Ok(Self::Cuda(storage))
+            }
+            Self::Metal(storage) => {
+                let storage = storage.avg_pool3d(layout, kernel_size, stride)?;
+                Ok(Self::Metal(storage))
+            }
+        }
+    }
+
+    pub(crate) fn max_pool3d(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+    ) -> Result<Self> {
+        match self {
+            Storage::Cpu(storage) => {
+                let storage = storage.max_pool3d(layout, kernel_size, stride)?;
+                Ok(Self::Cpu(storage))
+            }
+            Self::Cuda(storage) => {
+                let storage = storage.max_pool3d(layout, kernel_size, stride)?;
+                Ok(Self::Cuda(storage))
+            }
+            Self::Metal(storage) => {
+                let storage = storage.max_pool3d(layout, kernel_size, stride)?;
+                Ok(Self::Metal(storage))
+            }
+        }
+    }
+
     pub(crate) fn upsample_nearest1d(&self, layout: &Layout, sz: usize) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
@@ -501,6 +624,29 @@ impl Storage {
         }
     }
 
+    pub(crate) fn upsample_nearest3d(
+        &self,
+        layout: &Layout,
+        d: usize,
+        h: usize,
+        w: usize,
+    ) -> Result<Self> {
+        match self {
+            Storage::Cpu(storage) => {
+                let storage = storage.upsample_nearest3d(layout, d, h, w)?;
+                Ok(Self::Cpu(storage))
+            }
+            Self::Cuda(storage) => {
+                let storage = storage.upsample_nearest3d(layout, d, h, w)?;
+                Ok(Self::Cuda(storage))
+            }
+            Self::Metal(storage) => {
+                let storage = storage.upsample_nearest3d(layout, d, h, w)?;
+                Ok(Self::Metal(storage))
+            }
+        }
+    }
+
     pub(crate) fn where_cond(
         &self,
         layout: &Layout,
@@ -615,6 +761,128 @@ impl Storage {
         }
     }
 
+    pub(crate) fn scatter(
+        &self,
+        l: &Layout,
+        indexes: &Self,
+        indexes_l: &Layout,
+        source: &Self,
+        source_l: &Layout,
+        d: usize,
+    ) -> Result<Self> {
+        self.same_device(indexes, "scatter")?;
+        self.same_device(source, "scatter")?;
+        match (self, indexes, source) {
+            (Self::Cpu(s), Self::Cpu(indexes), Self::Cpu(source)) => {
+                let storage = s.scatter(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Cpu(storage))
+            }
+            (Self::Cuda(s), Self::Cuda(indexes), Self::Cuda(source)) => {
+                let storage = s.scatter(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Cuda(storage))
+            }
+            (Self::Metal(s), Self::Metal(indexes), Self::Metal(source)) => {
+                let storage = s.scatter(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Metal(storage))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn index_copy(
+        &self,
+        l: &Layout,
+        indexes: &Self,
+        indexes_l: &Layout,
+        source: &Self,
+        source_l: &Layout,
+        d: usize,
+    ) -> Result<Self> {
+        self.same_device(indexes, "index-copy")?;
+        self.same_device(source, "index-copy")?;
+        match (self, indexes, source) {
+            (Self::Cpu(s), Self::Cpu(indexes), Self::Cpu(source)) => {
+                let storage = s.index_copy(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Cpu(storage))
+            }
+            (Self::Cuda(s), Self::Cuda(indexes), Self::Cuda(source)) => {
+                let storage = s.index_copy(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Cuda(storage))
+            }
+            (Self::Metal(s), Self::Metal(indexes), Self::Metal(source)) => {
+                let storage = s.index_copy(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Metal(storage))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn masked_fill(
+        &self,
+        l: &Layout,
+        mask: &Self,
+        mask_l: &Layout,
+        value: f64,
+    ) -> Result<Self> {
+        self.same_device(mask, "masked-fill")?;
+        match (self, mask) {
+            (Self::Cpu(s), Self::Cpu(mask)) => {
+                let storage = s.masked_fill(l, mask, mask_l, value)?;
+                Ok(Self::Cpu(storage))
+            }
+            (Self::Cuda(s), Self::Cuda(mask)) => {
+                let storage = s.masked_fill(l, mask, mask_l, value)?;
+                Ok(Self::Cuda(storage))
+            }
+            (Self::Metal(s), Self::Metal(mask)) => {
+                let storage = s.masked_fill(l, mask, mask_l, value)?;
+                Ok(Self::Metal(storage))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn masked_select(
+        &self,
+        l: &Layout,
+        mask: &Self,
+        mask_l: &Layout,
+    ) -> Result<(Self, usize)> {
+        self.same_device(mask, "masked-select")?;
+        match (self, mask) {
+            (Self::Cpu(s), Self::Cpu(mask)) => {
+                let (storage, len) = s.masked_select(l, mask, mask_l)?;
+                Ok((Self::Cpu(storage), len))
+            }
+            (Self::Cuda(s), Self::Cuda(mask)) => {
+                let (storage, len) = s.masked_select(l, mask, mask_l)?;
+                Ok((Self::Cuda(storage), len))
+            }
+            (Self::Metal(s), Self::Metal(mask)) => {
+                let (storage, len) = s.masked_select(l, mask, mask_l)?;
+                Ok((Self::Metal(storage), len))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn nonzero(&self, l: &Layout) -> Result<(Self, usize)> {
+        match self {
+            Self::Cpu(s) => {
+                let (storage, len) = s.nonzero(l)?;
+                Ok((Self::Cpu(storage), len))
+            }
+            Self::Cuda(s) => {
+                let (storage, len) = s.nonzero(l)?;
+                Ok((Self::Cuda(storage), len))
+            }
+            Self::Metal(s) => {
+                let (storage, len) = s.nonzero(l)?;
+                Ok((Self::Metal(storage), len))
+            }
+        }
+    }
+
     pub(crate) fn index_select(
         &self,
         rhs: &Self,
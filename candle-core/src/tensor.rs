@@ -9,12 +9,12 @@ use crate::shape::{Dim, Dims};
 use crate::{bail, storage::Storage, DType, Device, Error, Layout, Result, Shape};
 use std::sync::{Arc, RwLock};
 
-/// Unique identifier for tensors.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+/// Unique identifier for tensors, the identifiers are increasing with the creation order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TensorId(usize);
 
 impl TensorId {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         // https://users.rust-lang.org/t/idiomatic-rust-way-to-generate-unique-id/33805
         use std::sync::atomic;
         static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(1);
@@ -735,7 +735,7 @@ impl Tensor {
         }
     }
 
-    fn squeeze_dims(self, dims: &[usize]) -> Result<Self> {
+    pub(crate) fn squeeze_dims(self, dims: &[usize]) -> Result<Self> {
         match dims {
             [] => Ok(self),
             [i] => self.squeeze(*i),
@@ -757,13 +757,17 @@ impl Tensor {
         }
     }
 
-    fn reduce_impl<D: Dim>(&self, dim: D, keepdim: bool, op: ReduceOp) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), op.name())?;
+    fn reduce_impl<D: Dims>(&self, dims: D, keepdim: bool, op: ReduceOp) -> Result<Self> {
+        let reduce_dims = dims.to_indexes(self.shape(), op.name())?;
+        let dim = match reduce_dims.as_slice() {
+            [dim] => *dim,
+            _ => return self.reduce_multi_impl(&reduce_dims, keepdim, op),
+        };
         let storage = self.storage().reduce_op(op, self.layout(), &[dim])?;
         let mut dims = self.dims().to_vec();
         dims[dim] = 1;
         let op = match op {
-            ReduceOp::Sum | ReduceOp::Min | ReduceOp::Max => {
+            ReduceOp::Sum | ReduceOp::Prod | ReduceOp::Min | ReduceOp::Max => {
                 BackpropOp::new1(self, |arg| Op::Reduce(arg, op, dims.to_vec()))
             }
             ReduceOp::ArgMin | ReduceOp::ArgMax => BackpropOp::none(),
@@ -776,24 +780,63 @@ impl Tensor {
         }
     }
 
-    fn sum_impl<D: Dims>(&self, sum_dims: D, keepdim: bool) -> Result<Self> {
-        let sum_dims = sum_dims.to_indexes(self.shape(), "sum")?;
-        let storage = self
-            .storage()
-            .reduce_op(ReduceOp::Sum, self.layout(), &sum_dims)?;
+    // The reductions that only support a single dimension are applied over multiple dimensions by
+    // moving these to the end and flattening them. The indexes returned by argmin/argmax are
+    // the positions in these flattened dimensions.
+    fn reduce_multi_impl(
+        &self,
+        reduce_dims: &[usize],
+        keepdim: bool,
+        op: ReduceOp,
+    ) -> Result<Self> {
+        let mut kept_dims = vec![];
+        let mut kept_sizes = vec![];
+        for (dim, &size) in self.dims().iter().enumerate() {
+            if !reduce_dims.contains(&dim) {
+                kept_dims.push(dim);
+                kept_sizes.push(size);
+            }
+        }
+        let reduced_size: usize = reduce_dims.iter().map(|&d| self.dims()[d]).product();
+        let mut perm = kept_dims;
+        perm.extend_from_slice(reduce_dims);
+        let mut flat_dims = kept_sizes.clone();
+        flat_dims.push(reduced_size);
+        let res =
+            self.permute(perm)?
+                .reshape(flat_dims)?
+                .reduce_impl(kept_sizes.len(), false, op)?;
+        if keepdim {
+            let mut dims = self.dims().to_vec();
+            for &dim in reduce_dims.iter() {
+                dims[dim] = 1
+            }
+            res.reshape(dims)
+        } else {
+            Ok(res)
+        }
+    }
+
+    fn fold_impl<D: Dims>(&self, reduce_dims: D, keepdim: bool, op: ReduceOp) -> Result<Self> {
+        let reduce_dims = reduce_dims.to_indexes(self.shape(), op.name())?;
+        let storage = self.storage().reduce_op(op, self.layout(), &reduce_dims)?;
         let mut dims = self.dims().to_vec();
-        for &sum_dim in sum_dims.iter() {
-            dims[sum_dim] = 1
+        for &reduce_dim in reduce_dims.iter() {
+            dims[reduce_dim] = 1
         }
-        let op = BackpropOp::new1(self, |a| Op::Reduce(a, ReduceOp::Sum, dims.to_vec()));
-        let sum = from_storage(storage, dims, op, false);
+        let backprop_op = BackpropOp::new1(self, |a| Op::Reduce(a, op, dims.to_vec()));
+        let res = from_storage(storage, dims, backprop_op, false);
         if keepdim {
-            Ok(sum)
+            Ok(res)
         } else {
-            sum.squeeze_dims(&sum_dims)
+            res.squeeze_dims(&reduce_dims)
         }
     }
 
+    fn sum_impl<D: Dims>(&self, sum_dims: D, keepdim: bool) -> Result<Self> {
+        self.fold_impl(sum_dims, keepdim, ReduceOp::Sum)
+    }
+
     /// Returns the sum of all elements in the input tensor. The sum is performed over all the
     /// input dimensions.
     ///
@@ -822,6 +865,32 @@ impl Tensor {
         self.sum_impl(sum_dims, false)
     }
 
+    /// Returns the product of all elements in the input tensor. The product is performed over all
+    /// the input dimensions.
+    ///
+    /// The resulting tensor has a shape that is similar to the shape of the input tensor, except
+    /// that the number of elements for each dimension index in `prod_dims` is 1.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    /// let s = a.prod_keepdim(0)?;
+    /// assert_eq!(s.to_vec2::<f32>()?, &[[3., 8.]]);
+    /// let s = a.prod_keepdim((0, 1))?;
+    /// assert_eq!(s.to_vec2::<f32>()?, &[[24.]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn prod_keepdim<D: Dims>(&self, prod_dims: D) -> Result<Self> {
+        self.fold_impl(prod_dims, true, ReduceOp::Prod)
+    }
+
+    /// Returns the product of all elements in the input tensor. The product is performed over all
+    /// the input dimensions and compared to `prod_keepdim` these dimensions are squeezed rather
+    /// than kept.
+    pub fn prod<D: Dims>(&self, prod_dims: D) -> Result<Self> {
+        self.fold_impl(prod_dims, false, ReduceOp::Prod)
+    }
+
     /// Returns the mean of all elements in the input tensor. The mean is performed over all the
     /// input dimensions.
     ///
@@ -856,64 +925,122 @@ impl Tensor {
         self.sum_impl(mean_dims, false)? * scale
     }
 
-    /// Returns the unbiased variance over the selected dimension.
-    pub fn var_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), "var")?;
-        let mean = self.mean_keepdim(dim)?;
+    /// Returns the unbiased variance over the selected dimensions.
+    pub fn var_keepdim<D: Dims>(&self, var_dims: D) -> Result<Self> {
+        let var_dims = var_dims.to_indexes(self.shape(), "var")?;
+        let n: usize = var_dims.iter().map(|&d| self.dims()[d]).product();
+        let mean = self.mean_keepdim(var_dims.as_slice())?;
         let squares = self.broadcast_sub(&mean)?.sqr()?;
-        squares.sum_impl(dim, true)? / (self.dim(dim)? - 1) as f64
+        squares.sum_impl(var_dims, true)? / (n - 1) as f64
+    }
+
+    /// Returns the unbiased variance over the selected dimensions.
+    pub fn var<D: Dims>(&self, var_dims: D) -> Result<Self> {
+        let var_dims = var_dims.to_indexes(self.shape(), "var")?;
+        self.var_keepdim(var_dims.as_slice())?
+            .squeeze_dims(&var_dims)
+    }
+
+    /// Gathers the maximum value across the selected dimensions. The resulting shape has the same
+    /// number of dimensions as the original tensor and the select dimensions have a single
+    /// element.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[[0f32, 5.], [2., 3.]], [[4., 1.], [7., 6.]]], &Device::Cpu)?;
+    /// let m = a.max_keepdim((1, 2))?;
+    /// assert_eq!(m.flatten_all()?.to_vec1::<f32>()?, &[5., 7.]);
+    /// let m = a.argmax((1, 2))?;
+    /// assert_eq!(m.to_vec1::<u32>()?, &[1, 2]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn max_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, true, ReduceOp::Max)
     }
 
-    /// Returns the unbiased variance over the selected dimension.
-    pub fn var<D: Dim>(&self, dim: D) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), "var")?;
-        self.var_keepdim(dim)?.squeeze(dim)
+    /// Similar to `max_keepdim` but the target dimensions are squeezed.
+    pub fn max<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, false, ReduceOp::Max)
     }
 
-    /// Gathers the maximum value across the selected dimension. The resulting shape has the same
-    /// number of dimensions as the original tensor and the select dimension has a single element.
-    pub fn max_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, true, ReduceOp::Max)
+    /// Gathers the minimum value across the selected dimensions. The resulting shape has the same
+    /// number of dimensions as the original tensor and the select dimensions have a single
+    /// element.
+    pub fn min_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, true, ReduceOp::Min)
     }
 
-    /// Similar to `max_keepdim` but the target dimension is squeezed.
-    pub fn max<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, false, ReduceOp::Max)
+    /// Similar to `min_keepdim` but the target dimensions are squeezed.
+    pub fn min<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, false, ReduceOp::Min)
     }
 
-    /// Gathers the minimum value across the selected dimension. The resulting shape has the same
-    /// number of dimensions as the original tensor and the select dimension has a single element.
-    pub fn min_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, true, ReduceOp::Min)
+    /// Returns the indexes of the maximum values across the selected dimensions. When there are
+    /// multiple dimensions, the index is the position in the row-major flattening of these.
+    pub fn argmax_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, true, ReduceOp::ArgMax)
     }
 
-    /// Similar to `min_keepdim` but the target dimension is squeezed.
-    pub fn min<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, false, ReduceOp::Min)
+    /// Similar to `argmax_keepdim` but the target dimensions are squeezed.
+    pub fn argmax<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, false, ReduceOp::ArgMax)
     }
 
-    pub fn argmax_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, true, ReduceOp::ArgMax)
+    /// Returns the indexes of the minimum values across the selected dimensions. When there are
+    /// multiple dimensions, the index is the position in the row-major flattening of these.
+    pub fn argmin_keepdim<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, true, ReduceOp::ArgMin)
     }
 
-    /// Similar to `argmax_keepdim` but the target dimension is squeezed.
-    pub fn argmax<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, false, ReduceOp::ArgMax)
+    /// Similar to `argmin_keepdim` but the target dimensions are squeezed.
+    pub fn argmin<D: Dims>(&self, dims: D) -> Result<Self> {
+        self.reduce_impl(dims, false, ReduceOp::ArgMin)
     }
 
-    pub fn argmin_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, true, ReduceOp::ArgMin)
+    /// Sorts the tensor along its last dimension, in ascending order if `asc` is true and in
+    /// descending order otherwise. Returns the sorted values and a u32 tensor with the indexes of
+    /// these values in the last dimension. The sort is stable and NaNs come last.
+    pub fn sort_last_dim(&self, asc: bool) -> Result<(Self, Self)> {
+        if self.rank() == 0 {
+            crate::bail!("sort_last_dim requires at least one dimension")
+        }
+        let last_dim = self.rank() - 1;
+        let xs = self.contiguous()?;
+        let (values, indexes) = xs.storage().sort_last_dim(xs.layout(), asc)?;
+        let indexes = from_storage(indexes, xs.shape(), BackpropOp::none(), false);
+        // The values are gathered from the input, this is used for the gradient.
+        let op = BackpropOp::new2(&xs, &indexes, |t1, t2| Op::Gather(t1, t2, last_dim));
+        let values = from_storage(values, xs.shape(), op, false);
+        Ok((values, indexes))
+    }
+
+    /// Returns the u32 indexes that sort the tensor along its last dimension, see
+    /// [`Self::sort_last_dim`].
+    pub fn arg_sort_last_dim(&self, asc: bool) -> Result<Self> {
+        let (_, indexes) = self.sort_last_dim(asc)?;
+        Ok(indexes)
     }
 
-    /// Similar to `argmin_keepdim` but the target dimension is squeezed.
-    pub fn argmin<D: Dim>(&self, dim: D) -> Result<Self> {
-        self.reduce_impl(dim, false, ReduceOp::ArgMin)
+    /// Returns the `k` largest values along dimension `dim` in descending order, along with their
+    /// u32 indexes in this dimension.
+    pub fn topk<D: Dim>(&self, k: usize, dim: D) -> Result<(Self, Self)> {
+        let dim = dim.to_index(self.shape(), "topk")?;
+        let dim_size = self.dim(dim)?;
+        if k > dim_size {
+            crate::bail!("topk: k {k} is larger than the size {dim_size} of dim {dim}")
+        }
+        let last_dim = self.rank() - 1;
+        let (values, indexes) = self.transpose(dim, last_dim)?.sort_last_dim(false)?;
+        let values = values.narrow(last_dim, 0, k)?.transpose(dim, last_dim)?;
+        let indexes = indexes.narrow(last_dim, 0, k)?.transpose(dim, last_dim)?;
+        Ok((values, indexes))
     }
 
     /// Element-wise comparison between two tensors, e.g. equality, greater than, ... The actual
     /// comparison operation is specified by the `op` argument.
     ///
-    /// The returned tensor has the same shape as the original tensors and uses `u8` elements.
+    /// The returned tensor has the same shape as the original tensors and uses `bool` elements.
+    /// The cuda and metal backends do not support this dtype yet and return `u8` elements instead.
     pub fn cmp<T: TensorOrScalar>(&self, rhs: T, op: CmpOp) -> Result<Self> {
         let rhs = match rhs.to_tensor_scalar()? {
             crate::scalar::TensorScalar::Tensor(rhs) => rhs,
@@ -940,26 +1067,26 @@ impl Tensor {
         self.cmp(rhs, CmpOp::Ne)
     }
 
-    /// Element-wise comparison with lower-than, the returned tensor uses value 1 where `self <
-    /// rhs` and 0 otherwise.
+    /// Element-wise comparison with lower-than, the returned tensor is true where `self <
+    /// rhs` and false otherwise.
     pub fn lt<T: TensorOrScalar>(&self, rhs: T) -> Result<Self> {
         self.cmp(rhs, CmpOp::Lt)
     }
 
-    /// Element-wise comparison with greater-than, the returned tensor uses value 1 where `self >
-    /// rhs` and 0 otherwise.
+    /// Element-wise comparison with greater-than, the returned tensor is true where `self >
+    /// rhs` and false otherwise.
     pub fn gt<T: TensorOrScalar>(&self, rhs: T) -> Result<Self> {
         self.cmp(rhs, CmpOp::Gt)
     }
 
-    /// Element-wise comparison with greater-equal, the returned tensor uses value 1 where `self >=
-    /// rhs` and 0 otherwise.
+    /// Element-wise comparison with greater-equal, the returned tensor is true where `self >=
+    /// rhs` and false otherwise.
     pub fn ge<T: TensorOrScalar>(&self, rhs: T) -> Result<Self> {
         self.cmp(rhs, CmpOp::Ge)
     }
 
-    /// Element-wise comparison with lower-equal, the returned tensor uses value 1 where `self <=
-    /// rhs` and 0 otherwise.
+    /// Element-wise comparison with lower-equal, the returned tensor is true where `self <=
+    /// rhs` and false otherwise.
     pub fn le<T: TensorOrScalar>(&self, rhs: T) -> Result<Self> {
         self.cmp(rhs, CmpOp::Le)
     }
@@ -1076,6 +1203,124 @@ impl Tensor {
         Ok(from_storage(storage, (n, c, h_out, w_out), op, false))
     }
 
+    /// Interpolate the input tensor to the `(target_d, target_h, target_w)` size, taking the value
+    /// of the nearest element.
+    ///
+    /// The input tensor should have five dimensions, `(batch, channels, d, h, w)`, the returned
+    /// tensor also has five dimensions, `(batch, channels, target_d, target_h, target_w)`.
+    pub fn interpolate3d(&self, target_d: usize, target_h: usize, target_w: usize) -> Result<Self> {
+        let (n, c, _d, _h, _w) = self.dims5()?;
+        let op = BackpropOp::new1(self, Op::UpsampleNearest3D);
+        let storage =
+            self.storage()
+                .upsample_nearest3d(self.layout(), target_d, target_h, target_w)?;
+        Ok(from_storage(
+            storage,
+            (n, c, target_d, target_h, target_w),
+            op,
+            false,
+        ))
+    }
+
+    /// Alias for `interpolate3d`.
+    pub fn upsample_nearest3d(
+        &self,
+        target_d: usize,
+        target_h: usize,
+        target_w: usize,
+    ) -> Result<Self> {
+        self.interpolate3d(target_d, target_h, target_w)
+    }
+
+    // Returns the output dims of a 3d pooling, checking that the kernel fits in the input.
+    fn pool3d_dims(
+        &self,
+        kernel_size: (usize, usize, usize),
+        stride: (usize, usize, usize),
+        op: &'static str,
+    ) -> Result<(usize, usize, usize, usize, usize)> {
+        let (n, c, d, h, w) = self.dims5()?;
+        if kernel_size.0 > d || kernel_size.1 > h || kernel_size.2 > w {
+            bail!(
+                "{op}: kernel size {kernel_size:?} is larger than the input {:?}",
+                self.shape()
+            )
+        }
+        if stride.0 == 0 || stride.1 == 0 || stride.2 == 0 {
+            bail!("{op}: stride {stride:?} should be positive")
+        }
+        let d_out = (d - kernel_size.0) / stride.0 + 1;
+        let h_out = (h - kernel_size.1) / stride.1 + 1;
+        let w_out = (w - kernel_size.2) / stride.2 + 1;
+        Ok((n, c, d_out, h_out, w_out))
+    }
+
+    /// 3D average pooling over an input tensor with multiple channels.
+    ///
+    /// The input tensor should have five dimensions, `(batch, channels, d, h, w)`, the returned
+    /// tensor also has five dimensions, `(batch, channels, d', h', w')`. The pooling is performed
+    /// on the three last dimensions using a kernel of size `sz`. The returned element is the
+    /// average value over the kernel window.
+    pub fn avg_pool3d<T: crate::ToUsize3>(&self, sz: T) -> Result<Self> {
+        let sz = sz.to_usize3();
+        self.avg_pool3d_with_stride(sz, sz)
+    }
+
+    /// Same as `avg_pool3d` but with a `stride` that can be set to a value different from the
+    /// kernel size.
+    pub fn avg_pool3d_with_stride<T: crate::ToUsize3>(
+        &self,
+        kernel_size: T,
+        stride: T,
+    ) -> Result<Self> {
+        let kernel_size = kernel_size.to_usize3();
+        let stride = stride.to_usize3();
+        // https://pytorch.org/docs/stable/generated/torch.nn.AvgPool3d.html
+        let dims = self.pool3d_dims(kernel_size, stride, "avg-pool3d")?;
+        let op = BackpropOp::new1(self, |arg| Op::AvgPool3D {
+            arg,
+            kernel_size,
+            stride,
+        });
+        let storage = self
+            .storage()
+            .avg_pool3d(self.layout(), kernel_size, stride)?;
+        Ok(from_storage(storage, dims, op, false))
+    }
+
+    /// 3D max pooling over an input tensor with multiple channels.
+    ///
+    /// The input tensor should have five dimensions, `(batch, channels, d, h, w)`, the returned
+    /// tensor also has five dimensions, `(batch, channels, d', h', w')`. The pooling is performed
+    /// on the three last dimensions using a kernel of size `sz`, the returned element is the
+    /// maximum value over the kernel window.
+    pub fn max_pool3d<T: crate::ToUsize3>(&self, sz: T) -> Result<Self> {
+        let sz = sz.to_usize3();
+        self.max_pool3d_with_stride(sz, sz)
+    }
+
+    /// Same as `max_pool3d` but with a `stride` that can be set to a value different from the
+    /// kernel size.
+    pub fn max_pool3d_with_stride<T: crate::ToUsize3>(
+        &self,
+        kernel_size: T,
+        stride: T,
+    ) -> Result<Self> {
+        let kernel_size = kernel_size.to_usize3();
+        let stride = stride.to_usize3();
+        // https://pytorch.org/docs/stable/generated/torch.nn.MaxPool3d.html
+        let dims = self.pool3d_dims(kernel_size, stride, "max-pool3d")?;
+        let op = BackpropOp::new1(self, |arg| Op::MaxPool3D {
+            arg,
+            kernel_size,
+            stride,
+        });
+        let storage = self
+            .storage()
+            .max_pool3d(self.layout(), kernel_size, stride)?;
+        Ok(from_storage(storage, dims, op, false))
+    }
+
     /// Returns the matrix-multiplication of the input tensor with the other provided tensor.
     ///
     /// # Arguments
@@ -1148,6 +1393,63 @@ impl Tensor {
         }
     }
 
+    /// Matrix multiplication with a low precision `rhs`, e.g. float8 weights, that gets
+    /// dequantized on the fly.
+    ///
+    /// `self` has shape `(..., m, k)` and `rhs` has shape `(k, n)`. `scale` holds the
+    /// dequantization scales, either a single per-tensor value or a `(k_blocks, n_blocks)` grid
+    /// where each value applies to one block of `rhs`. `rhs` is dequantized to the dtype of
+    /// `self` one block of rows at a time so that the full dequantized matrix is never
+    /// materialized.
+    pub fn matmul_scaled(&self, rhs: &Self, scale: &Self) -> Result<Self> {
+        let (k, n) = rhs.dims2()?;
+        let (k_blocks, n_blocks) = match scale.dims() {
+            [] | [1] => (1, 1),
+            &[k_blocks, n_blocks] => (k_blocks, n_blocks),
+            _ => bail!("matmul_scaled: unexpected scale shape {:?}", scale.shape()),
+        };
+        if k_blocks == 0 || n_blocks == 0 || k_blocks > k || n_blocks > n {
+            bail!(
+                "matmul_scaled: scale shape {:?} does not match rhs {:?}",
+                scale.shape(),
+                rhs.shape()
+            )
+        }
+        let block_k = k.div_ceil(k_blocks);
+        let block_n = n.div_ceil(n_blocks);
+        if k.div_ceil(block_k) != k_blocks || n.div_ceil(block_n) != n_blocks {
+            bail!(
+                "matmul_scaled: scale shape {:?} does not match rhs {:?}",
+                scale.shape(),
+                rhs.shape()
+            )
+        }
+        let dtype = self.dtype();
+        // Expand the scales over the columns, the rows are handled one block at a time.
+        let scale = scale
+            .reshape((k_blocks, n_blocks, 1))?
+            .to_dtype(dtype)?
+            .broadcast_as((k_blocks, n_blocks, block_n))?
+            .reshape((k_blocks, n_blocks * block_n))?
+            .narrow(1, 0, n)?;
+        let block_matmul = |block_idx: usize| -> Result<Tensor> {
+            let start = block_idx * block_k;
+            let len = block_k.min(k - start);
+            let rhs = rhs
+                .narrow(0, start, len)?
+                .to_dtype(dtype)?
+                .broadcast_mul(&scale.narrow(0, block_idx, 1)?)?;
+            let lhs = self.narrow(self.rank() - 1, start, len)?.contiguous()?;
+            lhs.broadcast_matmul(&rhs)
+        };
+        // k_blocks is at least 1, the first block initializes the result.
+        let mut ys = block_matmul(0)?;
+        for block_idx in 1..k_blocks {
+            ys = (ys + block_matmul(block_idx)?)?
+        }
+        Ok(ys)
+    }
+
     /// Returns a tensor with the same shape as the input tensor, the values are taken from
     /// `on_true` if the input tensor value is not zero, and `on_false` at the positions where the
     /// input tensor is equal to zero.
@@ -1165,6 +1467,68 @@ impl Tensor {
         Ok(from_storage(storage, shape, op, false))
     }
 
+    /// Returns a tensor where the elements of `self` for which `mask` is non-zero are replaced
+    /// with `value`. The mask is broadcasted to the shape of `self`.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    /// let mask = Tensor::new(&[0u8, 1], &Device::Cpu)?;
+    /// let xs = xs.masked_fill(&mask, f64::NEG_INFINITY)?;
+    /// assert_eq!(xs.to_vec2::<f32>()?, &[[1., f32::NEG_INFINITY], [3., f32::NEG_INFINITY]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn masked_fill(&self, mask: &Self, value: f64) -> Result<Self> {
+        let mask = mask.broadcast_as(self.shape())?;
+        let storage =
+            self.storage()
+                .masked_fill(self.layout(), &mask.storage(), mask.layout(), value)?;
+        let op = BackpropOp::new2(self, &mask, Op::MaskedFill);
+        Ok(from_storage(storage, self.shape(), op, false))
+    }
+
+    /// Returns a 1d tensor with the elements of `self` for which `mask` is non-zero, in row-major
+    /// order. The mask is broadcasted to the shape of `self`. On cuda and metal, the op runs on
+    /// the cpu and the result is copied back to the device.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    /// let mask = xs.ge(2.5)?;
+    /// let xs = xs.masked_select(&mask)?;
+    /// assert_eq!(xs.to_vec1::<f32>()?, &[3., 4.]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn masked_select(&self, mask: &Self) -> Result<Self> {
+        let mask = mask.broadcast_as(self.shape())?;
+        let (storage, len) =
+            self.storage()
+                .masked_select(self.layout(), &mask.storage(), mask.layout())?;
+        let op = BackpropOp::new2(self, &mask, Op::MaskedSelect);
+        Ok(from_storage(storage, len, op, false))
+    }
+
+    /// Returns the coordinates of the non-zero elements of `self` as a u32 tensor of shape
+    /// `(n, rank)`, the coordinates are in row-major order. On cuda and metal, the op runs on the
+    /// cpu and the result is copied back to the device.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let xs = Tensor::new(&[[0f32, 2.], [3., 0.]], &Device::Cpu)?;
+    /// let ids = xs.nonzero()?;
+    /// assert_eq!(ids.to_vec2::<u32>()?, &[[0, 1], [1, 0]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn nonzero(&self) -> Result<Self> {
+        let (storage, len) = self.storage().nonzero(self.layout())?;
+        Ok(from_storage(
+            storage,
+            (len, self.rank()),
+            BackpropOp::none(),
+            false,
+        ))
+    }
+
     /// Returns a tensor with the values from the `self` tensor at the index corresponding to the
     /// values hold in the `ids` tensor.
     ///
@@ -1242,6 +1606,66 @@ impl Tensor {
         Ok(from_storage(storage, self.shape(), op, false))
     }
 
+    /// Writes the values from `source` into `self` at the positions given by `indexes` along
+    /// `dim`, this is the overwriting counterpart of [`Tensor::scatter_add`]. When an index
+    /// appears multiple times, the last value written wins. On cuda and metal, the op runs on
+    /// the cpu and the result is copied back to the device.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let xs = Tensor::zeros((2, 3), candle_core::DType::F32, &Device::Cpu)?;
+    /// let ids = Tensor::new(&[[2u32, 0], [1, 2]], &Device::Cpu)?;
+    /// let src = Tensor::new(&[[1f32, 2.], [3., 4.]], &Device::Cpu)?;
+    /// let xs = xs.scatter(&ids, &src, 1)?;
+    /// assert_eq!(xs.to_vec2::<f32>()?, &[[2., 0., 1.], [0., 3., 4.]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn scatter<D: Dim>(&self, indexes: &Self, source: &Self, dim: D) -> Result<Self> {
+        let dim = dim.to_index(self.shape(), "scatter")?;
+        let source_dims = source.dims();
+        let self_dims = self.dims();
+        let mismatch = if source_dims.len() != self_dims.len() {
+            true
+        } else {
+            let mut mismatch = false;
+            for (i, (&d1, &d2)) in self_dims.iter().zip(source_dims.iter()).enumerate() {
+                if i != dim && d1 != d2 {
+                    mismatch = true;
+                    break;
+                }
+            }
+            mismatch
+        };
+        if mismatch {
+            Err(Error::ShapeMismatchBinaryOp {
+                op: "scatter (self, src)",
+                lhs: self.shape().clone(),
+                rhs: source.shape().clone(),
+            }
+            .bt())?
+        }
+        if indexes.dims() != source.dims() {
+            Err(Error::ShapeMismatchBinaryOp {
+                op: "scatter (indexes, src)",
+                lhs: indexes.shape().clone(),
+                rhs: source.shape().clone(),
+            }
+            .bt())?
+        }
+        let storage = self.storage().scatter(
+            self.layout(),
+            &indexes.storage(),
+            indexes.layout(),
+            &source.storage(),
+            source.layout(),
+            dim,
+        )?;
+        let op = BackpropOp::new3(self, indexes, source, |t1, t2, t3| {
+            Op::Scatter(t1, t2, t3, dim)
+        });
+        Ok(from_storage(storage, self.shape(), op, false))
+    }
+
     /// Embeds the values of the `src` tensor into the `self` tensor on the specified dimension.
     pub fn slice_scatter<D: Dim>(&self, src: &Self, dim: D, start: usize) -> Result<Self> {
         let dim = dim.to_index(self.shape(), "slice-scatter")?;
@@ -1311,6 +1735,78 @@ impl Tensor {
         Ok(from_storage(storage, self.shape(), op, false))
     }
 
+    /// Writes the values of the `src` tensor into `self` on the specified dimension starting at
+    /// `offset`. This is an in-place version of [`Tensor::slice_scatter`]: the storage of `self`
+    /// is modified, so all the tensors sharing this storage will see the update. `self` has to
+    /// be contiguous and this operation is not tracked for backpropagation.
+    pub fn slice_set<D: Dim>(&self, src: &Self, dim: D, offset: usize) -> Result<()> {
+        let dim = dim.to_index(self.shape(), "slice-set")?;
+        if self.same_storage(src) {
+            crate::bail!("slice_set: src and dst must not use the same storage")
+        }
+        if self.dtype() != src.dtype() {
+            Err(Error::DTypeMismatchBinaryOp {
+                lhs: self.dtype(),
+                rhs: src.dtype(),
+                op: "slice-set",
+            }
+            .bt())?
+        }
+        if self.device().location() != src.device.location() {
+            Err(Error::DeviceMismatchBinaryOp {
+                lhs: self.device().location(),
+                rhs: src.device().location(),
+                op: "slice-set",
+            }
+            .bt())?
+        }
+        if self.rank() != src.rank() {
+            Err(Error::UnexpectedNumberOfDims {
+                expected: self.rank(),
+                got: src.rank(),
+                shape: src.shape().clone(),
+            }
+            .bt())?
+        }
+        let shape_ok =
+            self.dims()
+                .iter()
+                .zip(src.dims().iter())
+                .enumerate()
+                .all(|(dim_idx, (&d1, &d2))| {
+                    if dim == dim_idx {
+                        d2 + offset <= d1
+                    } else {
+                        d1 == d2
+                    }
+                });
+        if !shape_ok {
+            Err(Error::ShapeMismatchBinaryOp {
+                op: "slice-set (self, src)",
+                lhs: self.shape().clone(),
+                rhs: src.shape().clone(),
+            }
+            .bt())?
+        }
+        let src = src.contiguous()?;
+        let block_size: usize = src.dims()[dim + 1..].iter().product();
+        let outer_size: usize = src.dims()[..dim].iter().product();
+        let src_chunk = src.dims()[dim] * block_size;
+        let dst_chunk = self.dims()[dim] * block_size;
+        let (src_storage, src_l) = src.storage_and_layout();
+        let (mut dst_storage, dst_l) = self.storage_mut_and_layout();
+        if !dst_l.is_contiguous() {
+            crate::bail!("slice_set: dst has to be contiguous")
+        }
+        for i in 0..outer_size {
+            let src_chunk_l =
+                Layout::contiguous_with_offset(src_chunk, src_l.start_offset() + i * src_chunk);
+            let dst_offset = dst_l.start_offset() + i * dst_chunk + offset * block_size;
+            src_storage.copy_strided_src(&mut dst_storage, dst_offset, &src_chunk_l)?;
+        }
+        Ok(())
+    }
+
     /// Accumulate element from `source` at indexes `indexes` and add them to `self`.
     pub fn index_add<D: Dim>(&self, indexes: &Self, source: &Self, dim: D) -> Result<Self> {
         let dim = dim.to_index(self.shape(), "index-add")?;
@@ -1362,6 +1858,116 @@ impl Tensor {
         Ok(from_storage(storage, self.shape(), op, false))
     }
 
+    /// Copies the slices of `source` along `dim` into `self` at the positions given by the 1d
+    /// `indexes` tensor, this is the overwriting counterpart of [`Tensor::index_add`]. On cuda and
+    /// metal, the op runs on the cpu and the result is copied back to the device.
+    pub fn index_copy<D: Dim>(&self, indexes: &Self, source: &Self, dim: D) -> Result<Self> {
+        let dim = dim.to_index(self.shape(), "index-copy")?;
+        let source_dims = source.dims();
+        let self_dims = self.dims();
+        let mismatch = if source_dims.len() != self_dims.len() {
+            true
+        } else {
+            let mut mismatch = false;
+            for (i, (&d1, &d2)) in self_dims.iter().zip(source_dims.iter()).enumerate() {
+                if i != dim && d1 != d2 {
+                    mismatch = true;
+                    break;
+                }
+            }
+            mismatch
+        };
+        if mismatch {
+            Err(Error::ShapeMismatchBinaryOp {
+                op: "index-copy (self, source)",
+                lhs: self.shape().clone(),
+                rhs: source.shape().clone(),
+            }
+            .bt())?
+        }
+        let indexes_len = indexes.dims1()?;
+        if source_dims[dim] != indexes_len {
+            Err(Error::ShapeMismatchBinaryOp {
+                op: "index-copy (ids, source))",
+                lhs: indexes.shape().clone(),
+                rhs: source.shape().clone(),
+            }
+            .bt())?
+        }
+        let storage = self.storage().index_copy(
+            self.layout(),
+            &indexes.storage(),
+            indexes.layout(),
+            &source.storage(),
+            source.layout(),
+            dim,
+        )?;
+        let op = BackpropOp::new3(self, indexes, source, |t1, t2, t3| {
+            Op::IndexCopy(t1, t2, t3, dim)
+        });
+        Ok(from_storage(storage, self.shape(), op, false))
+    }
+
+    /// Writes `values` into `self` at the positions given by a list of integer index tensors,
+    /// similar to PyTorch's `index_put`.
+    ///
+    /// The k tensors in `indexes` index the first k dimensions of `self` and are broadcasted
+    /// together to some shape `s`, `values` is broadcasted to `s` followed by the remaining
+    /// dimensions of `self`. When `accumulate` is true the values are added to the existing
+    /// ones, otherwise they overwrite them.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let xs = Tensor::zeros((3, 3), candle_core::DType::F32, &Device::Cpu)?;
+    /// let rows = Tensor::new(&[0u32, 2], &Device::Cpu)?;
+    /// let cols = Tensor::new(&[1u32, 0], &Device::Cpu)?;
+    /// let values = Tensor::new(&[5f32, 7.], &Device::Cpu)?;
+    /// let xs = xs.index_put(&[&rows, &cols], &values, false)?;
+    /// assert_eq!(xs.to_vec2::<f32>()?, &[[0., 5., 0.], [0., 0., 0.], [7., 0., 0.]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn index_put(&self, indexes: &[&Self], values: &Self, accumulate: bool) -> Result<Self> {
+        let dims = self.dims();
+        let n_indexed = indexes.len();
+        if n_indexed == 0 || n_indexed > dims.len() {
+            bail!(
+                "index-put: expected between 1 and {} index tensors, got {n_indexed}",
+                dims.len()
+            )
+        }
+        let mut ids_shape = indexes[0].shape().clone();
+        for ids in indexes[1..].iter() {
+            ids_shape = ids_shape.broadcast_shape_binary_op(ids.shape(), "index-put")?;
+        }
+        // Compute the linear index over the first n_indexed dimensions.
+        let last = n_indexed - 1;
+        let mut linear_ids = indexes[last]
+            .to_dtype(DType::U32)?
+            .broadcast_as(&ids_shape)?;
+        let mut stride = dims[last];
+        for (ids, &dim) in indexes[..last].iter().zip(dims[..last].iter()).rev() {
+            let ids = ids.to_dtype(DType::U32)?.broadcast_as(&ids_shape)?;
+            linear_ids = (linear_ids + ids.affine(stride as f64, 0.)?)?;
+            stride *= dim;
+        }
+        let linear_ids = linear_ids.flatten_all()?;
+        let rest = &dims[n_indexed..];
+        let mut values_dims = ids_shape.dims().to_vec();
+        values_dims.extend_from_slice(rest);
+        let mut flat_dims = vec![linear_ids.elem_count()];
+        flat_dims.extend_from_slice(rest);
+        let values = values.broadcast_as(values_dims)?.reshape(flat_dims)?;
+        let mut self_dims = vec![stride];
+        self_dims.extend_from_slice(rest);
+        let xs = self.reshape(self_dims)?;
+        let xs = if accumulate {
+            xs.index_add(&linear_ids, &values, 0)?
+        } else {
+            xs.index_copy(&linear_ids, &values, 0)?
+        };
+        xs.reshape(self.shape())
+    }
+
     /// Gather values across the target dimension.
     ///
     /// # Arguments
@@ -1850,6 +2456,20 @@ impl Tensor {
         }
     }
 
+    /// Returns a new tensor sharing the storage of this tensor but with a different op.
+    pub(crate) fn with_op(&self, op: BackpropOp) -> Tensor {
+        let tensor_ = Tensor_ {
+            id: TensorId::new(),
+            storage: self.storage.clone(),
+            layout: self.layout.clone(),
+            op,
+            is_variable: false,
+            dtype: self.dtype,
+            device: self.device.clone(),
+        };
+        Tensor(Arc::new(tensor_))
+    }
+
     /// If the target device is the same as the tensor device, only a shallow copy is performed.
     pub fn to_device(&self, device: &Device) -> Result<Tensor> {
         if self.device().same_device(device) {
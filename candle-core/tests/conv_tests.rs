@@ -595,3 +595,126 @@ test_device!(
     conv2d_grad_gpu,
     conv2_grad_metal
 );
+
+// A naive 3d convolution used as a reference, the kernel has shape (c_out, c_in, k_d, k_h, k_w).
+fn conv3d_naive(
+    t: &Tensor,
+    w: &Tensor,
+    padding: usize,
+    stride: usize,
+    dilation: usize,
+) -> Result<Tensor> {
+    let (b_sz, c_in, i_d, i_h, i_w) = t.dims5()?;
+    let (c_out, _, k_d, k_h, k_w) = w.dims5()?;
+    let out = |i: usize, k: usize| (i + 2 * padding - dilation * (k - 1) - 1) / stride + 1;
+    let (o_d, o_h, o_w) = (out(i_d, k_d), out(i_h, k_h), out(i_w, k_w));
+    let t = t.flatten_all()?.to_vec1::<f64>()?;
+    let w = w.flatten_all()?.to_vec1::<f64>()?;
+    let mut dst = vec![0f64; b_sz * c_out * o_d * o_h * o_w];
+    let src_pos = |o: usize, k: usize, size: usize| {
+        let pos = (o * stride + k * dilation) as i64 - padding as i64;
+        (pos >= 0 && pos < size as i64).then_some(pos as usize)
+    };
+    for (dst_idx, dst) in dst.iter_mut().enumerate() {
+        let x = dst_idx % o_w;
+        let y = dst_idx / o_w % o_h;
+        let z = dst_idx / (o_w * o_h) % o_d;
+        let c_o = dst_idx / (o_w * o_h * o_d) % c_out;
+        let b = dst_idx / (o_w * o_h * o_d * c_out);
+        for c_i in 0..c_in {
+            for k_z in 0..k_d {
+                for k_y in 0..k_h {
+                    for k_x in 0..k_w {
+                        let (Some(s_z), Some(s_y), Some(s_x)) = (
+                            src_pos(z, k_z, i_d),
+                            src_pos(y, k_y, i_h),
+                            src_pos(x, k_x, i_w),
+                        ) else {
+                            continue;
+                        };
+                        let t_idx = (((b * c_in + c_i) * i_d + s_z) * i_h + s_y) * i_w + s_x;
+                        let w_idx = (((c_o * c_in + c_i) * k_d + k_z) * k_h + k_y) * k_w + k_x;
+                        *dst += t[t_idx] * w[w_idx]
+                    }
+                }
+            }
+        }
+    }
+    Ok(Tensor::from_vec(
+        dst,
+        (b_sz, c_out, o_d, o_h, o_w),
+        &Device::Cpu,
+    )?)
+}
+
+fn max_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f64> {
+    Ok((lhs - rhs)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f64>()?)
+}
+
+#[test]
+fn conv3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::randn(0f64, 1., (2, 4, 5, 6, 7), dev)?;
+    let w = Tensor::randn(0f64, 1., (3, 4, 3, 2, 3), dev)?;
+    for (padding, stride, dilation) in [(0, 1, 1), (1, 1, 1), (1, 2, 1), (2, 1, 2), (1, 2, 2)] {
+        let res = t.conv3d(&w, padding, stride, dilation, 1)?;
+        let expected = conv3d_naive(&t, &w, padding, stride, dilation)?;
+        assert_eq!(res.dims(), expected.dims());
+        assert!(max_diff(&res, &expected)? < 1e-10);
+    }
+    // A non-contiguous kernel.
+    let w_t = Tensor::randn(0f64, 1., (4, 3, 3, 2, 3), dev)?;
+    let res = t.conv3d(&w_t.transpose(0, 1)?, 1, 1, 1, 1)?;
+    let expected = conv3d_naive(&t, &w_t.transpose(0, 1)?.contiguous()?, 1, 1, 1)?;
+    assert!(max_diff(&res, &expected)? < 1e-10);
+
+    // Grouped convolution.
+    let w = Tensor::randn(0f64, 1., (6, 2, 3, 3, 3), dev)?;
+    let res = t.conv3d(&w, 1, 1, 1, 2)?;
+    assert_eq!(res.dims(), [2, 6, 5, 6, 7]);
+    for g in 0..2 {
+        let expected = conv3d_naive(&t.narrow(1, 2 * g, 2)?, &w.narrow(0, 3 * g, 3)?, 1, 1, 1)?;
+        assert!(max_diff(&res.narrow(1, 3 * g, 3)?, &expected)? < 1e-10);
+    }
+
+    // With a single depth element, this matches conv2d.
+    let t = Tensor::randn(0f32, 1., (1, 4, 1, 5, 5), dev)?;
+    let w = Tensor::randn(0f32, 1., (2, 4, 1, 3, 3), dev)?;
+    let res = t.conv3d(&w, 0, 1, 1, 1)?.squeeze(2)?;
+    let expected = t.squeeze(2)?.conv2d(&w.squeeze(2)?, 0, 1, 1, 1)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&res.i(0)?, 4)?,
+        test_utils::to_vec3_round(&expected.i(0)?, 4)?
+    );
+    assert!(t.conv3d(&w.narrow(1, 0, 3)?, 0, 1, 1, 1).is_err());
+    Ok(())
+}
+
+#[test]
+fn conv_transpose3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    // The transposed convolution is the adjoint of the convolution using the same kernel:
+    // <conv3d(x, w), y> = <x, conv_transpose3d(y, w)>.
+    let w = Tensor::randn(0f64, 1., (3, 2, 3, 2, 3), dev)?;
+    for (padding, stride, dilation) in [(0, 1, 1), (1, 2, 1), (1, 1, 2), (2, 2, 2)] {
+        // The elements dropped by the stride are recovered via the output padding, the height
+        // and width are picked so that this padding is the same as for the depth.
+        let rem = |i: usize, k: usize| (i + 2 * padding - dilation * (k - 1) - 1) % stride;
+        let out_padding = rem(6, 3);
+        let h = (5..).find(|&h| rem(h, 2) == out_padding).unwrap();
+        let w_ = (5..).find(|&w| rem(w, 3) == out_padding).unwrap();
+        let x = Tensor::randn(0f64, 1., (2, 2, 6, h, w_), dev)?;
+        let conv = x.conv3d(&w, padding, stride, dilation, 1)?;
+        let y = Tensor::randn(0f64, 1., conv.shape(), dev)?;
+        let conv_t = y.conv_transpose3d(&w, padding, out_padding, stride, dilation)?;
+        assert_eq!(conv_t.dims(), x.dims());
+        let lhs = (conv * &y)?.sum_all()?.to_scalar::<f64>()?;
+        let rhs = (x * conv_t)?.sum_all()?.to_scalar::<f64>()?;
+        assert!((lhs - rhs).abs() < 1e-8, "{lhs} {rhs}");
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use candle_core::{test_device, Device, IndexOp, Result, Tensor, Var};
+
+fn max_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f32> {
+    assert_eq!(lhs.dims(), rhs.dims());
+    (lhs - rhs)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()
+}
+
+fn einsum_contractions(dev: &Device) -> Result<()> {
+    let a = Tensor::randn(0f32, 1., (2, 3, 4, 5), dev)?;
+    let b = Tensor::randn(0f32, 1., (2, 3, 6, 5), dev)?;
+
+    // Attention scores.
+    let s = Tensor::einsum("bhqd,bhkd->bhqk", &[&a, &b])?;
+    let expected = a.matmul(&b.t()?)?;
+    assert!(max_diff(&s, &expected)? < 1e-5);
+
+    // Same with spaces, an output permutation and the implicit form.
+    let s = Tensor::einsum("bhqd, bhkd -> hkbq", &[&a, &b])?;
+    assert!(max_diff(&s, &expected.permute((1, 3, 0, 2))?)? < 1e-5);
+    let m = a.i((0, 0))?;
+    let n = b.i((0, 0))?;
+    // The implicit output labels are sorted.
+    let s = Tensor::einsum("qd,kd", &[&m, &n])?;
+    assert!(max_diff(&s, &n.matmul(&m.t()?)?)? < 1e-5);
+
+    // Sums, outer products and multiple operands.
+    let s = Tensor::einsum("bhqd->hd", &[&a])?;
+    assert!(max_diff(&s, &a.sum((0, 2))?)? < 1e-5);
+    let u = Tensor::arange(1f32, 4., dev)?;
+    let v = Tensor::arange(1f32, 3., dev)?;
+    let s = Tensor::einsum("i,j->ij", &[&u, &v])?;
+    assert_eq!(s.to_vec2::<f32>()?, [[1., 2.], [2., 4.], [3., 6.]]);
+    let s = Tensor::einsum("i,i->", &[&u, &u])?;
+    assert_eq!(s.to_vec0::<f32>()?, 14.);
+    let w = Tensor::randn(0f32, 1., (5, 7), dev)?;
+    let s = Tensor::einsum("bhqd,bhkd,de->bhqe", &[&a, &a, &w])?;
+    let expected = a.broadcast_mul(&a.sum_keepdim(2)?)?.broadcast_matmul(&w)?;
+    assert!(max_diff(&s, &expected)? < 1e-4);
+    Ok(())
+}
+
+fn einsum_ellipsis(dev: &Device) -> Result<()> {
+    let a = Tensor::randn(0f32, 1., (2, 3, 4, 5), dev)?;
+    let b = Tensor::randn(0f32, 1., (3, 5, 6), dev)?;
+
+    // The ellipsis dims are broadcast and right-aligned.
+    let s = Tensor::einsum("...ij,...jk->...ik", &[&a, &b])?;
+    assert!(max_diff(&s, &a.broadcast_matmul(&b)?)? < 1e-5);
+    let s = Tensor::einsum("...ij,...jk", &[&a, &b])?;
+    assert!(max_diff(&s, &a.broadcast_matmul(&b)?)? < 1e-5);
+    let c = Tensor::randn(0f32, 1., (1, 5, 6), dev)?;
+    let s = Tensor::einsum("...ij,...jk->...ik", &[&a, &c])?;
+    assert!(max_diff(&s, &a.broadcast_matmul(&c)?)? < 1e-5);
+
+    // Ellipsis in the middle, summing over the ellipsis dims.
+    let s = Tensor::einsum("i...j->ji", &[&a])?;
+    assert!(max_diff(&s, &a.sum((1, 2))?.t()?)? < 1e-5);
+    let s = Tensor::einsum("...->", &[&a])?;
+    assert!(max_diff(&s, &a.sum_all()?)? < 1e-3);
+    Ok(())
+}
+
+fn einsum_diagonal(dev: &Device) -> Result<()> {
+    let a = Tensor::arange(0f32, 9., dev)?.reshape((3, 3))?;
+    assert_eq!(Tensor::einsum("ii", &[&a])?.to_vec0::<f32>()?, 12.);
+    assert_eq!(
+        Tensor::einsum("ii->i", &[&a])?.to_vec1::<f32>()?,
+        [0., 4., 8.]
+    );
+    let b = Tensor::arange(0f32, 18., dev)?.reshape((2, 3, 3))?;
+    assert_eq!(
+        Tensor::einsum("bii->bi", &[&b])?.to_vec2::<f32>()?,
+        [[0., 4., 8.], [9., 13., 17.]]
+    );
+    assert_eq!(
+        Tensor::einsum("iji->j", &[&b.reshape((3, 2, 3))?])?.to_vec1::<f32>()?,
+        [21., 30.]
+    );
+    let c = Tensor::arange(0f32, 8., dev)?.reshape((2, 2, 2))?;
+    assert_eq!(Tensor::einsum("iii", &[&c])?.to_vec0::<f32>()?, 7.);
+    // A diagonal combined with a contraction.
+    let s = Tensor::einsum("ii,ij->j", &[&a, &a])?;
+    assert_eq!(s.to_vec1::<f32>()?, [60., 72., 84.]);
+    Ok(())
+}
+
+#[test]
+fn einsum_errors() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Tensor::zeros((2, 3), candle_core::DType::F32, dev)?;
+    assert!(Tensor::einsum("ij,jk", &[&a]).is_err());
+    assert!(Tensor::einsum("ijk", &[&a]).is_err());
+    assert!(Tensor::einsum("ij->k", &[&a]).is_err());
+    assert!(Tensor::einsum("ij->ii", &[&a]).is_err());
+    assert!(Tensor::einsum("ii", &[&a]).is_err());
+    assert!(Tensor::einsum("i1", &[&a]).is_err());
+    assert!(Tensor::einsum("ij,ij", &[&a, &a.t()?]).is_err());
+    assert!(Tensor::einsum("......", &[&a]).is_err());
+    let b = Tensor::zeros((3, 3), candle_core::DType::F32, dev)?;
+    assert!(Tensor::einsum("...i,...i", &[&a, &b]).is_err());
+    Ok(())
+}
+
+#[test]
+fn einsum_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Var::new(&[[1f32, 2.], [3., 4.]], dev)?;
+    let b = Var::new(&[[1f32, 0.], [2., -1.], [0.5, 3.]], dev)?;
+    let s = Tensor::einsum("ij,kj,ll->", &[&a, &b, &a])?;
+    let grads = s.backward()?;
+    // s = tr(a) * sum_ij a_ij * (sum_k b_kj)
+    let bs = [3.5f32, 2.];
+    let dot = 1. * bs[0] + 2. * bs[1] + 3. * bs[0] + 4. * bs[1];
+    assert_eq!(s.to_vec0::<f32>()?, 5. * dot);
+    assert_eq!(
+        grads.get(&a).unwrap().to_vec2::<f32>()?,
+        [
+            [5. * bs[0] + dot, 5. * bs[1]],
+            [5. * bs[0], 5. * bs[1] + dot]
+        ]
+    );
+    assert_eq!(
+        grads.get(&b).unwrap().to_vec2::<f32>()?,
+        [[5. * 4., 5. * 6.], [5. * 4., 5. * 6.], [5. * 4., 5. * 6.]]
+    );
+    Ok(())
+}
+
+test_device!(
+    einsum_contractions,
+    einsum_contractions_cpu,
+    einsum_contractions_gpu,
+    einsum_contractions_metal
+);
+test_device!(
+    einsum_ellipsis,
+    einsum_ellipsis_cpu,
+    einsum_ellipsis_gpu,
+    einsum_ellipsis_metal
+);
+test_device!(
+    einsum_diagonal,
+    einsum_diagonal_cpu,
+    einsum_diagonal_gpu,
+    einsum_diagonal_metal
+);
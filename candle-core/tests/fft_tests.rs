@@ -0,0 +1,121 @@
+use candle_core::{test_utils, DType, Device, Result, Tensor, D};
+
+// A naive complex dft used as a reference, the input is interleaved (re, im).
+fn naive_dft(xs: &[f64], inverse: bool) -> Vec<f64> {
+    let n = xs.len() / 2;
+    let sign = if inverse { 1. } else { -1. };
+    let mut out = Vec::with_capacity(2 * n);
+    for k in 0..n {
+        let (mut re, mut im) = (0., 0.);
+        for j in 0..n {
+            let theta = sign * 2. * std::f64::consts::PI * (j * k) as f64 / n as f64;
+            re += xs[2 * j] * theta.cos() - xs[2 * j + 1] * theta.sin();
+            im += xs[2 * j] * theta.sin() + xs[2 * j + 1] * theta.cos();
+        }
+        out.push(re);
+        out.push(im);
+    }
+    out
+}
+
+fn max_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f64> {
+    (lhs - rhs)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_dtype(DType::F64)?
+        .to_scalar::<f64>()
+}
+
+#[test]
+fn fft() -> Result<()> {
+    let dev = &Device::Cpu;
+    for n in [1, 5, 8, 12, 1000] {
+        let xs = Tensor::randn(0f64, 1., (3, n, 2), dev)?;
+        let ys = xs.fft()?;
+        assert_eq!(ys.dims(), [3, n, 2]);
+        for b in 0..3 {
+            let inp = xs.get(b)?.flatten_all()?.to_vec1::<f64>()?;
+            let expected = Tensor::from_vec(naive_dft(&inp, false), (n, 2), dev)?;
+            assert!(max_diff(&ys.get(b)?, &expected)? < 1e-8);
+        }
+        let round_trip = ys.ifft()?;
+        assert!(max_diff(&round_trip, &xs)? < 1e-10);
+    }
+    Ok(())
+}
+
+#[test]
+fn rfft() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[1f32, 2., 3., 4.], dev)?;
+    let ys = xs.rfft()?;
+    assert_eq!(
+        test_utils::to_vec2_round(&ys, 4)?,
+        [[10.0, 0.0], [-2.0, 2.0], [-2.0, 0.0]]
+    );
+    assert_eq!(
+        test_utils::to_vec1_round(&ys.irfft(4)?, 4)?,
+        [1.0, 2.0, 3.0, 4.0]
+    );
+
+    for n in [6, 7] {
+        let xs = Tensor::randn(0f64, 1., (2, 3, n), dev)?;
+        let ys = xs.rfft()?;
+        assert_eq!(ys.dims(), [2, 3, n / 2 + 1, 2]);
+        let zeros = xs.zeros_like()?;
+        let full = Tensor::stack(&[&xs, &zeros], D::Minus1)?.fft()?;
+        assert!(max_diff(&ys, &full.narrow(2, 0, n / 2 + 1)?)? < 1e-10);
+        assert!(max_diff(&ys.irfft(n)?, &xs)? < 1e-10);
+    }
+    assert!(xs.irfft(5).is_err());
+    assert!(Tensor::new(&[1u32, 2], dev)?.rfft().is_err());
+    Ok(())
+}
+
+#[test]
+fn fft_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    // By Parseval's theorem, the energy of the fft is n times the energy of the signal.
+    let xs = candle_core::Var::randn(0f64, 1., (2, 8, 2), dev)?;
+    let energy = xs.fft()?.sqr()?.sum_all()?;
+    let grads = energy.backward()?;
+    let grad = grads.get(&xs).unwrap();
+    assert!(max_diff(grad, &(xs.as_tensor() * 16.)?)? < 1e-10);
+
+    let xs = candle_core::Var::randn(0f64, 1., (3, 9), dev)?;
+    let ys = xs.rfft()?.irfft(9)?;
+    let grads = ys.sum_all()?.backward()?;
+    let grad = grads.get(&xs).unwrap();
+    assert!(max_diff(grad, &xs.ones_like()?)? < 1e-10);
+    Ok(())
+}
+
+#[test]
+fn stft() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f64, 1., (2, 100), dev)?;
+    let window = Tensor::hann_window(16, DType::F64, dev)?;
+
+    let spec = xs.stft(16, 4, Some(&window), false)?;
+    assert_eq!(spec.dims(), [2, 9, 22, 2]);
+    // The third frame starts at sample 8.
+    let frame = xs.narrow(1, 8, 16)?.broadcast_mul(&window)?.rfft()?;
+    assert!(max_diff(&spec.narrow(2, 2, 1)?.squeeze(2)?, &frame)? < 1e-10);
+
+    let spec = xs.stft(16, 4, Some(&window), true)?;
+    assert_eq!(spec.dims(), [2, 9, 26, 2]);
+    let ys = spec.istft(16, 4, Some(&window), true, Some(100))?;
+    assert_eq!(ys.dims(), [2, 100]);
+    assert!(max_diff(&ys, &xs)? < 1e-10);
+
+    let spec = xs.stft(10, 5, None, false)?;
+    assert_eq!(spec.dims(), [2, 6, 19, 2]);
+    let ys = spec.istft(10, 5, None, false, None)?;
+    assert!(max_diff(&ys, &xs)? < 1e-10);
+
+    let empty = Tensor::zeros((2, 6, 0, 2), DType::F64, dev)?;
+    let err = empty.istft(10, 5, None, false, None).unwrap_err();
+    assert!(err.to_string().contains("at least one frame"), "{err}");
+    Ok(())
+}
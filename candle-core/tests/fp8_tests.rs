@@ -0,0 +1,148 @@
+use candle_core::{DType, Device, Result, Tensor, F8E4M3, F8E5M2};
+
+#[test]
+fn fp8_conversions() {
+    assert_eq!(F8E4M3::from_f32(1.).to_bits(), 0x38);
+    assert_eq!(F8E4M3::from_f32(-2.).to_bits(), 0xc0);
+    assert_eq!(F8E4M3::from_f32(448.).to_bits(), 0x7e);
+    // Out of range values saturate.
+    assert_eq!(F8E4M3::from_f32(1000.).to_f32(), 448.);
+    assert_eq!(F8E4M3::from_f32(f32::NEG_INFINITY).to_f32(), -448.);
+    // The smallest subnormal value and the rounding to even around it.
+    assert_eq!(F8E4M3::from_f32(2f32.powi(-9)).to_bits(), 0x01);
+    assert_eq!(F8E4M3::from_f32(2f32.powi(-10)).to_bits(), 0x00);
+    assert_eq!(F8E4M3::from_f32(1.0625).to_f32(), 1.);
+    assert_eq!(F8E4M3::from_f32(1.1875).to_f32(), 1.25);
+    assert!(F8E4M3::from_f32(f32::NAN).to_f32().is_nan());
+
+    assert_eq!(F8E5M2::from_f32(1.).to_bits(), 0x3c);
+    assert_eq!(F8E5M2::from_f32(57344.).to_bits(), 0x7b);
+    assert_eq!(F8E5M2::from_f32(1e6).to_f32(), f32::INFINITY);
+    assert_eq!(F8E5M2::from_f32(2f32.powi(-16)).to_bits(), 0x01);
+    assert!(F8E5M2::from_f32(f32::NAN).to_f32().is_nan());
+
+    // All the non-NaN values round trip.
+    for bits in 0..=255u8 {
+        let v = F8E4M3::from_bits(bits).to_f32();
+        if !v.is_nan() {
+            assert_eq!(F8E4M3::from_f32(v).to_f32(), v, "{bits}");
+        }
+        let v = F8E5M2::from_bits(bits).to_f32();
+        if !v.is_nan() {
+            assert_eq!(F8E5M2::from_f32(v).to_f32(), v, "{bits}");
+        }
+    }
+}
+
+#[test]
+fn fp8_tensors() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[0.5f32, -3., 448., 1000.], dev)?;
+    let t8 = t.to_dtype(DType::F8E4M3)?;
+    assert_eq!(t8.dtype(), DType::F8E4M3);
+    assert_eq!(
+        t8.to_dtype(DType::F32)?.to_vec1::<f32>()?,
+        [0.5, -3., 448., 448.]
+    );
+    assert_eq!(
+        t.to_dtype(DType::F8E5M2)?
+            .to_dtype(DType::BF16)?
+            .to_dtype(DType::F32)?
+            .to_vec1::<f32>()?,
+        [0.5, -3., 448., 1024.]
+    );
+    assert!(t8.exp().is_err());
+    assert_eq!(
+        format!("{t8}").lines().last().unwrap(),
+        "Tensor[[4], f8e4m3]"
+    );
+    Ok(())
+}
+
+#[test]
+fn fp8_safetensors() -> Result<()> {
+    // A safetensors file with float8 dtypes, written by hand.
+    let header = r#"{"w":{"dtype":"F8_E4M3","shape":[2,2],"data_offsets":[0,4]},"w_scale":{"dtype":"F32","shape":[],"data_offsets":[4,8]},"v":{"dtype":"F8_E5M2","shape":[2],"data_offsets":[8,10]}}"#;
+    let mut data = (header.len() as u64).to_le_bytes().to_vec();
+    data.extend_from_slice(header.as_bytes());
+    for v in [1f32, -2., 0.5, 448.] {
+        data.push(F8E4M3::from_f32(v).to_bits())
+    }
+    data.extend_from_slice(&0.25f32.to_le_bytes());
+    data.extend_from_slice(&[F8E5M2::from_f32(3.).to_bits(), 0x7c]);
+
+    let tensors = candle_core::safetensors::load_buffer(&data, &Device::Cpu)?;
+    let w = &tensors["w"];
+    assert_eq!(w.dtype(), DType::F8E4M3);
+    assert_eq!(
+        w.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [[1., -2.], [0.5, 448.]]
+    );
+    assert_eq!(tensors["w_scale"].to_scalar::<f32>()?, 0.25);
+    let v = &tensors["v"];
+    assert_eq!(v.dtype(), DType::F8E5M2);
+    assert_eq!(
+        v.to_dtype(DType::F32)?.to_vec1::<f32>()?,
+        [3., f32::INFINITY]
+    );
+
+    let path = std::env::temp_dir().join("candle_fp8.safetensors");
+    std::fs::write(&path, &data)?;
+    let st = unsafe { candle_core::safetensors::MmapedSafetensors::new(&path)? };
+    let w = st.load("w", &Device::Cpu)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(w.dtype(), DType::F8E4M3);
+    assert_eq!(
+        w.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [[1., -2.], [0.5, 448.]]
+    );
+
+    // The float8 dtypes are preserved when saving.
+    let path = std::env::temp_dir().join("candle_fp8_save.safetensors");
+    w.save_safetensors("w", &path)?;
+    let tensors = candle_core::safetensors::load(&path, &Device::Cpu)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(tensors["w"].dtype(), DType::F8E4M3);
+    assert_eq!(
+        tensors["w"].to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [[1., -2.], [0.5, 448.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn matmul_scaled() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (2, 3, 10), dev)?;
+    let w = Tensor::randn(0f32, 1., (10, 6), dev)?.to_dtype(DType::F8E4M3)?;
+    let w_f32 = w.to_dtype(DType::F32)?;
+
+    let scale = Tensor::new(0.5f32, dev)?;
+    let ys = xs.matmul_scaled(&w, &scale)?;
+    let expected = xs.broadcast_matmul(&(&w_f32 * 0.5)?)?;
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+
+    // Blocks of 4 rows and 3 columns, the last row block is partial.
+    let scale = Tensor::new(&[[1f32, 2.], [0.5, 4.], [3., 0.25]], dev)?;
+    let ys = xs.matmul_scaled(&w, &scale)?;
+    let full_scale: Vec<f32> = (0..10)
+        .flat_map(|i| {
+            let scale = &scale;
+            (0..6).map(move |j| {
+                let row = scale.get(i / 4).unwrap();
+                row.get(j / 3).unwrap().to_scalar::<f32>().unwrap()
+            })
+        })
+        .collect();
+    let full_scale = Tensor::from_vec(full_scale, (10, 6), dev)?;
+    let expected = xs.broadcast_matmul(&(&w_f32 * full_scale)?)?;
+    assert_eq!(ys.dims(), [2, 3, 6]);
+    let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-4);
+
+    // Four column blocks cannot evenly cover six columns.
+    let scale = Tensor::ones((3, 4), DType::F32, dev)?;
+    assert!(xs.matmul_scaled(&w, &scale).is_err());
+    Ok(())
+}
@@ -1,5 +1,8 @@
 use anyhow::{Context, Result};
-use candle_core::{test_device, test_utils, Device, Shape, Tensor, Var};
+use candle_core::{
+    test_device, test_utils, Device, GridSampleMode, GridSamplePadding, IndexOp, NormOrd, PadMode,
+    Shape, Tensor, Var,
+};
 
 fn simple_grad(device: &Device) -> Result<()> {
     let x = Var::new(&[3f32, 1., 4.], device)?;
@@ -315,6 +318,465 @@ fn binary_grad(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn topk_grad(device: &Device) -> Result<()> {
+    let x = Var::new(&[[3f32, 1., 4., 1.], [5., 9., 2., 6.]], device)?;
+    let x = x.as_tensor();
+    let (values, indexes) = x.topk(2, 1)?;
+    assert_eq!(values.to_vec2::<f32>()?, [[4., 3.], [9., 6.]]);
+    assert_eq!(indexes.to_vec2::<u32>()?, [[2, 0], [1, 3]]);
+    let y = (values * &Tensor::new(&[[1f32, 2.], [3., 4.]], device)?)?;
+    let grads = y.sum_all()?.backward()?;
+    let grad_x = grads.get(x).context("no grad for x")?;
+    assert_eq!(
+        grad_x.to_vec2::<f32>()?,
+        [[2., 0., 1., 0.], [0., 3., 0., 4.]]
+    );
+
+    // Sorting along the first dimension.
+    let (values, _) = x.topk(1, 0)?;
+    let grads = values.sqr()?.sum_all()?.backward()?;
+    let grad_x = grads.get(x).context("no grad for x")?;
+    assert_eq!(values.to_vec2::<f32>()?, [[5., 9., 4., 6.]]);
+    assert_eq!(
+        grad_x.to_vec2::<f32>()?,
+        [[0., 0., 8., 0.], [10., 18., 0., 12.]]
+    );
+    Ok(())
+}
+
+fn pad_grad(device: &Device) -> Result<()> {
+    let x = Var::new(&[1f32, 2., 3.], device)?;
+    let x = x.as_tensor();
+    let weights = Tensor::new(&[1f32, 2., 3., 4., 5., 6., 7.], device)?;
+    let grad = |mode| -> Result<Vec<f32>> {
+        let y = (x.pad(&[(2, 2)], mode)? * &weights)?;
+        let grads = y.sum_all()?.backward()?;
+        Ok(grads.get(x).context("no grad for x")?.to_vec1::<f32>()?)
+    };
+    assert_eq!(grad(PadMode::Constant(5.))?, [3., 4., 5.]);
+    // [3, 2, 1, 2, 3, 2, 1]
+    assert_eq!(grad(PadMode::Reflect)?, [10., 12., 6.]);
+    // [1, 1, 1, 2, 3, 3, 3]
+    assert_eq!(grad(PadMode::Replicate)?, [6., 4., 18.]);
+    // [2, 3, 1, 2, 3, 1, 2]
+    assert_eq!(grad(PadMode::Circular)?, [9., 12., 7.]);
+    Ok(())
+}
+
+// Compares the gradient of `f` at `x` with central finite differences, `f` should return a
+// scalar.
+fn check_grad(x: &Tensor, f: impl Fn(&Tensor) -> Result<Tensor>) -> Result<()> {
+    let var = Var::from_tensor(x)?;
+    let grads = f(var.as_tensor())?.backward()?;
+    let grad = grads.get(&var).context("no grad for x")?;
+    let grad = grad.flatten_all()?.to_vec1::<f64>()?;
+    let xs = x.flatten_all()?.to_vec1::<f64>()?;
+    let eps = 1e-6;
+    for i in 0..xs.len() {
+        let mut plus = xs.clone();
+        plus[i] += eps;
+        let mut minus = xs.clone();
+        minus[i] -= eps;
+        let plus = f(&Tensor::from_vec(plus, x.shape(), x.device())?)?.to_scalar::<f64>()?;
+        let minus = f(&Tensor::from_vec(minus, x.shape(), x.device())?)?.to_scalar::<f64>()?;
+        let expected = (plus - minus) / (2. * eps);
+        assert!(
+            (grad[i] - expected).abs() < 1e-5,
+            "{i} {} {expected}",
+            grad[i]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn masking_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::new(&[[0.5f64, -1.2, 2.], [1.5, -0.3, 0.7]], dev)?;
+    let mask = Tensor::new(&[[1u8, 0, 0], [0, 1, 1]], dev)?;
+    check_grad(&x, |x| Ok(x.masked_fill(&mask, 3.)?.sqr()?.sum_all()?))?;
+    check_grad(&x, |x| Ok(x.masked_select(&mask)?.exp()?.sum_all()?))?;
+    check_grad(&x, |x| {
+        Ok(x.t()?.masked_select(&x.t()?.gt(0f64)?)?.sqr()?.sum_all()?)
+    })?;
+
+    let ids = Tensor::new(&[[1u32, 0, 1]], dev)?;
+    let src = Tensor::new(&[[0.1f64, 0.2, 0.3]], dev)?;
+    check_grad(&x, |x| Ok(x.scatter(&ids, &src, 0)?.sqr()?.sum_all()?))?;
+    check_grad(&src, |src| Ok(x.scatter(&ids, src, 0)?.sqr()?.sum_all()?))?;
+
+    let ids = Tensor::new(&[2u32, 0], dev)?;
+    let src = Tensor::new(&[[0.1f64, 0.2], [0.3, 0.4]], dev)?;
+    check_grad(&x, |x| Ok(x.index_copy(&ids, &src, 1)?.sqr()?.sum_all()?))?;
+    check_grad(&src, |src| {
+        Ok(x.index_copy(&ids, src, 1)?.sqr()?.sum_all()?)
+    })?;
+    let rows = Tensor::new(&[1u32, 0], dev)?;
+    check_grad(&x, |x| {
+        Ok(x.index_put(&[&rows, &ids], &src.i(0)?, false)?
+            .sqr()?
+            .sum_all()?)
+    })?;
+    Ok(())
+}
+
+#[test]
+fn reduction_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::new(&[[0.5f64, -1.2, 2.], [1.5, -0.3, 0.7]], dev)?;
+    let w = Tensor::new(&[[0.3f64, -0.7, 1.1], [0.2, 0.9, -0.4]], dev)?;
+    check_grad(&x, |x| Ok((x.max((0, 1))? + x.min(1)?.sum_all()?)?))?;
+    check_grad(&x, |x| Ok(x.prod(1)?.sum_all()?))?;
+    check_grad(&x, |x| Ok(x.prod((0, 1))?))?;
+    // The zeros get the product of the other elements when they are alone in their slice.
+    let zeros = Tensor::new(&[[0f64, -1.2, 2.], [0., 0., 0.7]], dev)?;
+    check_grad(&zeros, |x| {
+        Ok((x.prod_keepdim(1)?.broadcast_mul(&w))?.sum_all()?)
+    })?;
+    check_grad(&zeros, |x| Ok((x.prod(0)? * w.i(0)?)?.sum_all()?))?;
+
+    check_grad(&x, |x| Ok(x.log_sum_exp(1)?.sum_all()?))?;
+    check_grad(&x, |x| Ok(x.log_sum_exp((0, 1))?))?;
+    check_grad(&x, |x| Ok((x.std((0, 1))? + x.var(1)?.sum_all()?)?))?;
+    for ord in [NormOrd::L1, NormOrd::L2, NormOrd::Inf] {
+        check_grad(&x, |x| {
+            Ok((x.norm_keepdim(ord, 1)?.broadcast_mul(&w))?.sum_all()?)
+        })?;
+    }
+    check_grad(&x, |x| Ok(x.norm(NormOrd::Frobenius, (0, 1))?))?;
+
+    let x = Tensor::new(
+        &[[0.5f64, -1.2, 0., 2., 0.3], [1.5, -0.3, 0.7, 0.4, 1.1]],
+        dev,
+    )?;
+    let w = Tensor::new(
+        &[[0.3f64, -0.7, 1.1, 0.5, 0.2], [0.2, 0.9, -0.4, 0.1, 0.8]],
+        dev,
+    )?;
+    check_grad(&x, |x| Ok((x.cumprod(1)? * &w)?.sum_all()?))?;
+    check_grad(&x, |x| Ok((x.cumprod(0)? * &w)?.sum_all()?))?;
+    check_grad(&x, |x| Ok((x.cummax(1)?.0 * &w)?.sum_all()?))?;
+    Ok(())
+}
+
+#[test]
+fn interpolate_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::randn(0f64, 1., (1, 2, 3, 4), dev)?;
+    let w = Tensor::randn(0f64, 1., (1, 2, 5, 3), dev)?;
+    for align_corners in [false, true] {
+        check_grad(&x, |x| {
+            Ok((x.upsample_bilinear2d(5, 3, align_corners)? * &w)?.sum_all()?)
+        })?;
+        check_grad(&x, |x| {
+            Ok((x.upsample_bicubic2d(5, 3, align_corners)? * &w)?.sum_all()?)
+        })?;
+    }
+
+    // Keep the grid away from the integer positions where the bilinear weights are not smooth.
+    let grid = Tensor::new(
+        &[
+            [[0.13f64, -0.42], [0.71, 0.58]],
+            [[-1.1, 0.2], [0.37, 0.96]],
+        ],
+        dev,
+    )?;
+    let grid = grid.unsqueeze(0)?;
+    let w = Tensor::randn(0f64, 1., (1, 2, 2, 2), dev)?;
+    for padding in [GridSamplePadding::Zeros, GridSamplePadding::Border] {
+        for align_corners in [false, true] {
+            let f = |x: &Tensor, grid: &Tensor| {
+                let ys = x.grid_sample(grid, GridSampleMode::Bilinear, padding, align_corners)?;
+                (ys * &w)?.sum_all()
+            };
+            check_grad(&x, |x| Ok(f(x, &grid)?))?;
+            check_grad(&grid, |grid| Ok(f(&x, grid)?))?;
+        }
+    }
+
+    let rois = Tensor::new(&[[0f64, 0.3, 0.2, 3.1, 2.5], [0., 1.2, 0.4, 2.2, 1.9]], dev)?;
+    let w = Tensor::randn(0f64, 1., (2, 2, 2, 3), dev)?;
+    for (sampling_ratio, aligned) in [(0, true), (2, false)] {
+        check_grad(&x, |x| {
+            Ok((x.roi_align(&rois, (2, 3), 1., sampling_ratio, aligned)? * &w)?.sum_all()?)
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn conv3d_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::randn(0f64, 1., (2, 3, 4, 5, 4), dev)?;
+    let w = Tensor::randn(0f64, 1., (2, 3, 3, 2, 3), dev)?;
+    for (padding, stride, dilation) in [(0, 1, 1), (1, 2, 1), (1, 1, 2)] {
+        let y = x.conv3d(&w, padding, stride, dilation, 1)?;
+        let weights = Tensor::randn(0f64, 1., y.shape(), dev)?;
+        let loss = |x: &Tensor, w: &Tensor| {
+            let y = x.conv3d(w, padding, stride, dilation, 1)?;
+            Ok((y * &weights)?.sum_all()?)
+        };
+        check_grad(&x, |x| loss(x, &w))?;
+        check_grad(&w, |w| loss(&x, w))?;
+    }
+    // Grouped convolutions are computed per group so the gradient flows through each of them.
+    let w = Tensor::randn(0f64, 1., (4, 1, 2, 2, 2), dev)?;
+    check_grad(&w, |w| {
+        Ok(x.narrow(1, 0, 2)?.conv3d(w, 0, 1, 1, 2)?.sqr()?.sum_all()?)
+    })?;
+
+    // The kernel for the transposed convolution has shape (c_in, c_out, k_d, k_h, k_w).
+    let w = Tensor::randn(0f64, 1., (3, 2, 2, 3, 2), dev)?;
+    for (padding, out_padding, stride, dilation) in [(0, 0, 1, 1), (1, 1, 2, 1), (1, 0, 1, 2)] {
+        let y = x.conv_transpose3d(&w, padding, out_padding, stride, dilation)?;
+        let weights = Tensor::randn(0f64, 1., y.shape(), dev)?;
+        let loss = |x: &Tensor, w: &Tensor| {
+            let y = x.conv_transpose3d(w, padding, out_padding, stride, dilation)?;
+            Ok((y * &weights)?.sum_all()?)
+        };
+        check_grad(&x, |x| loss(x, &w))?;
+        check_grad(&w, |w| loss(&x, w))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn pool3d_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::randn(0f64, 1., (2, 2, 4, 6, 4), dev)?;
+    let weights = Tensor::randn(0f64, 1., (2, 2, 2, 3, 2), dev)?;
+    check_grad(&x, |x| Ok((x.avg_pool3d(2)? * &weights)?.sum_all()?))?;
+    check_grad(&x, |x| Ok((x.max_pool3d(2)? * &weights)?.sum_all()?))?;
+    let weights = Tensor::randn(0f64, 1., (2, 2, 7, 6, 9), dev)?;
+    check_grad(&x, |x| {
+        Ok((x.upsample_nearest3d(7, 6, 9)? * &weights)?.sum_all()?)
+    })?;
+    // Overlapping windows are not supported.
+    let x = Var::from_tensor(&x)?;
+    assert!(x
+        .max_pool3d_with_stride(2, 1)?
+        .sum_all()?
+        .backward()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn conv_transpose_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::randn(0f64, 1., (2, 3, 5), dev)?;
+    let w = Tensor::randn(0f64, 1., (3, 2, 3), dev)?;
+    for (padding, out_padding, stride, dilation) in [(0, 0, 1, 1), (1, 1, 2, 1), (1, 0, 1, 2)] {
+        let y = x.conv_transpose1d(&w, padding, out_padding, stride, dilation)?;
+        let weights = Tensor::randn(0f64, 1., y.shape(), dev)?;
+        let loss = |x: &Tensor, w: &Tensor| {
+            let y = x.conv_transpose1d(w, padding, out_padding, stride, dilation)?;
+            Ok((y * &weights)?.sum_all()?)
+        };
+        check_grad(&x, |x| loss(x, &w))?;
+        check_grad(&w, |w| loss(&x, w))?;
+    }
+
+    let x = Tensor::randn(0f64, 1., (2, 3, 4, 5), dev)?;
+    let w = Tensor::randn(0f64, 1., (3, 2, 3, 2), dev)?;
+    for (padding, out_padding, stride, dilation) in [(0, 0, 1, 1), (1, 1, 2, 1), (1, 0, 1, 2)] {
+        let y = x.conv_transpose2d(&w, padding, out_padding, stride, dilation)?;
+        let weights = Tensor::randn(0f64, 1., y.shape(), dev)?;
+        let loss = |x: &Tensor, w: &Tensor| {
+            let y = x.conv_transpose2d(w, padding, out_padding, stride, dilation)?;
+            Ok((y * &weights)?.sum_all()?)
+        };
+        check_grad(&x, |x| loss(x, &w))?;
+        check_grad(&w, |w| loss(&x, w))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn upsample_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::randn(0f64, 1., (2, 3, 5), dev)?;
+    for size in [3, 5, 12] {
+        let weights = Tensor::randn(0f64, 1., (2, 3, size), dev)?;
+        check_grad(&x, |x| {
+            Ok((x.upsample_nearest1d(size)? * &weights)?.sum_all()?)
+        })?;
+    }
+    let x = Tensor::randn(0f64, 1., (2, 2, 4, 3), dev)?;
+    for (h, w) in [(8, 6), (7, 4), (2, 5)] {
+        let weights = Tensor::randn(0f64, 1., (2, 2, h, w), dev)?;
+        check_grad(&x, |x| {
+            Ok((x.upsample_nearest2d(h, w)? * &weights)?.sum_all()?)
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn pool2d_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Tensor::randn(0f64, 1., (2, 2, 7, 6), dev)?;
+    // Non-overlapping, overlapping and sparse windows, the input size is not always a multiple of
+    // the stride.
+    for (kernel_size, stride) in [
+        ((2, 2), (2, 2)),
+        ((3, 2), (1, 2)),
+        ((3, 3), (2, 1)),
+        ((2, 1), (3, 4)),
+    ] {
+        let y = x.avg_pool2d_with_stride(kernel_size, stride)?;
+        let weights = Tensor::randn(0f64, 1., y.shape(), dev)?;
+        check_grad(&x, |x| {
+            let y = x.avg_pool2d_with_stride(kernel_size, stride)?;
+            Ok((y * &weights)?.sum_all()?)
+        })?;
+        check_grad(&x, |x| {
+            let y = x.max_pool2d_with_stride(kernel_size, stride)?;
+            Ok((y * &weights)?.sum_all()?)
+        })?;
+    }
+
+    // The gradient is shared between the maximums of a window.
+    let x = Var::new(&[[[[1f32, 3., 3.], [2., 0., 0.]]]], dev)?;
+    let grads = x.max_pool2d_with_stride(2, 1)?.sum_all()?.backward()?;
+    let grad = grads.get(&x).context("no grad for x")?;
+    assert_eq!(
+        grad.i((0, 0))?.to_vec2::<f32>()?,
+        [[0., 1.5, 0.5], [0., 0., 0.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn checkpoint_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Var::new(&[[1f32, -2.], [0.5, 3.]], dev)?;
+    let b = Var::new(&[0.3f32, -0.7], dev)?;
+    // A tensor captured by the closure that is not a variable but depends on one.
+    let c = (b.as_tensor() * 2.)?.exp()?;
+    let f = {
+        let c = c.clone();
+        move |xs: &[Tensor]| {
+            let ys = xs[0].matmul(&xs[1])?.tanh()?;
+            ys.broadcast_mul(&c)?.sqr()
+        }
+    };
+    let x = Tensor::new(&[[0.2f32, 1.], [-1., 0.4], [2., -0.3]], dev)?;
+    let ys = f(&[x.clone(), a.as_tensor().clone()])?.broadcast_add(&b)?;
+    let grads = ys.sqr()?.sum_all()?.backward()?;
+    // Nested checkpoints, the outer closure captures the variable b.
+    let b_ = b.as_tensor().clone();
+    let ys = candle_core::checkpoint(
+        move |xs| {
+            let inner = candle_core::checkpoint(f.clone(), &[&xs[0], &xs[1]])?;
+            inner.broadcast_add(&b_)
+        },
+        &[&x, &a],
+    )?;
+    let grads_c = ys.sqr()?.sum_all()?.backward()?;
+    for v in [&a, &b] {
+        let g = grads.get(v).context("no grad")?.flatten_all()?;
+        let g_c = grads_c.get(v).context("no grad")?.flatten_all()?;
+        assert_eq!(
+            test_utils::to_vec1_round(&g, 4)?,
+            test_utils::to_vec1_round(&g_c, 4)?
+        );
+    }
+
+    // The closure can return one of its inputs.
+    let ys = candle_core::checkpoint(|xs| Ok(xs[0].clone()), &[&a])?;
+    let grads = (ys * 2.)?.sum_all()?.backward()?;
+    let grad = grads.get(&a).context("no grad for a")?;
+    assert_eq!(grad.to_vec2::<f32>()?, [[2., 2.], [2., 2.]]);
+    Ok(())
+}
+
+// Checks that two f64 tensors have the same values up to rounding errors.
+fn assert_close(lhs: &Tensor, rhs: &Tensor) -> Result<()> {
+    let diff = (lhs - rhs)?.abs()?.flatten_all()?.max(0)?;
+    let diff = diff.to_scalar::<f64>()?;
+    assert!(diff < 1e-10, "{lhs} {rhs}");
+    Ok(())
+}
+
+#[test]
+fn second_order_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let x = Var::new(&[0.5f64, -1.2, 2.], dev)?;
+    let grads = x.sin()?.sum_all()?.backward_with_graph()?;
+    let dx = grads.get(&x).context("no grad for x")?;
+    let grads = dx.sum_all()?.backward()?;
+    let d2x = grads.get(&x).context("no grad for x")?;
+    let x_ = x.to_vec1::<f64>()?;
+    let expected: Vec<f64> = x_.iter().map(|x| -x.sin()).collect();
+    assert_close(d2x, &Tensor::new(expected, dev)?)?;
+
+    // A gradient penalty as used in WGAN-GP: y = sum(w * tanh(x)), the penalty is the squared
+    // norm of dy/dx = w * (1 - tanh²(x)) and its derivative with respect to w is
+    // 2 * w * (1 - tanh²(x))².
+    let w = Var::new(&[1.5f64, -0.3, 0.8], dev)?;
+    let x = Tensor::new(&[0.2f64, -0.7, 1.1], dev)?;
+    let x = Var::from_tensor(&x)?;
+    let y = (w.as_tensor() * x.tanh()?)?.sum_all()?;
+    let grads = y.backward_with_graph()?;
+    let penalty = grads.get(&x).context("no grad for x")?.sqr()?.sum_all()?;
+    let grads = penalty.backward()?;
+    let dw = grads.get(&w).context("no grad for w")?;
+    let sech2 = (1. - x.tanh()?.sqr()?)?;
+    let expected = ((w.as_tensor() * 2.)? * sech2.sqr()?)?;
+    assert_close(dw, &expected)?;
+    Ok(())
+}
+
+#[test]
+fn autograd_products() -> Result<()> {
+    use candle_core::autograd;
+    let dev = &Device::Cpu;
+    let a = Tensor::new(&[[1f64, 2., 0.5], [-1., 0.3, 2.]], dev)?;
+    let x = Tensor::new(&[0.4f64, -0.2, 1.3], dev)?;
+    let f = |xs: &[Tensor]| a.matmul(&xs[0].unsqueeze(1)?)?.squeeze(1)?.sin();
+
+    // vjp: vᵀ diag(cos(ax)) a.
+    let v = Tensor::new(&[0.7f64, -1.5], dev)?;
+    let (ys, vjp) = autograd::vjp(f, &[&x], &v)?;
+    let ax = a.matmul(&x.unsqueeze(1)?)?.squeeze(1)?;
+    assert_eq!(ys.to_vec1::<f64>()?, ax.sin()?.to_vec1::<f64>()?);
+    let expected = (v * ax.cos()?)?.unsqueeze(0)?.matmul(&a)?.squeeze(0)?;
+    assert_close(&vjp[0], &expected)?;
+
+    // jvp: diag(cos(ax)) a t.
+    let t = Tensor::new(&[1f64, 0.5, -2.], dev)?;
+    let (_, jvp) = autograd::jvp(f, &[&x], &[&t])?;
+    let expected = (ax.cos()? * a.matmul(&t.unsqueeze(1)?)?.squeeze(1)?)?;
+    assert_close(&jvp, &expected)?;
+
+    // hvp: f(x, y) = sum(x² y + sin(x)) has the hessian blocks hxx = diag(2y - sin(x)),
+    // hxy = diag(2x) and hyy = 0.
+    let y = Tensor::new(&[2f64, -0.5, 0.1], dev)?;
+    let (vx, vy) = (Tensor::new(&[1f64, -1., 0.5], dev)?, t);
+    let f = |xs: &[Tensor]| ((xs[0].sqr()? * &xs[1])? + xs[0].sin()?)?.sum_all();
+    let (value, hvp) = autograd::hvp(f, &[&x, &y], &[&vx, &vy])?;
+    let expected = ((x.sqr()? * &y)? + x.sin()?)?.sum_all()?;
+    assert_eq!(value.to_scalar::<f64>()?, expected.to_scalar::<f64>()?);
+    let hx = ((((&y * 2.)? - x.sin()?)? * &vx)? + ((&x * 2.)? * &vy)?)?;
+    let hy = ((&x * 2.)? * &vx)?;
+    assert_close(&hvp[0], &hx)?;
+    assert_close(&hvp[1], &hy)?;
+
+    // The products are differentiable, e.g. with respect to a variable captured by f.
+    let w = Var::new(&[0.5f64, 1., -1.], dev)?;
+    let f = |xs: &[Tensor]| (xs[0].sqr()? * w.as_tensor())?.sum_all();
+    let (_, hvp) = autograd::hvp(f, &[&x], &[&vx])?;
+    // hvp = 2 w vx so its sum has the gradient 2 vx with respect to w.
+    let grads = hvp[0].sum_all()?.backward()?;
+    let dw = grads.get(&w).context("no grad for w")?;
+    assert_eq!(dw.to_vec1::<f64>()?, (&vx * 2.)?.to_vec1::<f64>()?);
+
+    assert!(autograd::hvp(f, &[&x], &[&y.unsqueeze(0)?]).is_err());
+    assert!(autograd::vjp(f, &[&x], &vx).is_err());
+    Ok(())
+}
+
 test_device!(
     simple_grad,
     simple_grad_cpu,
@@ -341,3 +803,5 @@ test_device!(
     binary_grad_gpu,
     binary_grad_metal
 );
+test_device!(topk_grad, topk_grad_cpu, topk_grad_gpu, topk_grad_metal);
+test_device!(pad_grad, pad_grad_cpu, pad_grad_gpu, pad_grad_metal);
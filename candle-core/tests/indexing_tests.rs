@@ -1,5 +1,5 @@
 use anyhow::Result;
-use candle_core::{Device, IndexOp, Tensor};
+use candle_core::{Bool, DType, Device, IndexOp, Tensor};
 
 #[test]
 fn integer_index() -> Result<()> {
@@ -120,3 +120,48 @@ fn slice_assign() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn tensor_index() -> Result<()> {
+    let dev = Device::Cpu;
+    let tensor = Tensor::arange(0u32, 2 * 3 * 4, &dev)?.reshape((2, 3, 4))?;
+
+    let ids = Tensor::new(&[[2u32, 0], [1, 1]], &dev)?;
+    let result = tensor.i((.., &ids))?;
+    assert_eq!(result.dims(), &[2, 2, 2, 4]);
+    assert_eq!(
+        result.i((1, .., .., 0))?.to_vec2::<u32>()?,
+        &[[20, 12], [16, 16]]
+    );
+    let result = tensor.i((.., &ids, 3))?;
+    assert_eq!(result.dims(), &[2, 2, 2]);
+    assert_eq!(result.i(0)?.to_vec2::<u32>()?, &[[11, 3], [7, 7]]);
+    let result = tensor.i((1, ..2, &Tensor::new(3u32, &dev)?))?;
+    assert_eq!(result.to_vec1::<u32>()?, &[15, 19]);
+    Ok(())
+}
+
+#[test]
+fn mask_index() -> Result<()> {
+    let dev = Device::Cpu;
+    let tensor = Tensor::arange(0u32, 2 * 3 * 4, &dev)?.reshape((2, 3, 4))?;
+
+    let mask = Tensor::new(&[0u8, 1, 1], &dev)?.to_dtype(DType::Bool)?;
+    let result = tensor.i((1, &mask, 1..3))?;
+    assert_eq!(result.to_vec2::<u32>()?, &[[17, 18], [21, 22]]);
+
+    let mask = tensor.i((.., .., 0))?.ge(8u32)?.to_dtype(DType::Bool)?;
+    let result = tensor.i(&mask)?;
+    assert_eq!(result.dims(), &[4, 4]);
+    assert_eq!(result.i((.., 0))?.to_vec1::<u32>()?, &[8, 12, 16, 20]);
+
+    let result = tensor.i((.., &[Bool(false), Bool(true), Bool(false)][..]))?;
+    assert_eq!(
+        result.squeeze(1)?.to_vec2::<u32>()?,
+        &[[4, 5, 6, 7], [16, 17, 18, 19]]
+    );
+
+    let mask = Tensor::new(&[1u8, 0], &dev)?.to_dtype(DType::Bool)?;
+    assert!(tensor.i((.., &mask)).is_err());
+    Ok(())
+}
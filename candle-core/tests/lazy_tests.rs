@@ -0,0 +1,116 @@
+use candle_core::{DType, Device, IndexOp, Result, Tensor, Var};
+
+#[test]
+fn lazy_fusion() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (3, 1000), dev)?;
+    let ys = Tensor::randn(0f32, 1., (3, 1000), dev)?;
+
+    let lazy = xs
+        .lazy()
+        .affine(0.5, -1.)
+        .gelu()
+        .mul(&ys.lazy())?
+        .add(&xs.lazy().exp())?;
+    let eager = ((xs.affine(0.5, -1.)?.gelu()? * &ys)? + xs.exp()?)?;
+    let lazy = lazy.materialize()?;
+    assert!(!lazy.track_op());
+    assert_eq!(lazy.to_vec2::<f32>()?, eager.to_vec2::<f32>()?);
+
+    // A sub-expression used several times and every unary op.
+    let h = xs.lazy().abs().sqrt();
+    let lazy = h
+        .mul(&h)?
+        .sub(&h.tanh())?
+        .maximum(&h.relu().floor())?
+        .minimum(&h.ceil().round())?
+        .add(&h.sin().cos().sqr())?
+        .add(&h.gelu_erf().erf().neg().recip())?
+        .add(&h.affine(1., 1.).log())?
+        .silu()?;
+    let h_ = xs.abs()?.sqrt()?;
+    let eager = (&h_ * &h_)?
+        .sub(&h_.tanh()?)?
+        .maximum(&h_.relu()?.floor()?)?
+        .minimum(&h_.ceil()?.round()?)?
+        .add(&h_.sin()?.cos()?.sqr()?)?
+        .add(&h_.gelu_erf()?.erf()?.neg()?.recip()?)?
+        .add(&(&h_ + 1.)?.log()?)?;
+    let eager = (&eager / (eager.neg()?.exp()? + 1.)?)?;
+    assert_eq!(
+        lazy.materialize()?.to_vec2::<f32>()?,
+        eager.to_vec2::<f32>()?
+    );
+
+    // Other float dtypes, the divisor is kept away from zero so that the f16 results do not
+    // overflow to infinities which difference would be nan.
+    let ys = (ys.abs()? + 1.)?;
+    for dtype in [DType::F64, DType::F16, DType::BF16] {
+        let (xs, ys) = (xs.to_dtype(dtype)?, ys.to_dtype(dtype)?);
+        let lazy = xs.lazy().neg().exp().div(&ys.lazy())?.materialize()?;
+        let eager = (xs.neg()?.exp()? / &ys)?;
+        assert_eq!(lazy.dtype(), dtype);
+        let diff = (lazy - eager)?.abs()?.sum_all()?.to_dtype(DType::F32)?;
+        assert_eq!(diff.to_scalar::<f32>()?, 0.);
+    }
+    Ok(())
+}
+
+#[test]
+fn lazy_layouts() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::arange(0f32, 24., dev)?.reshape((2, 3, 4))?;
+    let bias = Tensor::new(&[1f32, 2., 3., 4.], dev)?;
+    let scale = Tensor::new(&[[2f32], [3.], [4.]], dev)?;
+
+    // Strided inputs and broadcasting on both sides.
+    let xt = xs.transpose(1, 2)?.i((.., 1..3))?;
+    let lazy = xt.lazy().broadcast_mul(&scale.t()?.lazy())?;
+    let lazy = lazy.broadcast_add(&bias.i(1..3)?.unsqueeze(1)?.lazy())?;
+    let eager = xt
+        .broadcast_mul(&scale.t()?)?
+        .broadcast_add(&bias.i(1..3)?.unsqueeze(1)?)?;
+    assert_eq!(lazy.dims(), [2, 2, 3]);
+    assert_eq!(
+        lazy.materialize()?.to_vec3::<f32>()?,
+        eager.to_vec3::<f32>()?
+    );
+
+    // The broadcasted operand can be an expression itself.
+    let lazy = bias.lazy().exp().broadcast_sub(&xs.lazy())?.materialize()?;
+    let eager = bias.exp()?.broadcast_sub(&xs)?;
+    assert_eq!(lazy.to_vec3::<f32>()?, eager.to_vec3::<f32>()?);
+
+    // More elements than a single block, with a transposed input.
+    let xs = Tensor::randn(0f64, 1., (70, 50), dev)?;
+    let ys = Tensor::randn(0f64, 1., (50, 70), dev)?;
+    let lazy = xs.lazy().sub(&ys.t()?.lazy())?.sqr().materialize()?;
+    let eager = (&xs - ys.t()?)?.sqr()?;
+    assert_eq!(lazy.to_vec2::<f64>()?, eager.to_vec2::<f64>()?);
+
+    assert!(xs.lazy().add(&ys.lazy()).is_err());
+    assert!(xs.lazy().broadcast_add(&ys.lazy()).is_err());
+    assert!(xs.lazy().add(&xs.to_dtype(DType::F32)?.lazy()).is_err());
+    Ok(())
+}
+
+#[test]
+fn lazy_fallback() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Tensors tracking gradients are evaluated eagerly so that backprop works.
+    let x = Var::new(&[1f32, 2., 3.], dev)?;
+    let y = x.lazy().sqr().affine(3., 1.).materialize()?;
+    assert_eq!(y.to_vec1::<f32>()?, [4., 13., 28.]);
+    let grads = y.sum_all()?.backward()?;
+    assert_eq!(grads.get(&x).unwrap().to_vec1::<f32>()?, [6., 12., 18.]);
+
+    // Integer dtypes use the eager ops too.
+    let xs = Tensor::new(&[1u32, 5, 3], dev)?;
+    let ys = Tensor::new(&[4u32, 2, 3], dev)?;
+    let lazy = xs.lazy().maximum(&ys.lazy())?.affine(2., 1.);
+    assert_eq!(lazy.materialize()?.to_vec1::<u32>()?, [9, 11, 7]);
+
+    // Materializing an input returns it as is.
+    assert_eq!(xs.lazy().materialize()?.id(), xs.id());
+    Ok(())
+}
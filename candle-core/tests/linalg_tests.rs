@@ -0,0 +1,216 @@
+use candle_core::{linalg, test_utils, DType, Device, Result, Tensor, Var};
+
+fn max_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f64> {
+    (lhs - rhs)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_dtype(DType::F64)?
+        .to_scalar::<f64>()
+}
+
+// Returns a batch of symmetric positive-definite matrices.
+fn spd(b: usize, n: usize, dev: &Device) -> Result<Tensor> {
+    let xs = Tensor::randn(0f64, 1., (b, n, n), dev)?;
+    let eye = Tensor::eye(n, DType::F64, dev)?;
+    xs.matmul(&xs.t()?)?.broadcast_add(&(eye * n as f64)?)
+}
+
+#[test]
+fn inverse_det() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[4f32, 7.], [2., 6.]], dev)?;
+    assert_eq!(
+        test_utils::to_vec2_round(&linalg::inverse(&t)?, 4)?,
+        [[0.6, -0.7], [-0.2, 0.4]]
+    );
+    assert_eq!(linalg::det(&t)?.to_scalar::<f32>()?, 10.);
+
+    let t = Tensor::randn(0f64, 1., (2, 3, 5, 5), dev)?;
+    let inv = linalg::inverse(&t)?;
+    assert_eq!(inv.dims(), [2, 3, 5, 5]);
+    let eye = Tensor::eye(5, DType::F64, dev)?.broadcast_as(t.shape())?;
+    assert!(max_diff(&t.matmul(&inv)?, &eye)? < 1e-10);
+
+    // The determinant of a product is the product of the determinants.
+    let u = Tensor::randn(0f64, 1., (2, 3, 5, 5), dev)?;
+    let det_prod = linalg::det(&t.matmul(&u)?)?;
+    assert_eq!(det_prod.dims(), [2, 3]);
+    let prod_det = (linalg::det(&t)? * linalg::det(&u)?)?;
+    assert!(max_diff(&det_prod, &prod_det)? < 1e-8);
+
+    let singular = Tensor::new(&[[1f32, 2.], [2., 4.]], dev)?;
+    assert!(linalg::inverse(&singular).is_err());
+    assert_eq!(linalg::det(&singular)?.to_scalar::<f32>()?, 0.);
+    assert!(linalg::inverse(&Tensor::zeros((2, 3), DType::F32, dev)?).is_err());
+    assert!(linalg::inverse(&Tensor::zeros((2, 2), DType::U32, dev)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn solve() -> Result<()> {
+    let dev = &Device::Cpu;
+    let a = Tensor::randn(0f64, 1., (3, 4, 4), dev)?;
+    let b = Tensor::randn(0f64, 1., (3, 4, 2), dev)?;
+    let x = linalg::solve(&a, &b)?;
+    assert_eq!(x.dims(), [3, 4, 2]);
+    assert!(max_diff(&a.matmul(&x)?, &b)? < 1e-10);
+
+    let b = Tensor::randn(0f64, 1., (3, 4), dev)?;
+    let x = linalg::solve(&a, &b)?;
+    assert_eq!(x.dims(), [3, 4]);
+    assert!(max_diff(&a.matmul(&x.unsqueeze(2)?)?.squeeze(2)?, &b)? < 1e-10);
+    assert!(linalg::solve(&a, &Tensor::zeros((2, 4, 2), DType::F64, dev)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn cholesky() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[4f32, 2.], [2., 5.]], dev)?;
+    assert_eq!(
+        linalg::cholesky(&t)?.to_vec2::<f32>()?,
+        [[2., 0.], [1., 2.]]
+    );
+
+    let a = spd(3, 6, dev)?;
+    let l = linalg::cholesky(&a)?;
+    assert!(max_diff(&l.matmul(&l.t()?)?, &a)? < 1e-10);
+    let upper = l.broadcast_mul(&(1. - Tensor::tril2(6, DType::F64, dev)?)?)?;
+    assert_eq!(upper.abs()?.sum_all()?.to_scalar::<f64>()?, 0.);
+
+    let t = Tensor::new(&[[1f32, 2.], [2., 1.]], dev)?;
+    assert!(linalg::cholesky(&t).is_err());
+    Ok(())
+}
+
+#[test]
+fn qr() -> Result<()> {
+    let dev = &Device::Cpu;
+    for (m, n) in [(5, 3), (3, 5), (4, 4)] {
+        let a = Tensor::randn(0f64, 1., (2, m, n), dev)?;
+        let (q, r) = linalg::qr(&a)?;
+        let k = m.min(n);
+        assert_eq!(q.dims(), [2, m, k]);
+        assert_eq!(r.dims(), [2, k, n]);
+        assert!(max_diff(&q.matmul(&r)?, &a)? < 1e-10);
+        let eye = Tensor::eye(k, DType::F64, dev)?.broadcast_as((2, k, k))?;
+        assert!(max_diff(&q.t()?.matmul(&q)?, &eye)? < 1e-10);
+        for b in 0..2 {
+            let r = r.get(b)?.to_vec2::<f64>()?;
+            for (i, row) in r.iter().enumerate() {
+                assert!(row[i] >= 0.);
+                assert!(row[..i].iter().all(|&v| v == 0.));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn svd() -> Result<()> {
+    let dev = &Device::Cpu;
+    for (m, n) in [(6, 4), (4, 6), (5, 5)] {
+        let a = Tensor::randn(0f64, 1., (3, m, n), dev)?;
+        let (u, s, vt) = linalg::svd(&a)?;
+        let k = m.min(n);
+        assert_eq!(u.dims(), [3, m, k]);
+        assert_eq!(s.dims(), [3, k]);
+        assert_eq!(vt.dims(), [3, k, n]);
+        let rec = u.broadcast_mul(&s.unsqueeze(1)?)?.matmul(&vt)?;
+        assert!(max_diff(&rec, &a)? < 1e-10);
+        let eye = Tensor::eye(k, DType::F64, dev)?.broadcast_as((3, k, k))?;
+        assert!(max_diff(&u.t()?.matmul(&u)?, &eye)? < 1e-10);
+        assert!(max_diff(&vt.matmul(&vt.t()?)?, &eye)? < 1e-10);
+        for s in s.to_vec2::<f64>()? {
+            assert!(s.windows(2).all(|w| w[0] >= w[1]));
+        }
+    }
+
+    // A rank deficient matrix still gets orthonormal singular vectors.
+    let a = Tensor::new(&[[1f64, 2., 3.], [2., 4., 6.], [1., 1., 1.]], dev)?;
+    let (u, s, _vt) = linalg::svd(&a)?;
+    assert!(s.get(2)?.to_scalar::<f64>()? < 1e-12);
+    let eye = Tensor::eye(3, DType::F64, dev)?;
+    assert!(max_diff(&u.t()?.matmul(&u)?, &eye)? < 1e-10);
+    Ok(())
+}
+
+#[test]
+fn eigh() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[2f32, 1.], [1., 2.]], dev)?;
+    let (values, _) = linalg::eigh(&t)?;
+    assert_eq!(test_utils::to_vec1_round(&values, 4)?, [1., 3.]);
+
+    let xs = Tensor::randn(0f64, 1., (2, 5, 5), dev)?;
+    let a = (&xs + xs.t()?)?;
+    let (values, vectors) = linalg::eigh(&a)?;
+    assert_eq!(values.dims(), [2, 5]);
+    assert_eq!(vectors.dims(), [2, 5, 5]);
+    let av = a.matmul(&vectors)?;
+    let vl = vectors.broadcast_mul(&values.unsqueeze(1)?)?;
+    assert!(max_diff(&av, &vl)? < 1e-10);
+    let eye = Tensor::eye(5, DType::F64, dev)?.broadcast_as((2, 5, 5))?;
+    assert!(max_diff(&vectors.t()?.matmul(&vectors)?, &eye)? < 1e-10);
+    for v in values.to_vec2::<f64>()? {
+        assert!(v.windows(2).all(|w| w[0] <= w[1]));
+    }
+    Ok(())
+}
+
+// Compares the gradient of `f` at `x` with central finite differences.
+fn check_grad(x: &Tensor, f: impl Fn(&Tensor) -> Result<Tensor>) -> Result<()> {
+    let var = Var::from_tensor(x)?;
+    let grads = f(var.as_tensor())?.backward()?;
+    let grad = grads.get(&var).unwrap().flatten_all()?.to_vec1::<f64>()?;
+    let xs = x.flatten_all()?.to_vec1::<f64>()?;
+    let eps = 1e-6;
+    for i in 0..xs.len() {
+        let mut plus = xs.clone();
+        plus[i] += eps;
+        let mut minus = xs.clone();
+        minus[i] -= eps;
+        let plus = f(&Tensor::from_vec(plus, x.shape(), x.device())?)?.to_scalar::<f64>()?;
+        let minus = f(&Tensor::from_vec(minus, x.shape(), x.device())?)?.to_scalar::<f64>()?;
+        let expected = (plus - minus) / (2. * eps);
+        assert!(
+            (grad[i] - expected).abs() < 1e-5,
+            "{i} {} {expected}",
+            grad[i]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn linalg_grad() -> Result<()> {
+    let dev = &Device::Cpu;
+    let eye = (Tensor::eye(3, DType::F64, dev)? * 3.)?;
+    let a = Tensor::randn(0f64, 1., (2, 3, 3), dev)?.broadcast_add(&eye)?;
+    let w = Tensor::randn(0f64, 1., (2, 3, 3), dev)?;
+    check_grad(&a, |a| (linalg::inverse(a)? * &w)?.sum_all())?;
+    check_grad(&a, |a| {
+        (linalg::det(a)? * Tensor::new(&[1f64, -2.], dev)?)?.sum_all()
+    })?;
+    // The gradient of the determinant is the cofactor matrix, also defined for singular inputs.
+    let singular = Tensor::new(&[[1f64, 2., 3.], [2., 4., 6.], [1., 0., 1.]], dev)?;
+    check_grad(&singular, linalg::det)?;
+    let singular = Var::from_tensor(&singular)?;
+    let grads = linalg::det(&singular)?.backward()?;
+    let expected = Tensor::new(&[[4f64, 4., -4.], [-2., -2., 2.], [0., 0., 0.]], dev)?;
+    assert!(max_diff(grads.get(&singular).unwrap(), &expected)? < 1e-10);
+
+    let b = Tensor::randn(0f64, 1., (2, 3, 2), dev)?;
+    let wb = Tensor::randn(0f64, 1., (2, 3, 2), dev)?;
+    check_grad(&a, |a| (linalg::solve(a, &b)? * &wb)?.sum_all())?;
+    check_grad(&b, |b| (linalg::solve(&a, b)? * &wb)?.sum_all())?;
+
+    // The cholesky gradient is symmetric so it is checked through a symmetric parametrization.
+    let xs = Tensor::randn(0f64, 1., (2, 3, 3), dev)?;
+    check_grad(&xs, |xs| {
+        let a = xs.matmul(&xs.t()?)?.broadcast_add(&eye)?;
+        (linalg::cholesky(&a)? * &w)?.sum_all()
+    })?;
+    Ok(())
+}
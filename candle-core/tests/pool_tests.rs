@@ -1,4 +1,7 @@
-use candle_core::{test_device, test_utils, Device, IndexOp, Result, Tensor};
+use candle_core::{
+    test_device, test_utils, DType, Device, GridSampleMode, GridSamplePadding, IndexOp, Result,
+    Tensor,
+};
 
 // https://github.com/huggingface/candle/issues/364
 fn avg_pool2d(dev: &Device) -> Result<()> {
@@ -98,6 +101,236 @@ fn upsample_nearest2d(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn pool3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 32., dev)?.reshape((1, 1, 2, 4, 4))?;
+    let pool = t.avg_pool3d(2)?.i((0, 0, 0))?;
+    // The window average is the value at the center of the window.
+    assert_eq!(pool.to_vec2::<f32>()?, [[10.5, 12.5], [18.5, 20.5]]);
+    let pool = t.max_pool3d(2)?.i((0, 0, 0))?;
+    assert_eq!(pool.to_vec2::<f32>()?, [[21., 23.], [29., 31.]]);
+    let pool = t.max_pool3d_with_stride((1, 2, 2), (1, 1, 2))?.i((0, 0))?;
+    assert_eq!(pool.dims(), [2, 3, 2]);
+    assert_eq!(
+        pool.i(1)?.to_vec2::<f32>()?,
+        [[21., 23.], [25., 27.], [29., 31.]]
+    );
+
+    // Pooling over a single depth element matches the 2d version.
+    let t = Tensor::randn(0f32, 1., (2, 3, 1, 6, 4), dev)?;
+    let pool3d = t.avg_pool3d((1, 2, 2))?.squeeze(2)?;
+    let pool2d = t.squeeze(2)?.avg_pool2d(2)?;
+    assert_eq!(
+        test_utils::to_vec3_round(&pool3d.i(1)?, 4)?,
+        test_utils::to_vec3_round(&pool2d.i(1)?, 4)?
+    );
+    let pool3d = t.max_pool3d_with_stride((1, 3, 2), (1, 2, 1))?.squeeze(2)?;
+    let pool2d = t.squeeze(2)?.max_pool2d_with_stride((3, 2), (2, 1))?;
+    assert_eq!(
+        pool3d.i(0)?.to_vec3::<f32>()?,
+        pool2d.i(0)?.to_vec3::<f32>()?
+    );
+    assert!(t.max_pool3d(2).is_err());
+    Ok(())
+}
+
+#[test]
+fn upsample_nearest3d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 12., dev)?.reshape((1, 1, 2, 2, 3))?;
+    let upsampled = t.upsample_nearest3d(4, 2, 6)?.i((0, 0))?;
+    assert_eq!(upsampled.dims(), [4, 2, 6]);
+    assert_eq!(
+        upsampled.to_vec3::<f32>()?,
+        [
+            [[0., 0., 1., 1., 2., 2.], [3., 3., 4., 4., 5., 5.]],
+            [[0., 0., 1., 1., 2., 2.], [3., 3., 4., 4., 5., 5.]],
+            [[6., 6., 7., 7., 8., 8.], [9., 9., 10., 10., 11., 11.]],
+            [[6., 6., 7., 7., 8., 8.], [9., 9., 10., 10., 11., 11.]]
+        ]
+    );
+    let downsampled = t.upsample_nearest3d(1, 1, 2)?;
+    assert_eq!(downsampled.flatten_all()?.to_vec1::<f32>()?, [0., 1.]);
+    Ok(())
+}
+
+#[test]
+fn upsample_bilinear2d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 4., dev)?.reshape((1, 1, 2, 2))?;
+    let upsampled = t.upsample_bilinear2d(4, 4, false)?.i((0, 0))?;
+    assert_eq!(
+        upsampled.to_vec2::<f32>()?,
+        [
+            [0.0, 0.25, 0.75, 1.0],
+            [0.5, 0.75, 1.25, 1.5],
+            [1.5, 1.75, 2.25, 2.5],
+            [2.0, 2.25, 2.75, 3.0]
+        ]
+    );
+    let t = Tensor::arange(0f32, 6., dev)?.reshape((1, 1, 2, 3))?;
+    let upsampled = t.upsample_bilinear2d(3, 5, true)?.i((0, 0))?;
+    assert_eq!(
+        upsampled.to_vec2::<f32>()?,
+        [
+            [0.0, 0.5, 1.0, 1.5, 2.0],
+            [1.5, 2.0, 2.5, 3.0, 3.5],
+            [3.0, 3.5, 4.0, 4.5, 5.0]
+        ]
+    );
+    // Resizing to the same size is the identity.
+    let t = Tensor::randn(0f32, 1., (2, 3, 4, 5), dev)?;
+    for align_corners in [false, true] {
+        let bilinear = t.upsample_bilinear2d(4, 5, align_corners)?;
+        let bicubic = t.upsample_bicubic2d(4, 5, align_corners)?;
+        let diff = (bilinear - &t)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-6, "{diff}");
+        let diff = (bicubic - &t)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{diff}");
+    }
+    // Non-contiguous inputs are supported.
+    let transposed = t.transpose(2, 3)?.upsample_bicubic2d(3, 2, false)?;
+    let expected = t.upsample_bicubic2d(2, 3, false)?.transpose(2, 3)?;
+    let diff = (transposed - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_scalar::<f32>()? < 1e-5);
+    assert!(t.narrow(2, 0, 0)?.upsample_bilinear2d(2, 2, false).is_err());
+    Ok(())
+}
+
+#[test]
+fn upsample_bicubic2d() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 4., dev)?.reshape((1, 1, 2, 2))?;
+    let upsampled = t.upsample_bicubic2d(4, 4, false)?.i((0, 0))?;
+    assert_eq!(
+        test_utils::to_vec2_round(&upsampled, 4)?,
+        [
+            [-0.3164, 0.0156, 0.5625, 0.8945],
+            [0.3477, 0.6797, 1.2266, 1.5586],
+            [1.4414, 1.7734, 2.3203, 2.6523],
+            [2.1055, 2.4375, 2.9844, 3.3164]
+        ]
+    );
+    let t = Tensor::arange(0f32, 6., dev)?.reshape((1, 1, 2, 3))?;
+    let upsampled = t.upsample_bicubic2d(3, 4, true)?.i((0, 0))?;
+    assert_eq!(
+        test_utils::to_vec2_round(&upsampled, 4)?,
+        [
+            [0.0, 0.5741, 1.4259, 2.0],
+            [1.5, 2.0741, 2.9259, 3.5],
+            [3.0, 3.5741, 4.4259, 5.0]
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn grid_sample() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[[[1f32, 2.], [3., 4.]]]], dev)?;
+    let grid = Tensor::new(&[[[[0f32, 0.], [-1., -1.], [0.4, -0.6]]]], dev)?;
+    let sample = |mode, padding, align_corners| -> Result<Vec<f32>> {
+        let sampled = t.grid_sample(&grid, mode, padding, align_corners)?;
+        test_utils::to_vec1_round(&sampled.flatten_all()?, 4)
+    };
+    let (bilinear, nearest) = (GridSampleMode::Bilinear, GridSampleMode::Nearest);
+    let (zeros, border) = (GridSamplePadding::Zeros, GridSamplePadding::Border);
+    assert_eq!(sample(bilinear, zeros, true)?, [2.5, 1., 2.1]);
+    assert_eq!(sample(bilinear, zeros, false)?, [2.5, 0.25, 1.71]);
+    assert_eq!(sample(bilinear, border, false)?, [2.5, 1., 1.9]);
+    assert_eq!(sample(nearest, zeros, true)?, [4., 1., 2.]);
+
+    // An identity grid with aligned corners returns the input.
+    let t = Tensor::randn(0f32, 1., (2, 3, 4, 5), dev)?;
+    let ys = Tensor::arange(0f32, 4., dev)?.affine(2. / 3., -1.)?;
+    let xs = Tensor::arange(0f32, 5., dev)?.affine(0.5, -1.)?;
+    let grid = Tensor::stack(
+        &[
+            xs.unsqueeze(0)?.broadcast_as((4, 5))?,
+            ys.unsqueeze(1)?.broadcast_as((4, 5))?,
+        ],
+        2,
+    )?
+    .unsqueeze(0)?
+    .repeat((2, 1, 1, 1))?;
+    for mode in [bilinear, nearest] {
+        let sampled = t.grid_sample(&grid, mode, zeros, true)?;
+        let diff = (sampled - &t)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "{diff}");
+    }
+
+    // The locations are not rounded with half precision inputs.
+    let t = (0..1024).map(|v| (v % 256) as f32).collect::<Vec<_>>();
+    let t = Tensor::from_vec(t, (1, 1, 32, 32), dev)?.to_dtype(DType::BF16)?;
+    let grid = Tensor::new(&[[[[1f32, 1.], [0.5, 0.5]]]], dev)?.to_dtype(DType::BF16)?;
+    let sampled = t.grid_sample(&grid, nearest, zeros, true)?;
+    let sampled = sampled
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    assert_eq!(sampled, [255., 247.]);
+    let sampled = t.grid_sample(&grid.narrow(2, 0, 1)?, bilinear, zeros, true)?;
+    let sampled = sampled
+        .to_dtype(DType::F32)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    assert_eq!(sampled, [255.]);
+    Ok(())
+}
+
+#[test]
+fn roi_align() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 16., dev)?.reshape((1, 1, 4, 4))?;
+    let t = Tensor::cat(&[&t, &(&t * 2.)?], 0)?;
+    let rois = Tensor::new(
+        &[
+            [1f32, 0., 0., 4., 4.],
+            [0., 0., 0., 4., 4.],
+            [0., 2., 2., 4., 4.],
+        ],
+        dev,
+    )?;
+    // The aligned samples fall on the pixels so the bins are the average of 2x2 blocks.
+    for sampling_ratio in [0, 2] {
+        let pooled = t.roi_align(&rois.i(..2)?, (2, 2), 1., sampling_ratio, true)?;
+        assert_eq!(pooled.dims(), [2, 1, 2, 2]);
+        assert_eq!(
+            pooled.flatten_from(1)?.to_vec2::<f32>()?,
+            [[5., 9., 21., 25.], [2.5, 4.5, 10.5, 12.5]]
+        );
+    }
+    // The samples past the last pixel use its value.
+    let pooled = t.roi_align(&rois, (2, 2), 1., 2, true)?;
+    assert_eq!(
+        pooled.i(2)?.flatten_all()?.to_vec1::<f32>()?,
+        [10., 10.875, 13.5, 14.375]
+    );
+    let pooled = t.roi_align(&rois, (2, 2), 1., 0, true)?;
+    assert_eq!(
+        pooled.i(2)?.flatten_all()?.to_vec1::<f32>()?,
+        [10., 11., 14., 15.]
+    );
+    let pooled = t.roi_align(&rois.i(1..2)?, (1, 1), 0.5, 1, false)?;
+    assert_eq!(pooled.flatten_all()?.to_vec1::<f32>()?, [5.]);
+    let pooled = t.roi_align(&rois.narrow(0, 0, 0)?, (2, 3), 1., 0, true)?;
+    assert_eq!(pooled.dims(), [0, 1, 2, 3]);
+    Ok(())
+}
+
 test_device!(avg_pool2d, avg_pool2d_cpu, avg_pool2d_gpu, avg_pool2d_metal);
 test_device!(
     avg_pool2d_pytorch,
@@ -1,4 +1,4 @@
-use candle_core::{DType, Result, Tensor};
+use candle_core::{Bool, DType, Device, Result, Tensor};
 
 #[test]
 fn npy() -> Result<()> {
@@ -22,3 +22,26 @@ fn npz() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn safetensors_int_and_bool() -> Result<()> {
+    let dev = &Device::Cpu;
+    let path = std::env::temp_dir().join("candle_int_and_bool.safetensors");
+    let tensors = std::collections::HashMap::from([
+        ("i8".to_string(), Tensor::new(&[-1i8, 2], dev)?),
+        ("i16".to_string(), Tensor::new(&[-1i16, 2], dev)?),
+        ("i32".to_string(), Tensor::new(&[-1i32, 2], dev)?),
+        (
+            "bool".to_string(),
+            Tensor::new(&[Bool(true), Bool(false)], dev)?,
+        ),
+    ]);
+    candle_core::safetensors::save(&tensors, &path)?;
+    let loaded = candle_core::safetensors::load(&path, dev)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(loaded["i8"].to_vec1::<i8>()?, [-1, 2]);
+    assert_eq!(loaded["i16"].to_vec1::<i16>()?, [-1, 2]);
+    assert_eq!(loaded["i32"].to_vec1::<i32>()?, [-1, 2]);
+    assert_eq!(loaded["bool"].to_vec1::<Bool>()?, [Bool(true), Bool(false)]);
+    Ok(())
+}
@@ -1,4 +1,6 @@
-use candle_core::{test_device, test_utils, DType, Device, IndexOp, Result, Tensor};
+use candle_core::{
+    test_device, test_utils, Bool, DType, Device, IndexOp, NormOrd, PadMode, Result, Tensor,
+};
 
 fn zeros(device: &Device) -> Result<()> {
     let tensor = Tensor::zeros((5, 2), DType::F32, device)?;
@@ -491,6 +493,67 @@ fn argmin(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn sort(device: &Device) -> Result<()> {
+    let data = &[[[3u32, 1, 4], [1, 5, 9]], [[2, 1, 7], [8, 2, 8]]];
+    let tensor = Tensor::new(data, device)?;
+    let (values, indexes) = tensor.sort_last_dim(true)?;
+    assert_eq!(
+        values.to_vec3::<u32>()?,
+        &[[[1, 3, 4], [1, 5, 9]], [[1, 2, 7], [2, 8, 8]]]
+    );
+    // Ties are ordered by index.
+    assert_eq!(
+        indexes.to_vec3::<u32>()?,
+        &[[[1, 0, 2], [0, 1, 2]], [[1, 0, 2], [1, 0, 2]]]
+    );
+    let (values, indexes) = tensor.sort_last_dim(false)?;
+    assert_eq!(
+        values.to_vec3::<u32>()?,
+        &[[[4, 3, 1], [9, 5, 1]], [[7, 2, 1], [8, 8, 2]]]
+    );
+    assert_eq!(
+        indexes.to_vec3::<u32>()?,
+        &[[[2, 0, 1], [2, 1, 0]], [[2, 0, 1], [0, 2, 1]]]
+    );
+    assert_eq!(
+        tensor.t()?.arg_sort_last_dim(true)?.to_vec3::<u32>()?,
+        &[[[1, 0], [0, 1], [0, 1]], [[0, 1], [0, 1], [0, 1]]]
+    );
+
+    // NaNs come last in both orders.
+    let tensor = Tensor::new(&[2f32, f32::NAN, -1., 3.5, -1.], device)?;
+    assert_eq!(
+        tensor.arg_sort_last_dim(true)?.to_vec1::<u32>()?,
+        &[2, 4, 0, 3, 1]
+    );
+    assert_eq!(
+        tensor.arg_sort_last_dim(false)?.to_vec1::<u32>()?,
+        &[3, 0, 2, 4, 1]
+    );
+
+    // Rows with more elements than threads per block.
+    let data: Vec<f32> = (0..3000).map(|i| ((i * 7919) % 3001) as f32).collect();
+    let tensor = Tensor::new(data.as_slice(), device)?.reshape((2, 1500))?;
+    let (values, indexes) = tensor.sort_last_dim(true)?;
+    let values = values.to_vec2::<f32>()?;
+    for (row, (values, indexes)) in values.iter().zip(indexes.to_vec2::<u32>()?).enumerate() {
+        assert!(values.windows(2).all(|w| w[0] <= w[1]));
+        for (v, i) in values.iter().zip(indexes.iter()) {
+            assert_eq!(*v, data[row * 1500 + *i as usize]);
+        }
+    }
+
+    let tensor = Tensor::new(&[[3f32, 1., 4., 1.], [5., 9., 2., 6.]], device)?;
+    let (values, indexes) = tensor.topk(3, 1)?;
+    assert_eq!(values.to_vec2::<f32>()?, &[[4., 3., 1.], [9., 6., 5.]]);
+    assert_eq!(indexes.to_vec2::<u32>()?, &[[2, 0, 1], [1, 3, 0]]);
+    let (values, indexes) = tensor.topk(1, 0)?;
+    assert_eq!(values.to_vec2::<f32>()?, &[[5., 9., 4., 6.]]);
+    assert_eq!(indexes.to_vec2::<u32>()?, &[[1, 1, 0, 1]]);
+    assert!(tensor.topk(5, 1).is_err());
+    Ok(())
+}
+
 fn argmax(device: &Device) -> Result<()> {
     let data = &[[[3u32, 1, 4], [1, 5, 9]], [[2, 1, 7], [8, 2, 8]]];
     let tensor = Tensor::new(data, device)?;
@@ -673,12 +736,16 @@ fn embeddings(device: &Device) -> Result<()> {
 fn cmp(device: &Device) -> Result<()> {
     let t1 = Tensor::new(&[[0f32, 1f32], [2f32, 3f32], [4f32, 5f32]], device)?;
     let t2 = Tensor::new(&[[1f32, 0f32], [3f32, 3f32], [4f32, 7f32]], device)?;
-    assert_eq!(t1.eq(&t2)?.to_vec2::<u8>()?, &[[0, 0], [0, 1], [1, 0]]);
-    assert_eq!(t1.ne(&t2)?.to_vec2::<u8>()?, &[[1, 1], [1, 0], [0, 1]]);
-    assert_eq!(t1.le(&t2)?.to_vec2::<u8>()?, &[[1, 0], [1, 1], [1, 1]]);
-    assert_eq!(t1.lt(&t2)?.to_vec2::<u8>()?, &[[1, 0], [1, 0], [0, 1]]);
-    assert_eq!(t1.gt(&t2)?.to_vec2::<u8>()?, &[[0, 1], [0, 0], [0, 0]]);
-    assert_eq!(t1.ge(&t2)?.to_vec2::<u8>()?, &[[0, 1], [0, 1], [1, 0]]);
+    if device.is_cpu() {
+        assert_eq!(t1.eq(&t2)?.dtype(), DType::Bool);
+    }
+    let to_u8 = |t: Tensor| t.to_dtype(DType::U8)?.to_vec2::<u8>();
+    assert_eq!(to_u8(t1.eq(&t2)?)?, &[[0, 0], [0, 1], [1, 0]]);
+    assert_eq!(to_u8(t1.ne(&t2)?)?, &[[1, 1], [1, 0], [0, 1]]);
+    assert_eq!(to_u8(t1.le(&t2)?)?, &[[1, 0], [1, 1], [1, 1]]);
+    assert_eq!(to_u8(t1.lt(&t2)?)?, &[[1, 0], [1, 0], [0, 1]]);
+    assert_eq!(to_u8(t1.gt(&t2)?)?, &[[0, 1], [0, 0], [0, 0]]);
+    assert_eq!(to_u8(t1.ge(&t2)?)?, &[[0, 1], [0, 1], [1, 0]]);
     Ok(())
 }
 
@@ -821,6 +888,37 @@ fn slice_scatter(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn slice_set(device: &Device) -> Result<()> {
+    let (b, h, max_t, d) = (2, 3, 4, 5);
+    let cache = Tensor::zeros((b, h, max_t, d), DType::F32, device)?;
+    let tensor = Tensor::randn(0f32, 1f32, (b, h, 4, d), device)?;
+    cache.slice_set(&tensor, 2, 0)?;
+    let diff = (cache.narrow(2, 0, 4)? - &tensor)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+    let cache = Tensor::zeros((b, h, max_t, d), DType::F32, device)?;
+    cache.slice_set(&tensor.narrow(2, 0, 1)?, 2, 1)?;
+    cache.slice_set(&tensor.narrow(2, 1, 2)?, 2, 2)?;
+    let diff = (cache.narrow(2, 1, 3)? - tensor.narrow(2, 0, 3)?)?
+        .abs()?
+        .sum_all()?
+        .to_vec0::<f32>()?;
+    assert_eq!(diff, 0.);
+    let untouched = cache.narrow(2, 0, 1)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+    assert_eq!(untouched, 0.);
+    // Non-contiguous sources are supported.
+    let cache = Tensor::zeros((3, 2), DType::F32, device)?;
+    let src = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], device)?.t()?;
+    cache.slice_set(&src, 0, 0)?;
+    assert_eq!(cache.to_vec2::<f32>()?, &[[1., 4.], [2., 5.], [3., 6.]]);
+    // The source has to fit in the destination.
+    assert!(cache.slice_set(&src, 1, 1).is_err());
+    assert!(cache.slice_set(&cache.narrow(0, 0, 1)?, 0, 1).is_err());
+    Ok(())
+}
+
 fn scatter_add(device: &Device) -> Result<()> {
     let t = Tensor::arange(0f32, 12f32, device)?.reshape((4, 3))?;
     assert_eq!(
@@ -1083,6 +1181,7 @@ test_device!(min, min_cpu, min_gpu, min_metal);
 test_device!(max, max_cpu, max_gpu, max_metal);
 test_device!(argmax, argmax_cpu, argmax_gpu, argmax_metal);
 test_device!(argmin, argmin_cpu, argmin_gpu, argmin_metal);
+test_device!(sort, sort_cpu, sort_gpu, sort_metal);
 test_device!(transpose, transpose_cpu, transpose_gpu, transpose_metal);
 test_device!(unary_op, unary_op_cpu, unary_op_gpu, unary_op_metal);
 test_device!(binary_op, binary_op_cpu, binary_op_gpu, binary_op_metal);
@@ -1121,6 +1220,7 @@ test_device!(
     slice_scatter_gpu,
     slice_scatter_metal
 );
+test_device!(slice_set, slice_set_cpu, slice_set_gpu, slice_set_metal);
 test_device!(randn, randn_cpu, randn_gpu, randn_metal);
 test_device!(clamp, clamp_cpu, clamp_gpu, clamp_metal);
 test_device!(var, var_cpu, var_gpu, var_metal);
@@ -1152,6 +1252,40 @@ fn pad_with_same() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn pad() -> Result<()> {
+    let t = Tensor::arange(1f32, 4f32, &Device::Cpu)?;
+    let pad = |mode| t.pad(&[(2, 2)], mode)?.to_vec1::<f32>();
+    assert_eq!(
+        pad(PadMode::Constant(-1.))?,
+        [-1., -1., 1., 2., 3., -1., -1.]
+    );
+    assert_eq!(pad(PadMode::Constant(0.))?, [0., 0., 1., 2., 3., 0., 0.]);
+    assert_eq!(pad(PadMode::Reflect)?, [3., 2., 1., 2., 3., 2., 1.]);
+    assert_eq!(pad(PadMode::Replicate)?, [1., 1., 1., 2., 3., 3., 3.]);
+    assert_eq!(pad(PadMode::Circular)?, [2., 3., 1., 2., 3., 1., 2.]);
+    assert!(t.pad(&[(3, 0)], PadMode::Reflect).is_err());
+    assert_eq!(
+        t.pad(&[(7, 1)], PadMode::Circular)?.to_vec1::<f32>()?,
+        [3., 1., 2., 3., 1., 2., 3., 1., 2., 3., 1.]
+    );
+
+    let t = Tensor::arange(0u32, 6u32, &Device::Cpu)?.reshape((1, 2, 3))?;
+    let t = t.pad(&[(1, 0), (0, 2)], PadMode::Replicate)?;
+    assert_eq!(
+        t.to_vec3::<u32>()?,
+        [[[0, 1, 2, 2, 2], [0, 1, 2, 2, 2], [3, 4, 5, 5, 5]]]
+    );
+    let t = Tensor::new(&[[1u8, 2]], &Device::Cpu)?;
+    assert_eq!(
+        t.pad(&[(1, 1), (0, 1)], PadMode::Constant(7.))?
+            .to_vec2::<u8>()?,
+        [[7, 7, 7], [1, 2, 7], [7, 7, 7]]
+    );
+    assert!(t.pad(&[(0, 0), (0, 0), (1, 1)], PadMode::Reflect).is_err());
+    Ok(())
+}
+
 #[test]
 fn i64_abs() -> Result<()> {
     let t = Tensor::new(&[-42i64, 1337], &Device::Cpu)?;
@@ -1160,6 +1294,61 @@ fn i64_abs() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn small_int_and_bool() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[-3i8, 0, 5], dev)?;
+    assert_eq!(t.dtype(), DType::I8);
+    assert_eq!(t.abs()?.to_vec1::<i8>()?, [3, 0, 5]);
+    assert_eq!((&t + &t)?.to_vec1::<i8>()?, [-6, 0, 10]);
+    let t = Tensor::new(&[-300i16, 2], dev)?;
+    assert_eq!(t.sum_all()?.to_scalar::<i16>()?, -298);
+    let t = Tensor::new(&[[1i32, -2], [3, 4]], dev)?;
+    assert_eq!(
+        t.to_dtype(DType::F32)?.to_vec2::<f32>()?,
+        [[1., -2.], [3., 4.]]
+    );
+    assert_eq!(t.max_keepdim(1)?.to_vec2::<i32>()?, [[1], [4]]);
+    assert_eq!(
+        t.to_dtype(DType::I8)?
+            .to_dtype(DType::I64)?
+            .to_vec2::<i64>()?,
+        [[1, -2], [3, 4]]
+    );
+
+    let b = t.to_dtype(DType::Bool)?;
+    assert_eq!(b.dtype(), DType::Bool);
+    let b = Tensor::new(&[2u8, 0, 1], dev)?.to_dtype(DType::Bool)?;
+    assert_eq!(b.to_vec1::<Bool>()?, [Bool(true), Bool(false), Bool(true)]);
+    assert_eq!(b.to_dtype(DType::U8)?.to_vec1::<u8>()?, [1, 0, 1]);
+    let b = Tensor::new(&[Bool(true), Bool(false), Bool(true)], dev)?;
+    let on_true = Tensor::new(&[1f32, 2., 3.], dev)?;
+    let on_false = Tensor::new(&[-1f32, -2., -3.], dev)?;
+    assert_eq!(
+        b.where_cond(&on_true, &on_false)?.to_vec1::<f32>()?,
+        [1., -2., 3.]
+    );
+    assert!(b.neg().is_err());
+    assert!(Tensor::new(&[1i32, 4], dev)?.sqrt().is_err());
+    let lhs = Tensor::new(&[Bool(true), Bool(false), Bool(true)], dev)?;
+    let rhs = Tensor::new(&[Bool(true), Bool(true), Bool(false)], dev)?;
+    let div = (&lhs / &rhs)?.to_vec1::<Bool>()?;
+    assert_eq!(div, [Bool(true), Bool(false), Bool(false)]);
+    assert_eq!(
+        (&lhs * &rhs)?.to_vec1::<Bool>()?,
+        [Bool(true), Bool(false), Bool(false)]
+    );
+    let t = Tensor::new(&[-3i8, 0, 5], dev)?;
+    assert_eq!(
+        t.ge(0i64)?.to_vec1::<Bool>()?,
+        [Bool(false), Bool(true), Bool(true)]
+    );
+
+    let ids = Tensor::new(&[2i32, 0], dev)?;
+    assert_eq!(on_true.index_select(&ids, 0)?.to_vec1::<f32>()?, [3., 1.]);
+    Ok(())
+}
+
 #[test]
 fn tril_triu_eye() -> Result<()> {
     let t = Tensor::tril2(4, DType::F32, &Device::Cpu)?;
@@ -1221,3 +1410,167 @@ fn cumsum() -> Result<()> {
     );
     Ok(())
 }
+
+#[test]
+fn extended_reductions() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[[0f32, 5.], [2., 3.]], [[4., 1.], [7., 6.]]], dev)?;
+    assert_eq!(t.max((1, 2))?.to_vec1::<f32>()?, [5., 7.]);
+    assert_eq!(t.min((1, 2))?.to_vec1::<f32>()?, [0., 1.]);
+    assert_eq!(t.max_keepdim((0, 2))?.to_vec3::<f32>()?, [[[5.], [7.]]]);
+    assert_eq!(t.argmax((0, 2))?.to_vec1::<u32>()?, [1, 2]);
+    assert_eq!(t.argmin((1, 2))?.to_vec1::<u32>()?, [0, 1]);
+    assert_eq!(t.max((0, 1, 2))?.to_scalar::<f32>()?, 7.);
+
+    assert_eq!(t.prod(2)?.to_vec2::<f32>()?, [[0., 6.], [4., 42.]]);
+    assert_eq!(t.prod((0, 1))?.to_vec1::<f32>()?, [0., 90.]);
+    assert_eq!(t.prod_keepdim(1)?.dims(), [2, 1, 2]);
+
+    assert_eq!(
+        test_utils::to_vec1_round(&t.var((1, 2))?, 4)?,
+        [4.3333, 7.0]
+    );
+    assert_eq!(
+        test_utils::to_vec1_round(&t.std((1, 2))?, 4)?,
+        [2.0817, 2.6458]
+    );
+    assert_eq!(t.std_keepdim((0, 1, 2))?.dims(), [1, 1, 1]);
+
+    let lse = Tensor::new(&[[1f32, 2., 3.], [-1e4, -1e4, -1e4]], dev)?.log_sum_exp(1)?;
+    assert_eq!(test_utils::to_vec1_round(&lse, 2)?, [3.41, -9998.9]);
+    let lse = t.log_sum_exp((1, 2))?;
+    let expected = t.exp()?.sum((1, 2))?.log()?;
+    assert_eq!(
+        test_utils::to_vec1_round(&lse, 4)?,
+        test_utils::to_vec1_round(&expected, 4)?
+    );
+    let lse = Tensor::new(&[f32::NEG_INFINITY, f32::NEG_INFINITY], dev)?.log_sum_exp(0)?;
+    assert_eq!(lse.to_scalar::<f32>()?, f32::NEG_INFINITY);
+
+    assert_eq!(
+        test_utils::to_vec1_round(&t.norm(NormOrd::Frobenius, (1, 2))?, 4)?,
+        [6.1644, 10.0995]
+    );
+    assert_eq!(
+        test_utils::to_vec1_round(&t.norm(NormOrd::L2, (1, 2))?, 4)?,
+        [6.1644, 10.0995]
+    );
+    assert_eq!(
+        t.norm(NormOrd::L1, 0)?.to_vec2::<f32>()?,
+        [[4., 6.], [9., 9.]]
+    );
+    assert_eq!(t.norm(NormOrd::Inf, (0, 1))?.to_vec1::<f32>()?, [7., 6.]);
+    assert_eq!(
+        t.zeros_like()?.norm(NormOrd::L2, 2)?.to_vec2::<f32>()?,
+        [[0., 0.], [0., 0.]]
+    );
+    assert!(t.norm(NormOrd::Frobenius, 1).is_err());
+
+    let to_u8 = |t: Tensor| t.to_dtype(DType::U8);
+    assert_eq!(to_u8(t.all(2)?)?.to_vec2::<u8>()?, [[0, 1], [1, 1]]);
+    assert_eq!(to_u8(t.all((0, 1))?)?.to_vec1::<u8>()?, [0, 1]);
+    assert_eq!(t.any_keepdim((1, 2))?.dims(), [2, 1, 1]);
+    let b = Tensor::new(&[[0u8, 0, 1], [0, 0, 0]], dev)?;
+    assert_eq!(to_u8(b.any(1)?)?.to_vec1::<u8>()?, [1, 0]);
+    assert_eq!(to_u8(b.any((0, 1))?)?.to_scalar::<u8>()?, 1);
+    assert_eq!(to_u8(b.all((0, 1))?)?.to_scalar::<u8>()?, 0);
+
+    let t = Tensor::new(&[1f32, 2., -1., 3., 0.5, 2., 1.], dev)?;
+    assert_eq!(
+        t.cumprod(0)?.to_vec1::<f32>()?,
+        [1., 2., -2., -6., -3., -6., -6.]
+    );
+    let t = Tensor::new(&[[1f32, 2.], [0., 3.], [4., 5.]], dev)?;
+    assert_eq!(
+        t.cumprod(0)?.to_vec2::<f32>()?,
+        [[1., 2.], [0., 6.], [0., 30.]]
+    );
+    let t = Tensor::new(&[3f32, 1., 4., 1., 5., 9., 2., 6., 5., 3., 9.], dev)?;
+    let (values, indexes) = t.cummax(0)?;
+    assert_eq!(
+        values.to_vec1::<f32>()?,
+        [3., 3., 4., 4., 5., 9., 9., 9., 9., 9., 9.]
+    );
+    assert_eq!(
+        indexes.to_vec1::<u32>()?,
+        [0, 0, 2, 2, 4, 5, 5, 5, 5, 5, 10]
+    );
+    let t = Tensor::new(&[[1f32, 5.], [3., 2.], [2., 7.]], dev)?;
+    let (values, indexes) = t.cummax(0)?;
+    assert_eq!(values.to_vec2::<f32>()?, [[1., 5.], [3., 5.], [3., 7.]]);
+    assert_eq!(indexes.to_vec2::<u32>()?, [[0, 0], [1, 0], [1, 2]]);
+    Ok(())
+}
+
+#[test]
+fn masking_and_scatter() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[1f32, -2., 3.], [-4., 5., -6.]], dev)?;
+    let mask = t.lt(0f32)?;
+    assert_eq!(
+        t.masked_fill(&mask, 0.)?.to_vec2::<f32>()?,
+        [[1., 0., 3.], [0., 5., 0.]]
+    );
+    // The mask is broadcasted to the shape of the tensor.
+    let mask = Tensor::new(&[Bool(true), Bool(false), Bool(true)], dev)?;
+    assert_eq!(
+        t.masked_fill(&mask, 9.)?.to_vec2::<f32>()?,
+        [[9., -2., 9.], [9., 5., 9.]]
+    );
+    assert_eq!(
+        t.t()?.masked_select(&t.t()?.gt(0f32)?)?.to_vec1::<f32>()?,
+        [1., 5., 3.]
+    );
+    assert_eq!(
+        t.masked_select(&mask)?.to_vec1::<f32>()?,
+        [1., 3., -4., -6.]
+    );
+
+    let ids = t.gt(0f32)?.nonzero()?;
+    assert_eq!(ids.to_vec2::<u32>()?, [[0, 0], [0, 2], [1, 1]]);
+    let ids = Tensor::new(&[0f32, 0.], dev)?.nonzero()?;
+    assert_eq!(ids.dims(), [0, 1]);
+
+    let ids = Tensor::new(&[[2u32, 0, 0], [1, 1, 0]], dev)?;
+    let src = Tensor::new(&[[10f32, 20., 30.], [40., 50., 60.]], dev)?;
+    let zeros = Tensor::zeros((3, 3), DType::F32, dev)?;
+    assert_eq!(
+        zeros.scatter(&ids, &src, 0)?.to_vec2::<f32>()?,
+        [[0., 20., 60.], [40., 50., 0.], [10., 0., 0.]]
+    );
+    let ids = Tensor::new(&[[2u32, 0], [1, 2]], dev)?;
+    let src = Tensor::new(&[[1f32, 2.], [3., 4.]], dev)?;
+    assert_eq!(
+        zeros.i(..2)?.scatter(&ids, &src, 1)?.to_vec2::<f32>()?,
+        [[2., 0., 1.], [0., 3., 4.]]
+    );
+    assert!(zeros.scatter(&ids, &src, 0).is_err());
+
+    let ids = Tensor::new(&[2u32, 0], dev)?;
+    let src = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], dev)?;
+    assert_eq!(
+        zeros.index_copy(&ids, &src, 1)?.to_vec2::<f32>()?,
+        [[2., 0., 1.], [4., 0., 3.], [6., 0., 5.]]
+    );
+
+    let t = Tensor::arange(0f32, 12., dev)?.reshape((2, 3, 2))?;
+    let i0 = Tensor::new(&[1u32, 0], dev)?;
+    let i1 = Tensor::new(&[[2u32], [1]], dev)?;
+    let values = Tensor::new(&[-1f32, -2.], dev)?;
+    // The index tensors broadcast to (2, 2), so four rows of size 2 are written.
+    let out = t.index_put(&[&i0, &i1], &values, false)?;
+    assert_eq!(
+        out.to_vec3::<f32>()?,
+        [
+            [[0., 1.], [-1., -2.], [-1., -2.]],
+            [[6., 7.], [-1., -2.], [-1., -2.]]
+        ]
+    );
+    let i0 = Tensor::new(&[0u32, 0, 1], dev)?;
+    let i2 = Tensor::new(&[1i64, 1, 0], dev)?;
+    let i1 = Tensor::new(&[2u8], dev)?;
+    let out = t.index_put(&[&i0, &i1, &i2], &values.i(0)?, true)?;
+    assert_eq!(out.i((.., 2))?.to_vec2::<f32>()?, [[4., 3.], [9., 11.]]);
+    assert!(t.index_put(&[], &values, true).is_err());
+    Ok(())
+}
@@ -45,6 +45,10 @@ struct Args {
     /// L2 normalization for embeddings.
     #[arg(long, default_value = "true")]
     normalize_embeddings: bool,
+
+    /// Use the fused scaled dot-product attention.
+    #[arg(long)]
+    use_sdpa: bool,
 }
 
 impl Args {
@@ -74,6 +78,7 @@ impl Args {
         };
         let config = std::fs::read_to_string(config_filename)?;
         let config: Config = serde_json::from_str(&config)?;
+        let config = config.with_sdpa(self.use_sdpa);
         let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
         let vb = if self.use_pth {
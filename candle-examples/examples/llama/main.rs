@@ -82,6 +82,11 @@ struct Args {
     #[arg(long)]
     use_flash_attn: bool,
 
+    /// Use the fused scaled dot-product attention, this reduces the memory usage on cpu for long
+    /// sequences.
+    #[arg(long)]
+    use_sdpa: bool,
+
     /// The folder name that contains safetensor weights and json files
     /// (same structure as huggingface online)
     #[arg(long)]
@@ -120,11 +125,12 @@ fn main() -> Result<()> {
     };
     let (llama, tokenizer_filename, cache) = match args.npy {
         Some(filename) => {
-            let config = if args.v1 {
+            let mut config = if args.v1 {
                 Config::config_7b_v1(args.use_flash_attn)
             } else {
                 Config::config_7b_v2(args.use_flash_attn)
             };
+            config.use_sdpa = args.use_sdpa;
             let cache = model::Cache::new(!args.no_kv_cache, dtype, &config, &device)?;
             let vb = VarBuilder::from_npz(filename, dtype, &device)?;
             let tokenizer = std::path::PathBuf::from("llama-tokenizer.json");
@@ -153,7 +159,8 @@ fn main() -> Result<()> {
                 _ => api.get("config.json")?,
             };
             let config: LlamaConfig = serde_json::from_slice(&std::fs::read(config_filename)?)?;
-            let config = config.into_config(args.use_flash_attn);
+            let mut config = config.into_config(args.use_flash_attn);
+            config.use_sdpa = args.use_sdpa;
 
             let mut filenames = vec![];
             for rfilename in [
@@ -47,6 +47,10 @@ struct Args {
     /// Text to be translated
     #[arg(long)]
     text: String,
+
+    /// Use beam search with this number of beams rather than greedy decoding.
+    #[arg(long)]
+    beam_size: Option<usize>,
 }
 
 pub fn main() -> anyhow::Result<()> {
@@ -125,6 +129,28 @@ pub fn main() -> anyhow::Result<()> {
         model.encoder().forward(&tokens, 0)?
     };
 
+    if let Some(beam_size) = args.beam_size {
+        use candle_transformers::generation::{BeamSearch, BeamSearchConfig};
+        let beam_config = BeamSearchConfig {
+            max_new_tokens: 512,
+            suppress_tokens: vec![config.pad_token_id],
+            ..BeamSearchConfig::new(beam_size, config.eos_token_id)
+        };
+        let hyps = BeamSearch::new(beam_config).generate(
+            &mut model,
+            &encoder_xs,
+            &[config.decoder_start_token_id],
+        )?;
+        if let Some(hyp) = hyps.first() {
+            let text = tokenizer_dec
+                .tokenizer()
+                .decode(&hyp.tokens, true)
+                .map_err(E::msg)?;
+            println!("{text}");
+        }
+        return Ok(());
+    }
+
     let mut token_ids = vec![config.decoder_start_token_id];
     for index in 0..1000 {
         let context_size = if index >= 1 { 1 } else { token_ids.len() };
@@ -136,6 +136,11 @@ struct Args {
     #[arg(long)]
     use_flash_attn: bool,
 
+    /// Use the fused scaled dot-product attention, this reduces the memory usage on cpu for long
+    /// sequences.
+    #[arg(long)]
+    use_sdpa: bool,
+
     #[arg(long)]
     prompt: String,
 
@@ -236,7 +241,8 @@ fn main() -> Result<()> {
     let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
 
     let start = std::time::Instant::now();
-    let config = Config::config_7b_v0_1(args.use_flash_attn);
+    let mut config = Config::config_7b_v0_1(args.use_flash_attn);
+    config.use_sdpa = args.use_sdpa;
     let (model, device) = if args.quantized {
         let filename = &filenames[0];
         let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename)?;
@@ -6,11 +6,14 @@ extern crate accelerate_src;
 
 use clap::{Parser, ValueEnum};
 use std::io::Write;
+use std::sync::Arc;
 use tokenizers::Tokenizer;
 
 use candle::quantized::{ggml_file, gguf_file};
 use candle::{Device, Tensor};
-use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::generation::{
+    Grammar, GrammarConstraint, LogitsProcessor, TokenVocabulary,
+};
 
 use candle_examples::token_output_stream::TokenOutputStream;
 use candle_transformers::models::quantized_llama as model;
@@ -209,6 +212,14 @@ struct Args {
     /// Group-Query Attention, use 8 for the 70B version of LLaMAv2.
     #[arg(long)]
     gqa: Option<usize>,
+
+    /// Constrain the output to the JSON documents matching the schema from this file.
+    #[arg(long)]
+    json_schema: Option<String>,
+
+    /// Constrain the output to a GBNF grammar read from this file.
+    #[arg(long, conflicts_with = "json_schema")]
+    grammar: Option<String>,
 }
 
 impl Args {
@@ -389,6 +400,21 @@ fn main() -> anyhow::Result<()> {
     println!("model built");
 
     let tokenizer = args.tokenizer()?;
+    let grammar = match (&args.json_schema, &args.grammar) {
+        (Some(schema), _) => {
+            let schema = serde_json::from_str(&std::fs::read_to_string(schema)?)?;
+            Some(Grammar::from_json_schema(&schema)?)
+        }
+        (None, Some(grammar)) => Some(Grammar::parse(&std::fs::read_to_string(grammar)?)?),
+        (None, None) => None,
+    };
+    let grammar = match grammar {
+        None => None,
+        Some(grammar) => {
+            let vocabulary = TokenVocabulary::from_tokenizer(&tokenizer)?;
+            Some((Arc::new(grammar), Arc::new(vocabulary)))
+        }
+    };
     let mut tos = TokenOutputStream::new(tokenizer);
     let prompt = match args.prompt.as_deref() {
         Some("chat") => Prompt::Chat,
@@ -449,7 +475,18 @@ fn main() -> anyhow::Result<()> {
             prompt_tokens
         };
         let mut all_tokens = vec![];
+        let eos_token = if args.which.is_open_chat() {
+            "<|end_of_turn|>"
+        } else {
+            "</s>"
+        };
+        let eos_token = *tos.tokenizer().get_vocab(true).get(eos_token).unwrap();
         let mut logits_processor = LogitsProcessor::new(args.seed, temperature, args.top_p);
+        if let Some((grammar, vocabulary)) = &grammar {
+            let constraint =
+                GrammarConstraint::new(grammar.clone(), vocabulary.clone(), &[eos_token])?;
+            logits_processor = logits_processor.with_constraint(constraint)
+        }
 
         let start_prompt_processing = std::time::Instant::now();
         let mut next_token = {
@@ -465,12 +502,6 @@ fn main() -> anyhow::Result<()> {
             std::io::stdout().flush()?;
         }
 
-        let eos_token = if args.which.is_open_chat() {
-            "<|end_of_turn|>"
-        } else {
-            "</s>"
-        };
-        let eos_token = *tos.tokenizer().get_vocab(true).get(eos_token).unwrap();
         let start_post_prompt = std::time::Instant::now();
         let mut sampled = 0;
         for index in 0..to_sample {
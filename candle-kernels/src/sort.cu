@@ -0,0 +1,84 @@
+// Bitonic argsort along the last dimension of a contiguous tensor, each block is
+// responsible for sorting one row. The number of columns is padded to a power of
+// two, the padding indexes being sorted after all the actual values.
+#include "cuda_utils.cuh"
+#include <stdint.h>
+
+// Returns true if the value at index a comes before the value at index b. NaNs
+// come last and ties are broken using the index so that the sort is stable.
+template <typename T, bool ASC>
+__device__ bool sort_before(const T *row, const uint32_t a, const uint32_t b,
+                            const size_t ncols) {
+  if (a >= ncols) return false;
+  if (b >= ncols) return true;
+  const T va = row[a];
+  const T vb = row[b];
+  const bool a_nan = va != va;
+  const bool b_nan = vb != vb;
+  if (a_nan || b_nan) {
+    return a_nan == b_nan ? a < b : b_nan;
+  }
+  if (va < vb) return ASC;
+  if (vb < va) return !ASC;
+  return a < b;
+}
+
+// idx is a scratch buffer with ncols_pad elements per row.
+template <typename T, bool ASC>
+__device__ void asort(const T *src, uint32_t *idx, uint32_t *dst,
+                      const size_t ncols, const size_t ncols_pad) {
+  const T *row = src + blockIdx.x * ncols;
+  uint32_t *row_idx = idx + blockIdx.x * ncols_pad;
+  for (size_t col = threadIdx.x; col < ncols_pad; col += blockDim.x) {
+    row_idx[col] = col;
+  }
+  __syncthreads();
+  for (size_t k = 2; k <= ncols_pad; k *= 2) {
+    for (size_t j = k / 2; j > 0; j /= 2) {
+      for (size_t col = threadIdx.x; col < ncols_pad; col += blockDim.x) {
+        const size_t ixj = col ^ j;
+        if (ixj > col) {
+          const uint32_t a = row_idx[col];
+          const uint32_t b = row_idx[ixj];
+          const bool swap = (col & k) == 0
+                                ? sort_before<T, ASC>(row, b, a, ncols)
+                                : sort_before<T, ASC>(row, a, b, ncols);
+          if (swap) {
+            row_idx[col] = b;
+            row_idx[ixj] = a;
+          }
+        }
+      }
+      __syncthreads();
+    }
+  }
+  for (size_t col = threadIdx.x; col < ncols; col += blockDim.x) {
+    dst[blockIdx.x * ncols + col] = row_idx[col];
+  }
+}
+
+#define ASORT_OP(TYPENAME, ASC_NAME, DESC_NAME)                                \
+  extern "C" __global__ void ASC_NAME(const TYPENAME *src, uint32_t *idx,      \
+                                      uint32_t *dst, const size_t ncols,       \
+                                      const size_t ncols_pad) {                \
+    asort<TYPENAME, true>(src, idx, dst, ncols, ncols_pad);                    \
+  }                                                                            \
+  extern "C" __global__ void DESC_NAME(const TYPENAME *src, uint32_t *idx,     \
+                                       uint32_t *dst, const size_t ncols,      \
+                                       const size_t ncols_pad) {               \
+    asort<TYPENAME, false>(src, idx, dst, ncols, ncols_pad);                   \
+  }
+
+#if __CUDA_ARCH__ >= 800
+ASORT_OP(__nv_bfloat16, asort_asc_bf16, asort_desc_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+ASORT_OP(__half, asort_asc_f16, asort_desc_f16)
+#endif
+
+ASORT_OP(float, asort_asc_f32, asort_desc_f32)
+ASORT_OP(double, asort_asc_f64, asort_desc_f64)
+ASORT_OP(uint8_t, asort_asc_u8, asort_desc_u8)
+ASORT_OP(uint32_t, asort_asc_u32, asort_desc_u32)
+ASORT_OP(int64_t, asort_asc_i64, asort_desc_i64)
@@ -269,6 +269,116 @@ impl Benchmark for SoftmaxLastDim {
     const ITERS: usize = 100;
 }
 
+// The feed-forward block of bert-base, on a sequence of 128 tokens.
+struct BertMlpWeights {
+    xs: Tensor,
+    w1: Tensor,
+    b1: Tensor,
+    w2: Tensor,
+    b2: Tensor,
+}
+
+impl BertMlpWeights {
+    fn new() -> Result<Self> {
+        let dev = &Device::Cpu;
+        Ok(Self {
+            xs: Tensor::randn(0f32, 1., (128, 768), dev)?,
+            w1: Tensor::randn(0f32, 0.02, (3072, 768), dev)?,
+            b1: Tensor::randn(0f32, 0.02, 3072, dev)?,
+            w2: Tensor::randn(0f32, 0.02, (768, 3072), dev)?,
+            b2: Tensor::randn(0f32, 0.02, 768, dev)?,
+        })
+    }
+}
+
+struct BertMlp;
+impl Benchmark for BertMlp {
+    type PreProcessData = BertMlpWeights;
+    type RunResult = Tensor;
+    fn preprocess() -> Result<Self::PreProcessData> {
+        BertMlpWeights::new()
+    }
+
+    fn run_one(d: &Self::PreProcessData) -> Result<Self::RunResult> {
+        let h = d.xs.matmul(&d.w1.t()?)?.broadcast_add(&d.b1)?.gelu()?;
+        h.matmul(&d.w2.t()?)?.broadcast_add(&d.b2)? + &d.xs
+    }
+
+    const ITERS: usize = 100;
+}
+
+struct BertMlpLazy;
+impl Benchmark for BertMlpLazy {
+    type PreProcessData = BertMlpWeights;
+    type RunResult = Tensor;
+    fn preprocess() -> Result<Self::PreProcessData> {
+        BertMlpWeights::new()
+    }
+
+    fn run_one(d: &Self::PreProcessData) -> Result<Self::RunResult> {
+        let h = d.xs.matmul(&d.w1.t()?)?.lazy();
+        let h = h.broadcast_add(&d.b1.lazy())?.gelu().materialize()?;
+        let ys = h.matmul(&d.w2.t()?)?.lazy().broadcast_add(&d.b2.lazy())?;
+        ys.add(&d.xs.lazy())?.materialize()
+    }
+
+    const ITERS: usize = 100;
+}
+
+// The feed-forward block of a 1B llama model, on a sequence of 32 tokens.
+struct LlamaMlpWeights {
+    xs: Tensor,
+    w1: Tensor,
+    w2: Tensor,
+    w3: Tensor,
+}
+
+impl LlamaMlpWeights {
+    fn new() -> Result<Self> {
+        let dev = &Device::Cpu;
+        Ok(Self {
+            xs: Tensor::randn(0f32, 1., (32, 2048), dev)?,
+            w1: Tensor::randn(0f32, 0.02, (5632, 2048), dev)?,
+            w2: Tensor::randn(0f32, 0.02, (2048, 5632), dev)?,
+            w3: Tensor::randn(0f32, 0.02, (5632, 2048), dev)?,
+        })
+    }
+}
+
+struct LlamaMlp;
+impl Benchmark for LlamaMlp {
+    type PreProcessData = LlamaMlpWeights;
+    type RunResult = Tensor;
+    fn preprocess() -> Result<Self::PreProcessData> {
+        LlamaMlpWeights::new()
+    }
+
+    fn run_one(d: &Self::PreProcessData) -> Result<Self::RunResult> {
+        let gate = candle_nn::ops::silu(&d.xs.matmul(&d.w1.t()?)?)?;
+        let h = (gate * d.xs.matmul(&d.w3.t()?)?)?;
+        h.matmul(&d.w2.t()?)
+    }
+
+    const ITERS: usize = 100;
+}
+
+struct LlamaMlpLazy;
+impl Benchmark for LlamaMlpLazy {
+    type PreProcessData = LlamaMlpWeights;
+    type RunResult = Tensor;
+    fn preprocess() -> Result<Self::PreProcessData> {
+        LlamaMlpWeights::new()
+    }
+
+    fn run_one(d: &Self::PreProcessData) -> Result<Self::RunResult> {
+        let gate = d.xs.matmul(&d.w1.t()?)?.lazy().silu()?;
+        let h = gate.mul(&d.xs.matmul(&d.w3.t()?)?.lazy())?.materialize()?;
+        h.matmul(&d.w2.t()?)
+    }
+
+    const ITERS: usize = 100;
+}
+
 fn run<B: Benchmark>(iters: Option<usize>) -> Result<()> {
     use std::hint::black_box;
 
@@ -292,6 +402,10 @@ enum Task {
     Qmatmul,
     Softmax,
     SoftmaxLastDim,
+    BertMlp,
+    BertMlpLazy,
+    LlamaMlp,
+    LlamaMlpLazy,
 }
 
 #[derive(Parser, Debug)]
@@ -316,6 +430,10 @@ fn main() -> Result<()> {
         Task::Softmax => run::<Softmax>(args.iters)?,
         Task::SoftmaxLastDim => run::<SoftmaxLastDim>(args.iters)?,
         Task::Qmatmul => run::<QMatMul>(args.iters)?,
+        Task::BertMlp => run::<BertMlp>(args.iters)?,
+        Task::BertMlpLazy => run::<BertMlpLazy>(args.iters)?,
+        Task::LlamaMlp => run::<LlamaMlp>(args.iters)?,
+        Task::LlamaMlpLazy => run::<LlamaMlpLazy>(args.iters)?,
     }
     Ok(())
 }
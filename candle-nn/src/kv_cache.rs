@@ -0,0 +1,358 @@
+//! Cache for the keys and values of attention layers.
+//!
+//! The cached entries are stored in a preallocated buffer and new entries are written in place,
+//! so that extending the cache by a single position does not copy the previous entries. The
+//! buffer grows geometrically when its capacity is exceeded.
+use candle::{Result, Tensor};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+static NEXT_CACHE_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn next_cache_id() -> usize {
+    NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug)]
+struct Buffer {
+    data: Tensor,
+    // The id of the cache that allocated this buffer, other caches that end up sharing the
+    // buffer via a snapshot never write to it.
+    owner: usize,
+    // The number of leading positions that are referenced by a snapshot and so cannot be
+    // overwritten.
+    protected_len: AtomicUsize,
+}
+
+impl Buffer {
+    fn capacity(&self, dim: usize) -> usize {
+        self.data.dims()[dim]
+    }
+}
+
+/// A snapshot of the state of a [`Cache`], see [`Cache::snapshot`].
+#[derive(Debug, Clone)]
+pub struct CacheSnapshot {
+    buffer: Option<Arc<Buffer>>,
+    len: usize,
+    offset: usize,
+}
+
+/// A cache for a single tensor that gets extended along dimension `dim`.
+///
+/// When a sliding window is used, only the last `sliding_window` positions are returned by
+/// [`Cache::append`] in addition to the newly appended ones, the older positions are evicted.
+#[derive(Debug)]
+pub struct Cache {
+    id: usize,
+    buffer: Option<Arc<Buffer>>,
+    dim: usize,
+    // The number of positions stored in the buffer.
+    len: usize,
+    // The absolute position of the first entry of the buffer, this is non-zero once some
+    // positions have been evicted by the sliding window.
+    offset: usize,
+    capacity: usize,
+    sliding_window: Option<usize>,
+}
+
+impl Clone for Cache {
+    fn clone(&self) -> Self {
+        // The clone shares the buffer with the original cache but gets its own id so that it
+        // copies the buffer before writing to it.
+        Self {
+            id: next_cache_id(),
+            buffer: self.buffer.clone(),
+            dim: self.dim,
+            len: self.len,
+            offset: self.offset,
+            capacity: self.capacity,
+            sliding_window: self.sliding_window,
+        }
+    }
+}
+
+impl Cache {
+    /// Creates an empty cache, the buffer is allocated on the first call to [`Cache::append`]
+    /// with room for `capacity` positions along `dim`, or more if the appended tensor does not
+    /// fit.
+    pub fn new(dim: usize, capacity: usize) -> Self {
+        Self {
+            id: next_cache_id(),
+            buffer: None,
+            dim,
+            len: 0,
+            offset: 0,
+            capacity: usize::max(capacity, 1),
+            sliding_window: None,
+        }
+    }
+
+    /// Only keeps the last `sliding_window` positions. The capacity should be larger than the
+    /// window so that the evictions only result in a copy once in a while.
+    pub fn with_sliding_window(mut self, sliding_window: usize) -> Self {
+        self.sliding_window = Some(sliding_window);
+        self
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn sliding_window(&self) -> Option<usize> {
+        self.sliding_window
+    }
+
+    /// The number of positions that have been appended to the cache, including the evicted
+    /// ones.
+    pub fn current_seq_len(&self) -> usize {
+        self.offset + self.len
+    }
+
+    /// The number of positions that the buffer can hold before having to be reallocated.
+    pub fn capacity(&self) -> usize {
+        match &self.buffer {
+            None => self.capacity,
+            Some(buffer) => buffer.capacity(self.dim),
+        }
+    }
+
+    // The number of retained positions at the end of the buffer that are visible.
+    fn visible_len(&self, len: usize) -> usize {
+        match self.sliding_window {
+            None => len,
+            Some(sliding_window) => usize::min(len, sliding_window),
+        }
+    }
+
+    /// The cached positions that are still visible, `None` if the cache is empty.
+    pub fn current_data(&self) -> Result<Option<Tensor>> {
+        match &self.buffer {
+            Some(buffer) if self.len > 0 => {
+                let visible_len = self.visible_len(self.len);
+                let data = buffer
+                    .data
+                    .narrow(self.dim, self.len - visible_len, visible_len)?;
+                Ok(Some(data))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Empties the cache, the buffer is kept so that it can be reused.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.offset = 0;
+    }
+
+    // Copies the visible positions to a new buffer with room for at least `extra` additional
+    // positions.
+    fn reallocate(&mut self, src: &Tensor, extra: usize) -> Result<()> {
+        let (keep, prev) = match &self.buffer {
+            None => (0, None),
+            Some(buffer) => (self.visible_len(self.len), Some(buffer.data.clone())),
+        };
+        let mut capacity = self.capacity();
+        if keep + extra > capacity {
+            capacity = usize::max(2 * capacity, keep + extra)
+        }
+        let mut dims = src.dims().to_vec();
+        dims[self.dim] = capacity;
+        let data = Tensor::zeros(dims, src.dtype(), src.device())?;
+        if let Some(prev) = prev {
+            if keep > 0 {
+                data.slice_set(&prev.narrow(self.dim, self.len - keep, keep)?, self.dim, 0)?;
+            }
+        }
+        self.offset += self.len - keep;
+        self.len = keep;
+        self.capacity = capacity;
+        self.buffer = Some(Arc::new(Buffer {
+            data,
+            owner: self.id,
+            protected_len: AtomicUsize::new(0),
+        }));
+        Ok(())
+    }
+
+    /// Appends `src` to the cache and returns the visible cached positions, this includes the
+    /// newly appended ones.
+    ///
+    /// The returned tensor is a view on the cache buffer, its content may change if the cache
+    /// is rolled back and new positions are appended.
+    pub fn append(&mut self, src: &Tensor) -> Result<Tensor> {
+        let seq_len = src.dim(self.dim)?;
+        // Appending to an empty cache with a different shape, e.g. after a reset, uses a new
+        // buffer.
+        if let Some(buffer) = &self.buffer {
+            let (buffer_dims, src_dims) = (buffer.data.dims(), src.dims());
+            let same_dims = buffer_dims.len() == src_dims.len()
+                && (0..src_dims.len()).all(|d| d == self.dim || buffer_dims[d] == src_dims[d]);
+            if self.len == 0 && (!same_dims || buffer.data.dtype() != src.dtype()) {
+                self.buffer = None
+            }
+        }
+        let can_write = match &self.buffer {
+            None => false,
+            Some(buffer) => {
+                buffer.owner == self.id
+                    && self.len >= buffer.protected_len.load(Ordering::Relaxed)
+                    && self.len + seq_len <= buffer.capacity(self.dim)
+            }
+        };
+        if !can_write {
+            self.reallocate(src, seq_len)?
+        }
+        let data = match &self.buffer {
+            None => candle::bail!("kv-cache buffer has not been allocated"),
+            Some(buffer) => &buffer.data,
+        };
+        data.slice_set(src, self.dim, self.len)?;
+        let prev_len = self.visible_len(self.len);
+        let start = self.len - prev_len;
+        self.len += seq_len;
+        data.narrow(self.dim, start, self.len - start)
+    }
+
+    /// Rolls the cache back so that only the first `seq_len` positions are kept.
+    ///
+    /// With a sliding window, this fails if some of the positions that would be visible have
+    /// already been evicted.
+    pub fn rollback(&mut self, seq_len: usize) -> Result<()> {
+        let current_seq_len = self.current_seq_len();
+        if seq_len > current_seq_len {
+            candle::bail!("cannot rollback kv-cache to {seq_len}, current length {current_seq_len}")
+        }
+        let visible_len = self.visible_len(seq_len);
+        if seq_len < self.offset + visible_len {
+            candle::bail!("cannot rollback kv-cache to {seq_len}, positions have been evicted")
+        }
+        self.len = seq_len - self.offset;
+        Ok(())
+    }
+
+    /// Returns a snapshot of the current state of the cache. This does not copy the cached
+    /// data, the positions covered by the snapshot are instead never overwritten in place.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        if let Some(buffer) = &self.buffer {
+            buffer.protected_len.fetch_max(self.len, Ordering::Relaxed);
+        }
+        CacheSnapshot {
+            buffer: self.buffer.clone(),
+            len: self.len,
+            offset: self.offset,
+        }
+    }
+
+    /// Restores the state of the cache from a snapshot.
+    pub fn restore(&mut self, snapshot: &CacheSnapshot) {
+        self.buffer = snapshot.buffer.clone();
+        self.len = snapshot.len;
+        self.offset = snapshot.offset;
+    }
+
+    /// Selects some entries of the cached data along dimension `dim`, e.g. to reorder the
+    /// batch elements after a beam search step.
+    pub fn index_select(&mut self, indexes: &Tensor, dim: usize) -> Result<()> {
+        if let Some(buffer) = &self.buffer {
+            let data = buffer.data.index_select(indexes, dim)?;
+            self.buffer = Some(Arc::new(Buffer {
+                data,
+                owner: self.id,
+                protected_len: AtomicUsize::new(0),
+            }));
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the state of a [`KvCache`], see [`KvCache::snapshot`].
+#[derive(Debug, Clone)]
+pub struct KvCacheSnapshot {
+    k: CacheSnapshot,
+    v: CacheSnapshot,
+}
+
+/// A cache for the keys and values of an attention layer.
+#[derive(Debug, Clone)]
+pub struct KvCache {
+    k: Cache,
+    v: Cache,
+}
+
+impl KvCache {
+    /// Creates an empty cache where keys and values get extended along dimension `dim` and with
+    /// an initial capacity of `capacity` positions.
+    pub fn new(dim: usize, capacity: usize) -> Self {
+        Self {
+            k: Cache::new(dim, capacity),
+            v: Cache::new(dim, capacity),
+        }
+    }
+
+    /// Only keeps the last `sliding_window` positions, see [`Cache::with_sliding_window`].
+    pub fn with_sliding_window(self, sliding_window: usize) -> Self {
+        Self {
+            k: self.k.with_sliding_window(sliding_window),
+            v: self.v.with_sliding_window(sliding_window),
+        }
+    }
+
+    pub fn k_cache(&self) -> &Cache {
+        &self.k
+    }
+
+    pub fn v_cache(&self) -> &Cache {
+        &self.v
+    }
+
+    pub fn k(&self) -> Result<Option<Tensor>> {
+        self.k.current_data()
+    }
+
+    pub fn v(&self) -> Result<Option<Tensor>> {
+        self.v.current_data()
+    }
+
+    /// Appends the keys and values for some new positions and returns the visible keys and
+    /// values, see [`Cache::append`].
+    pub fn append(&mut self, k: &Tensor, v: &Tensor) -> Result<(Tensor, Tensor)> {
+        let k = self.k.append(k)?;
+        let v = self.v.append(v)?;
+        Ok((k, v))
+    }
+
+    pub fn current_seq_len(&self) -> usize {
+        self.k.current_seq_len()
+    }
+
+    pub fn reset(&mut self) {
+        self.k.reset();
+        self.v.reset();
+    }
+
+    /// Rolls the cache back so that only the first `seq_len` positions are kept.
+    pub fn rollback(&mut self, seq_len: usize) -> Result<()> {
+        self.k.rollback(seq_len)?;
+        self.v.rollback(seq_len)
+    }
+
+    /// Returns a snapshot of the current state of the cache, see [`Cache::snapshot`].
+    pub fn snapshot(&self) -> KvCacheSnapshot {
+        KvCacheSnapshot {
+            k: self.k.snapshot(),
+            v: self.v.snapshot(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &KvCacheSnapshot) {
+        self.k.restore(&snapshot.k);
+        self.v.restore(&snapshot.v);
+    }
+
+    /// Selects some entries of the cached keys and values along dimension `dim`.
+    pub fn index_select(&mut self, indexes: &Tensor, dim: usize) -> Result<()> {
+        self.k.index_select(indexes, dim)?;
+        self.v.index_select(indexes, dim)
+    }
+}
@@ -60,13 +60,8 @@ pub fn mse(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
 ///
 /// The resulting tensor is a scalar containing the average value over the batch.
 pub fn binary_cross_entropy_with_logit(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
-    let inp = crate::ops::sigmoid(inp)?;
-
-    let left_side = target * inp.log()?;
-    let right_side = (target.affine(-1., 1.))? * inp.affine(-1., 1.)?.log()?;
-
-    let loss = left_side? + right_side?;
-    let loss = loss?.neg()?.mean_all()?;
-
-    Ok(loss)
+    // -t * log(sigmoid(x)) - (1 - t) * log(1 - sigmoid(x)) simplifies to log(1 + exp(x)) - t * x,
+    // the first term is computed as a log-sum-exp so that it does not overflow.
+    let softplus = Tensor::stack(&[&inp.zeros_like()?, inp], 0)?.log_sum_exp(0)?;
+    (softplus - (target * inp)?)?.mean_all()
 }
@@ -27,11 +27,7 @@ pub fn softmax<D: candle::shape::Dim>(xs: &Tensor, dim: D) -> Result<Tensor> {
 
 pub fn log_softmax<D: candle::shape::Dim>(xs: &Tensor, d: D) -> Result<Tensor> {
     let d = d.to_index(xs.shape(), "log-softmax")?;
-    let max = xs.max_keepdim(d)?;
-    let diff = xs.broadcast_sub(&max)?;
-    let sum_exp = diff.exp()?.sum_keepdim(d)?;
-    let log_sm = diff.broadcast_sub(&sum_exp.log()?)?;
-    Ok(log_sm)
+    xs.log_softmax(d)
 }
 
 pub fn silu(xs: &Tensor) -> Result<Tensor> {
@@ -71,7 +67,7 @@ pub fn dropout(xs: &Tensor, drop_p: f32) -> Result<Tensor> {
     let rand = Tensor::rand(0f32, 1f32, xs.shape(), xs.device())?;
     let scale = 1.0 / (1.0 - drop_p as f64);
     let drop_p = Tensor::new(drop_p, xs.device())?.broadcast_as(xs.shape())?;
-    let mask = (rand.ge(&drop_p)? * scale)?.to_dtype(xs.dtype())?;
+    let mask = (rand.ge(&drop_p)?.to_dtype(xs.dtype())? * scale)?;
     xs * mask
 }
 
@@ -233,15 +229,421 @@ pub fn pixel_unshuffle(xs: &Tensor, downscale_factor: usize) -> Result<Tensor> {
 
 // https://pytorch.org/docs/stable/generated/torch.nn.ReplicationPad2d.html
 pub fn replication_pad2d(xs: &Tensor, pad: usize) -> Result<Tensor> {
-    match pad {
-        0 => Ok(xs.clone()),
-        1 => {
-            let (_b_size, _c, h, w) = xs.dims4()?;
-            let (first, last) = (xs.narrow(3, 0, 1)?, xs.narrow(3, w - 1, 1)?);
-            let xs = Tensor::cat(&[&first, xs, &last], 3)?;
-            let (first, last) = (xs.narrow(2, 0, 1)?, xs.narrow(2, h - 1, 1)?);
-            Tensor::cat(&[&first, &xs, &last], 2)
+    let (_b_size, _c, _h, _w) = xs.dims4()?;
+    xs.pad(&[(pad, pad), (pad, pad)], candle::PadMode::Replicate)
+}
+
+// The number of queries and of keys/values processed together by the cpu attention kernel.
+const SDPA_BLOCK_Q: usize = 32;
+const SDPA_BLOCK_KV: usize = 64;
+
+// Indexes a rank 4 strided layout.
+#[derive(Debug, Clone, Copy)]
+struct Strided4 {
+    offset: usize,
+    stride: [usize; 4],
+}
+
+impl Strided4 {
+    fn new(layout: &Layout) -> Result<Self> {
+        let stride: [usize; 4] = match layout.stride().try_into() {
+            Ok(stride) => stride,
+            Err(_) => candle::bail!("sdpa expects rank 4 tensors, got {:?}", layout.shape()),
+        };
+        Ok(Self {
+            offset: layout.start_offset(),
+            stride,
+        })
+    }
+
+    fn index(&self, i0: usize, i1: usize, i2: usize, i3: usize) -> usize {
+        self.offset
+            + i0 * self.stride[0]
+            + i1 * self.stride[1]
+            + i2 * self.stride[2]
+            + i3 * self.stride[3]
+    }
+}
+
+struct Sdpa {
+    scale: f32,
+    causal: bool,
+    // When causal, query `i` only attends to the keys that are at most `sliding_window`
+    // positions before it.
+    sliding_window: Option<usize>,
+    // An additive f32 mask broadcasted to `(b_size, num_heads, seq_len_q, seq_len_kv)`.
+    mask: Option<Tensor>,
+}
+
+impl Sdpa {
+    // Flash attention: the queries are processed by blocks and the keys/values by tiles, the
+    // softmax is computed online so that the attention matrix is never materialized.
+    fn cpu_fwd_t<T: candle::WithDType>(
+        &self,
+        (q, q_l): (&[T], &Layout),
+        (k, k_l): (&[T], &Layout),
+        (v, v_l): (&[T], &Layout),
+        mask: Option<(&[f32], &Layout)>,
+    ) -> Result<(CpuStorage, Shape)> {
+        let (b_size, num_heads, seq_len_q, head_dim) = q_l.shape().dims4()?;
+        let (_, num_kv_heads, seq_len_kv, head_dim_v) = v_l.shape().dims4()?;
+        let n_rep = num_heads / num_kv_heads;
+        let (q_s, k_s, v_s) = (
+            Strided4::new(q_l)?,
+            Strided4::new(k_l)?,
+            Strided4::new(v_l)?,
+        );
+        let mask = match mask {
+            None => None,
+            Some((mask, mask_l)) => Some((mask, Strided4::new(mask_l)?)),
+        };
+        // Query `i` attends to the keys up to `i + offset` when causal.
+        let offset = seq_len_kv as isize - seq_len_q as isize;
+        let (scale, causal) = (self.scale, self.causal);
+        let window = self.sliding_window.filter(|_| causal);
+        let mut dst = vec![T::zero(); b_size * num_heads * seq_len_q * head_dim_v];
+        dst.par_chunks_mut(seq_len_q * head_dim_v)
+            .enumerate()
+            .for_each(|(bh, dst)| {
+                let (b_idx, h_idx) = (bh / num_heads, bh % num_heads);
+                let h_kv_idx = h_idx / n_rep;
+                let mut q_tile = vec![0f32; SDPA_BLOCK_Q * head_dim];
+                let mut k_tile = vec![0f32; SDPA_BLOCK_KV * head_dim];
+                let mut v_tile = vec![0f32; SDPA_BLOCK_KV * head_dim_v];
+                let mut acc = vec![0f32; SDPA_BLOCK_Q * head_dim_v];
+                let mut row_max = [f32::NEG_INFINITY; SDPA_BLOCK_Q];
+                let mut row_sum = [0f32; SDPA_BLOCK_Q];
+                let mut scores = [0f32; SDPA_BLOCK_KV];
+                for q_start in (0..seq_len_q).step_by(SDPA_BLOCK_Q) {
+                    let n_q = SDPA_BLOCK_Q.min(seq_len_q - q_start);
+                    for i in 0..n_q {
+                        for x in 0..head_dim {
+                            let q = q[q_s.index(b_idx, h_idx, q_start + i, x)].to_f64() as f32;
+                            q_tile[i * head_dim + x] = q * scale
+                        }
+                    }
+                    acc.fill(0.);
+                    row_max.fill(f32::NEG_INFINITY);
+                    row_sum.fill(0.);
+                    // The keys past this point are masked for all the queries of the block.
+                    let kv_end = if causal {
+                        ((q_start + n_q) as isize + offset).clamp(0, seq_len_kv as isize) as usize
+                    } else {
+                        seq_len_kv
+                    };
+                    // The keys before this point are out of the sliding window for all the
+                    // queries of the block.
+                    let kv_begin = match window {
+                        None => 0,
+                        Some(w) => (q_start as isize + offset - w as isize)
+                            .clamp(0, kv_end as isize) as usize,
+                    };
+                    for kv_start in (kv_begin..kv_end).step_by(SDPA_BLOCK_KV) {
+                        let n_kv = SDPA_BLOCK_KV.min(kv_end - kv_start);
+                        for j in 0..n_kv {
+                            for x in 0..head_dim {
+                                let k = k[k_s.index(b_idx, h_kv_idx, kv_start + j, x)];
+                                k_tile[j * head_dim + x] = k.to_f64() as f32
+                            }
+                            for x in 0..head_dim_v {
+                                let v = v[v_s.index(b_idx, h_kv_idx, kv_start + j, x)];
+                                v_tile[j * head_dim_v + x] = v.to_f64() as f32
+                            }
+                        }
+                        for i in 0..n_q {
+                            let q_row = &q_tile[i * head_dim..(i + 1) * head_dim];
+                            let q_pos = (q_start + i) as isize + offset;
+                            let mut tile_max = f32::NEG_INFINITY;
+                            for (j, score) in scores.iter_mut().enumerate().take(n_kv) {
+                                let kv_pos = kv_start + j;
+                                let mut s = if causal && kv_pos as isize > q_pos
+                                    || window.is_some_and(|w| q_pos - kv_pos as isize > w as isize)
+                                {
+                                    f32::NEG_INFINITY
+                                } else {
+                                    let k_row = &k_tile[j * head_dim..(j + 1) * head_dim];
+                                    q_row.iter().zip(k_row.iter()).map(|(q, k)| q * k).sum()
+                                };
+                                if let Some((mask, mask_s)) = mask {
+                                    s += mask[mask_s.index(b_idx, h_idx, q_start + i, kv_pos)]
+                                }
+                                *score = s;
+                                tile_max = tile_max.max(s);
+                            }
+                            if tile_max == f32::NEG_INFINITY {
+                                continue;
+                            }
+                            let new_max = row_max[i].max(tile_max);
+                            let correction = (row_max[i] - new_max).exp();
+                            let acc = &mut acc[i * head_dim_v..(i + 1) * head_dim_v];
+                            if correction != 1. {
+                                acc.iter_mut().for_each(|a| *a *= correction)
+                            }
+                            let mut sum = 0f32;
+                            for (j, score) in scores.iter().enumerate().take(n_kv) {
+                                let p = (score - new_max).exp();
+                                if p == 0. {
+                                    continue;
+                                }
+                                sum += p;
+                                let v_row = &v_tile[j * head_dim_v..(j + 1) * head_dim_v];
+                                for (a, v) in acc.iter_mut().zip(v_row.iter()) {
+                                    *a += p * v
+                                }
+                            }
+                            row_sum[i] = row_sum[i] * correction + sum;
+                            row_max[i] = new_max;
+                        }
+                    }
+                    for i in 0..n_q {
+                        // Fully masked queries get a zero output.
+                        let inv_sum = if row_sum[i] > 0. { 1. / row_sum[i] } else { 0. };
+                        let dst =
+                            &mut dst[(q_start + i) * head_dim_v..(q_start + i + 1) * head_dim_v];
+                        for (d, a) in dst.iter_mut().zip(acc[i * head_dim_v..].iter()) {
+                            *d = T::from_f64((a * inv_sum) as f64)
+                        }
+                    }
+                }
+            });
+        let storage = candle::WithDType::to_cpu_storage_owned(dst);
+        Ok((storage, (b_size, num_heads, seq_len_q, head_dim_v).into()))
+    }
+
+    // The additive bias combining the mask and the causal masking, if any.
+    fn bias(
+        &self,
+        seq_len_q: usize,
+        seq_len_kv: usize,
+        device: &candle::Device,
+    ) -> Result<Option<Tensor>> {
+        let causal = if self.causal {
+            let offset = seq_len_kv as isize - seq_len_q as isize;
+            let window = self.sliding_window;
+            let bias: Vec<f32> = (0..seq_len_q)
+                .flat_map(|i| {
+                    (0..seq_len_kv).map(move |j| {
+                        let (i, j) = (i as isize + offset, j as isize);
+                        if j > i || window.is_some_and(|w| i - j > w as isize) {
+                            f32::NEG_INFINITY
+                        } else {
+                            0.
+                        }
+                    })
+                })
+                .collect();
+            Some(Tensor::from_vec(bias, (seq_len_q, seq_len_kv), device)?)
+        } else {
+            None
+        };
+        match (&self.mask, causal) {
+            (None, causal) => Ok(causal),
+            (Some(mask), None) => Ok(Some(mask.clone())),
+            (Some(mask), Some(causal)) => Ok(Some(mask.broadcast_add(&causal)?)),
         }
-        n => candle::bail!("replication-pad with a size of {n} is not supported"),
+    }
+
+    // The attention probabilities, computed in f32 with the keys repeated for each query head.
+    // As in the cpu kernel, the probabilities of the fully masked queries are zeros rather than
+    // nans.
+    fn probs(&self, q: &Tensor, k: &Tensor) -> Result<Tensor> {
+        let (_, _, seq_len_q, _) = q.dims4()?;
+        let (_, _, seq_len_kv, _) = k.dims4()?;
+        let att = (q.contiguous()?.matmul(&k.t()?)? * self.scale as f64)?;
+        let att = match self.bias(seq_len_q, seq_len_kv, q.device())? {
+            None => return softmax_last_dim(&att),
+            Some(bias) => att.broadcast_add(&bias)?,
+        };
+        let probs = softmax_last_dim(&att)?;
+        let masked = att
+            .max_keepdim(candle::D::Minus1)?
+            .eq(f64::NEG_INFINITY)?
+            .broadcast_as(probs.shape())?;
+        masked.where_cond(&probs.zeros_like()?, &probs)
+    }
+}
+
+fn sdpa_repeat_kv(xs: &Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        xs.contiguous()
+    } else {
+        let (b_size, num_kv_heads, seq_len, head_dim) = xs.dims4()?;
+        xs.unsqueeze(2)?
+            .expand((b_size, num_kv_heads, n_rep, seq_len, head_dim))?
+            .reshape((b_size, num_kv_heads * n_rep, seq_len, head_dim))
+    }
+}
+
+impl candle::CustomOp3 for Sdpa {
+    fn name(&self) -> &'static str {
+        "sdpa"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        let mask = self.mask.as_ref().map(|m| m.storage_and_layout());
+        let mask = match &mask {
+            None => None,
+            Some((storage, layout)) => match &**storage {
+                candle::Storage::Cpu(storage) => Some((storage.as_slice::<f32>()?, *layout)),
+                _ => candle::bail!("sdpa mask must be a cpu tensor"),
+            },
+        };
+        match (s1, s2, s3) {
+            (CpuStorage::BF16(q), CpuStorage::BF16(k), CpuStorage::BF16(v)) => {
+                self.cpu_fwd_t((q, l1), (k, l2), (v, l3), mask)
+            }
+            (CpuStorage::F16(q), CpuStorage::F16(k), CpuStorage::F16(v)) => {
+                self.cpu_fwd_t((q, l1), (k, l2), (v, l3), mask)
+            }
+            (CpuStorage::F32(q), CpuStorage::F32(k), CpuStorage::F32(v)) => {
+                self.cpu_fwd_t((q, l1), (k, l2), (v, l3), mask)
+            }
+            (CpuStorage::F64(q), CpuStorage::F64(k), CpuStorage::F64(v)) => {
+                self.cpu_fwd_t((q, l1), (k, l2), (v, l3), mask)
+            }
+            _ => candle::bail!("unsupported dtype for sdpa {:?}", s1),
+        }
+    }
+
+    fn bwd(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        use candle::DType;
+        let (b_size, num_heads, _, _) = q.dims4()?;
+        let (_, num_kv_heads, seq_len_kv, _) = k.dims4()?;
+        let n_rep = num_heads / num_kv_heads;
+        let f32_q = q.to_dtype(DType::F32)?.contiguous()?;
+        let f32_k = sdpa_repeat_kv(&k.to_dtype(DType::F32)?, n_rep)?;
+        let f32_v = sdpa_repeat_kv(&v.to_dtype(DType::F32)?, n_rep)?;
+        let grad_res = grad_res.to_dtype(DType::F32)?.contiguous()?;
+        let res = res.to_dtype(DType::F32)?;
+        let probs = self.probs(&f32_q, &f32_k)?;
+        let grad_v = probs.t()?.matmul(&grad_res)?;
+        let grad_probs = grad_res.matmul(&f32_v.t()?)?;
+        let delta = (&grad_res * &res)?.sum_keepdim(3)?;
+        let grad_att = (probs * grad_probs.broadcast_sub(&delta)?)?;
+        let grad_q = (grad_att.matmul(&f32_k)? * self.scale as f64)?;
+        let grad_k = (grad_att.t()?.matmul(&f32_q)? * self.scale as f64)?;
+        // Sum the gradients of the query heads sharing the same key/value head.
+        let sum_groups = |xs: Tensor| -> Result<Tensor> {
+            if n_rep == 1 {
+                return Ok(xs);
+            }
+            let (_, _, _, dim) = xs.dims4()?;
+            xs.reshape((b_size, num_kv_heads, n_rep, seq_len_kv, dim))?
+                .sum(2)
+        };
+        let grad_k = sum_groups(grad_k)?.to_dtype(k.dtype())?;
+        let grad_v = sum_groups(grad_v)?.to_dtype(v.dtype())?;
+        Ok((
+            Some(grad_q.to_dtype(q.dtype())?),
+            Some(grad_k),
+            Some(grad_v),
+        ))
+    }
+}
+
+/// Scaled dot-product attention, `softmax(q @ k^T * scale + mask) @ v`.
+///
+/// `q` has shape `(b_size, num_heads, seq_len_q, head_dim)`, `k` and `v` have shapes
+/// `(b_size, num_kv_heads, seq_len_kv, head_dim)` and `(b_size, num_kv_heads, seq_len_kv,
+/// head_dim_v)` where `num_heads` is a multiple of `num_kv_heads` (grouped-query attention). The
+/// optional `mask` is added to the attention scores and must be broadcastable to `(b_size,
+/// num_heads, seq_len_q, seq_len_kv)`, masked entries being set to `-inf`. When `causal` is set,
+/// query `i` only attends to the keys up to `i + seq_len_kv - seq_len_q`.
+///
+/// On cpu this uses a tiled kernel with an online softmax so that the memory usage does not grow
+/// with the square of the sequence length, other devices use the materialized attention.
+/// Queries for which all the keys are masked get a zero output, and a zero gradient.
+pub fn sdpa(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f32,
+    causal: bool,
+) -> Result<Tensor> {
+    sdpa_(q, k, v, mask, scale, causal, None)
+}
+
+/// Causal [`sdpa`] where each query only attends to the `sliding_window` keys before it, as well
+/// as to the key at its own position. The window is applied inside the cpu kernel so that no
+/// `(seq_len_q, seq_len_kv)` mask has to be materialized, the keys out of the window are not
+/// visited.
+pub fn sdpa_sliding_window(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f32,
+    sliding_window: usize,
+) -> Result<Tensor> {
+    sdpa_(q, k, v, mask, scale, true, Some(sliding_window))
+}
+
+fn sdpa_(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f32,
+    causal: bool,
+    sliding_window: Option<usize>,
+) -> Result<Tensor> {
+    let (b_size, num_heads, seq_len_q, head_dim) = q.dims4()?;
+    let (k_b_size, num_kv_heads, seq_len_kv, k_head_dim) = k.dims4()?;
+    let (v_b_size, v_num_kv_heads, v_seq_len_kv, _) = v.dims4()?;
+    if k_b_size != b_size || v_b_size != b_size {
+        candle::bail!("sdpa batch size mismatch {b_size} {k_b_size} {v_b_size}")
+    }
+    if k_head_dim != head_dim {
+        candle::bail!("sdpa head dim mismatch between q {head_dim} and k {k_head_dim}")
+    }
+    if v_num_kv_heads != num_kv_heads || v_seq_len_kv != seq_len_kv {
+        candle::bail!(
+            "sdpa shape mismatch between k {:?} and v {:?}",
+            k.shape(),
+            v.shape()
+        )
+    }
+    if num_kv_heads == 0 || num_heads % num_kv_heads != 0 {
+        candle::bail!("sdpa num_heads {num_heads} is not a multiple of num_kv_heads {num_kv_heads}")
+    }
+    let mask = match mask {
+        None => None,
+        Some(mask) => Some(
+            mask.to_dtype(candle::DType::F32)?
+                .broadcast_as((b_size, num_heads, seq_len_q, seq_len_kv))?,
+        ),
+    };
+    let op = Sdpa {
+        scale,
+        causal,
+        sliding_window,
+        mask,
+    };
+    if q.device().is_cpu() {
+        q.apply_op3(k, v, op)
+    } else {
+        let n_rep = num_heads / num_kv_heads;
+        let dtype = q.dtype();
+        let probs = op.probs(
+            &q.to_dtype(candle::DType::F32)?,
+            &sdpa_repeat_kv(&k.to_dtype(candle::DType::F32)?, n_rep)?,
+        )?;
+        probs.to_dtype(dtype)?.matmul(&sdpa_repeat_kv(v, n_rep)?)
     }
 }
@@ -0,0 +1,205 @@
+//! Paged cache for the keys and values of attention layers, https://arxiv.org/abs/2309.06180
+//!
+//! The keys and values of all the sequences are stored in a shared pool of fixed-size blocks.
+//! Each sequence has a [`BlockTable`] mapping its positions to blocks, the blocks are handed
+//! out by a [`BlockAllocator`] as the sequence grows and returned to it once the sequence is
+//! done. This avoids reserving memory for the maximum sequence length of every sequence.
+//!
+//! A position is identified by its slot, `block * block_size + offset_in_block`, the cache
+//! tensors have the slots as their first dimension.
+use candle::{DType, Device, Result, Tensor};
+
+/// The block table of a sequence, i.e. the blocks that hold its cached positions.
+#[derive(Debug, Clone)]
+pub struct BlockTable {
+    blocks: Vec<usize>,
+    block_size: usize,
+    len: usize,
+}
+
+impl BlockTable {
+    pub fn new(block_size: usize) -> Self {
+        Self {
+            blocks: vec![],
+            block_size,
+            len: 0,
+        }
+    }
+
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The number of positions that have slots in this table.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of additional blocks required to add `num_tokens` positions.
+    pub fn blocks_needed(&self, num_tokens: usize) -> usize {
+        let total_blocks = (self.len + num_tokens).div_ceil(self.block_size);
+        total_blocks.saturating_sub(self.blocks.len())
+    }
+
+    /// The slot of position `pos`.
+    pub fn slot(&self, pos: usize) -> usize {
+        self.blocks[pos / self.block_size] * self.block_size + pos % self.block_size
+    }
+
+    /// The slots of all the positions, in order.
+    pub fn slots(&self) -> Vec<u32> {
+        (0..self.len).map(|pos| self.slot(pos) as u32).collect()
+    }
+}
+
+/// Hands out the blocks of a paged cache.
+#[derive(Debug, Clone)]
+pub struct BlockAllocator {
+    num_blocks: usize,
+    block_size: usize,
+    free_blocks: Vec<usize>,
+}
+
+impl BlockAllocator {
+    pub fn new(num_blocks: usize, block_size: usize) -> Self {
+        // Blocks are popped from the end, so that the low blocks get used first.
+        let free_blocks = (0..num_blocks).rev().collect();
+        Self {
+            num_blocks,
+            block_size,
+            free_blocks,
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    /// Returns true if `num_tokens` positions can be added to `table`.
+    pub fn can_append(&self, table: &BlockTable, num_tokens: usize) -> bool {
+        table.blocks_needed(num_tokens) <= self.free_blocks.len()
+    }
+
+    /// Adds `num_tokens` positions to `table`, allocating new blocks as needed, and returns the
+    /// slots of these positions. This fails without modifying the table if there are not
+    /// enough free blocks.
+    pub fn append_slots(&mut self, table: &mut BlockTable, num_tokens: usize) -> Result<Vec<u32>> {
+        if table.block_size != self.block_size {
+            candle::bail!(
+                "block size mismatch, table {} allocator {}",
+                table.block_size,
+                self.block_size
+            )
+        }
+        let blocks_needed = table.blocks_needed(num_tokens);
+        if blocks_needed > self.free_blocks.len() {
+            candle::bail!(
+                "out of kv-cache blocks, {blocks_needed} needed but {} available",
+                self.free_blocks.len()
+            )
+        }
+        let new_len = self.free_blocks.len() - blocks_needed;
+        table.blocks.extend(self.free_blocks.drain(new_len..).rev());
+        let start = table.len;
+        table.len += num_tokens;
+        Ok((start..table.len)
+            .map(|pos| table.slot(pos) as u32)
+            .collect())
+    }
+
+    /// Returns all the blocks of `table` to the allocator and empties the table.
+    pub fn release(&mut self, table: &mut BlockTable) {
+        self.free_blocks.extend(table.blocks.drain(..).rev());
+        table.len = 0;
+    }
+}
+
+/// The paged keys and values of an attention layer.
+///
+/// Both caches have shape `(num_blocks * block_size, num_kv_heads, head_dim)`.
+#[derive(Debug, Clone)]
+pub struct PagedKvCache {
+    k: Tensor,
+    v: Tensor,
+    block_size: usize,
+}
+
+impl PagedKvCache {
+    pub fn new(
+        num_blocks: usize,
+        block_size: usize,
+        num_kv_heads: usize,
+        head_dim: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let shape = (num_blocks * block_size, num_kv_heads, head_dim);
+        let k = Tensor::zeros(shape, dtype, device)?;
+        let v = Tensor::zeros(shape, dtype, device)?;
+        Ok(Self { k, v, block_size })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.k.dims()[0] / self.block_size
+    }
+
+    pub fn k(&self) -> &Tensor {
+        &self.k
+    }
+
+    pub fn v(&self) -> &Tensor {
+        &self.v
+    }
+
+    /// Writes the keys and values of some positions in place, `k` and `v` have shape
+    /// `(num_tokens, num_kv_heads, head_dim)` and `slots` holds the slot of each token.
+    pub fn write(&self, slots: &[u32], k: &Tensor, v: &Tensor) -> Result<()> {
+        let num_tokens = k.dim(0)?;
+        if slots.len() != num_tokens {
+            candle::bail!("got {} slots for {num_tokens} tokens", slots.len())
+        }
+        // Consecutive slots, e.g. from the same block, are written with a single copy.
+        let mut start = 0;
+        while start < num_tokens {
+            let mut end = start + 1;
+            while end < num_tokens && slots[end] == slots[end - 1] + 1 {
+                end += 1
+            }
+            let slot = slots[start] as usize;
+            self.k
+                .slice_set(&k.narrow(0, start, end - start)?, 0, slot)?;
+            self.v
+                .slice_set(&v.narrow(0, start, end - start)?, 0, slot)?;
+            start = end
+        }
+        Ok(())
+    }
+
+    /// Gathers the keys and values stored at `slots`, a one dimensional u32 tensor. The
+    /// returned tensors have shape `(num_slots, num_kv_heads, head_dim)`.
+    pub fn gather(&self, slots: &Tensor) -> Result<(Tensor, Tensor)> {
+        let k = self.k.index_select(slots, 0)?;
+        let v = self.v.index_select(slots, 0)?;
+        Ok((k, v))
+    }
+}
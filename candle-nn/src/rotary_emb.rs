@@ -0,0 +1,517 @@
+//! Rotary position embeddings (RoPE) and ALiBi attention biases.
+//!
+//! The rotary embeddings rotate pairs of values of each attention head by an angle that depends
+//! on the position of the token. [`rope`] rotates the first half of the head dimension with the
+//! second half, as done by most hugging face models, whereas [`rope_interleaved`] rotates
+//! consecutive values, as done by the original llama implementation and llama.cpp.
+use candle::{CpuStorage, DType, Device, Layout, Result, Shape, Tensor, WithDType, D};
+use rayon::prelude::*;
+
+/// How the rotary frequencies are adjusted to extend the context length of a model.
+///
+/// This can be deserialized from the `rope_scaling` entry of a hugging face config, e.g.
+/// `{"type": "linear", "factor": 2.0}` or `{"rope_type": "llama3", "factor": 8.0, ...}`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "RawRopeScaling")]
+pub enum RopeScaling {
+    /// Position interpolation, the positions are divided by `factor`.
+    Linear { factor: f64 },
+    /// NTK-aware scaling, the base is multiplied by `factor^(d / (d - 2))` where `d` is the head
+    /// dimension.
+    Ntk { factor: f64 },
+    /// Dynamic NTK scaling. The base only changes for sequences longer than the original
+    /// context so the tables are the unscaled ones within this context.
+    Dynamic { factor: f64 },
+    /// YaRN scaling, <https://arxiv.org/abs/2309.00071>. The frequencies that make less than
+    /// `beta_slow` rotations over the original context are interpolated, the ones making more
+    /// than `beta_fast` rotations are kept, and the ones in between are blended.
+    Yarn {
+        factor: f64,
+        original_max_position_embeddings: usize,
+        beta_fast: f64,
+        beta_slow: f64,
+    },
+    /// The Llama 3.1 scaling, the frequencies with a wavelength longer than
+    /// `original_max_position_embeddings / low_freq_factor` are divided by `factor`, the ones
+    /// shorter than `original_max_position_embeddings / high_freq_factor` are kept, and the ones
+    /// in between are blended.
+    Llama3 {
+        factor: f64,
+        low_freq_factor: f64,
+        high_freq_factor: f64,
+        original_max_position_embeddings: usize,
+    },
+    /// A scaling type that is not supported, e.g. `default`, the frequencies are not adjusted.
+    Other(String),
+}
+
+// The `rope_scaling` entry as found in the configs, older configs use `type` and newer ones
+// `rope_type`.
+#[derive(serde::Deserialize)]
+struct RawRopeScaling {
+    #[serde(rename = "type")]
+    type_: Option<String>,
+    rope_type: Option<String>,
+    factor: Option<f64>,
+    original_max_position_embeddings: Option<usize>,
+    beta_fast: Option<f64>,
+    beta_slow: Option<f64>,
+    low_freq_factor: Option<f64>,
+    high_freq_factor: Option<f64>,
+}
+
+impl TryFrom<RawRopeScaling> for RopeScaling {
+    type Error = String;
+
+    fn try_from(raw: RawRopeScaling) -> std::result::Result<Self, Self::Error> {
+        let rope_type = match raw.rope_type.or(raw.type_) {
+            None => return Err("missing rope scaling type".to_string()),
+            Some(rope_type) => rope_type,
+        };
+        let factor = || {
+            raw.factor
+                .ok_or(format!("missing factor for {rope_type} rope scaling"))
+        };
+        let original_max_position_embeddings = || {
+            raw.original_max_position_embeddings.ok_or(format!(
+                "missing original_max_position_embeddings for {rope_type} rope scaling"
+            ))
+        };
+        let scaling = match rope_type.as_str() {
+            "linear" => Self::Linear { factor: factor()? },
+            "ntk" => Self::Ntk { factor: factor()? },
+            "dynamic" => Self::Dynamic { factor: factor()? },
+            "yarn" => Self::Yarn {
+                factor: factor()?,
+                original_max_position_embeddings: original_max_position_embeddings()?,
+                beta_fast: raw.beta_fast.unwrap_or(32.),
+                beta_slow: raw.beta_slow.unwrap_or(1.),
+            },
+            "llama3" => Self::Llama3 {
+                factor: factor()?,
+                low_freq_factor: raw.low_freq_factor.unwrap_or(1.),
+                high_freq_factor: raw.high_freq_factor.unwrap_or(4.),
+                original_max_position_embeddings: original_max_position_embeddings()?,
+            },
+            _ => Self::Other(rope_type),
+        };
+        Ok(scaling)
+    }
+}
+
+impl RopeScaling {
+    fn factor(&self) -> f64 {
+        match self {
+            Self::Linear { factor }
+            | Self::Ntk { factor }
+            | Self::Dynamic { factor }
+            | Self::Yarn { factor, .. }
+            | Self::Llama3 { factor, .. } => *factor,
+            Self::Other(_) => 1.,
+        }
+    }
+}
+
+// Returns the head_dim / 2 inverse frequencies together with the scale applied to the cos and
+// sin tables.
+fn inv_freqs(head_dim: usize, base: f64, scaling: Option<&RopeScaling>) -> (Vec<f32>, f32) {
+    let freqs = |base: f64| -> Vec<f32> {
+        (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / (base as f32).powf(i as f32 / head_dim as f32))
+            .collect()
+    };
+    let d = head_dim as f64;
+    match scaling {
+        None | Some(RopeScaling::Dynamic { .. } | RopeScaling::Other(_)) => (freqs(base), 1.),
+        Some(RopeScaling::Linear { factor }) => {
+            let freqs = freqs(base).iter().map(|f| f / *factor as f32).collect();
+            (freqs, 1.)
+        }
+        Some(RopeScaling::Ntk { factor }) => (freqs(base * factor.powf(d / (d - 2.))), 1.),
+        Some(&RopeScaling::Yarn {
+            factor,
+            original_max_position_embeddings,
+            beta_fast,
+            beta_slow,
+        }) => {
+            // The dimension whose rotation makes `num_rotations` turns over the original context.
+            let correction_dim = |num_rotations: f64| {
+                let turns = original_max_position_embeddings as f64
+                    / (num_rotations * 2. * std::f64::consts::PI);
+                d * turns.ln() / (2. * base.ln())
+            };
+            let low = correction_dim(beta_fast).floor().max(0.);
+            let high = correction_dim(beta_slow).ceil().min(d - 1.);
+            let high = if low == high { high + 0.001 } else { high };
+            let freqs = (0..head_dim / 2)
+                .map(|i| {
+                    let inv_freq = 1. / base.powf(2. * i as f64 / d);
+                    let interpolation = ((i as f64 - low) / (high - low)).clamp(0., 1.);
+                    let f = inv_freq / factor * interpolation + inv_freq * (1. - interpolation);
+                    f as f32
+                })
+                .collect();
+            let mscale = if factor > 1. {
+                0.1 * factor.ln() + 1.
+            } else {
+                1.
+            };
+            (freqs, mscale as f32)
+        }
+        Some(&RopeScaling::Llama3 {
+            factor,
+            low_freq_factor,
+            high_freq_factor,
+            original_max_position_embeddings,
+        }) => {
+            let original = original_max_position_embeddings as f64;
+            let low_freq_wavelen = original / low_freq_factor;
+            let high_freq_wavelen = original / high_freq_factor;
+            let freqs = (0..head_dim / 2)
+                .map(|i| {
+                    let inv_freq = 1. / base.powf(2. * i as f64 / d);
+                    let wavelen = 2. * std::f64::consts::PI / inv_freq;
+                    let f = if wavelen < high_freq_wavelen {
+                        inv_freq
+                    } else if wavelen > low_freq_wavelen {
+                        inv_freq / factor
+                    } else {
+                        let smooth = (original / wavelen - low_freq_factor)
+                            / (high_freq_factor - low_freq_factor);
+                        (1. - smooth) * inv_freq / factor + smooth * inv_freq
+                    };
+                    f as f32
+                })
+                .collect();
+            (freqs, 1.)
+        }
+    }
+}
+
+/// Returns the cos and sin tables for the positions `0..max_seq_len`, both with shape
+/// `(max_seq_len, head_dim / 2)`, for rotary frequencies `base^(-2i / head_dim)` adjusted by
+/// `scaling`.
+pub fn rope_tables(
+    head_dim: usize,
+    max_seq_len: usize,
+    base: f64,
+    scaling: Option<&RopeScaling>,
+    dtype: DType,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    if head_dim == 0 || !head_dim.is_multiple_of(2) {
+        candle::bail!("rope requires an even head dimension, got {head_dim}")
+    }
+    if let Some(scaling) = scaling {
+        if scaling.factor() <= 0. {
+            candle::bail!("invalid rope scaling {scaling:?}")
+        }
+    }
+    let (inv_freqs, mscale) = inv_freqs(head_dim, base, scaling);
+    let mut cos = Vec::with_capacity(max_seq_len * inv_freqs.len());
+    let mut sin = Vec::with_capacity(max_seq_len * inv_freqs.len());
+    for pos in 0..max_seq_len {
+        for inv_freq in inv_freqs.iter() {
+            let angle = pos as f32 * inv_freq;
+            if mscale == 1. {
+                cos.push(angle.cos());
+                sin.push(angle.sin());
+            } else {
+                cos.push(angle.cos() * mscale);
+                sin.push(angle.sin() * mscale);
+            }
+        }
+    }
+    let shape = (max_seq_len, head_dim / 2);
+    let cos = Tensor::from_vec(cos, shape, device)?.to_dtype(dtype)?;
+    let sin = Tensor::from_vec(sin, shape, device)?.to_dtype(dtype)?;
+    Ok((cos, sin))
+}
+
+struct RotaryEmb {
+    interleaved: bool,
+}
+
+impl RotaryEmb {
+    // The indexes of the two values rotated together by the rotation `i` of a head of size `d`.
+    fn pair(&self, i: usize, d: usize) -> (usize, usize) {
+        if self.interleaved {
+            (2 * i, 2 * i + 1)
+        } else {
+            (i, i + d / 2)
+        }
+    }
+
+    // Splits xs with shape (b, h, t, d) into the two (b, h, t, d / 2) tensors rotated together.
+    fn split(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (b_size, num_heads, seq_len, d) = xs.dims4()?;
+        if self.interleaved {
+            let xs = xs.reshape((b_size, num_heads, seq_len, d / 2, 2))?;
+            let x1 = xs.narrow(D::Minus1, 0, 1)?.squeeze(D::Minus1)?;
+            let x2 = xs.narrow(D::Minus1, 1, 1)?.squeeze(D::Minus1)?;
+            Ok((x1, x2))
+        } else {
+            let x1 = xs.narrow(D::Minus1, 0, d / 2)?;
+            let x2 = xs.narrow(D::Minus1, d / 2, d / 2)?;
+            Ok((x1, x2))
+        }
+    }
+
+    // The inverse of `split`.
+    fn merge(&self, x1: &Tensor, x2: &Tensor) -> Result<Tensor> {
+        if self.interleaved {
+            Tensor::stack(&[x1, x2], D::Minus1)?.flatten_from(D::Minus2)
+        } else {
+            Tensor::cat(&[x1, x2], D::Minus1)
+        }
+    }
+
+    // The cos or sin table with a shape that broadcasts over (b, h, t, d / 2).
+    fn table(cs: &Tensor) -> Result<Tensor> {
+        match cs.rank() {
+            2 => cs.unsqueeze(0)?.unsqueeze(0),
+            _ => cs.unsqueeze(1),
+        }
+    }
+
+    // The rotation using tensor operations, this is used on the devices without a kernel.
+    fn fwd(&self, xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+        let (x1, x2) = self.split(xs)?;
+        let (cos, sin) = (Self::table(cos)?, Self::table(sin)?);
+        let y1 = (x1.broadcast_mul(&cos)? - x2.broadcast_mul(&sin)?)?;
+        let y2 = (x1.broadcast_mul(&sin)? + x2.broadcast_mul(&cos)?)?;
+        self.merge(&y1, &y2)
+    }
+
+    fn cpu_fwd_t<T: WithDType>(
+        &self,
+        (xs, l_xs): (&[T], &Layout),
+        (cos, l_cos): (&[T], &Layout),
+        (sin, l_sin): (&[T], &Layout),
+    ) -> Result<(CpuStorage, Shape)> {
+        fn contiguous<'a, T>(vs: &'a [T], l: &Layout) -> Result<&'a [T]> {
+            match l.contiguous_offsets() {
+                None => candle::bail!("rope requires contiguous inputs"),
+                Some((o1, o2)) => Ok(&vs[o1..o2]),
+            }
+        }
+        let (xs, cos, sin) = (
+            contiguous(xs, l_xs)?,
+            contiguous(cos, l_cos)?,
+            contiguous(sin, l_sin)?,
+        );
+        let (_b_size, num_heads, seq_len, d) = l_xs.shape().dims4()?;
+        let batched = l_cos.shape().rank() == 3;
+        let mut dst = vec![T::zero(); xs.len()];
+        if !dst.is_empty() {
+            dst.par_chunks_mut(seq_len * d)
+                .zip(xs.par_chunks(seq_len * d))
+                .enumerate()
+                .for_each(|(bh, (dst, src))| {
+                    let cs_offset = if batched {
+                        bh / num_heads * seq_len * d / 2
+                    } else {
+                        0
+                    };
+                    for i_t in 0..seq_len {
+                        for i in 0..d / 2 {
+                            let (i1, i2) = self.pair(i, d);
+                            let (i1, i2) = (i_t * d + i1, i_t * d + i2);
+                            let i_cs = cs_offset + i_t * d / 2 + i;
+                            let (c, s) = (cos[i_cs], sin[i_cs]);
+                            dst[i1] = src[i1] * c - src[i2] * s;
+                            dst[i2] = src[i1] * s + src[i2] * c;
+                        }
+                    }
+                });
+        }
+        let storage = candle::WithDType::to_cpu_storage_owned(dst);
+        Ok((storage, l_xs.shape().clone()))
+    }
+}
+
+impl candle::CustomOp3 for RotaryEmb {
+    fn name(&self) -> &'static str {
+        if self.interleaved {
+            "rope-interleaved"
+        } else {
+            "rope"
+        }
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &CpuStorage,
+        l1: &Layout,
+        s2: &CpuStorage,
+        l2: &Layout,
+        s3: &CpuStorage,
+        l3: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        match (s1, s2, s3) {
+            (CpuStorage::BF16(xs), CpuStorage::BF16(cos), CpuStorage::BF16(sin)) => {
+                self.cpu_fwd_t((xs, l1), (cos, l2), (sin, l3))
+            }
+            (CpuStorage::F16(xs), CpuStorage::F16(cos), CpuStorage::F16(sin)) => {
+                self.cpu_fwd_t((xs, l1), (cos, l2), (sin, l3))
+            }
+            (CpuStorage::F32(xs), CpuStorage::F32(cos), CpuStorage::F32(sin)) => {
+                self.cpu_fwd_t((xs, l1), (cos, l2), (sin, l3))
+            }
+            (CpuStorage::F64(xs), CpuStorage::F64(cos), CpuStorage::F64(sin)) => {
+                self.cpu_fwd_t((xs, l1), (cos, l2), (sin, l3))
+            }
+            _ => {
+                use candle::backend::BackendStorage;
+                candle::bail!("unsupported dtype for rope {:?}", s1.dtype())
+            }
+        }
+    }
+
+    fn bwd(
+        &self,
+        xs: &Tensor,
+        cos: &Tensor,
+        sin: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        // The rotation is orthogonal so the gradient is rotated by the opposite angle.
+        let grad_xs = rope_impl(grad_res, cos, &sin.neg()?, self.interleaved)?;
+        let (x1, x2) = self.split(xs)?;
+        let (g1, g2) = self.split(grad_res)?;
+        let grad_cos = ((&g1 * &x1)? + (&g2 * &x2)?)?;
+        let grad_sin = ((g2 * x1)? - (g1 * x2)?)?;
+        // Sum over the dimensions along which the tables are broadcasted.
+        let (grad_cos, grad_sin) = match cos.rank() {
+            2 => (grad_cos.sum((0, 1))?, grad_sin.sum((0, 1))?),
+            _ => (grad_cos.sum(1)?, grad_sin.sum(1)?),
+        };
+        Ok((Some(grad_xs), Some(grad_cos), Some(grad_sin)))
+    }
+}
+
+fn rope_impl(xs: &Tensor, cos: &Tensor, sin: &Tensor, interleaved: bool) -> Result<Tensor> {
+    let (b_size, _num_heads, seq_len, d) = xs.dims4()?;
+    if d % 2 != 0 {
+        candle::bail!("rope requires an even head dimension, got {:?}", xs.shape())
+    }
+    for cs in [cos, sin] {
+        let expected = match cs.rank() {
+            2 => vec![seq_len, d / 2],
+            _ => vec![b_size, seq_len, d / 2],
+        };
+        if cs.dims() != expected {
+            candle::bail!(
+                "rope shape mismatch, xs {:?}, cos {:?}, sin {:?}",
+                xs.shape(),
+                cos.shape(),
+                sin.shape()
+            )
+        }
+        if cs.dtype() != xs.dtype() {
+            candle::bail!("rope dtype mismatch {:?} {:?}", xs.dtype(), cs.dtype())
+        }
+    }
+    let op = RotaryEmb { interleaved };
+    if xs.device().is_cpu() {
+        xs.contiguous()?
+            .apply_op3(&cos.contiguous()?, &sin.contiguous()?, op)
+    } else {
+        op.fwd(xs, cos, sin)
+    }
+}
+
+/// Applies rotary embeddings to `xs`, rotating the first half of the last dimension with the
+/// second half.
+///
+/// `xs` has shape `(b_size, num_heads, seq_len, head_dim)`. `cos` and `sin` have shape
+/// `(seq_len, head_dim / 2)`, or `(b_size, seq_len, head_dim / 2)` when the positions differ
+/// between the batch elements, and have the same dtype as `xs`. The output at `(.., i)` and
+/// `(.., i + head_dim / 2)` is `(x1 * cos - x2 * sin, x1 * sin + x2 * cos)`.
+///
+/// On cpu this uses a single fused kernel.
+pub fn rope(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    rope_impl(xs, cos, sin, false)
+}
+
+/// Applies rotary embeddings to `xs`, rotating consecutive pairs of values of the last
+/// dimension. The shapes are the same as for [`rope`].
+pub fn rope_interleaved(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
+    rope_impl(xs, cos, sin, true)
+}
+
+/// Applies rotary embeddings to `xs` with shape `(b_size, num_heads, seq_len, head_dim)`, for the
+/// positions starting at `index_pos` and the frequencies of [`rope_tables`].
+///
+/// This recomputes the tables, models should rather build them once with [`rope_tables`] and use
+/// [`rope`].
+pub fn rope_with_scaling(
+    xs: &Tensor,
+    index_pos: usize,
+    base: f64,
+    scaling: Option<&RopeScaling>,
+) -> Result<Tensor> {
+    let (_b_size, _num_heads, seq_len, head_dim) = xs.dims4()?;
+    let (cos, sin) = rope_tables(
+        head_dim,
+        index_pos + seq_len,
+        base,
+        scaling,
+        xs.dtype(),
+        xs.device(),
+    )?;
+    let cos = cos.narrow(0, index_pos, seq_len)?;
+    let sin = sin.narrow(0, index_pos, seq_len)?;
+    rope(xs, &cos, &sin)
+}
+
+// The ALiBi slope of each head, <https://arxiv.org/abs/2108.12409>. When the number of heads is
+// not a power of two, the slopes of the next power of two are interleaved.
+fn alibi_slopes(num_heads: usize, max_bias: f64) -> Vec<f32> {
+    let num_heads2 = num_heads.next_power_of_two();
+    let slopes: Vec<f32> = (1..=num_heads2)
+        .map(|v| 1f32 / 2f32.powf((v as f64 * max_bias) as f32 / num_heads2 as f32))
+        .collect();
+    if num_heads2 == num_heads {
+        slopes
+    } else {
+        slopes
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .chain(slopes.iter().step_by(2))
+            .take(num_heads)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Returns the ALiBi attention bias with shape `(num_heads, seq_len_q, seq_len_kv)` and dtype
+/// f32, to be added to the attention scores.
+///
+/// The queries are the last `seq_len_q` positions of the keys, the bias between query `i` and key
+/// `j` is `-slope_h * |i + seq_len_kv - seq_len_q - j|` where the slope of head `h` is
+/// `2^(-max_bias * (h + 1) / num_heads)`. The usual value for `max_bias` is 8.
+pub fn alibi_bias(
+    num_heads: usize,
+    seq_len_q: usize,
+    seq_len_kv: usize,
+    max_bias: f64,
+    device: &Device,
+) -> Result<Tensor> {
+    if seq_len_q > seq_len_kv {
+        candle::bail!("alibi_bias: more queries {seq_len_q} than keys {seq_len_kv}")
+    }
+    let offset = seq_len_kv - seq_len_q;
+    let bias: Vec<f32> = alibi_slopes(num_heads, max_bias)
+        .into_iter()
+        .flat_map(|slope| {
+            (0..seq_len_q).flat_map(move |i| {
+                (0..seq_len_kv).map(move |j| -(slope * (i + offset).abs_diff(j) as f32))
+            })
+        })
+        .collect();
+    Tensor::from_vec(bias, (num_heads, seq_len_q, seq_len_kv), device)
+}
@@ -0,0 +1,165 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::kv_cache::{Cache, KvCache};
+use candle_nn::paged_kv_cache::{BlockAllocator, BlockTable, PagedKvCache};
+
+fn positions(start: u32, len: u32) -> Result<Tensor> {
+    Tensor::arange(start, start + len, &Device::Cpu)?.reshape((1, len as usize))
+}
+
+fn values(t: &Tensor) -> Result<Vec<u32>> {
+    t.squeeze(0)?.to_vec1::<u32>()
+}
+
+#[test]
+fn cache_append() -> Result<()> {
+    let mut cache = Cache::new(1, 4);
+    assert!(cache.current_data()?.is_none());
+    let data = cache.append(&positions(0, 3)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2]);
+    let data = cache.append(&positions(3, 1)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2, 3]);
+    assert_eq!(cache.capacity(), 4);
+    // Exceeding the capacity grows the buffer.
+    let data = cache.append(&positions(4, 2)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(cache.capacity(), 8);
+    assert_eq!(cache.current_seq_len(), 6);
+    cache.reset();
+    assert_eq!(cache.current_seq_len(), 0);
+    let data = cache.append(&positions(10, 2)?)?;
+    assert_eq!(values(&data)?, [10, 11]);
+    Ok(())
+}
+
+#[test]
+fn cache_sliding_window() -> Result<()> {
+    let mut cache = Cache::new(1, 6).with_sliding_window(3);
+    let data = cache.append(&positions(0, 4)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2, 3]);
+    // The last 3 positions are returned on top of the new ones.
+    let data = cache.append(&positions(4, 1)?)?;
+    assert_eq!(values(&data)?, [1, 2, 3, 4]);
+    let data = cache.append(&positions(5, 1)?)?;
+    assert_eq!(values(&data)?, [2, 3, 4, 5]);
+    // The buffer is full, the evicted positions get dropped when copying to a new buffer.
+    let data = cache.append(&positions(6, 2)?)?;
+    assert_eq!(values(&data)?, [3, 4, 5, 6, 7]);
+    assert_eq!(cache.capacity(), 6);
+    assert_eq!(cache.current_seq_len(), 8);
+    assert_eq!(values(&cache.current_data()?.unwrap())?, [5, 6, 7]);
+    cache.rollback(7)?;
+    assert_eq!(values(&cache.current_data()?.unwrap())?, [4, 5, 6]);
+    assert!(cache.rollback(5).is_err());
+    Ok(())
+}
+
+#[test]
+fn cache_rollback() -> Result<()> {
+    let mut cache = Cache::new(1, 8);
+    cache.append(&positions(0, 5)?)?;
+    assert!(cache.rollback(6).is_err());
+    cache.rollback(2)?;
+    assert_eq!(cache.current_seq_len(), 2);
+    let data = cache.append(&positions(20, 2)?)?;
+    assert_eq!(values(&data)?, [0, 1, 20, 21]);
+    Ok(())
+}
+
+#[test]
+fn cache_snapshot() -> Result<()> {
+    let mut cache = Cache::new(1, 8);
+    cache.append(&positions(0, 3)?)?;
+    let snapshot = cache.snapshot();
+    let data = cache.append(&positions(3, 2)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2, 3, 4]);
+    let snapshot2 = cache.snapshot();
+    cache.restore(&snapshot);
+    assert_eq!(cache.current_seq_len(), 3);
+    // Appending after the snapshot does not require a copy but would overwrite the data of
+    // the second snapshot so a new buffer is used.
+    let data = cache.append(&positions(10, 1)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2, 10]);
+    cache.restore(&snapshot2);
+    assert_eq!(values(&cache.current_data()?.unwrap())?, [0, 1, 2, 3, 4]);
+    // Rolling back below a snapshot and appending does not modify the snapshot.
+    cache.rollback(1)?;
+    cache.append(&positions(30, 3)?)?;
+    cache.restore(&snapshot);
+    assert_eq!(values(&cache.current_data()?.unwrap())?, [0, 1, 2]);
+    // Clones do not write to the shared buffer.
+    let mut clone = cache.clone();
+    clone.append(&positions(40, 1)?)?;
+    let data = cache.append(&positions(50, 1)?)?;
+    assert_eq!(values(&data)?, [0, 1, 2, 50]);
+    assert_eq!(values(&clone.current_data()?.unwrap())?, [0, 1, 2, 40]);
+    Ok(())
+}
+
+#[test]
+fn kv_cache() -> Result<()> {
+    let device = &Device::Cpu;
+    let (b, h, d) = (2, 3, 4);
+    let mut cache = KvCache::new(2, 16);
+    let k1 = Tensor::randn(0f32, 1f32, (b, h, 5, d), device)?;
+    let v1 = Tensor::randn(0f32, 1f32, (b, h, 5, d), device)?;
+    cache.append(&k1, &v1)?;
+    let k2 = Tensor::randn(0f32, 1f32, (b, h, 1, d), device)?;
+    let v2 = Tensor::randn(0f32, 1f32, (b, h, 1, d), device)?;
+    let (k, v) = cache.append(&k2, &v2)?;
+    assert_eq!(k.dims(), [b, h, 6, d]);
+    let diff = (k - Tensor::cat(&[&k1, &k2], 2)?)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_vec0::<f32>()?, 0.);
+    let diff = (v - Tensor::cat(&[&v1, &v2], 2)?)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_vec0::<f32>()?, 0.);
+
+    // Swap the two batch elements.
+    cache.index_select(&Tensor::new(&[1u32, 0], device)?, 0)?;
+    let k = cache.k()?.unwrap();
+    let diff = (k.narrow(0, 0, 1)? - k1.narrow(0, 1, 1)?.pad_with_zeros(2, 0, 1)?)?
+        .narrow(2, 0, 5)?
+        .abs()?
+        .sum_all()?;
+    assert_eq!(diff.to_vec0::<f32>()?, 0.);
+    assert_eq!(cache.current_seq_len(), 6);
+    Ok(())
+}
+
+#[test]
+fn paged_kv_cache() -> Result<()> {
+    let device = &Device::Cpu;
+    let mut allocator = BlockAllocator::new(4, 2);
+    let mut table1 = BlockTable::new(2);
+    let mut table2 = BlockTable::new(2);
+    assert_eq!(allocator.append_slots(&mut table1, 3)?, [0, 1, 2]);
+    assert_eq!(allocator.append_slots(&mut table2, 1)?, [4]);
+    assert_eq!(allocator.append_slots(&mut table1, 1)?, [3]);
+    assert_eq!(allocator.num_free_blocks(), 1);
+    // Both sequences require a new block, only one of them gets it.
+    assert!(allocator.can_append(&table1, 1));
+    assert_eq!(allocator.append_slots(&mut table2, 2)?, [5, 6]);
+    assert!(!allocator.can_append(&table1, 1));
+    assert!(allocator.append_slots(&mut table1, 1).is_err());
+    assert_eq!(table1.len(), 4);
+    assert_eq!(table2.slots(), [4, 5, 6]);
+
+    let cache = PagedKvCache::new(4, 2, 1, 2, DType::F32, device)?;
+    let k = Tensor::arange(0f32, 6., device)?.reshape((3, 1, 2))?;
+    cache.write(&table2.slots(), &k, &(&k + 10.)?)?;
+    allocator.release(&mut table1);
+    assert_eq!(allocator.num_free_blocks(), 2);
+    assert_eq!(allocator.append_slots(&mut table1, 1)?, [0]);
+    cache.write(&table1.slots(), &k.narrow(0, 2, 1)?, &k.narrow(0, 2, 1)?)?;
+    let (k, v) = cache.gather(&Tensor::new(&[5u32, 0, 4], device)?)?;
+    assert_eq!(k.flatten_all()?.to_vec1::<f32>()?, [2., 3., 4., 5., 0., 1.]);
+    assert_eq!(
+        v.flatten_all()?.to_vec1::<f32>()?,
+        [12., 13., 4., 5., 10., 11.]
+    );
+    Ok(())
+}
@@ -84,5 +84,11 @@ fn binary_cross_entropy_with_logit() -> Result<()> {
     let loss = candle_nn::loss::binary_cross_entropy_with_logit(&inp, &target)?;
 
     assert_eq!(to_vec0_round(&loss, 4)?, 0.8224);
+
+    // Large logits do not overflow.
+    let inp = Tensor::new(&[[100f32, -100.], [-100., 100.]], &cpu)?;
+    let target = Tensor::new(&[[0f32, 0.], [0., 1.]], &cpu)?;
+    let loss = candle_nn::loss::binary_cross_entropy_with_logit(&inp, &target)?;
+    assert_eq!(to_vec0_round(&loss, 4)?, 25.);
     Ok(())
 }
@@ -4,7 +4,10 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use candle::{test_utils::to_vec3_round, Device, Result, Tensor};
+use candle::{
+    test_utils::{to_vec1_round, to_vec3_round},
+    Device, IndexOp, Result, Tensor,
+};
 
 #[test]
 fn softmax() -> Result<()> {
@@ -60,5 +63,376 @@ fn softmax_numerical_stability() -> Result<()> {
     let xs = Tensor::new(&[1234f32, 0.], dev)?;
     let softmax = candle_nn::ops::softmax(&xs, 0)?;
     assert_eq!(softmax.to_vec1::<f32>()?, &[1f32, 0.]);
+    let log_softmax = candle_nn::ops::log_softmax(&xs, 0)?;
+    assert_eq!(log_softmax.to_vec1::<f32>()?, &[0f32, -1234.]);
+    let xs = Tensor::new(&[0f32, f32::NEG_INFINITY], dev)?;
+    let log_softmax = candle_nn::ops::log_softmax(&xs, 0)?;
+    assert_eq!(log_softmax.to_vec1::<f32>()?, &[0f32, f32::NEG_INFINITY]);
+    let xs = Tensor::new(&[1e6f32, 1e6, 1e6], dev)?;
+    let log_softmax = candle_nn::ops::log_softmax(&xs, 0)?;
+    assert_eq!(
+        to_vec1_round(&log_softmax, 4)?,
+        &[-1.0986, -1.0986, -1.0986]
+    );
+    Ok(())
+}
+
+// The materialized attention used as a reference for sdpa.
+fn naive_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+    scale: f64,
+    causal: bool,
+) -> Result<Tensor> {
+    let (_, num_heads, seq_len_q, _) = q.dims4()?;
+    let (_, num_kv_heads, seq_len_kv, _) = k.dims4()?;
+    let n_rep = num_heads / num_kv_heads;
+    let k = Tensor::cat(&vec![k; n_rep], 2)?.reshape(((), num_heads, seq_len_kv, k.dim(3)?))?;
+    let v = Tensor::cat(&vec![v; n_rep], 2)?.reshape(((), num_heads, seq_len_kv, v.dim(3)?))?;
+    let mut att = (q.contiguous()?.matmul(&k.t()?)? * scale)?;
+    if let Some(mask) = mask {
+        att = att.broadcast_add(mask)?
+    }
+    if causal {
+        let offset = seq_len_kv - seq_len_q;
+        let bias: Vec<f32> = (0..seq_len_q)
+            .flat_map(|i| {
+                (0..seq_len_kv).map(move |j| {
+                    if j > i + offset {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        att = att.broadcast_add(&Tensor::from_vec(
+            bias,
+            (seq_len_q, seq_len_kv),
+            q.device(),
+        )?)?
+    }
+    candle_nn::ops::softmax(&att, candle::D::Minus1)?.matmul(&v)
+}
+
+fn max_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f32> {
+    (lhs - rhs)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()
+}
+
+#[test]
+fn sdpa() -> Result<()> {
+    let device = &Device::Cpu;
+    // Long enough sequences to span multiple query blocks and key/value tiles.
+    let (b_size, num_heads, num_kv_heads, seq_len_q, seq_len_kv, head_dim) = (2, 4, 2, 37, 150, 8);
+    // The queries are not contiguous.
+    let q = Tensor::randn(0f32, 1., (b_size, seq_len_q, num_heads, head_dim), device)?
+        .transpose(1, 2)?;
+    let k = Tensor::randn(
+        0f32,
+        1.,
+        (b_size, num_kv_heads, seq_len_kv, head_dim),
+        device,
+    )?;
+    let v = Tensor::randn(0f32, 1., (b_size, num_kv_heads, seq_len_kv, 5), device)?;
+    let mask: Vec<f32> = (0..b_size * seq_len_kv)
+        .map(|i| {
+            if i % 7 == 3 {
+                f32::NEG_INFINITY
+            } else {
+                (i % 3) as f32 * 0.5
+            }
+        })
+        .collect();
+    let mask = Tensor::from_vec(mask, (b_size, 1, 1, seq_len_kv), device)?;
+    let scale = 1. / (head_dim as f64).sqrt();
+    for (mask, causal) in [
+        (None, false),
+        (None, true),
+        (Some(&mask), false),
+        (Some(&mask), true),
+    ] {
+        let ys = candle_nn::ops::sdpa(&q, &k, &v, mask, scale as f32, causal)?;
+        let expected = naive_attention(&q, &k, &v, mask, scale, causal)?;
+        assert_eq!(ys.dims(), [b_size, num_heads, seq_len_q, 5]);
+        let diff = max_diff(&ys, &expected)?;
+        assert!(diff < 1e-5, "{diff} {causal}");
+    }
+
+    let ys = candle_nn::ops::sdpa(
+        &q.to_dtype(candle::DType::F16)?,
+        &k.to_dtype(candle::DType::F16)?,
+        &v.to_dtype(candle::DType::F16)?,
+        Some(&mask),
+        scale as f32,
+        true,
+    )?;
+    let expected = naive_attention(&q, &k, &v, Some(&mask), scale, true)?;
+    let diff = max_diff(&ys.to_dtype(candle::DType::F32)?, &expected)?;
+    assert!(diff < 1e-2, "{diff}");
+
+    // The sliding window is equivalent to an additive mask on the keys that are too far back.
+    let offset = seq_len_kv - seq_len_q;
+    for sliding_window in [0, 5, 70] {
+        let window_mask: Vec<f32> = (0..seq_len_q)
+            .flat_map(|i| {
+                (0..seq_len_kv).map(move |j| {
+                    if j + sliding_window < i + offset {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let window_mask = Tensor::from_vec(window_mask, (seq_len_q, seq_len_kv), device)?;
+        let ys =
+            candle_nn::ops::sdpa_sliding_window(&q, &k, &v, None, scale as f32, sliding_window)?;
+        let expected = naive_attention(&q, &k, &v, Some(&window_mask), scale, true)?;
+        let diff = max_diff(&ys, &expected)?;
+        assert!(diff < 1e-5, "{diff} {sliding_window}");
+    }
+    Ok(())
+}
+
+#[test]
+fn sdpa_grad() -> Result<()> {
+    let device = &Device::Cpu;
+    let q = candle::Var::randn(0f32, 1., (1, 4, 5, 3), device)?;
+    let k = candle::Var::randn(0f32, 1., (1, 2, 7, 3), device)?;
+    let v = candle::Var::randn(0f32, 1., (1, 2, 7, 2), device)?;
+    let mask = Tensor::new(&[0f32, 0., f32::NEG_INFINITY, 0., -1., 0., 0.], device)?;
+    let w = Tensor::randn(0f32, 1., (1, 4, 5, 2), device)?;
+    let ys = candle_nn::ops::sdpa(&q, &k, &v, Some(&mask), 0.5, true)?;
+    let grads = (ys * &w)?.sum_all()?.backward()?;
+    let expected = naive_attention(&q, &k, &v, Some(&mask), 0.5, true)?;
+    let expected_grads = (expected * &w)?.sum_all()?.backward()?;
+    for var in [&q, &k, &v] {
+        let grad = grads.get(var).unwrap();
+        let expected = expected_grads.get(var).unwrap();
+        assert_eq!(grad.dims(), var.dims());
+        let diff = max_diff(grad, expected)?;
+        assert!(diff < 1e-5, "{diff}");
+    }
+
+    // The fully masked queries have a zero output and a zero gradient.
+    let mask = Tensor::new(&[[0f32, 0., 0.], [f32::NEG_INFINITY; 3]], device)?;
+    let q = candle::Var::randn(0f32, 1., (1, 1, 2, 3), device)?;
+    let k = candle::Var::randn(0f32, 1., (1, 1, 3, 3), device)?;
+    let v = candle::Var::randn(0f32, 1., (1, 1, 3, 2), device)?;
+    let ys = candle_nn::ops::sdpa(&q, &k, &v, Some(&mask), 0.5, false)?;
+    assert_eq!(ys.i((0, 0, 1))?.to_vec1::<f32>()?, [0., 0.]);
+    let grads = ys.sum_all()?.backward()?;
+    assert_eq!(
+        grads.get(&q).unwrap().i((0, 0, 1))?.to_vec1::<f32>()?,
+        [0., 0., 0.]
+    );
+    for var in [&q, &k, &v] {
+        let grad = grads.get(var).unwrap().flatten_all()?.to_vec1::<f32>()?;
+        assert!(grad.iter().all(|g| g.is_finite()), "{grad:?}");
+    }
+    Ok(())
+}
+
+// The rotary embeddings as computed by the models before the fused op, `cos` and `sin` have shape
+// (seq_len, head_dim / 2).
+fn naive_rope(xs: &Tensor, cos: &Tensor, sin: &Tensor, interleaved: bool) -> Result<Tensor> {
+    use candle::D;
+    let (b_size, num_heads, seq_len, head_dim) = xs.dims4()?;
+    if interleaved {
+        let xs = xs.reshape((b_size, num_heads, seq_len, head_dim / 2, 2))?;
+        let (cos, sin) = (cos.unsqueeze(D::Minus1)?, sin.unsqueeze(D::Minus1)?);
+        let x0 = xs.narrow(D::Minus1, 0, 1)?;
+        let x1 = xs.narrow(D::Minus1, 1, 1)?;
+        let y0 = (x0.broadcast_mul(&cos)? - x1.broadcast_mul(&sin)?)?;
+        let y1 = (x0.broadcast_mul(&sin)? + x1.broadcast_mul(&cos)?)?;
+        Tensor::cat(&[y0, y1], D::Minus1)?.flatten_from(D::Minus2)
+    } else {
+        let cos = Tensor::cat(&[cos, cos], D::Minus1)?;
+        let sin = Tensor::cat(&[sin, sin], D::Minus1)?;
+        let x1 = xs.narrow(D::Minus1, 0, head_dim / 2)?;
+        let x2 = xs.narrow(D::Minus1, head_dim / 2, head_dim / 2)?;
+        let rotated = Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)?;
+        xs.broadcast_mul(&cos)? + rotated.broadcast_mul(&sin)?
+    }
+}
+
+#[test]
+fn rope() -> Result<()> {
+    use candle_nn::rotary_emb;
+    let device = &Device::Cpu;
+    let (b_size, num_heads, seq_len, head_dim) = (2, 3, 5, 8);
+    // A non-contiguous input.
+    let xs =
+        Tensor::randn(0f32, 1., (b_size, seq_len, num_heads, head_dim), device)?.transpose(1, 2)?;
+    let cos = Tensor::randn(0f32, 1., (seq_len, head_dim / 2), device)?;
+    let sin = Tensor::randn(0f32, 1., (seq_len, head_dim / 2), device)?;
+    let ys = rotary_emb::rope(&xs, &cos, &sin)?;
+    assert_eq!(ys.dims(), [b_size, num_heads, seq_len, head_dim]);
+    assert!(max_diff(&ys, &naive_rope(&xs, &cos, &sin, false)?)? < 1e-6);
+    let ys = rotary_emb::rope_interleaved(&xs, &cos, &sin)?;
+    assert!(max_diff(&ys, &naive_rope(&xs, &cos, &sin, true)?)? < 1e-6);
+
+    // Tables with a different position for each batch element.
+    let cos_b = Tensor::randn(0f32, 1., (b_size, seq_len, head_dim / 2), device)?;
+    let sin_b = Tensor::randn(0f32, 1., (b_size, seq_len, head_dim / 2), device)?;
+    for interleaved in [false, true] {
+        let ys = if interleaved {
+            rotary_emb::rope_interleaved(&xs, &cos_b, &sin_b)?
+        } else {
+            rotary_emb::rope(&xs, &cos_b, &sin_b)?
+        };
+        for b in 0..b_size {
+            let expected = naive_rope(
+                &xs.get(b)?.unsqueeze(0)?,
+                &cos_b.get(b)?,
+                &sin_b.get(b)?,
+                interleaved,
+            )?;
+            assert!(max_diff(&ys.get(b)?.unsqueeze(0)?, &expected)? < 1e-6);
+        }
+    }
+
+    assert!(rotary_emb::rope(&xs, &cos.narrow(0, 0, 4)?, &sin).is_err());
+    assert!(rotary_emb::rope(&xs, &cos.to_dtype(candle::DType::F64)?, &sin).is_err());
+    assert!(rotary_emb::rope(&xs.narrow(3, 0, 7)?, &cos, &sin).is_err());
+    Ok(())
+}
+
+#[test]
+fn rope_grad() -> Result<()> {
+    use candle_nn::rotary_emb;
+    let device = &Device::Cpu;
+    let xs = candle::Var::randn(0f32, 1., (2, 3, 4, 6), device)?;
+    let w = Tensor::randn(0f32, 1., (2, 3, 4, 6), device)?;
+    for batched in [false, true] {
+        let dims: &[usize] = if batched { &[2, 4, 3] } else { &[4, 3] };
+        let cos = candle::Var::randn(0f32, 1., dims, device)?;
+        let sin = candle::Var::randn(0f32, 1., dims, device)?;
+        for interleaved in [false, true] {
+            let ys = if interleaved {
+                rotary_emb::rope_interleaved(&xs, &cos, &sin)?
+            } else {
+                rotary_emb::rope(&xs, &cos, &sin)?
+            };
+            let grads = (ys * &w)?.sum_all()?.backward()?;
+            // The reference uses the same tables for all the batch elements so it is computed
+            // one batch element at a time.
+            let expected: Vec<Tensor> = (0..2)
+                .map(|b| {
+                    let (cos, sin) = if batched {
+                        (cos.get(b)?, sin.get(b)?)
+                    } else {
+                        (cos.as_tensor().clone(), sin.as_tensor().clone())
+                    };
+                    naive_rope(&xs.get(b)?.unsqueeze(0)?, &cos, &sin, interleaved)
+                })
+                .collect::<Result<_>>()?;
+            let expected = Tensor::cat(&expected, 0)?;
+            let expected_grads = (expected * &w)?.sum_all()?.backward()?;
+            for var in [&xs, &cos, &sin] {
+                let grad = grads.get(var).unwrap();
+                assert_eq!(grad.dims(), var.dims());
+                let diff = max_diff(grad, expected_grads.get(var).unwrap())?;
+                assert!(diff < 1e-5, "{diff} {batched} {interleaved}");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn rope_scaling() -> Result<()> {
+    use candle::DType;
+    use candle_nn::rotary_emb::{rope_tables, rope_with_scaling, RopeScaling};
+    type Tables = (Vec<Vec<f32>>, Vec<Vec<f32>>);
+    let device = &Device::Cpu;
+    let tables = |scaling: Option<RopeScaling>| -> Result<Tables> {
+        let (cos, sin) = rope_tables(64, 1024, 10000., scaling.as_ref(), DType::F32, device)?;
+        Ok((cos.to_vec2()?, sin.to_vec2()?))
+    };
+    let (cos, sin) = tables(None)?;
+    assert_eq!(cos[0], [1.; 32]);
+    assert_eq!(sin[3][0], 3f32.sin());
+    assert_eq!(sin[3][16], (3. / 100f32).sin());
+
+    // Linear scaling interpolates the positions.
+    let (cos_l, _) = tables(Some(RopeScaling::Linear { factor: 4. }))?;
+    for i in 0..32 {
+        assert!((cos_l[400][i] - cos[100][i]).abs() < 1e-4)
+    }
+
+    // NTK scaling keeps the highest frequency and rescales the lowest one by `factor`.
+    let (_, sin_n) = tables(Some(RopeScaling::Ntk { factor: 4. }))?;
+    assert_eq!(sin_n[5][0], sin[5][0]);
+    let lowest = 1. / 10000f64.powf(62. / 64.) / 4.;
+    assert!((sin_n[500][31] as f64 - (500. * lowest).sin()).abs() < 1e-4);
+
+    // YaRN keeps the high frequencies, interpolates the low ones and scales the tables.
+    let yarn = RopeScaling::Yarn {
+        factor: 4.,
+        original_max_position_embeddings: 256,
+        beta_fast: 32.,
+        beta_slow: 1.,
+    };
+    let (cos_y, sin_y) = tables(Some(yarn))?;
+    let mscale = 0.1 * 4f32.ln() + 1.;
+    assert_eq!(cos_y[0][0], mscale);
+    assert!((sin_y[7][0] - 7f32.sin() * mscale).abs() < 1e-5);
+    let lowest = 1. / 10000f32.powf(62. / 64.);
+    assert!((sin_y[500][31] - (500. * lowest / 4.).sin() * mscale).abs() < 1e-4);
+
+    // The llama3 scaling keeps the short wavelengths and divides the long ones by `factor`.
+    let llama3 = RopeScaling::Llama3 {
+        factor: 8.,
+        low_freq_factor: 1.,
+        high_freq_factor: 4.,
+        original_max_position_embeddings: 256,
+    };
+    let (_, sin_l) = tables(Some(llama3))?;
+    assert_eq!(sin_l[7][0], sin[7][0]);
+    assert!((sin_l[500][31] - (500. * lowest / 8.).sin()).abs() < 1e-4);
+    // Dynamic scaling does not change the tables within the original context.
+    let (_, sin_d) = tables(Some(RopeScaling::Dynamic { factor: 2. }))?;
+    assert_eq!(sin_d, sin);
+
+    // Applying the rope at some offset uses the positions starting at this offset.
+    let xs = Tensor::randn(0f32, 1., (1, 2, 3, 64), device)?;
+    let scaling = RopeScaling::Linear { factor: 2. };
+    let ys = rope_with_scaling(&xs, 10, 10000., Some(&scaling))?;
+    let (cos, sin) = rope_tables(64, 13, 10000., Some(&scaling), DType::F32, device)?;
+    let expected = naive_rope(&xs, &cos.narrow(0, 10, 3)?, &sin.narrow(0, 10, 3)?, false)?;
+    assert!(max_diff(&ys, &expected)? < 1e-6);
+    assert!(rope_tables(7, 4, 10000., None, DType::F32, device).is_err());
+    Ok(())
+}
+
+#[test]
+fn alibi_bias() -> Result<()> {
+    use candle_nn::rotary_emb::alibi_bias;
+    let device = &Device::Cpu;
+    let bias = alibi_bias(2, 2, 3, 8., device)?;
+    assert_eq!(
+        bias.to_vec3::<f32>()?,
+        [
+            [[-0.0625, -0., -0.0625], [-0.125, -0.0625, -0.]],
+            [
+                [-0.00390625, -0., -0.00390625],
+                [-0.0078125, -0.00390625, -0.]
+            ]
+        ]
+    );
+    // With three heads, the slopes of four heads are interleaved.
+    let bias = alibi_bias(3, 1, 2, 8., device)?;
+    assert_eq!(
+        bias.squeeze(1)?.to_vec2::<f32>()?,
+        [[-0.0625, -0.], [-0.00390625, -0.], [-0.25, -0.]]
+    );
+    assert!(alibi_bias(2, 3, 2, 8., device).is_err());
     Ok(())
 }
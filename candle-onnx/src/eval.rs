@@ -10,7 +10,11 @@ pub fn dtype(dt: DataType) -> Option<DType> {
     match dt {
         DataType::Uint8 => Some(DType::U8),
         DataType::Uint32 => Some(DType::U32),
+        DataType::Int8 => Some(DType::I8),
+        DataType::Int16 => Some(DType::I16),
+        DataType::Int32 => Some(DType::I32),
         DataType::Int64 => Some(DType::I64),
+        DataType::Bool => Some(DType::Bool),
         DataType::Float16 => Some(DType::F16),
         DataType::Float => Some(DType::F32),
         DataType::Double => Some(DType::F64),
@@ -101,16 +105,18 @@ fn get_attr_opt<'a, T: Attr + ?Sized>(
 pub fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
     let dims: Vec<usize> = t.dims.iter().map(|&x| x as usize).collect();
     match DataType::try_from(t.data_type) {
+        // The int32 values are widened to i64 as the gpu backends do not support i32.
         Ok(DataType::Int32) => {
             if t.int32_data.is_empty() {
-                let len = t.raw_data.len() / 4;
-                let data: &[i32] =
-                    unsafe { std::slice::from_raw_parts(t.raw_data.as_ptr() as *const i32, len) };
-                let data = data.iter().map(|v| *v as i64).collect::<Vec<_>>();
-                Tensor::from_vec(data, len, &Device::Cpu)
+                let data = t
+                    .raw_data
+                    .chunks_exact(4)
+                    .map(|v| i32::from_le_bytes([v[0], v[1], v[2], v[3]]) as i64)
+                    .collect::<Vec<_>>();
+                Tensor::from_vec(data, dims.as_slice(), &Device::Cpu)
             } else {
                 let data = t.int32_data.iter().map(|v| *v as i64).collect::<Vec<_>>();
-                Tensor::from_vec(data, t.int32_data.len(), &Device::Cpu)
+                Tensor::from_vec(data, dims.as_slice(), &Device::Cpu)
             }
         }
         Ok(dt) => match dtype(dt) {
@@ -121,6 +127,9 @@ pub fn get_tensor(t: &onnx::TensorProto, name: &str) -> Result<Tensor> {
                     Tensor::from_slice(&t.double_data, dims.as_slice(), &Device::Cpu)
                 } else if dt == DType::I64 && !t.int64_data.is_empty() {
                     Tensor::from_slice(&t.int64_data, dims.as_slice(), &Device::Cpu)
+                } else if !t.int32_data.is_empty() {
+                    // The int8, int16, uint8 and bool values are stored in `int32_data`.
+                    Tensor::from_slice(&t.int32_data, dims.as_slice(), &Device::Cpu)?.to_dtype(dt)
                 } else {
                     Tensor::from_raw_buffer(
                         t.raw_data.as_slice(),
@@ -501,6 +510,83 @@ pub fn simple_eval(
                 };
                 values.insert(node.output[0].clone(), xs);
             }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Pad
+            "Pad" => {
+                let xs = get(&node.input[0])?;
+                // Optional inputs can be omitted by using an empty name.
+                let opt_input = |i: usize| match node.input.get(i) {
+                    Some(name) if !name.is_empty() => get(name).map(Some),
+                    _ => Ok(None),
+                };
+                // Before opset 11, the pads and the constant value were attributes.
+                let pads = match get_attr_opt::<[i64]>(node, "pads")? {
+                    Some(pads) => pads.to_vec(),
+                    None => get(&node.input[1])?
+                        .to_dtype(DType::I64)?
+                        .to_vec1::<i64>()?,
+                };
+                let mode = match get_attr_opt::<str>(node, "mode")? {
+                    None | Some("constant") => {
+                        // The constant value can be a scalar or a one element tensor.
+                        let value = match opt_input(2)? {
+                            Some(value) => {
+                                let vs = value.flatten_all()?.to_dtype(DType::F64)?;
+                                match vs.to_vec1::<f64>()?.as_slice() {
+                                    &[value] => value,
+                                    _ => bail!(
+                                        "unexpected constant_value shape {:?} for {}",
+                                        value.shape(),
+                                        node.name
+                                    ),
+                                }
+                            }
+                            None => {
+                                get_attr_opt::<f32>(node, "value")?.copied().unwrap_or(0.) as f64
+                            }
+                        };
+                        candle::PadMode::Constant(value)
+                    }
+                    Some("reflect") => candle::PadMode::Reflect,
+                    Some("edge") => candle::PadMode::Replicate,
+                    Some("wrap") => candle::PadMode::Circular,
+                    Some(mode) => bail!("unsupported pad mode {mode} for {}", node.name),
+                };
+                let axes = match opt_input(3)? {
+                    Some(axes) => axes
+                        .to_dtype(DType::I64)?
+                        .to_vec1::<i64>()?
+                        .iter()
+                        .map(|&axis| xs.normalize_axis(axis))
+                        .collect::<Result<Vec<_>>>()?,
+                    None => (0..xs.rank()).collect(),
+                };
+                if pads.len() != 2 * axes.len() {
+                    bail!(
+                        "unexpected pads {pads:?} for {} axes in {}",
+                        axes.len(),
+                        node.name
+                    )
+                }
+                let mut xs = xs.clone();
+                let mut padding = vec![(0, 0); xs.rank()];
+                for (i, &axis) in axes.iter().enumerate() {
+                    let (begin, end) = (pads[i], pads[i + axes.len()]);
+                    // Negative pads remove values.
+                    let trim_begin = begin.min(0).unsigned_abs() as usize;
+                    let trim_end = end.min(0).unsigned_abs() as usize;
+                    let dim = xs.dim(axis)?;
+                    if trim_begin.saturating_add(trim_end) > dim {
+                        bail!(
+                            "pads {pads:?} remove more than the {dim} values of axis {axis} in {}",
+                            node.name
+                        )
+                    }
+                    xs = xs.narrow(axis, trim_begin, dim - trim_begin - trim_end)?;
+                    padding[axis] = (begin.max(0) as usize, end.max(0) as usize)
+                }
+                let xs = xs.pad(&padding, mode)?;
+                values.insert(node.output[0].clone(), xs);
+            }
             "Gather" => {
                 let xs = get(&node.input[0])?;
                 let indices = get(&node.input[1])?;
@@ -727,6 +813,7 @@ pub fn simple_eval(
                 let input = get(&node.input[0])?;
                 let dt: i64 = *get_attr(node, "to")?;
                 let dtype = match DataType::try_from(dt as i32) {
+                    // Same as for the initializers, int32 values are widened to i64.
                     Ok(DataType::Int32) => DType::I64,
                     Ok(dt) => match dtype(dt) {
                         Some(dt) => dt,
@@ -760,6 +847,17 @@ pub fn simple_eval(
                 let output = input.cumsum(axis as usize)?;
                 values.insert(node.output[0].clone(), output);
             }
+            // https://github.com/onnx/onnx/blob/main/docs/Operators.md#Einsum
+            "Einsum" => {
+                let equation: &str = get_attr(node, "equation")?;
+                let inputs = node
+                    .input
+                    .iter()
+                    .map(|n| get(n.as_str()))
+                    .collect::<Result<Vec<_>>>()?;
+                let output = Tensor::einsum(equation, &inputs)?;
+                values.insert(node.output[0].clone(), output);
+            }
             op_type => bail!("unsupported op_type {op_type} for op {node:?}"),
         }
     }
@@ -5,7 +5,8 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use candle::{Device, Result, Tensor};
-use candle_onnx::onnx::{GraphProto, ModelProto, NodeProto, ValueInfoProto};
+use candle_onnx::onnx::attribute_proto::AttributeType;
+use candle_onnx::onnx::{AttributeProto, GraphProto, ModelProto, NodeProto, ValueInfoProto};
 use std::collections::HashMap;
 
 const INPUT_X: &str = "x";
@@ -365,6 +366,66 @@ fn test_matmul_operation() -> Result<()> {
     Ok(())
 }
 
+// "Einsum"
+#[test]
+fn test_einsum_operation() -> Result<()> {
+    let manual_graph = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![NodeProto {
+            op_type: "Einsum".to_string(),
+            domain: "".to_string(),
+            attribute: vec![AttributeProto {
+                name: "equation".to_string(),
+                r#type: AttributeType::String.into(),
+                s: b"bij,bjk->bik".to_vec(),
+                ..AttributeProto::default()
+            }],
+            input: vec![INPUT_X.to_string(), INPUT_Y.to_string()],
+            output: vec![OUTPUT_Z.to_string()],
+            name: "".to_string(),
+            doc_string: "".to_string(),
+        }],
+        name: "".to_string(),
+        initializer: vec![],
+        input: vec![],
+        output: vec![ValueInfoProto {
+            name: OUTPUT_Z.to_string(),
+            doc_string: "".to_string(),
+            r#type: None,
+        }],
+        value_info: vec![],
+        doc_string: "".to_string(),
+        sparse_initializer: vec![],
+        quantization_annotation: vec![],
+    }));
+
+    let mut inputs: HashMap<String, Tensor> = HashMap::new();
+    inputs.insert(
+        INPUT_X.to_string(),
+        Tensor::from_vec(
+            vec![1.0f32, 2.0f32, 3.0f32, 4.0f32],
+            &[1, 2, 2],
+            &Device::Cpu,
+        )?,
+    );
+    inputs.insert(
+        INPUT_Y.to_string(),
+        Tensor::from_vec(
+            vec![5.0f32, 6.0f32, 7.0f32, 8.0f32],
+            &[1, 2, 2],
+            &Device::Cpu,
+        )?,
+    );
+
+    let eval = candle_onnx::simple_eval(&manual_graph, inputs)?;
+    assert_eq!(eval.len(), 1);
+
+    let z = eval.get(OUTPUT_Z).expect("Output 'z' not found");
+    let results = z.to_vec3::<f32>()?;
+    assert_eq!(results, vec![vec![vec![19.0, 22.0], vec![43.0, 50.0]]]);
+
+    Ok(())
+}
+
 // "Reshape"
 #[test]
 fn test_reshape_operation() -> Result<()> {
@@ -677,8 +738,91 @@ fn test_dropout_operation() -> Result<()> {
     Ok(())
 }
 
+// "Pad"
+#[test]
+fn test_pad_operation() -> Result<()> {
+    let manual_graph = create_model_proto_with_graph(Some(GraphProto {
+        node: vec![NodeProto {
+            op_type: "Pad".to_string(),
+            domain: "".to_string(),
+            attribute: vec![],
+            input: vec![
+                INPUT_X.to_string(),
+                INPUT_Y.to_string(),
+                "value".to_string(),
+            ],
+            output: vec![OUTPUT_Z.to_string()],
+            name: "".to_string(),
+            doc_string: "".to_string(),
+        }],
+        name: "".to_string(),
+        initializer: vec![],
+        input: vec![],
+        output: vec![ValueInfoProto {
+            name: OUTPUT_Z.to_string(),
+            doc_string: "".to_string(),
+            r#type: None,
+        }],
+        value_info: vec![],
+        doc_string: "".to_string(),
+        sparse_initializer: vec![],
+        quantization_annotation: vec![],
+    }));
+    let pad = |pads: &[i64], value: Tensor| -> Result<Tensor> {
+        let x = Tensor::from_vec(vec![1.0f32, 2.0, 3.0, 4.0], &[2, 2], &Device::Cpu)?;
+        let mut inputs: HashMap<String, Tensor> = HashMap::new();
+        inputs.insert(INPUT_X.to_string(), x);
+        inputs.insert(INPUT_Y.to_string(), Tensor::new(pads, &Device::Cpu)?);
+        inputs.insert("value".to_string(), value);
+        let mut eval = candle_onnx::simple_eval(&manual_graph, inputs)?;
+        assert_eq!(eval.len(), 1);
+        Ok(eval.remove(OUTPUT_Z).expect("Output 'z' not found"))
+    };
+
+    // The begin values for all the axes come first, then the end values. Negative pads crop.
+    let z = pad(&[1, -1, 0, 1], Tensor::new(9f32, &Device::Cpu)?)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![9.0, 9.0], vec![2.0, 9.0], vec![4.0, 9.0]]
+    );
+
+    // The constant value can also be a one element tensor.
+    let z = pad(&[0, 0, 1, 0], Tensor::new(&[7f32], &Device::Cpu)?)?;
+    assert_eq!(
+        z.to_vec2::<f32>()?,
+        vec![vec![1.0, 2.0], vec![3.0, 4.0], vec![7.0, 7.0]]
+    );
+
+    // Cropping more values than the axis holds is an error.
+    assert!(pad(&[0, -2, 0, -1], Tensor::new(0f32, &Device::Cpu)?).is_err());
+
+    Ok(())
+}
+
 // Below are ops that are implemented but not tested yet
 
+// Initializers stored in "int32_data"
+#[test]
+fn test_get_tensor_int32_data() -> Result<()> {
+    use candle_onnx::onnx::{tensor_proto::DataType, TensorProto};
+
+    let tensor = |data_type: DataType, int32_data: Vec<i32>| TensorProto {
+        dims: vec![int32_data.len() as i64],
+        data_type: data_type as i32,
+        int32_data,
+        ..TensorProto::default()
+    };
+    let t = candle_onnx::eval::get_tensor(&tensor(DataType::Int32, vec![-1, 2]), "t")?;
+    assert_eq!(t.to_vec1::<i64>()?, [-1, 2]);
+    let t = candle_onnx::eval::get_tensor(&tensor(DataType::Int8, vec![-1, 2]), "t")?;
+    assert_eq!(t.to_vec1::<i8>()?, [-1, 2]);
+    let t = candle_onnx::eval::get_tensor(&tensor(DataType::Int16, vec![-300, 2]), "t")?;
+    assert_eq!(t.to_vec1::<i16>()?, [-300, 2]);
+    let t = candle_onnx::eval::get_tensor(&tensor(DataType::Bool, vec![0, 1]), "t")?;
+    assert_eq!(t.to_vec1::<candle::Bool>()?, [false.into(), true.into()]);
+    Ok(())
+}
+
 // "MaxPool"
 // #[test]
 
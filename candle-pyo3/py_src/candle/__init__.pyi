@@ -6,6 +6,9 @@ from candle.typing import _ArrayLike, Device, Scalar, Index, Shape
 class bf16(DType):
     pass
 
+class bool(DType):
+    pass
+
 @staticmethod
 def cat(tensors: List[Tensor], dim: int) -> Tensor:
     """
@@ -22,9 +25,24 @@ class f32(DType):
 class f64(DType):
     pass
 
+class f8e4m3(DType):
+    pass
+
+class f8e5m2(DType):
+    pass
+
+class i16(DType):
+    pass
+
+class i32(DType):
+    pass
+
 class i64(DType):
     pass
 
+class i8(DType):
+    pass
+
 @staticmethod
 def ones(*shape: Shape, dtype: Optional[DType] = None, device: Optional[Device] = None) -> Tensor:
     """
@@ -151,13 +151,19 @@ macro_rules! pydtype {
     };
 }
 
+pydtype!(i8, |v| v);
+pydtype!(i16, |v| v);
+pydtype!(i32, |v| v);
 pydtype!(i64, |v| v);
+pydtype!(::candle::Bool, |v: ::candle::Bool| v.0);
 pydtype!(u8, |v| v);
 pydtype!(u32, |v| v);
 pydtype!(f16, f32::from);
 pydtype!(bf16, f32::from);
 pydtype!(f32, |v| v);
 pydtype!(f64, |v| v);
+pydtype!(::candle::F8E4M3, f32::from);
+pydtype!(::candle::F8E5M2, f32::from);
 
 fn actual_index(t: &Tensor, dim: usize, index: i64) -> ::candle::Result<usize> {
     let dim = t.dim(dim)?;
@@ -200,11 +206,17 @@ trait MapDType {
         match t.dtype() {
             DType::U8 => self.f::<u8>(t),
             DType::U32 => self.f::<u32>(t),
+            DType::I8 => self.f::<i8>(t),
+            DType::I16 => self.f::<i16>(t),
+            DType::I32 => self.f::<i32>(t),
             DType::I64 => self.f::<i64>(t),
+            DType::Bool => self.f::<::candle::Bool>(t),
             DType::BF16 => self.f::<bf16>(t),
             DType::F16 => self.f::<f16>(t),
             DType::F32 => self.f::<f32>(t),
             DType::F64 => self.f::<f64>(t),
+            DType::F8E4M3 => self.f::<::candle::F8E4M3>(t),
+            DType::F8E5M2 => self.f::<::candle::F8E5M2>(t),
         }
     }
 }
@@ -1601,11 +1613,17 @@ fn candle(py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_class::<PyDType>()?;
     m.add("u8", PyDType(DType::U8))?;
     m.add("u32", PyDType(DType::U32))?;
+    m.add("i8", PyDType(DType::I8))?;
+    m.add("i16", PyDType(DType::I16))?;
+    m.add("i32", PyDType(DType::I32))?;
     m.add("i64", PyDType(DType::I64))?;
+    m.add("bool", PyDType(DType::Bool))?;
     m.add("bf16", PyDType(DType::BF16))?;
     m.add("f16", PyDType(DType::F16))?;
     m.add("f32", PyDType(DType::F32))?;
     m.add("f64", PyDType(DType::F64))?;
+    m.add("f8e4m3", PyDType(DType::F8E4M3))?;
+    m.add("f8e5m2", PyDType(DType::F8E5M2))?;
     m.add_function(wrap_pyfunction!(cat, m)?)?;
     m.add_function(wrap_pyfunction!(ones, m)?)?;
     m.add_function(wrap_pyfunction!(rand, m)?)?;
@@ -80,7 +80,7 @@ def test_tensor_can_be_scliced_3d():
 
 def assert_bool(t: Tensor, expected: bool):
     assert t.shape == ()
-    assert str(t.dtype) == str(candle.u8)
+    assert str(t.dtype) == str(candle.bool)
     assert bool(t.values()) == expected
 
 
@@ -0,0 +1,396 @@
+//! Beam search decoding for encoder-decoder models.
+//!
+//! The implementation follows the one from the transformers library, at each step the
+//! `2 * num_beams` best continuations are considered so that there are still `num_beams` live
+//! beams when some of the candidates end with the end of sequence token.
+use candle::{DType, Device, IndexOp, Result, Tensor, D};
+
+/// An encoder-decoder model whose decoder can be run on several beams at once.
+pub trait EncoderDecoder {
+    /// Runs the decoder and returns the logits for the last position with shape
+    /// `(num_beams, vocab_size)`. `tokens` contains the full decoded sequences so far with shape
+    /// `(num_beams, seq_len)`, models using a self-attention kv cache only have to process the
+    /// tokens starting from `index_pos`. `encoder_xs` has already been repeated for each beam.
+    fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        encoder_xs: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor>;
+
+    /// Selects the kv cache entries of the beams listed in the `u32` tensor `beam_indices`.
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()>;
+
+    fn clear_kv_cache(&mut self);
+}
+
+impl EncoderDecoder for crate::models::marian::MTModel {
+    fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        encoder_xs: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let seq_len = tokens.dim(1)?;
+        let tokens = tokens.narrow(1, index_pos, seq_len - index_pos)?;
+        let logits = self.decode(&tokens, encoder_xs, index_pos)?;
+        logits.i((.., seq_len - index_pos - 1))
+    }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.reorder_kv_cache(beam_indices)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.reset_kv_cache()
+    }
+}
+
+impl EncoderDecoder for crate::models::trocr::TrOCRModel {
+    fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        encoder_xs: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let seq_len = tokens.dim(1)?;
+        let tokens = tokens.narrow(1, index_pos, seq_len - index_pos)?;
+        let logits = self.decode(&tokens, encoder_xs, index_pos)?;
+        logits.i((.., seq_len - index_pos - 1))
+    }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.reorder_kv_cache(beam_indices)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.reset_kv_cache()
+    }
+}
+
+impl EncoderDecoder for crate::models::t5::T5ForConditionalGeneration {
+    fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        encoder_xs: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let seq_len = tokens.dim(1)?;
+        let tokens = tokens.narrow(1, index_pos, seq_len - index_pos)?;
+        self.decode(&tokens, encoder_xs)
+    }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.reorder_kv_cache(beam_indices)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+// The whisper decoder does not cache the self-attention keys and values so the full sequences
+// are processed at each step, only the cross-attention cache has to be flushed on a new run.
+impl EncoderDecoder for crate::models::whisper::model::Whisper {
+    fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        encoder_xs: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let seq_len = tokens.dim(1)?;
+        let ys = self.decoder.forward(tokens, encoder_xs, index_pos == 0)?;
+        let logits = self.decoder.final_linear(&ys.narrow(1, seq_len - 1, 1)?)?;
+        logits.squeeze(1)
+    }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(beam_indices)
+    }
+
+    fn clear_kv_cache(&mut self) {}
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearchConfig {
+    pub num_beams: usize,
+    /// The maximum number of tokens to generate, excluding the start tokens.
+    pub max_new_tokens: usize,
+    /// Exponent applied to the hypothesis length when normalizing the scores, values above 0
+    /// favor longer sequences.
+    pub length_penalty: f64,
+    /// When set, the search stops as soon as `num_beams` hypotheses are finished, otherwise it
+    /// stops when no live beam can beat the finished hypotheses.
+    pub early_stopping: bool,
+    /// The number of hypotheses returned, at most `num_beams`.
+    pub num_return_sequences: usize,
+    pub eos_token_id: u32,
+    /// Tokens that are never generated, e.g. the padding token for Marian models.
+    pub suppress_tokens: Vec<u32>,
+}
+
+impl BeamSearchConfig {
+    pub fn new(num_beams: usize, eos_token_id: u32) -> Self {
+        Self {
+            num_beams,
+            max_new_tokens: 256,
+            length_penalty: 1.0,
+            early_stopping: false,
+            num_return_sequences: 1,
+            eos_token_id,
+            suppress_tokens: vec![],
+        }
+    }
+}
+
+/// A finished sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, excluding the start tokens but including the final end of
+    /// sequence token if any.
+    pub tokens: Vec<u32>,
+    /// The sum of the log probabilities of the tokens normalized by the length penalty.
+    pub score: f64,
+}
+
+type AllowedTokensFn = dyn FnMut(&[u32]) -> Option<Vec<u32>>;
+
+pub struct BeamSearch {
+    config: BeamSearchConfig,
+    forced_prefix: Vec<u32>,
+    allowed_tokens: Option<Box<AllowedTokensFn>>,
+}
+
+impl BeamSearch {
+    pub fn new(config: BeamSearchConfig) -> Self {
+        Self {
+            config,
+            forced_prefix: vec![],
+            allowed_tokens: None,
+        }
+    }
+
+    /// Forces the first generated tokens, e.g. a language code for multilingual models.
+    pub fn with_forced_prefix(mut self, forced_prefix: Vec<u32>) -> Self {
+        self.forced_prefix = forced_prefix;
+        self
+    }
+
+    /// Restricts the tokens that can follow a sequence of generated tokens, returning `None`
+    /// allows all the tokens.
+    pub fn with_allowed_tokens<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&[u32]) -> Option<Vec<u32>> + 'static,
+    {
+        self.allowed_tokens = Some(Box::new(f));
+        self
+    }
+
+    pub fn config(&self) -> &BeamSearchConfig {
+        &self.config
+    }
+
+    // Returns the log probabilities with shape (num_beams, vocab_size) after applying the
+    // constraints, disallowed tokens get a log probability of -inf.
+    fn constrained_log_probs(&mut self, logits: &Tensor, beams: &[Vec<u32>]) -> Result<Vec<f32>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let (num_beams, vocab_size) = logits.dims2()?;
+        let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+        let mut log_probs = log_probs.flatten_all()?.to_vec1::<f32>()?;
+        let step = beams.first().map_or(0, |b| b.len());
+        for (beam_idx, beam) in beams.iter().enumerate().take(num_beams) {
+            let row = &mut log_probs[beam_idx * vocab_size..(beam_idx + 1) * vocab_size];
+            for &token in self.config.suppress_tokens.iter() {
+                if let Some(v) = row.get_mut(token as usize) {
+                    *v = f32::NEG_INFINITY
+                }
+            }
+            let allowed = match self.forced_prefix.get(step) {
+                Some(&token) => Some(vec![token]),
+                None => self.allowed_tokens.as_mut().and_then(|f| f(beam)),
+            };
+            if let Some(allowed) = allowed {
+                let mut mask = vec![false; vocab_size];
+                for token in allowed {
+                    if let Some(v) = mask.get_mut(token as usize) {
+                        *v = true
+                    }
+                }
+                for (v, allowed) in row.iter_mut().zip(mask) {
+                    if !allowed {
+                        *v = f32::NEG_INFINITY
+                    }
+                }
+            }
+        }
+        Ok(log_probs)
+    }
+
+    /// Runs the beam search and returns the best hypotheses sorted by decreasing score.
+    /// `encoder_xs` is the encoder output for a single sequence and `start_tokens` are the
+    /// tokens the decoder starts from, e.g. the decoder start token.
+    pub fn generate<M: EncoderDecoder>(
+        &mut self,
+        model: &mut M,
+        encoder_xs: &Tensor,
+        start_tokens: &[u32],
+    ) -> Result<Vec<Hypothesis>> {
+        let num_beams = self.config.num_beams;
+        if num_beams == 0 {
+            candle::bail!("beam search requires at least one beam")
+        }
+        if start_tokens.is_empty() {
+            candle::bail!("beam search requires at least one start token")
+        }
+        let device = encoder_xs.device().clone();
+        let encoder_xs = encoder_xs.repeat(num_beams)?;
+        let start_len = start_tokens.len();
+        let mut tokens = vec![start_tokens.to_vec(); num_beams];
+        // Only the first beam is live initially so that the beams do not all end up with the
+        // same tokens.
+        let mut beam_scores = vec![f32::NEG_INFINITY; num_beams];
+        beam_scores[0] = 0.;
+        let mut hyps = BeamHypotheses::new(&self.config);
+        model.clear_kv_cache();
+        let mut index_pos = 0;
+        let mut done = false;
+        for step in 0..self.config.max_new_tokens {
+            let input = tokens_to_tensor(&tokens, &device)?;
+            let logits = model.decode_step(&input, &encoder_xs, index_pos)?;
+            index_pos = tokens[0].len();
+            let generated = tokens
+                .iter()
+                .map(|t| t[start_len..].to_vec())
+                .collect::<Vec<_>>();
+            let log_probs = self.constrained_log_probs(&logits, &generated)?;
+            let vocab_size = log_probs.len() / num_beams;
+            let mut candidates = log_probs
+                .iter()
+                .enumerate()
+                .map(|(idx, lp)| {
+                    (
+                        idx / vocab_size,
+                        idx % vocab_size,
+                        beam_scores[idx / vocab_size] + lp,
+                    )
+                })
+                .filter(|(_, _, score)| score.is_finite())
+                .collect::<Vec<_>>();
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+            candidates.truncate(2 * num_beams);
+
+            let mut next_beams = Vec::with_capacity(num_beams);
+            for (rank, &(beam_idx, token, score)) in candidates.iter().enumerate() {
+                if token as u32 == self.config.eos_token_id {
+                    // Only add an end of sequence hypothesis if it is one of the best num_beams
+                    // candidates.
+                    if rank < num_beams {
+                        let mut hyp = generated[beam_idx].clone();
+                        hyp.push(token as u32);
+                        hyps.add(hyp, score as f64);
+                    }
+                } else {
+                    next_beams.push((beam_idx, token as u32, score));
+                }
+                if next_beams.len() == num_beams {
+                    break;
+                }
+            }
+            if next_beams.is_empty() || hyps.is_done(next_beams[0].2 as f64, step + 1) {
+                done = true;
+                break;
+            }
+            // Pad with dead beams when the constraints leave too few candidates.
+            while next_beams.len() < num_beams {
+                let (beam_idx, token, _) = next_beams[0];
+                next_beams.push((beam_idx, token, f32::NEG_INFINITY))
+            }
+            let beam_indices = next_beams.iter().map(|b| b.0 as u32).collect::<Vec<_>>();
+            model.reorder_kv_cache(&Tensor::new(beam_indices.as_slice(), &device)?)?;
+            tokens = next_beams
+                .iter()
+                .map(|&(beam_idx, token, _)| {
+                    let mut t = tokens[beam_idx].clone();
+                    t.push(token);
+                    t
+                })
+                .collect();
+            beam_scores = next_beams.iter().map(|b| b.2).collect();
+        }
+        // When the maximum length is reached, the live beams are candidates too.
+        if !done {
+            for (beam, &score) in tokens.iter().zip(beam_scores.iter()) {
+                if score.is_finite() {
+                    hyps.add(beam[start_len..].to_vec(), score as f64)
+                }
+            }
+        }
+        Ok(hyps.into_sorted(self.config.num_return_sequences))
+    }
+}
+
+fn tokens_to_tensor(tokens: &[Vec<u32>], device: &Device) -> Result<Tensor> {
+    let seq_len = tokens[0].len();
+    let data = tokens.iter().flatten().copied().collect::<Vec<_>>();
+    Tensor::from_vec(data, (tokens.len(), seq_len), device)
+}
+
+struct BeamHypotheses {
+    num_beams: usize,
+    length_penalty: f64,
+    early_stopping: bool,
+    hyps: Vec<Hypothesis>,
+}
+
+impl BeamHypotheses {
+    fn new(config: &BeamSearchConfig) -> Self {
+        Self {
+            num_beams: config.num_beams,
+            length_penalty: config.length_penalty,
+            early_stopping: config.early_stopping,
+            hyps: Vec::with_capacity(config.num_beams + 1),
+        }
+    }
+
+    fn worst_score(&self) -> f64 {
+        self.hyps
+            .iter()
+            .map(|h| h.score)
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    fn add(&mut self, tokens: Vec<u32>, sum_log_probs: f64) {
+        let score = sum_log_probs / (tokens.len().max(1) as f64).powf(self.length_penalty);
+        if self.hyps.len() < self.num_beams || score > self.worst_score() {
+            self.hyps.push(Hypothesis { tokens, score });
+            if self.hyps.len() > self.num_beams {
+                let worst = self
+                    .hyps
+                    .iter()
+                    .enumerate()
+                    .min_by(|a, b| a.1.score.total_cmp(&b.1.score))
+                    .map(|(idx, _)| idx)
+                    .unwrap();
+                self.hyps.remove(worst);
+            }
+        }
+    }
+
+    // Returns true when none of the live beams can improve on the finished hypotheses.
+    fn is_done(&self, best_sum_log_probs: f64, cur_len: usize) -> bool {
+        if self.hyps.len() < self.num_beams {
+            false
+        } else if self.early_stopping {
+            true
+        } else {
+            let cur_score = best_sum_log_probs / (cur_len as f64).powf(self.length_penalty);
+            self.worst_score() >= cur_score
+        }
+    }
+
+    fn into_sorted(mut self, n: usize) -> Vec<Hypothesis> {
+        self.hyps.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.hyps.truncate(n);
+        self.hyps
+    }
+}
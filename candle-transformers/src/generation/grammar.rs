@@ -0,0 +1,885 @@
+//! Grammar constrained sampling.
+//!
+//! Grammars are written in the GBNF format from llama.cpp, the `root` rule describes the
+//! whole output:
+//!
+//! ```text
+//! root   ::= answer "."
+//! answer ::= "yes" | "no" | "maybe, " [0-9]+ "%"
+//! ```
+//!
+//! A grammar is matched by a pushdown automaton over unicode characters whose state is the set
+//! of stacks of rule positions that are still possible. At each sampling step, the tokens of the
+//! vocabulary are matched by walking a prefix tree of their bytes so that the work for the
+//! tokens sharing a prefix is only done once, and the tokens that cannot continue the output
+//! get masked. Left recursive rules are not supported.
+use super::Constraint;
+use candle::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    // Matches a single character within one of the ranges, or outside all of them when
+    // negated.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    Rule(usize),
+}
+
+impl Element {
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Self::Chars { ranges, negated } => {
+                ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != *negated
+            }
+            Self::Rule(_) => false,
+        }
+    }
+
+    // Returns true if some character with a code point in `lo..=hi` is matched.
+    fn matches_any(&self, lo: u32, hi: u32) -> bool {
+        match self {
+            Self::Chars {
+                ranges,
+                negated: false,
+            } => ranges
+                .iter()
+                .any(|&(l, h)| (l as u32) <= hi && lo <= (h as u32)),
+            Self::Chars {
+                ranges,
+                negated: true,
+            } => {
+                // Look for a code point that is not covered by the ranges.
+                let mut c = lo;
+                while c <= hi {
+                    match ranges
+                        .iter()
+                        .find(|&&(l, h)| (l as u32) <= c && c <= (h as u32))
+                    {
+                        None => return true,
+                        Some(&(_, h)) => c = h as u32 + 1,
+                    }
+                }
+                false
+            }
+            Self::Rule(_) => false,
+        }
+    }
+}
+
+type Alternative = Vec<Element>;
+
+// A position within an alternative of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Position {
+    rule: usize,
+    alt: usize,
+    pos: usize,
+}
+
+// The positions of the rules being matched, the last one is the next element to match. An
+// empty stack means that the grammar has been fully matched.
+type Stack = Vec<Position>;
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+struct Parser {
+    src: Vec<char>,
+    pos: usize,
+    names: HashMap<String, usize>,
+    rule_names: Vec<String>,
+    rules: Vec<Option<Vec<Alternative>>>,
+}
+
+impl Parser {
+    fn new(src: &str) -> Self {
+        Self {
+            src: src.chars().collect(),
+            pos: 0,
+            names: HashMap::new(),
+            rule_names: vec![],
+            rules: vec![],
+        }
+    }
+
+    fn line(&self) -> usize {
+        self.src[..self.pos].iter().filter(|&&c| c == '\n').count() + 1
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            None => candle::bail!("unexpected end of grammar"),
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                candle::bail!("expected '{s}' on line {} of the grammar", self.line())
+            }
+            self.pos += 1
+        }
+        Ok(())
+    }
+
+    // Skips whitespaces and comments, new lines are only skipped when `newlines` is set.
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' => self.pos += 1,
+                '\r' | '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while !matches!(self.peek(), None | Some('\n')) {
+                        self.pos += 1
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        match self.names.get(name) {
+            Some(&id) => id,
+            None => {
+                let id = self.rules.len();
+                self.names.insert(name.to_string(), id);
+                self.rule_names.push(name.to_string());
+                self.rules.push(None);
+                id
+            }
+        }
+    }
+
+    // Creates a rule for a group or a repetition within rule `name`.
+    fn new_rule(&mut self, name: &str, alts: Vec<Alternative>) -> usize {
+        let mut index = self.rules.len();
+        while self.names.contains_key(&format!("{name}_{index}")) {
+            index += 1
+        }
+        let id = self.rule_id(&format!("{name}_{index}"));
+        self.rules[id] = Some(alts);
+        id
+    }
+
+    fn parse_name(&mut self) -> Result<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1
+        }
+        if start == self.pos {
+            candle::bail!(
+                "expected a rule name on line {} of the grammar",
+                self.line()
+            )
+        }
+        Ok(self.src[start..self.pos].iter().collect())
+    }
+
+    fn parse_hex(&mut self, len: usize) -> Result<char> {
+        let mut value = 0u32;
+        for _ in 0..len {
+            let c = self.next()?;
+            match c.to_digit(16) {
+                None => candle::bail!("invalid hex digit {c:?} on line {}", self.line()),
+                Some(d) => value = value * 16 + d,
+            }
+        }
+        match char::from_u32(value) {
+            None => candle::bail!("invalid character {value:#x} on line {}", self.line()),
+            Some(c) => Ok(c),
+        }
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let c = match self.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            'x' => self.parse_hex(2)?,
+            'u' => self.parse_hex(4)?,
+            'U' => self.parse_hex(8)?,
+            c @ ('\\' | '"' | '\'' | '[' | ']' | '-' | '/') => c,
+            c => candle::bail!("unknown escape '\\{c}' on line {}", self.line()),
+        };
+        Ok(c)
+    }
+
+    fn parse_literal(&mut self, seq: &mut Alternative) -> Result<()> {
+        self.expect("\"")?;
+        while self.peek() != Some('"') {
+            let c = self.parse_char()?;
+            seq.push(Element::Chars {
+                ranges: vec![(c, c)],
+                negated: false,
+            })
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_char_class(&mut self) -> Result<Element> {
+        self.expect("[")?;
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1
+        }
+        let mut ranges = vec![];
+        while self.peek() != Some(']') {
+            let lo = self.parse_char()?;
+            let hi = if self.peek() == Some('-') && self.src.get(self.pos + 1) != Some(&']') {
+                self.pos += 1;
+                self.parse_char()?
+            } else {
+                lo
+            };
+            ranges.push((lo, hi))
+        }
+        self.pos += 1;
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    fn parse_usize(&mut self) -> Result<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1
+        }
+        let digits: String = self.src[start..self.pos].iter().collect();
+        match digits.parse() {
+            Ok(v) => Ok(v),
+            Err(_) => candle::bail!("expected a number on line {}", self.line()),
+        }
+    }
+
+    // Repeats `item` between `min` and `max` times, `max` being unbounded when `None`.
+    fn repeat(
+        &mut self,
+        name: &str,
+        item: Alternative,
+        min: usize,
+        max: Option<usize>,
+    ) -> Result<Alternative> {
+        let mut seq = Vec::with_capacity(item.len() * min + 1);
+        for _ in 0..min {
+            seq.extend_from_slice(&item)
+        }
+        match max {
+            None => {
+                // item* is expanded to the right recursive rule `r ::= item r | `.
+                let id = self.new_rule(name, vec![]);
+                let mut alt = item;
+                alt.push(Element::Rule(id));
+                self.rules[id] = Some(vec![alt, vec![]]);
+                seq.push(Element::Rule(id))
+            }
+            Some(max) => {
+                if max < min {
+                    candle::bail!("invalid repetition {{{min},{max}}} on line {}", self.line())
+                }
+                // Nested optional items so that the repetitions are not ambiguous.
+                let mut next = None;
+                for _ in min..max {
+                    let mut alt = item.clone();
+                    if let Some(next) = next {
+                        alt.push(Element::Rule(next))
+                    }
+                    next = Some(self.new_rule(name, vec![alt, vec![]]))
+                }
+                if let Some(next) = next {
+                    seq.push(Element::Rule(next))
+                }
+            }
+        }
+        Ok(seq)
+    }
+
+    fn parse_sequence(&mut self, name: &str, nested: bool) -> Result<Alternative> {
+        let mut seq = vec![];
+        // The start of the last item, repetition operators apply to this item.
+        let mut last_start = None;
+        loop {
+            self.skip_space(nested);
+            let c = match self.peek() {
+                None | Some('|' | ')' | '\r' | '\n') => break,
+                Some(c) => c,
+            };
+            let start = seq.len();
+            match c {
+                '"' => self.parse_literal(&mut seq)?,
+                '[' => seq.push(self.parse_char_class()?),
+                '.' => {
+                    self.pos += 1;
+                    seq.push(Element::Chars {
+                        ranges: vec![],
+                        negated: true,
+                    })
+                }
+                '(' => {
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alts = self.parse_alternatives(name, true)?;
+                    self.skip_space(true);
+                    self.expect(")")?;
+                    seq.push(Element::Rule(self.new_rule(name, alts)))
+                }
+                '*' | '+' | '?' | '{' => {
+                    let item = match last_start {
+                        None => candle::bail!("missing item before '{c}' on line {}", self.line()),
+                        Some(last_start) => seq.split_off(last_start),
+                    };
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        '?' => (0, Some(1)),
+                        _ => {
+                            self.skip_space(false);
+                            let min = self.parse_usize()?;
+                            self.skip_space(false);
+                            let max = if self.peek() == Some(',') {
+                                self.pos += 1;
+                                self.skip_space(false);
+                                if self.peek() == Some('}') {
+                                    None
+                                } else {
+                                    Some(self.parse_usize()?)
+                                }
+                            } else {
+                                Some(min)
+                            };
+                            self.skip_space(false);
+                            self.expect("}")?;
+                            (min, max)
+                        }
+                    };
+                    let repeated = self.repeat(name, item, min, max)?;
+                    seq.extend(repeated);
+                    last_start = None;
+                    continue;
+                }
+                c if is_name_char(c) => {
+                    let rule_name = self.parse_name()?;
+                    seq.push(Element::Rule(self.rule_id(&rule_name)))
+                }
+                c => candle::bail!("unexpected character {c:?} on line {}", self.line()),
+            }
+            last_start = Some(start)
+        }
+        Ok(seq)
+    }
+
+    fn parse_alternatives(&mut self, name: &str, nested: bool) -> Result<Vec<Alternative>> {
+        let mut alts = vec![self.parse_sequence(name, nested)?];
+        loop {
+            // At the top level, a new line ends the rule unless the next line starts with `|`.
+            let pos = self.pos;
+            self.skip_space(true);
+            if self.peek() != Some('|') {
+                self.pos = pos;
+                break;
+            }
+            self.pos += 1;
+            alts.push(self.parse_sequence(name, nested)?)
+        }
+        Ok(alts)
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                break;
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            self.expect("::=")?;
+            let alts = self.parse_alternatives(&name, false)?;
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                candle::bail!("rule {name} is defined twice")
+            }
+            self.rules[id] = Some(alts)
+        }
+        let root = match self.names.get("root") {
+            None => candle::bail!("the grammar has no root rule"),
+            Some(&root) => root,
+        };
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (name, rule) in self.rule_names.iter().zip(self.rules) {
+            match rule {
+                None => candle::bail!("rule {name} is used but not defined"),
+                Some(rule) => rules.push(rule),
+            }
+        }
+        // A chain of expansions that does not consume any character and that is longer than
+        // the number of elements has to go through some position twice.
+        let max_expansions = rules
+            .iter()
+            .flat_map(|alts| alts.iter().map(|alt| alt.len() + 1))
+            .sum();
+        Ok(Grammar {
+            rules,
+            names: self.rule_names,
+            root,
+            max_expansions,
+        })
+    }
+}
+
+/// A context-free grammar, see the [module level documentation](self) for the format.
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    rules: Vec<Vec<Alternative>>,
+    names: Vec<String>,
+    root: usize,
+    max_expansions: usize,
+}
+
+impl Grammar {
+    /// Parses a grammar in the GBNF format.
+    pub fn parse(src: &str) -> Result<Self> {
+        Parser::new(src).parse()
+    }
+
+    /// Builds a grammar for the JSON documents that match a JSON schema, see
+    /// [`super::json_schema::json_schema_to_grammar`].
+    pub fn from_json_schema(schema: &serde_json::Value) -> Result<Self> {
+        let grammar = super::json_schema::json_schema_to_grammar(schema)?;
+        Self::parse(&grammar)
+    }
+
+    /// The names of the rules, including the ones generated for groups and repetitions.
+    pub fn rule_names(&self) -> &[String] {
+        &self.names
+    }
+
+    // Expands the rule references at the top of `stack` until the next element to match is a
+    // character, and pushes the resulting stacks to `out`.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>, depth: usize) -> Result<()> {
+        if depth > self.max_expansions {
+            candle::bail!("the grammar is left recursive")
+        }
+        // Pop the alternatives that have been fully matched.
+        while let Some(top) = stack.last() {
+            if top.pos < self.rules[top.rule][top.alt].len() {
+                break;
+            }
+            stack.pop();
+        }
+        let top = match stack.last_mut() {
+            None => {
+                out.push(stack);
+                return Ok(());
+            }
+            Some(top) => top,
+        };
+        match self.rules[top.rule][top.alt][top.pos] {
+            Element::Chars { .. } => out.push(stack),
+            Element::Rule(rule) => {
+                top.pos += 1;
+                for alt in 0..self.rules[rule].len() {
+                    let mut stack = stack.clone();
+                    stack.push(Position { rule, alt, pos: 0 });
+                    self.expand(stack, out, depth + 1)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn initial_stacks(&self) -> Result<Vec<Stack>> {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root].len() {
+            let stack = vec![Position {
+                rule: self.root,
+                alt,
+                pos: 0,
+            }];
+            self.expand(stack, &mut stacks, 0)?
+        }
+        stacks.sort();
+        stacks.dedup();
+        Ok(stacks)
+    }
+
+    fn accept_char(&self, stacks: &[Stack], c: char) -> Result<Vec<Stack>> {
+        let mut new_stacks = vec![];
+        for stack in stacks.iter() {
+            if let Some(top) = stack.last() {
+                if self.rules[top.rule][top.alt][top.pos].matches(c) {
+                    let mut stack = stack.clone();
+                    if let Some(top) = stack.last_mut() {
+                        top.pos += 1
+                    }
+                    self.expand(stack, &mut new_stacks, 0)?
+                }
+            }
+        }
+        new_stacks.sort();
+        new_stacks.dedup();
+        Ok(new_stacks)
+    }
+
+    fn accepts_any_char(&self, stacks: &[Stack], lo: u32, hi: u32) -> bool {
+        stacks.iter().any(|stack| match stack.last() {
+            None => false,
+            Some(top) => self.rules[top.rule][top.alt][top.pos].matches_any(lo, hi),
+        })
+    }
+
+    fn accept_str(&self, text: &str) -> Result<Vec<Stack>> {
+        let mut stacks = self.initial_stacks()?;
+        for c in text.chars() {
+            stacks = self.accept_char(&stacks, c)?;
+            if stacks.is_empty() {
+                break;
+            }
+        }
+        Ok(stacks)
+    }
+
+    /// Returns true if the whole of `text` is matched by the grammar.
+    pub fn matches(&self, text: &str) -> Result<bool> {
+        let stacks = self.accept_str(text)?;
+        Ok(stacks.iter().any(|s| s.is_empty()))
+    }
+
+    /// Returns true if `text` can be extended into a string that is matched by the grammar.
+    pub fn matches_prefix(&self, text: &str) -> Result<bool> {
+        Ok(!self.accept_str(text)?.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    tokens: Vec<u32>,
+}
+
+/// The bytes of each token of a tokenizer vocabulary, organized as a prefix tree.
+#[derive(Debug, Clone)]
+pub struct TokenVocabulary {
+    tokens: Vec<Vec<u8>>,
+    nodes: Vec<TrieNode>,
+}
+
+// The mapping from the unicode characters used by byte-level BPE tokenizers to bytes, see
+// https://github.com/openai/gpt-2/blob/master/src/encoder.py
+#[cfg(feature = "tokenizers")]
+fn byte_level_chars() -> HashMap<char, u8> {
+    let mut chars = HashMap::new();
+    let mut n = 0;
+    for b in 0..=255u8 {
+        let printable = matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+        let c = if printable {
+            b as u32
+        } else {
+            n += 1;
+            255 + n
+        };
+        if let Some(c) = char::from_u32(c) {
+            chars.insert(c, b);
+        }
+    }
+    chars
+}
+
+impl TokenVocabulary {
+    /// Creates a vocabulary where `tokens[i]` holds the bytes that token `i` adds to the
+    /// generated text. Special tokens such as the end of sequence token should be empty, these
+    /// never get allowed by the grammar.
+    pub fn new(tokens: Vec<Vec<u8>>) -> Self {
+        let mut nodes = vec![TrieNode::default()];
+        for (id, bytes) in tokens.iter().enumerate() {
+            if bytes.is_empty() {
+                continue;
+            }
+            let mut node = 0;
+            for &byte in bytes.iter() {
+                let child = nodes[node]
+                    .children
+                    .iter()
+                    .find(|(b, _)| *b == byte)
+                    .map(|(_, child)| *child);
+                node = match child {
+                    Some(child) => child,
+                    None => {
+                        let child = nodes.len();
+                        nodes.push(TrieNode::default());
+                        nodes[node].children.push((byte, child));
+                        child
+                    }
+                }
+            }
+            nodes[node].tokens.push(id as u32)
+        }
+        Self { tokens, nodes }
+    }
+
+    /// Extracts the bytes of each token from a tokenizer. Byte-level BPE tokenizers and
+    /// sentencepiece style tokenizers using `▁` for spaces and `<0xXX>` byte fallback tokens
+    /// are supported.
+    #[cfg(feature = "tokenizers")]
+    pub fn from_tokenizer(tokenizer: &tokenizers::Tokenizer) -> Result<Self> {
+        let byte_level = matches!(
+            tokenizer.get_decoder(),
+            Some(tokenizers::DecoderWrapper::ByteLevel(_))
+        );
+        let byte_level_chars = byte_level_chars();
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let mut tokens = Vec::with_capacity(vocab_size);
+        for id in 0..vocab_size as u32 {
+            let token = match tokenizer.id_to_token(id) {
+                None => {
+                    tokens.push(vec![]);
+                    continue;
+                }
+                Some(token) => token,
+            };
+            // Special tokens are the ones that get skipped when decoding.
+            let decode = |skip_special_tokens| match tokenizer.decode(&[id], skip_special_tokens) {
+                Ok(text) => Ok(text),
+                Err(err) => candle::bail!("cannot decode: {err}"),
+            };
+            if decode(true)? != decode(false)? {
+                tokens.push(vec![]);
+                continue;
+            }
+            let bytes = if byte_level {
+                token
+                    .chars()
+                    .map(|c| byte_level_chars.get(&c).copied())
+                    .collect::<Option<Vec<_>>>()
+                    .unwrap_or_else(|| token.as_bytes().to_vec())
+            } else if token.len() == 6 && token.starts_with("<0x") && token.ends_with('>') {
+                match u8::from_str_radix(&token[3..5], 16) {
+                    Ok(byte) => vec![byte],
+                    Err(_) => token.into_bytes(),
+                }
+            } else {
+                token.replace('▁', " ").into_bytes()
+            };
+            tokens.push(bytes)
+        }
+        Ok(Self::new(tokens))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn token_bytes(&self, token: u32) -> Option<&[u8]> {
+        self.tokens.get(token as usize).map(|t| t.as_slice())
+    }
+}
+
+enum Utf8 {
+    Char(char),
+    // The first bytes of a character, with the range of the characters starting with them.
+    Incomplete(u32, u32),
+    Invalid,
+}
+
+fn decode_utf8(bytes: &[u8]) -> Utf8 {
+    let (len, min) = match bytes.first() {
+        None => return Utf8::Incomplete(0, char::MAX as u32),
+        Some(b) if b & 0x80 == 0 => (1, 0),
+        Some(b) if b & 0xE0 == 0xC0 => (2, 0x80),
+        Some(b) if b & 0xF0 == 0xE0 => (3, 0x800),
+        Some(b) if b & 0xF8 == 0xF0 => (4, 0x10000),
+        Some(_) => return Utf8::Invalid,
+    };
+    if bytes.len() > len || bytes[1..].iter().any(|b| b & 0xC0 != 0x80) {
+        return Utf8::Invalid;
+    }
+    if bytes.len() < len {
+        // The missing continuation bytes each hold 6 bits.
+        let prefix = bytes[1..]
+            .iter()
+            .fold((bytes[0] & (0x7F >> len)) as u32, |acc, b| {
+                (acc << 6) | (b & 0x3F) as u32
+            });
+        let missing_bits = 6 * (len - bytes.len()) as u32;
+        let lo = (prefix << missing_bits).max(min);
+        let hi = ((prefix + 1) << missing_bits).min(char::MAX as u32 + 1) - 1;
+        return if lo > hi {
+            Utf8::Invalid
+        } else {
+            Utf8::Incomplete(lo, hi)
+        };
+    }
+    match std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.chars().next())
+    {
+        None => Utf8::Invalid,
+        Some(c) => Utf8::Char(c),
+    }
+}
+
+/// A [`Constraint`] restricting the generated text to the strings matched by a grammar.
+#[derive(Debug, Clone)]
+pub struct GrammarConstraint {
+    grammar: Arc<Grammar>,
+    vocabulary: Arc<TokenVocabulary>,
+    eos_token_ids: Vec<u32>,
+    stacks: Vec<Stack>,
+    // The first bytes of a character that is split over multiple tokens.
+    partial_char: Vec<u8>,
+    done: bool,
+}
+
+impl GrammarConstraint {
+    /// Creates a constraint for `grammar`, the end of sequence tokens are allowed once the
+    /// grammar has been fully matched.
+    pub fn new(
+        grammar: Arc<Grammar>,
+        vocabulary: Arc<TokenVocabulary>,
+        eos_token_ids: &[u32],
+    ) -> Result<Self> {
+        let stacks = grammar.initial_stacks()?;
+        Ok(Self {
+            grammar,
+            vocabulary,
+            eos_token_ids: eos_token_ids.to_vec(),
+            stacks,
+            partial_char: vec![],
+            done: false,
+        })
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    pub fn vocabulary(&self) -> &TokenVocabulary {
+        &self.vocabulary
+    }
+
+    // Marks the tokens below `node` that can continue the grammar, `stacks` and `partial_char`
+    // describe the state after the bytes leading to `node`.
+    fn walk(
+        &self,
+        node: usize,
+        stacks: &[Stack],
+        partial_char: &[u8],
+        allowed: &mut [bool],
+    ) -> Result<()> {
+        for &(byte, child) in self.vocabulary.nodes[node].children.iter() {
+            let mut bytes = partial_char.to_vec();
+            bytes.push(byte);
+            let tokens = &self.vocabulary.nodes[child].tokens;
+            match decode_utf8(&bytes) {
+                Utf8::Invalid => {}
+                Utf8::Incomplete(lo, hi) => {
+                    // The character is only known once all its bytes have been generated, in
+                    // the meantime the tokens are allowed if any of the characters starting
+                    // with these bytes can follow.
+                    if self.grammar.accepts_any_char(stacks, lo, hi) {
+                        tokens.iter().for_each(|&t| allowed[t as usize] = true);
+                        self.walk(child, stacks, &bytes, allowed)?
+                    }
+                }
+                Utf8::Char(c) => {
+                    let stacks = self.grammar.accept_char(stacks, c)?;
+                    if !stacks.is_empty() {
+                        tokens.iter().for_each(|&t| allowed[t as usize] = true);
+                        self.walk(child, &stacks, &[], allowed)?
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Constraint for GrammarConstraint {
+    fn allowed_tokens(&mut self) -> Result<Vec<bool>> {
+        let mut allowed = vec![false; self.vocabulary.len()];
+        if self.done {
+            return Ok(allowed);
+        }
+        self.walk(0, &self.stacks, &self.partial_char, &mut allowed)?;
+        if self.is_complete() {
+            for &token in self.eos_token_ids.iter() {
+                if let Some(allowed) = allowed.get_mut(token as usize) {
+                    *allowed = true
+                }
+            }
+        }
+        Ok(allowed)
+    }
+
+    fn advance(&mut self, token: u32) -> Result<()> {
+        if self.done {
+            candle::bail!("the grammar has already been completed")
+        }
+        if self.eos_token_ids.contains(&token) {
+            if !self.is_complete() {
+                candle::bail!("end of sequence token before the end of the grammar")
+            }
+            self.done = true;
+            return Ok(());
+        }
+        let bytes = match self.vocabulary.token_bytes(token) {
+            Some(bytes) if !bytes.is_empty() => bytes,
+            _ => candle::bail!("token {token} is not allowed by the grammar"),
+        };
+        // The state is only updated once the whole token has been accepted.
+        let mut stacks = self.stacks.clone();
+        let mut partial_char = self.partial_char.clone();
+        for &byte in bytes.iter() {
+            partial_char.push(byte);
+            match decode_utf8(&partial_char) {
+                Utf8::Invalid => candle::bail!("token {token} is not valid utf-8"),
+                Utf8::Incomplete(lo, hi) => {
+                    if !self.grammar.accepts_any_char(&stacks, lo, hi) {
+                        candle::bail!("token {token} is not allowed by the grammar")
+                    }
+                }
+                Utf8::Char(c) => {
+                    partial_char.clear();
+                    stacks = self.grammar.accept_char(&stacks, c)?;
+                    if stacks.is_empty() {
+                        candle::bail!("token {token} is not allowed by the grammar")
+                    }
+                }
+            }
+        }
+        self.stacks = stacks;
+        self.partial_char = partial_char;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.done || (self.partial_char.is_empty() && self.stacks.iter().any(|s| s.is_empty()))
+    }
+
+    fn reset(&mut self) {
+        // The initial stacks have already been computed successfully in `new`.
+        self.stacks = self.grammar.initial_stacks().unwrap_or_default();
+        self.partial_char.clear();
+        self.done = false
+    }
+}
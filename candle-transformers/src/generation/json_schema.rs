@@ -0,0 +1,362 @@
+//! Conversion of JSON schemas to grammars, see [`super::grammar`].
+//!
+//! The supported keywords are `type`, `properties`, `required`, `additionalProperties`, `items`,
+//! `prefixItems`, `minItems`, `maxItems`, `minLength`, `maxLength`, `enum`, `const`, `anyOf`,
+//! `oneOf` and local `$ref` to `#/definitions/...` or `#/$defs/...`. Object properties are
+//! generated in alphabetical order, with the required properties first.
+use candle::Result;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// The rules for the primitive types along with the other primitive rules they use.
+const PRIMITIVES: [(&str, &str, &[&str]); 11] = [
+    ("ws", r#"| " " | "\n" [ \t]{0,20}"#, &[]),
+    (
+        "char",
+        r#"[^"\\\x7F\x00-\x1F] | "\\" (["\\/bfnrt] | "u" [0-9a-fA-F]{4})"#,
+        &[],
+    ),
+    ("string", r#""\"" char* "\"" ws"#, &["char", "ws"]),
+    ("integral-part", r#"[0] | [1-9] [0-9]{0,15}"#, &[]),
+    (
+        "number",
+        r#"("-"? integral-part) ("." [0-9]+)? ([eE] [-+]? [0-9]+)? ws"#,
+        &["integral-part", "ws"],
+    ),
+    (
+        "integer",
+        r#"("-"? integral-part) ws"#,
+        &["integral-part", "ws"],
+    ),
+    ("boolean", r#"("true" | "false") ws"#, &["ws"]),
+    ("null", r#""null" ws"#, &["ws"]),
+    (
+        "value",
+        "object | array | string | number | boolean | null",
+        &["object", "array", "string", "number", "boolean", "null"],
+    ),
+    (
+        "object",
+        r#""{" ws ( string ":" ws value ("," ws string ":" ws value)* )? "}" ws"#,
+        &["ws", "string", "value"],
+    ),
+    (
+        "array",
+        r#""[" ws ( value ("," ws value)* )? "]" ws"#,
+        &["ws", "value"],
+    ),
+];
+
+// Escapes a string as a GBNF literal.
+fn gbnf_literal(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c if c.is_control() => literal.push_str(&format!("\\u{:04x}", c as u32)),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn json_literal(value: &Value) -> String {
+    gbnf_literal(&value.to_string())
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+struct Converter<'a> {
+    root_schema: &'a Value,
+    // The rules in the order they have been added, with their index in `rule_index`.
+    rules: Vec<(String, String)>,
+    rule_index: HashMap<String, usize>,
+    refs: HashMap<String, String>,
+}
+
+impl<'a> Converter<'a> {
+    fn new(root_schema: &'a Value) -> Self {
+        // The root rule comes first, its body is set once the whole schema has been visited.
+        Self {
+            root_schema,
+            rules: vec![("root".to_string(), String::new())],
+            rule_index: HashMap::from([("root".to_string(), 0)]),
+            refs: HashMap::new(),
+        }
+    }
+
+    // Adds a rule and returns its name, a suffix is added to the name if another rule already
+    // uses it or if it is the name of a primitive rule.
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = sanitize_name(name);
+        let mut unique_name = name.clone();
+        let mut index = 1;
+        while self.rule_index.contains_key(&unique_name)
+            || PRIMITIVES.iter().any(|(n, _, _)| *n == unique_name)
+        {
+            unique_name = format!("{name}{index}");
+            index += 1
+        }
+        self.rule_index
+            .insert(unique_name.clone(), self.rules.len());
+        self.rules.push((unique_name.clone(), body));
+        unique_name
+    }
+
+    fn primitive(&mut self, name: &str) -> String {
+        if !self.rule_index.contains_key(name) {
+            if let Some((_, body, deps)) = PRIMITIVES.iter().find(|(n, _, _)| *n == name) {
+                self.rule_index.insert(name.to_string(), self.rules.len());
+                self.rules.push((name.to_string(), body.to_string()));
+                for dep in deps.iter() {
+                    self.primitive(dep);
+                }
+            }
+        }
+        name.to_string()
+    }
+
+    fn resolve_ref(&mut self, reference: &str) -> Result<String> {
+        if let Some(name) = self.refs.get(reference) {
+            return Ok(name.clone());
+        }
+        let def_name = match reference
+            .strip_prefix("#/definitions/")
+            .or_else(|| reference.strip_prefix("#/$defs/"))
+        {
+            None => candle::bail!("unsupported $ref {reference}"),
+            Some(def_name) => def_name,
+        };
+        let root_schema = self.root_schema;
+        let schema = match root_schema
+            .get("definitions")
+            .or_else(|| root_schema.get("$defs"))
+            .and_then(|defs| defs.get(def_name))
+        {
+            None => candle::bail!("cannot resolve $ref {reference}"),
+            Some(schema) => schema,
+        };
+        // Reserve the name first so that recursive references resolve to this rule.
+        let name = self.add_rule(def_name, String::new());
+        self.refs.insert(reference.to_string(), name.clone());
+        let body = self.visit(schema, &format!("{name}-def"))?;
+        self.rules[self.rule_index[&name]].1 = body;
+        Ok(name)
+    }
+
+    fn visit_object(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let ws = self.primitive("ws");
+        let properties = match schema.get("properties").and_then(|p| p.as_object()) {
+            Some(properties) if !properties.is_empty() => properties,
+            _ => {
+                return match schema.get("additionalProperties") {
+                    Some(value @ Value::Object(_)) => {
+                        let string = self.primitive("string");
+                        let value = self.visit(value, &format!("{name}-additional-value"))?;
+                        let kv = self.add_rule(
+                            &format!("{name}-kv"),
+                            format!(r#"{string} ":" {ws} {value}"#),
+                        );
+                        Ok(format!(
+                            r#""{{" {ws} ( {kv} ( "," {ws} {kv} )* )? "}}" {ws}"#
+                        ))
+                    }
+                    Some(Value::Bool(false)) => Ok(format!(r#""{{" {ws} "}}" {ws}"#)),
+                    _ => Ok(self.primitive("object")),
+                };
+            }
+        };
+        let required = schema
+            .get("required")
+            .and_then(|r| r.as_array())
+            .map(|r| r.iter().filter_map(|v| v.as_str()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut required_kvs = vec![];
+        let mut optional_kvs = vec![];
+        for (prop_name, prop_schema) in properties.iter() {
+            let prop_rule_name = format!("{name}-{prop_name}");
+            let value = self.visit(prop_schema, &prop_rule_name)?;
+            let key = json_literal(&Value::String(prop_name.clone()));
+            let kv = self.add_rule(
+                &format!("{prop_rule_name}-kv"),
+                format!(r#"{key} {ws} ":" {ws} {value}"#),
+            );
+            if required.contains(&prop_name.as_str()) {
+                required_kvs.push(kv)
+            } else {
+                optional_kvs.push(kv)
+            }
+        }
+        let mut body = format!(r#""{{" {ws}"#);
+        for (i, kv) in required_kvs.iter().enumerate() {
+            if i > 0 {
+                body.push_str(&format!(r#" "," {ws}"#))
+            }
+            body.push_str(&format!(" {kv}"))
+        }
+        if !optional_kvs.is_empty() {
+            if required_kvs.is_empty() {
+                // Any subset of the optional properties, the first one has no leading comma.
+                let alts = (0..optional_kvs.len())
+                    .map(|i| {
+                        let mut alt = optional_kvs[i].clone();
+                        for kv in optional_kvs[i + 1..].iter() {
+                            alt.push_str(&format!(r#" ( "," {ws} {kv} )?"#))
+                        }
+                        alt
+                    })
+                    .collect::<Vec<_>>();
+                let rest = self.add_rule(&format!("{name}-rest"), alts.join(" | "));
+                body.push_str(&format!(" {rest}?"))
+            } else {
+                for kv in optional_kvs.iter() {
+                    body.push_str(&format!(r#" ( "," {ws} {kv} )?"#))
+                }
+            }
+        }
+        body.push_str(&format!(r#" "}}" {ws}"#));
+        Ok(body)
+    }
+
+    fn visit_array(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let ws = self.primitive("ws");
+        if let Some(prefix_items) = schema.get("prefixItems").and_then(|p| p.as_array()) {
+            let mut items = Vec::with_capacity(prefix_items.len());
+            for (i, item) in prefix_items.iter().enumerate() {
+                items.push(self.visit(item, &format!("{name}-tuple-{i}"))?)
+            }
+            let items = items.join(&format!(r#" "," {ws} "#));
+            return Ok(format!(r#""[" {ws} {items} "]" {ws}"#));
+        }
+        let item = match schema.get("items") {
+            None => self.primitive("value"),
+            Some(items) => {
+                let body = self.visit(items, &format!("{name}-item"))?;
+                self.add_rule(&format!("{name}-item"), body)
+            }
+        };
+        let min_items = schema.get("minItems").and_then(|v| v.as_u64()).unwrap_or(0);
+        let max_items = schema.get("maxItems").and_then(|v| v.as_u64());
+        let items = match max_items {
+            Some(0) => String::new(),
+            _ => {
+                let repeat = match max_items {
+                    None => format!("{{{},}}", min_items.saturating_sub(1)),
+                    Some(max) => format!("{{{},{}}}", min_items.saturating_sub(1), max - 1),
+                };
+                let items = format!(r#"{item} ( "," {ws} {item} ){repeat}"#);
+                if min_items == 0 {
+                    format!("( {items} )?")
+                } else {
+                    items
+                }
+            }
+        };
+        Ok(format!(r#""[" {ws} {items} "]" {ws}"#))
+    }
+
+    fn visit_string(&mut self, schema: &Value) -> Result<String> {
+        if schema.get("pattern").is_some() {
+            candle::bail!("the pattern keyword is not supported")
+        }
+        let min_length = schema.get("minLength").and_then(|v| v.as_u64());
+        let max_length = schema.get("maxLength").and_then(|v| v.as_u64());
+        if min_length.is_none() && max_length.is_none() {
+            return Ok(self.primitive("string"));
+        }
+        let char = self.primitive("char");
+        let ws = self.primitive("ws");
+        let min_length = min_length.unwrap_or(0);
+        let repeat = match max_length {
+            None => format!("{{{min_length},}}"),
+            Some(max_length) => format!("{{{min_length},{max_length}}}"),
+        };
+        Ok(format!(r#""\"" {char}{repeat} "\"" {ws}"#))
+    }
+
+    fn visit_type(&mut self, schema: &Value, type_: &str, name: &str) -> Result<String> {
+        match type_ {
+            "object" => self.visit_object(schema, name),
+            "array" => self.visit_array(schema, name),
+            "string" => self.visit_string(schema),
+            "number" | "integer" | "boolean" | "null" => Ok(self.primitive(type_)),
+            type_ => candle::bail!("unsupported type {type_}"),
+        }
+    }
+
+    // Returns the body of a rule matching `schema`, rules for the sub-schemas are named after
+    // `name`.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let schema_obj = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Object(schema_obj) => schema_obj,
+            _ => candle::bail!("unsupported schema {schema}"),
+        };
+        if let Some(reference) = schema_obj.get("$ref").and_then(|r| r.as_str()) {
+            return self.resolve_ref(reference);
+        }
+        if let Some(value) = schema_obj.get("const") {
+            let ws = self.primitive("ws");
+            return Ok(format!("{} {ws}", json_literal(value)));
+        }
+        if let Some(values) = schema_obj.get("enum").and_then(|e| e.as_array()) {
+            let ws = self.primitive("ws");
+            let values = values.iter().map(json_literal).collect::<Vec<_>>();
+            return Ok(format!("( {} ) {ws}", values.join(" | ")));
+        }
+        if schema_obj.contains_key("allOf") {
+            candle::bail!("the allOf keyword is not supported")
+        }
+        let alternatives = schema_obj
+            .get("anyOf")
+            .or_else(|| schema_obj.get("oneOf"))
+            .and_then(|a| a.as_array());
+        if let Some(alternatives) = alternatives {
+            let mut rules = Vec::with_capacity(alternatives.len());
+            for (i, alt) in alternatives.iter().enumerate() {
+                let body = self.visit(alt, &format!("{name}-{i}"))?;
+                rules.push(self.add_rule(&format!("{name}-{i}"), body))
+            }
+            return Ok(format!("( {} )", rules.join(" | ")));
+        }
+        match schema_obj.get("type") {
+            Some(Value::String(type_)) => self.visit_type(schema, type_, name),
+            Some(Value::Array(types)) => {
+                let mut rules = Vec::with_capacity(types.len());
+                for type_ in types.iter() {
+                    let type_ = match type_.as_str() {
+                        None => candle::bail!("unsupported type {type_}"),
+                        Some(type_) => type_,
+                    };
+                    let body = self.visit_type(schema, type_, &format!("{name}-{type_}"))?;
+                    rules.push(self.add_rule(&format!("{name}-{type_}"), body))
+                }
+                Ok(format!("( {} )", rules.join(" | ")))
+            }
+            Some(type_) => candle::bail!("unsupported type {type_}"),
+            None if schema_obj.contains_key("properties") => self.visit_object(schema, name),
+            None => Ok(self.primitive("value")),
+        }
+    }
+}
+
+/// Returns a GBNF grammar matching the JSON documents that are valid for `schema`.
+pub fn json_schema_to_grammar(schema: &Value) -> Result<String> {
+    let mut converter = Converter::new(schema);
+    converter.rules[0].1 = converter.visit(schema, "root")?;
+    let mut grammar = String::new();
+    for (name, body) in converter.rules.iter() {
+        grammar.push_str(&format!("{name} ::= {body}\n"))
+    }
+    Ok(grammar)
+}
@@ -1,83 +1,389 @@
 use candle::{DType, Error, Result, Tensor};
 use rand::{distributions::Distribution, SeedableRng};
+use std::collections::HashMap;
+
+pub mod beam_search;
+pub mod grammar;
+pub mod json_schema;
+pub mod scheduler;
+pub mod speculative;
+pub use beam_search::{BeamSearch, BeamSearchConfig, EncoderDecoder, Hypothesis};
+pub use grammar::{Grammar, GrammarConstraint, TokenVocabulary};
+pub use scheduler::{ContinuousBatching, PagedModel, Scheduler, SchedulerConfig};
+pub use speculative::{SpeculativeDecoding, SpeculativeModel};
+
+/// The strategy used to pick the next token from the (penalized) logits.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Sampling {
+    /// Greedy decoding, always returns the most likely token.
+    ArgMax,
+    /// Sample from the full distribution.
+    All { temperature: f64 },
+    /// Sample from the `k` most likely tokens.
+    TopK { k: usize, temperature: f64 },
+    /// Nucleus sampling, sample from the smallest set of tokens whose cumulative probability
+    /// exceeds `p`.
+    TopP { p: f64, temperature: f64 },
+    /// Top-k filtering followed by nucleus sampling.
+    TopKThenTopP { k: usize, p: f64, temperature: f64 },
+    /// Sample from the tokens whose probability is at least `p` times the probability of the
+    /// most likely token.
+    MinP { p: f64, temperature: f64 },
+    /// Locally typical sampling, sample from the smallest set of tokens whose cumulative
+    /// probability exceeds `p` when tokens are ordered by how close their information content
+    /// is to the entropy of the distribution.
+    /// https://arxiv.org/abs/2202.00666
+    Typical { p: f64, temperature: f64 },
+}
+
+/// Restricts the tokens that can be sampled at each step, e.g. to the ones that keep the
+/// generated text matching a [`Grammar`].
+pub trait Constraint: Send {
+    /// Returns a mask of the tokens that can be sampled next, tokens past the end of the mask
+    /// are not allowed.
+    fn allowed_tokens(&mut self) -> Result<Vec<bool>>;
+
+    /// Updates the state with the token that has been sampled.
+    fn advance(&mut self, token: u32) -> Result<()>;
+
+    /// Returns true if the generated tokens form a complete output.
+    fn is_complete(&self) -> bool;
+
+    /// Goes back to the initial state, before any token has been generated.
+    fn reset(&mut self);
+}
 
 pub struct LogitsProcessor {
     rng: rand::rngs::StdRng,
-    temperature: Option<f64>,
-    top_p: Option<f64>,
+    sampling: Sampling,
+    repeat_penalty: f32,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    penalty_last_n: usize,
+    logit_bias: HashMap<u32, f32>,
+    banned_tokens: Vec<u32>,
+    constraint: Option<Box<dyn Constraint>>,
 }
 
 impl LogitsProcessor {
-    pub fn new(seed: u64, temperature: Option<f64>, top_p: Option<f64>) -> Self {
-        let temperature = if temperature.map_or(true, |v| v < 1e-7) {
-            None
-        } else {
-            temperature
-        };
+    pub fn from_sampling(seed: u64, sampling: Sampling) -> Self {
         Self {
             rng: rand::rngs::StdRng::seed_from_u64(seed),
-            temperature,
-            top_p,
+            sampling,
+            repeat_penalty: 1.,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            penalty_last_n: 64,
+            logit_bias: HashMap::new(),
+            banned_tokens: vec![],
+            constraint: None,
         }
     }
 
+    pub fn new(seed: u64, temperature: Option<f64>, top_p: Option<f64>) -> Self {
+        let temperature = temperature.and_then(|v| if v < 1e-7 { None } else { Some(v) });
+        let sampling = match temperature {
+            None => Sampling::ArgMax,
+            Some(temperature) => match top_p {
+                None => Sampling::All { temperature },
+                Some(p) => Sampling::TopP { p, temperature },
+            },
+        };
+        Self::from_sampling(seed, sampling)
+    }
+
+    /// Divides positive logits and multiplies negative logits of tokens that appear in the
+    /// context by `penalty`, 1. means no penalty.
+    pub fn with_repeat_penalty(mut self, penalty: f32) -> Self {
+        self.repeat_penalty = penalty;
+        self
+    }
+
+    /// Subtracts `penalty` times the number of occurrences in the context from each logit.
+    pub fn with_frequency_penalty(mut self, penalty: f32) -> Self {
+        self.frequency_penalty = penalty;
+        self
+    }
+
+    /// Subtracts `penalty` from the logits of the tokens that appear in the context.
+    pub fn with_presence_penalty(mut self, penalty: f32) -> Self {
+        self.presence_penalty = penalty;
+        self
+    }
+
+    /// The number of trailing context tokens considered by the penalties.
+    pub fn with_penalty_last_n(mut self, last_n: usize) -> Self {
+        self.penalty_last_n = last_n;
+        self
+    }
+
+    /// Adds a fixed value to the logits of some tokens.
+    pub fn with_logit_bias(mut self, logit_bias: HashMap<u32, f32>) -> Self {
+        self.logit_bias = logit_bias;
+        self
+    }
+
+    /// Tokens that can never be sampled.
+    pub fn with_banned_tokens(mut self, banned_tokens: Vec<u32>) -> Self {
+        self.banned_tokens = banned_tokens;
+        self
+    }
+
+    /// Only samples the tokens allowed by `constraint`, the constraint is advanced with each
+    /// token returned by [`Self::sample_with_context`].
+    pub fn with_constraint(mut self, constraint: impl Constraint + 'static) -> Self {
+        self.constraint = Some(Box::new(constraint));
+        self
+    }
+
+    pub fn constraint(&self) -> Option<&dyn Constraint> {
+        self.constraint.as_deref()
+    }
+
+    pub fn constraint_mut(&mut self) -> Option<&mut (dyn Constraint + 'static)> {
+        self.constraint.as_deref_mut()
+    }
+
+    pub fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
     fn sample_argmax(&mut self, logits: Tensor) -> Result<u32> {
-        let logits_v: Vec<f32> = logits.to_vec1()?;
-        let next_token = logits_v
-            .iter()
-            .enumerate()
-            .max_by(|(_, u), (_, v)| u.total_cmp(v))
-            .map(|(i, _)| i as u32)
-            .unwrap();
-        Ok(next_token)
+        logits.argmax(0)?.to_scalar::<u32>()
     }
 
-    fn sample_multinomial(&mut self, prs: &Vec<f32>) -> Result<u32> {
+    fn sample_multinomial(&mut self, prs: &[f32]) -> Result<u32> {
         let distr = rand::distributions::WeightedIndex::new(prs).map_err(Error::wrap)?;
         let next_token = distr.sample(&mut self.rng) as u32;
         Ok(next_token)
     }
 
-    fn sample_topp(&mut self, prs: &mut Vec<f32>, top_p: f32) -> Result<u32> {
+    // Clamps the probabilities outside of the smallest set of tokens that exceed probability
+    // `top_p` to zero.
+    fn clamp_topp(prs: &mut [f32], top_p: f32) -> Result<()> {
+        if top_p <= 0.0 || top_p >= 1.0 {
+            return Ok(());
+        }
         // top-p sampling (or "nucleus sampling") samples from the smallest set of
         // tokens that exceed probability top_p. This way we never sample tokens that
         // have very low probabilities and are less likely to go "off the rails".
-        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
-        argsort_indices.sort_by(|&i, &j| prs[j].partial_cmp(&prs[i]).unwrap());
+        let argsort_indices = Tensor::new(&*prs, &candle::Device::Cpu)?
+            .arg_sort_last_dim(false)?
+            .to_vec1::<u32>()?;
 
         // Clamp smaller probabilities to zero.
         let mut cumsum = 0.;
-        for index in &argsort_indices {
+        for index in argsort_indices {
+            let index = index as usize;
             if cumsum >= top_p {
+                prs[index] = 0.0;
+            } else {
+                cumsum += prs[index];
+            }
+        }
+        Ok(())
+    }
+
+    // Clamps all the probabilities outside of the k most likely tokens to zero.
+    fn clamp_topk(prs: &mut [f32], top_k: usize) {
+        if top_k == 0 || top_k >= prs.len() {
+            return;
+        }
+        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+        // Partial sort by descending probability, only the k-th element ends up at its sorted
+        // position.
+        argsort_indices.select_nth_unstable_by(top_k, |&i, &j| prs[j].total_cmp(&prs[i]));
+        for &index in &argsort_indices[top_k..] {
+            prs[index] = 0.0;
+        }
+    }
+
+    fn clamp_topk_topp(prs: &mut [f32], top_k: usize, top_p: f32) -> Result<()> {
+        Self::clamp_topk(prs, top_k);
+        // Renormalize so that the top-p cutoff applies to the remaining distribution.
+        let sum = prs.iter().sum::<f32>();
+        prs.iter_mut().for_each(|v| *v /= sum);
+        Self::clamp_topp(prs, top_p)
+    }
+
+    fn clamp_minp(prs: &mut [f32], min_p: f32) {
+        let max_pr = prs.iter().copied().fold(0f32, f32::max);
+        let threshold = min_p * max_pr;
+        for pr in prs.iter_mut() {
+            if *pr < threshold {
+                *pr = 0.0
+            }
+        }
+    }
+
+    fn clamp_typical(prs: &mut [f32], typical_p: f32) {
+        if typical_p <= 0.0 || typical_p >= 1.0 {
+            return;
+        }
+        let entropy = prs
+            .iter()
+            .filter(|&&pr| pr > 0.)
+            .map(|pr| -pr * pr.ln())
+            .sum::<f32>();
+        // The distance between the information content of each token and the entropy.
+        let shifted = prs
+            .iter()
+            .map(|pr| (-pr.ln() - entropy).abs())
+            .collect::<Vec<_>>();
+        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+        argsort_indices.sort_by(|&i, &j| shifted[i].total_cmp(&shifted[j]));
+        let mut cumsum = 0.;
+        for index in &argsort_indices {
+            if cumsum >= typical_p {
                 prs[*index] = 0.0;
             } else {
                 cumsum += prs[*index];
             }
         }
-        // Sample with clamped probabilities.
+    }
+
+    /// Applies the logit bias, banned tokens and penalties to a one dimensional tensor of
+    /// logits, `context` holds the previous tokens of the sequence.
+    pub fn apply_penalties(&self, logits: &Tensor, context: &[u32]) -> Result<Tensor> {
+        let vocab_size = logits.dim(0)?;
+        let device = logits.device();
+        let mut logits = logits.clone();
+        if !self.logit_bias.is_empty() || !self.banned_tokens.is_empty() {
+            let mut bias = vec![0f32; vocab_size];
+            for (&token, &b) in self.logit_bias.iter() {
+                if let Some(v) = bias.get_mut(token as usize) {
+                    *v += b
+                }
+            }
+            for &token in self.banned_tokens.iter() {
+                if let Some(v) = bias.get_mut(token as usize) {
+                    *v = f32::NEG_INFINITY
+                }
+            }
+            let bias = Tensor::from_vec(bias, vocab_size, device)?;
+            logits = (logits + bias)?;
+        }
+        let start_at = context.len().saturating_sub(self.penalty_last_n);
+        let context = &context[start_at..];
+        let has_penalty = self.repeat_penalty != 1.
+            || self.frequency_penalty != 0.
+            || self.presence_penalty != 0.;
+        if has_penalty && !context.is_empty() {
+            let counts = crate::utils::token_counts(context, vocab_size, device)?;
+            if self.repeat_penalty != 1. {
+                logits = crate::utils::apply_repeat_penalty_counts(
+                    &logits,
+                    self.repeat_penalty,
+                    &counts,
+                )?;
+            }
+            if self.frequency_penalty != 0. {
+                logits = (logits - (&counts * self.frequency_penalty as f64)?)?;
+            }
+            if self.presence_penalty != 0. {
+                let present = counts.gt(0f32)?.to_dtype(DType::F32)?;
+                logits = (logits - (present * self.presence_penalty as f64)?)?;
+            }
+        }
+        Ok(logits)
+    }
+
+    // Returns the probabilities used for sampling with the non-argmax strategies, the tokens
+    // that cannot be sampled have a probability of zero. The result is not normalized.
+    fn truncated_probs(&self, logits: &Tensor) -> Result<Vec<f32>> {
+        let prs = |temperature: f64| -> Result<Vec<f32>> {
+            let logits = (logits / temperature)?;
+            let prs = candle_nn::ops::softmax_last_dim(&logits)?;
+            prs.to_vec1()
+        };
+        let prs = match self.sampling {
+            Sampling::ArgMax => {
+                let mut prs = vec![0f32; logits.dim(0)?];
+                prs[logits.argmax(0)?.to_scalar::<u32>()? as usize] = 1.0;
+                prs
+            }
+            Sampling::All { temperature } => prs(temperature)?,
+            Sampling::TopK { k, temperature } => {
+                let mut prs = prs(temperature)?;
+                Self::clamp_topk(&mut prs, k);
+                prs
+            }
+            Sampling::TopP { p, temperature } => {
+                let mut prs = prs(temperature)?;
+                // top-p (nucleus) sampling, clamping the least likely tokens to zero
+                Self::clamp_topp(&mut prs, p as f32)?;
+                prs
+            }
+            Sampling::TopKThenTopP { k, p, temperature } => {
+                let mut prs = prs(temperature)?;
+                Self::clamp_topk_topp(&mut prs, k, p as f32)?;
+                prs
+            }
+            Sampling::MinP { p, temperature } => {
+                let mut prs = prs(temperature)?;
+                Self::clamp_minp(&mut prs, p as f32);
+                prs
+            }
+            Sampling::Typical { p, temperature } => {
+                let mut prs = prs(temperature)?;
+                Self::clamp_typical(&mut prs, p as f32);
+                prs
+            }
+        };
+        Ok(prs)
+    }
+
+    /// Returns the normalized probability distribution that the next token is sampled from,
+    /// this is a one-hot vector for [`Sampling::ArgMax`].
+    pub fn probs_with_context(&self, logits: &Tensor, context: &[u32]) -> Result<Vec<f32>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let logits = self.apply_penalties(&logits, context)?;
+        let mut prs = self.truncated_probs(&logits)?;
+        let sum = prs.iter().sum::<f32>();
+        prs.iter_mut().for_each(|v| *v /= sum);
+        Ok(prs)
+    }
+
+    /// Samples a token from some probabilities, these do not have to be normalized.
+    pub fn sample_probs(&mut self, prs: &[f32]) -> Result<u32> {
         self.sample_multinomial(prs)
     }
 
     pub fn sample(&mut self, logits: &Tensor) -> Result<u32> {
+        self.sample_with_context(logits, &[])
+    }
+
+    /// Samples the next token, the penalties are computed using the tokens in `context`.
+    pub fn sample_with_context(&mut self, logits: &Tensor, context: &[u32]) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
-        let next_token = match self.temperature {
-            None => self.sample_argmax(logits)?,
-            Some(temperature) => {
-                let logits = &(&logits / temperature)?;
-                let prs = candle_nn::ops::softmax_last_dim(logits)?;
-                let mut prs: Vec<f32> = prs.to_vec1()?;
-                let top_p = self.top_p.unwrap_or(1.);
-                if top_p <= 0.0 || top_p >= 1.0 {
-                    // simply sample from the predicted probability distribution
-                    self.sample_multinomial(&prs)?
-                } else {
-                    // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    self.sample_topp(&mut prs, top_p as f32)?
-                }
+        let mut logits = self.apply_penalties(&logits, context)?;
+        if let Some(constraint) = self.constraint.as_mut() {
+            let allowed = constraint.allowed_tokens()?;
+            let vocab_size = logits.dim(0)?;
+            let mask = (0..vocab_size)
+                .map(|i| match allowed.get(i) {
+                    Some(true) => 0f32,
+                    _ => f32::NEG_INFINITY,
+                })
+                .collect::<Vec<_>>();
+            if mask.iter().all(|v| v.is_infinite()) {
+                candle::bail!("no token is allowed by the constraint")
+            }
+            let mask = Tensor::from_vec(mask, vocab_size, logits.device())?;
+            logits = (logits + mask)?;
+        }
+        let next_token = match self.sampling {
+            Sampling::ArgMax => self.sample_argmax(logits)?,
+            _ => {
+                let prs = self.truncated_probs(&logits)?;
+                self.sample_multinomial(&prs)?
             }
         };
+        if let Some(constraint) = self.constraint.as_mut() {
+            constraint.advance(next_token)?
+        }
         Ok(next_token)
     }
 }
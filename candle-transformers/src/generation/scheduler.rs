@@ -0,0 +1,408 @@
+//! Continuous batching over a paged kv cache.
+//!
+//! The [`Scheduler`] tracks the sequences being served and decides, between two forward
+//! passes, which ones get processed. Waiting sequences are admitted as soon as there are enough
+//! free kv-cache blocks for them, so that new requests do not have to wait for the current
+//! batch to complete. When the cache runs out of blocks, the most recently admitted sequences
+//! are evicted: their blocks are freed and their keys and values are recomputed from their
+//! tokens once they get admitted again.
+//!
+//! [`ContinuousBatching`] drives a model with this scheduler.
+use super::LogitsProcessor;
+use crate::paged_attention::PagedAttentionMetadata;
+use candle::{Device, IndexOp, Result, Tensor};
+use candle_nn::paged_kv_cache::{BlockAllocator, BlockTable};
+use std::collections::{HashMap, VecDeque};
+
+/// A causal language model that supports paged attention.
+pub trait PagedModel {
+    /// Allocates the paged kv cache of the model.
+    fn allocate_paged_kv_cache(&mut self, num_blocks: usize, block_size: usize) -> Result<()>;
+
+    /// Runs the model on the concatenated new tokens of a batch of sequences and returns the
+    /// logits for the last token of each sequence with shape `(num_sequences, vocab_size)`.
+    fn forward_paged(
+        &mut self,
+        input_ids: &Tensor,
+        metadata: &PagedAttentionMetadata,
+    ) -> Result<Tensor>;
+}
+
+impl PagedModel for crate::models::quantized_llama::ModelWeights {
+    fn allocate_paged_kv_cache(&mut self, num_blocks: usize, block_size: usize) -> Result<()> {
+        self.allocate_paged_kv_cache(num_blocks, block_size)
+    }
+
+    fn forward_paged(
+        &mut self,
+        input_ids: &Tensor,
+        metadata: &PagedAttentionMetadata,
+    ) -> Result<Tensor> {
+        self.forward_paged(input_ids, metadata)
+    }
+}
+
+impl PagedModel for crate::models::mistral::Model {
+    fn allocate_paged_kv_cache(&mut self, num_blocks: usize, block_size: usize) -> Result<()> {
+        self.allocate_paged_kv_cache(num_blocks, block_size)
+    }
+
+    fn forward_paged(
+        &mut self,
+        input_ids: &Tensor,
+        metadata: &PagedAttentionMetadata,
+    ) -> Result<Tensor> {
+        self.forward_paged(input_ids, metadata)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// The number of blocks in the paged kv cache.
+    pub num_blocks: usize,
+    /// The number of positions per block.
+    pub block_size: usize,
+    /// The maximum number of sequences processed in a single forward pass.
+    pub max_num_seqs: usize,
+    /// The maximum number of tokens processed in a single forward pass, a sequence whose
+    /// prompt is longer than this is still admitted when nothing else is running.
+    pub max_num_batched_tokens: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Sequence {
+    id: usize,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    max_new_tokens: usize,
+    eos_token_id: Option<u32>,
+    block_table: BlockTable,
+    // The number of tokens whose keys and values are in the cache.
+    num_computed: usize,
+}
+
+/// The sequences to process in the next forward pass, see [`Scheduler::schedule`].
+#[derive(Debug, Clone)]
+pub struct ScheduledBatch {
+    /// The ids of the scheduled sequences, in batch order.
+    pub ids: Vec<usize>,
+    /// The new tokens of all the sequences concatenated.
+    pub input_ids: Tensor,
+    pub metadata: PagedAttentionMetadata,
+}
+
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    config: SchedulerConfig,
+    allocator: BlockAllocator,
+    waiting: VecDeque<Sequence>,
+    // The admitted sequences, in admission order.
+    running: Vec<Sequence>,
+    next_id: usize,
+    num_preemptions: usize,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            allocator: BlockAllocator::new(config.num_blocks, config.block_size),
+            waiting: VecDeque::new(),
+            running: vec![],
+            next_id: 0,
+            num_preemptions: 0,
+        }
+    }
+
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.config
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// Returns true if there are no waiting or running sequences.
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.allocator.num_free_blocks()
+    }
+
+    /// The number of times a running sequence has been evicted to free some blocks.
+    pub fn num_preemptions(&self) -> usize {
+        self.num_preemptions
+    }
+
+    /// Adds a new sequence to the waiting queue and returns its id. The sequence is done once
+    /// `max_new_tokens` tokens have been generated or when `eos_token_id` is generated.
+    pub fn add_sequence(
+        &mut self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        eos_token_id: Option<u32>,
+    ) -> Result<usize> {
+        if prompt.is_empty() {
+            candle::bail!("cannot schedule an empty prompt")
+        }
+        if max_new_tokens == 0 {
+            candle::bail!("max_new_tokens should be positive")
+        }
+        // The last generated token is never processed by the model.
+        let max_len = prompt.len() + max_new_tokens - 1;
+        let block_table = BlockTable::new(self.config.block_size);
+        let blocks_needed = block_table.blocks_needed(max_len);
+        if blocks_needed > self.config.num_blocks {
+            candle::bail!(
+                "sequence of length {max_len} requires {blocks_needed} blocks, the cache only has {}",
+                self.config.num_blocks
+            )
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(Sequence {
+            id,
+            tokens: prompt.to_vec(),
+            prompt_len: prompt.len(),
+            max_new_tokens,
+            eos_token_id,
+            block_table,
+            num_computed: 0,
+        });
+        Ok(id)
+    }
+
+    /// Removes a sequence, returns false if there is no such sequence.
+    pub fn abort(&mut self, id: usize) -> bool {
+        if let Some(index) = self.waiting.iter().position(|s| s.id == id) {
+            self.waiting.remove(index);
+            true
+        } else if let Some(index) = self.running.iter().position(|s| s.id == id) {
+            let mut seq = self.running.remove(index);
+            self.allocator.release(&mut seq.block_table);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The prompt and the generated tokens of a waiting or running sequence.
+    pub fn tokens(&self, id: usize) -> Option<&[u32]> {
+        self.running
+            .iter()
+            .chain(self.waiting.iter())
+            .find(|s| s.id == id)
+            .map(|s| s.tokens.as_slice())
+    }
+
+    fn preempt_last(&mut self) {
+        if let Some(mut seq) = self.running.pop() {
+            self.allocator.release(&mut seq.block_table);
+            seq.num_computed = 0;
+            self.num_preemptions += 1;
+            self.waiting.push_front(seq)
+        }
+    }
+
+    /// Selects the sequences to process in the next forward pass and reserves the kv-cache
+    /// slots for their new tokens. Returns `None` when there is nothing to process.
+    ///
+    /// The running sequences each get a slot for their last generated token, evicting the most
+    /// recently admitted sequences if needed. Waiting sequences are then admitted in order as
+    /// long as they fit in the cache and in the batch limits.
+    pub fn schedule(&mut self, device: &Device) -> Result<Option<ScheduledBatch>> {
+        let mut num_preempted = 0;
+        let mut index = 0;
+        while index < self.running.len() {
+            let seq = &mut self.running[index];
+            let num_tokens = seq.tokens.len() - seq.num_computed;
+            if self.allocator.can_append(&seq.block_table, num_tokens) {
+                self.allocator
+                    .append_slots(&mut seq.block_table, num_tokens)?;
+                index += 1
+            } else {
+                self.preempt_last();
+                num_preempted += 1
+            }
+        }
+        let mut num_batched_tokens = self.running.len();
+        // Sequences are only admitted when nothing had to be evicted, otherwise the evicted
+        // sequences could be readmitted straight away.
+        while num_preempted == 0 && self.running.len() < self.config.max_num_seqs {
+            let seq = match self.waiting.front() {
+                None => break,
+                Some(seq) => seq,
+            };
+            let num_tokens = seq.tokens.len();
+            if num_batched_tokens > 0
+                && num_batched_tokens + num_tokens > self.config.max_num_batched_tokens
+            {
+                break;
+            }
+            if !self.allocator.can_append(&seq.block_table, num_tokens) {
+                break;
+            }
+            if let Some(mut seq) = self.waiting.pop_front() {
+                self.allocator
+                    .append_slots(&mut seq.block_table, num_tokens)?;
+                num_batched_tokens += num_tokens;
+                self.running.push(seq)
+            }
+        }
+        if self.running.is_empty() {
+            return Ok(None);
+        }
+
+        let mut ids = Vec::with_capacity(self.running.len());
+        let mut input_ids = Vec::with_capacity(num_batched_tokens);
+        let mut num_tokens = Vec::with_capacity(self.running.len());
+        for seq in self.running.iter_mut() {
+            ids.push(seq.id);
+            input_ids.extend_from_slice(&seq.tokens[seq.num_computed..]);
+            num_tokens.push(seq.tokens.len() - seq.num_computed);
+            seq.num_computed = seq.tokens.len();
+        }
+        let block_tables = self
+            .running
+            .iter()
+            .map(|s| &s.block_table)
+            .collect::<Vec<_>>();
+        let metadata = PagedAttentionMetadata::new(&block_tables, &num_tokens, device)?;
+        Ok(Some(ScheduledBatch {
+            ids,
+            input_ids: Tensor::new(input_ids, device)?,
+            metadata,
+        }))
+    }
+
+    /// Appends a newly generated token to a running sequence. If this completes the sequence,
+    /// the sequence is removed, its blocks are freed and the generated tokens are returned.
+    pub fn append_token(&mut self, id: usize, token: u32) -> Result<Option<Vec<u32>>> {
+        let index = match self.running.iter().position(|s| s.id == id) {
+            None => candle::bail!("no running sequence with id {id}"),
+            Some(index) => index,
+        };
+        let seq = &mut self.running[index];
+        seq.tokens.push(token);
+        let num_generated = seq.tokens.len() - seq.prompt_len;
+        if num_generated < seq.max_new_tokens && Some(token) != seq.eos_token_id {
+            return Ok(None);
+        }
+        let mut seq = self.running.remove(index);
+        self.allocator.release(&mut seq.block_table);
+        Ok(Some(seq.tokens[seq.prompt_len..].to_vec()))
+    }
+}
+
+/// The result of a single step for a sequence, see [`ContinuousBatching::step`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SequenceOutput {
+    pub id: usize,
+    pub token: u32,
+    /// All the generated tokens, only set when the sequence is done.
+    pub finished: Option<Vec<u32>>,
+}
+
+/// Serves many sequences with a single model, new sequences can be added at any time and get
+/// batched with the ones that are already being generated.
+pub struct ContinuousBatching<M: PagedModel> {
+    model: M,
+    scheduler: Scheduler,
+    logits_processors: HashMap<usize, LogitsProcessor>,
+    device: Device,
+}
+
+impl<M: PagedModel> ContinuousBatching<M> {
+    /// Allocates the paged kv cache of the model according to `config`.
+    pub fn new(mut model: M, config: SchedulerConfig, device: &Device) -> Result<Self> {
+        model.allocate_paged_kv_cache(config.num_blocks, config.block_size)?;
+        Ok(Self {
+            model,
+            scheduler: Scheduler::new(config),
+            logits_processors: HashMap::new(),
+            device: device.clone(),
+        })
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Adds a new sequence and returns its id, `logits_processor` is used to sample the tokens
+    /// of this sequence.
+    pub fn add_request(
+        &mut self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        eos_token_id: Option<u32>,
+        logits_processor: LogitsProcessor,
+    ) -> Result<usize> {
+        let id = self
+            .scheduler
+            .add_sequence(prompt, max_new_tokens, eos_token_id)?;
+        self.logits_processors.insert(id, logits_processor);
+        Ok(id)
+    }
+
+    /// Removes a sequence, returns false if there is no such sequence.
+    pub fn abort(&mut self, id: usize) -> bool {
+        self.logits_processors.remove(&id);
+        self.scheduler.abort(id)
+    }
+
+    /// Runs a single forward pass on the scheduled sequences and returns the token generated
+    /// for each of them. Sequences that are waiting for free blocks are not part of the output.
+    pub fn step(&mut self) -> Result<Vec<SequenceOutput>> {
+        let batch = match self.scheduler.schedule(&self.device)? {
+            None => return Ok(vec![]),
+            Some(batch) => batch,
+        };
+        let logits = self
+            .model
+            .forward_paged(&batch.input_ids, &batch.metadata)?;
+        let mut outputs = Vec::with_capacity(batch.ids.len());
+        for (i, &id) in batch.ids.iter().enumerate() {
+            let logits = logits.i(i)?;
+            let logits_processor = match self.logits_processors.get_mut(&id) {
+                None => candle::bail!("no logits processor for sequence {id}"),
+                Some(logits_processor) => logits_processor,
+            };
+            let context = self.scheduler.tokens(id).unwrap_or(&[]);
+            let token = logits_processor.sample_with_context(&logits, context)?;
+            let finished = self.scheduler.append_token(id, token)?;
+            if finished.is_some() {
+                self.logits_processors.remove(&id);
+            }
+            outputs.push(SequenceOutput {
+                id,
+                token,
+                finished,
+            })
+        }
+        Ok(outputs)
+    }
+
+    /// Steps until all the sequences are done and returns the generated tokens of each
+    /// sequence, in completion order.
+    pub fn run(&mut self) -> Result<Vec<(usize, Vec<u32>)>> {
+        let mut finished = vec![];
+        while !self.scheduler.is_empty() {
+            for output in self.step()? {
+                if let Some(tokens) = output.finished {
+                    finished.push((output.id, tokens))
+                }
+            }
+        }
+        Ok(finished)
+    }
+}
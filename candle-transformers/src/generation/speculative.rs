@@ -0,0 +1,276 @@
+//! Speculative decoding, https://arxiv.org/abs/2211.17192
+//!
+//! A small draft model proposes a few tokens that are then verified with a single forward pass
+//! of the target model. The draft tokens are accepted or rejected using rejection sampling so
+//! that the generated tokens follow the same distribution as when sampling from the target
+//! model alone.
+use super::LogitsProcessor;
+use candle::{Device, IndexOp, Result, Tensor};
+use rand::{Rng, SeedableRng};
+
+/// A causal language model that can be used as the target or draft model.
+pub trait SpeculativeModel {
+    /// Runs the model on `input_ids`, a `(1, seq_len)` tensor whose first token is at position
+    /// `index_pos`, and returns the logits for all the positions as a `(seq_len, vocab_size)`
+    /// tensor.
+    fn forward_all(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor>;
+
+    /// Discards the kv cache entries past the first `seq_len` positions.
+    fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()>;
+
+    fn clear_kv_cache(&mut self);
+}
+
+impl SpeculativeModel for crate::models::quantized_llama::ModelWeights {
+    fn forward_all(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward_all(input_ids, index_pos)?.squeeze(0)
+    }
+
+    fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        self.rollback_kv_cache(seq_len)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+impl SpeculativeModel for crate::models::llama::Llama {
+    fn forward_all(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        crate::models::llama::Llama::forward_all(self, input_ids, index_pos)?.squeeze(0)
+    }
+
+    fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        crate::models::llama::Llama::rollback_kv_cache(self, seq_len)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        crate::models::llama::Llama::clear_kv_cache(self)
+    }
+}
+
+impl SpeculativeModel for crate::models::llama2_c::Llama {
+    fn forward_all(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids, index_pos)?.squeeze(0)
+    }
+
+    fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        crate::models::llama2_c::Llama::rollback_kv_cache(self, seq_len)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        crate::models::llama2_c::Llama::clear_kv_cache(self)
+    }
+}
+
+impl SpeculativeModel for crate::models::quantized_llama2_c::QLlama {
+    fn forward_all(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids, index_pos)?.squeeze(0)
+    }
+
+    fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        crate::models::quantized_llama2_c::QLlama::rollback_kv_cache(self, seq_len)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        crate::models::quantized_llama2_c::QLlama::clear_kv_cache(self)
+    }
+}
+
+/// Generates tokens from a target model using a draft model to propose `num_draft_tokens`
+/// tokens at each step.
+///
+/// Both models must use the same vocabulary. The logits processor defines the sampling
+/// strategy and penalties, it is applied to the logits of both models.
+pub struct SpeculativeDecoding<T: SpeculativeModel, D: SpeculativeModel> {
+    target: T,
+    draft: D,
+    logits_processor: LogitsProcessor,
+    num_draft_tokens: usize,
+    rng: rand::rngs::StdRng,
+    device: Device,
+    tokens: Vec<u32>,
+    // The number of tokens that have been processed by each model and are in their kv caches.
+    target_len: usize,
+    draft_len: usize,
+    num_drafted: usize,
+    num_accepted: usize,
+}
+
+impl<T: SpeculativeModel, D: SpeculativeModel> SpeculativeDecoding<T, D> {
+    pub fn new(
+        target: T,
+        draft: D,
+        logits_processor: LogitsProcessor,
+        num_draft_tokens: usize,
+        seed: u64,
+        device: &Device,
+    ) -> Self {
+        Self {
+            target,
+            draft,
+            logits_processor,
+            num_draft_tokens,
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            device: device.clone(),
+            tokens: vec![],
+            target_len: 0,
+            draft_len: 0,
+            num_drafted: 0,
+            num_accepted: 0,
+        }
+    }
+
+    pub fn target(&self) -> &T {
+        &self.target
+    }
+
+    pub fn draft(&self) -> &D {
+        &self.draft
+    }
+
+    pub fn into_inner(self) -> (T, D) {
+        (self.target, self.draft)
+    }
+
+    /// The prompt followed by all the generated tokens.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// The fraction of the draft tokens that have been accepted.
+    pub fn acceptance_rate(&self) -> f64 {
+        if self.num_drafted == 0 {
+            0.
+        } else {
+            self.num_accepted as f64 / self.num_drafted as f64
+        }
+    }
+
+    /// Clears the kv caches of both models and starts a new sequence from `prompt`.
+    pub fn reset(&mut self, prompt: &[u32]) -> Result<()> {
+        if prompt.is_empty() {
+            candle::bail!("speculative decoding requires a non-empty prompt")
+        }
+        self.target.clear_kv_cache();
+        self.draft.clear_kv_cache();
+        self.tokens = prompt.to_vec();
+        self.target_len = 0;
+        self.draft_len = 0;
+        self.num_drafted = 0;
+        self.num_accepted = 0;
+        Ok(())
+    }
+
+    // Runs `model` on the tokens from `start` onwards.
+    fn forward<M: SpeculativeModel>(
+        model: &mut M,
+        tokens: &[u32],
+        start: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        let input = Tensor::new(&tokens[start..], device)?.unsqueeze(0)?;
+        model.forward_all(&input, start)
+    }
+
+    /// Runs a single speculative step and returns the newly generated tokens. This produces
+    /// between one and `num_draft_tokens + 1` tokens.
+    pub fn step(&mut self) -> Result<Vec<u32>> {
+        let prompt_len = self.tokens.len();
+        if prompt_len == 0 {
+            candle::bail!("speculative decoding requires a non-empty prompt, call reset first")
+        }
+
+        // Let the draft model propose some tokens.
+        let mut seq = self.tokens.clone();
+        let mut draft_prs = Vec::with_capacity(self.num_draft_tokens);
+        for _ in 0..self.num_draft_tokens {
+            let logits = Self::forward(&mut self.draft, &seq, self.draft_len, &self.device)?;
+            self.draft_len = seq.len();
+            let logits = logits.i(logits.dim(0)? - 1)?;
+            let prs = self.logits_processor.probs_with_context(&logits, &seq)?;
+            let token = self.logits_processor.sample_probs(&prs)?;
+            draft_prs.push(prs);
+            seq.push(token);
+        }
+        let drafts = seq[prompt_len..].to_vec();
+
+        // Score all the draft tokens with a single forward pass of the target model.
+        let logits = Self::forward(&mut self.target, &seq, self.target_len, &self.device)?;
+        // The logits for the token at position `prompt_len`.
+        let offset = prompt_len - 1 - self.target_len;
+
+        let mut new_tokens = Vec::with_capacity(drafts.len() + 1);
+        let mut num_accepted = 0;
+        for (i, (&token, draft_prs)) in drafts.iter().zip(draft_prs.iter()).enumerate() {
+            let logits = logits.i(offset + i)?;
+            let prs = self
+                .logits_processor
+                .probs_with_context(&logits, &seq[..prompt_len + i])?;
+            // Accept the draft token with probability min(1, p / q).
+            let (p, q) = (prs[token as usize], draft_prs[token as usize]);
+            if self.rng.gen::<f32>() * q < p {
+                new_tokens.push(token);
+                num_accepted += 1;
+                continue;
+            }
+            // On rejection, sample from the residual distribution max(0, p - q).
+            let residual = prs
+                .iter()
+                .zip(draft_prs.iter())
+                .map(|(p, q)| f32::max(p - q, 0.))
+                .collect::<Vec<_>>();
+            let token = if residual.iter().any(|&v| v > 0.) {
+                self.logits_processor.sample_probs(&residual)?
+            } else {
+                self.logits_processor.sample_probs(&prs)?
+            };
+            new_tokens.push(token);
+            break;
+        }
+        if num_accepted == drafts.len() {
+            // All the draft tokens have been accepted, sample an extra token from the target.
+            let logits = logits.i(offset + drafts.len())?;
+            let token = self.logits_processor.sample_with_context(&logits, &seq)?;
+            new_tokens.push(token);
+        }
+
+        // The kv caches only retain the accepted tokens, the last generated token has not been
+        // processed by either model.
+        let valid_len = prompt_len + num_accepted;
+        self.target.rollback_kv_cache(valid_len)?;
+        self.target_len = valid_len;
+        if self.draft_len > valid_len {
+            self.draft.rollback_kv_cache(valid_len)?;
+            self.draft_len = valid_len;
+        }
+        self.num_drafted += drafts.len();
+        self.num_accepted += num_accepted;
+        self.tokens.extend_from_slice(&new_tokens);
+        Ok(new_tokens)
+    }
+
+    /// Generates up to `max_new_tokens` tokens after `prompt`, stopping early when
+    /// `eos_token_id` is generated. The returned tokens exclude the prompt.
+    pub fn generate(
+        &mut self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        eos_token_id: Option<u32>,
+    ) -> Result<Vec<u32>> {
+        self.reset(prompt)?;
+        let mut generated = Vec::with_capacity(max_new_tokens);
+        while generated.len() < max_new_tokens {
+            for token in self.step()? {
+                if generated.len() >= max_new_tokens {
+                    break;
+                }
+                generated.push(token);
+                if Some(token) == eos_token_id {
+                    return Ok(generated);
+                }
+            }
+        }
+        Ok(generated)
+    }
+}
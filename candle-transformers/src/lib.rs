@@ -1,6 +1,7 @@
 pub mod generation;
 pub mod models;
 pub mod object_detection;
+pub mod paged_attention;
 pub mod pipelines;
 pub mod quantized_nn;
 pub mod quantized_var_builder;
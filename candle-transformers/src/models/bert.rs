@@ -61,6 +61,8 @@ pub struct Config {
     use_cache: bool,
     classifier_dropout: Option<f64>,
     model_type: Option<String>,
+    #[serde(default)]
+    use_sdpa: bool,
 }
 
 impl Default for Config {
@@ -82,11 +84,19 @@ impl Default for Config {
             use_cache: true,
             classifier_dropout: None,
             model_type: Some("bert".to_string()),
+            use_sdpa: false,
         }
     }
 }
 
 impl Config {
+    /// Use the fused [`candle_nn::ops::sdpa`] attention rather than materializing the attention
+    /// matrix.
+    pub fn with_sdpa(mut self, use_sdpa: bool) -> Self {
+        self.use_sdpa = use_sdpa;
+        self
+    }
+
     fn _all_mini_lm_l6_v2() -> Self {
         // https://huggingface.co/sentence-transformers/all-MiniLM-L6-v2/blob/main/config.json
         Self {
@@ -106,6 +116,7 @@ impl Config {
             use_cache: true,
             classifier_dropout: None,
             model_type: Some("bert".to_string()),
+            use_sdpa: false,
         }
     }
 }
@@ -200,6 +211,7 @@ struct BertSelfAttention {
     dropout: Dropout,
     num_attention_heads: usize,
     attention_head_size: usize,
+    use_sdpa: bool,
     span: tracing::Span,
     span_softmax: tracing::Span,
 }
@@ -220,6 +232,7 @@ impl BertSelfAttention {
             dropout,
             num_attention_heads: config.num_attention_heads,
             attention_head_size,
+            use_sdpa: config.use_sdpa,
             span: tracing::span!(tracing::Level::TRACE, "self-attn"),
             span_softmax: tracing::span!(tracing::Level::TRACE, "softmax"),
         })
@@ -246,15 +259,20 @@ impl Module for BertSelfAttention {
         let key_layer = self.transpose_for_scores(&key_layer)?;
         let value_layer = self.transpose_for_scores(&value_layer)?;
 
-        let attention_scores = query_layer.matmul(&key_layer.t()?)?;
-        let attention_scores = (attention_scores / (self.attention_head_size as f64).sqrt())?;
-        let attention_probs = {
-            let _enter_sm = self.span_softmax.enter();
-            candle_nn::ops::softmax(&attention_scores, candle::D::Minus1)?
+        // The attention dropout is a no-op, see `Dropout`, so both paths compute the same values.
+        let context_layer = if self.use_sdpa {
+            let scale = 1f32 / (self.attention_head_size as f32).sqrt();
+            candle_nn::ops::sdpa(&query_layer, &key_layer, &value_layer, None, scale, false)?
+        } else {
+            let attention_scores = query_layer.matmul(&key_layer.t()?)?;
+            let attention_scores = (attention_scores / (self.attention_head_size as f64).sqrt())?;
+            let attention_probs = {
+                let _enter_sm = self.span_softmax.enter();
+                candle_nn::ops::softmax(&attention_scores, candle::D::Minus1)?
+            };
+            let attention_probs = self.dropout.forward(&attention_probs)?;
+            attention_probs.matmul(&value_layer)?
         };
-        let attention_probs = self.dropout.forward(&attention_probs)?;
-
-        let context_layer = attention_probs.matmul(&value_layer)?;
         let context_layer = context_layer.transpose(1, 2)?.contiguous()?;
         let context_layer = context_layer.flatten_from(candle::D::Minus2)?;
         Ok(context_layer)
@@ -241,8 +241,8 @@ impl DinoVisionTransformer {
             .reshape((1, sqrt_n as usize, sqrt_n as usize, dim))?
             .transpose(2, 3)?
             .transpose(1, 2)?;
-        // This uses bicubic interpolation in the original implementation.
-        let patch_pos_embed = patch_pos_embed.upsample_nearest2d(h0 as usize, w0 as usize)?;
+        let patch_pos_embed =
+            patch_pos_embed.upsample_bicubic2d(h0 as usize, w0 as usize, false)?;
         let el_count = patch_pos_embed.shape().elem_count();
         let patch_pos_embed =
             patch_pos_embed
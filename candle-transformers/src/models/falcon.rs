@@ -126,55 +126,40 @@ impl Config {
     }
 }
 
-fn rotate_half(x: &Tensor) -> Result<Tensor> {
-    let l = x.dim(D::Minus1)?;
-    let x1 = x.narrow(D::Minus1, 0, l / 2)?;
-    let x2 = x.narrow(D::Minus1, l / 2, l - l / 2)?;
-    let x21 = Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)?;
-    Ok(x21)
-}
-
 #[derive(Debug)]
 struct FalconRotaryEmbedding {
-    inv_freq: Tensor,
-    cache: Option<(usize, Tensor, Tensor)>,
+    head_dim: usize,
+    cache: Option<(DType, Tensor, Tensor)>,
 }
 
 impl FalconRotaryEmbedding {
-    fn load(device: &Device, cfg: &Config) -> Result<Self> {
-        let head_dim = cfg.head_dim();
-        let inv_freq: Vec<_> = (0..head_dim)
-            .step_by(2)
-            .map(|i| 1f32 / 10000f32.powf(i as f32 / head_dim as f32))
-            .collect();
-        Ok(Self {
-            inv_freq: Tensor::new(inv_freq.as_slice(), device)?,
+    fn load(cfg: &Config) -> Self {
+        Self {
+            head_dim: cfg.head_dim(),
             cache: None,
-        })
+        }
     }
 
-    fn cos_sin(
-        &mut self,
-        seq_len: usize,
-        device: &Device,
-        dtype: DType,
-    ) -> Result<(Tensor, Tensor)> {
+    fn cos_sin(&mut self, device: &Device, dtype: DType) -> Result<(Tensor, Tensor)> {
         match &self.cache {
-            Some((s, cos, sin)) if *s == seq_len => {
+            Some((d, cos, sin)) if *d == dtype => {
                 return Ok((cos.clone(), sin.clone()));
             }
             _ => {}
         }
-        let t = Tensor::arange(0, seq_len as u32, device)?.to_dtype(dtype)?;
-        let inv_freq = self.inv_freq.to_dtype(dtype)?;
-        let freqs = t.unsqueeze(1)?.matmul(&inv_freq.unsqueeze(0)?)?;
-        let emb = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
-        let cos = emb.cos()?;
-        let sin = emb.sin()?;
-        self.cache = Some((seq_len, cos.clone(), sin.clone()));
+        let (cos, sin) = candle_nn::rotary_emb::rope_tables(
+            self.head_dim,
+            MAX_SEQ_LEN,
+            10_000.,
+            None,
+            dtype,
+            device,
+        )?;
+        self.cache = Some((dtype, cos.clone(), sin.clone()));
         Ok((cos, sin))
     }
 
+    // The queries and keys have shape (b_sz * num_heads, seq_len, head_dim).
     fn forward(
         &mut self,
         query: &Tensor,
@@ -182,22 +167,15 @@ impl FalconRotaryEmbedding {
         past_kv_len: usize,
     ) -> Result<(Tensor, Tensor)> {
         let (_batch, seq_len, _head_dim) = query.dims3()?;
-        let (cos, sin) = self.cos_sin(MAX_SEQ_LEN, query.device(), query.dtype())?;
+        let (cos, sin) = self.cos_sin(query.device(), query.dtype())?;
         let cos = cos.narrow(0, past_kv_len, seq_len)?;
         let sin = sin.narrow(0, past_kv_len, seq_len)?;
-        let qs = (query.broadcast_mul(&cos)? + &rotate_half(query)?.broadcast_mul(&sin)?)?;
-        let ks = (key.broadcast_mul(&cos)? + &rotate_half(key)?.broadcast_mul(&sin)?)?;
+        let qs = candle_nn::rotary_emb::rope(&query.unsqueeze(0)?, &cos, &sin)?.squeeze(0)?;
+        let ks = candle_nn::rotary_emb::rope(&key.unsqueeze(0)?, &cos, &sin)?.squeeze(0)?;
         Ok((qs, ks))
     }
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug)]
 struct FalconAttention {
     query_key_value: Linear,
@@ -215,7 +193,7 @@ struct FalconAttention {
 impl FalconAttention {
     fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
         let maybe_rotary = if cfg.rotary() {
-            let rotary = FalconRotaryEmbedding::load(vb.device(), cfg)?;
+            let rotary = FalconRotaryEmbedding::load(cfg);
             Some(rotary)
         } else {
             None
@@ -282,7 +260,10 @@ impl FalconAttention {
             (query, key)
         };
         let (mut key, mut value) = (key, value);
-        let mask = masked_fill(&mask.to_dtype(DType::F32)?, mask, -1e9)?.to_dtype(query.dtype())?;
+        let mask = mask
+            .to_dtype(DType::F32)?
+            .masked_fill(mask, -1e9)?
+            .to_dtype(query.dtype())?;
         if self.use_cache {
             if let Some((cache_k, cache_v)) = &self.kv_cache {
                 // TODO: we could trim the tensors to MAX_SEQ_LEN so that this would work for
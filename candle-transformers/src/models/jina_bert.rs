@@ -268,38 +268,6 @@ impl BertLayer {
     }
 }
 
-fn build_alibi_bias(cfg: &Config) -> Result<Tensor> {
-    let n_heads = cfg.num_attention_heads;
-    let seq_len = cfg.max_position_embeddings;
-    let alibi_bias = Tensor::arange(0, seq_len as i64, &Device::Cpu)?.to_dtype(DType::F32)?;
-    let alibi_bias = {
-        let a1 = alibi_bias.reshape((1, seq_len))?;
-        let a2 = alibi_bias.reshape((seq_len, 1))?;
-        a1.broadcast_sub(&a2)?.abs()?.broadcast_left(n_heads)?
-    };
-    let mut n_heads2 = 1;
-    while n_heads2 < n_heads {
-        n_heads2 *= 2
-    }
-    let slopes = (1..=n_heads2)
-        .map(|v| -1f32 / 2f32.powf((v * 8) as f32 / n_heads2 as f32))
-        .collect::<Vec<_>>();
-    let slopes = if n_heads2 == n_heads {
-        slopes
-    } else {
-        slopes
-            .iter()
-            .skip(1)
-            .step_by(2)
-            .chain(slopes.iter().step_by(2))
-            .take(n_heads)
-            .cloned()
-            .collect::<Vec<f32>>()
-    };
-    let slopes = Tensor::new(slopes, &Device::Cpu)?.reshape((1, (), 1, 1))?;
-    alibi_bias.to_dtype(DType::F32)?.broadcast_mul(&slopes)
-}
-
 #[derive(Clone, Debug)]
 struct BertEncoder {
     alibi: Tensor,
@@ -316,7 +284,14 @@ impl BertEncoder {
             .map(|index| BertLayer::new(vb.pp(&format!("layer.{index}")), cfg))
             .collect::<Result<Vec<_>>>()?;
         let span = tracing::span!(tracing::Level::TRACE, "encoder");
-        let alibi = build_alibi_bias(cfg)?.to_device(vb.device())?;
+        let alibi = candle_nn::rotary_emb::alibi_bias(
+            cfg.num_attention_heads,
+            cfg.max_position_embeddings,
+            cfg.max_position_embeddings,
+            8.,
+            vb.device(),
+        )?
+        .unsqueeze(0)?;
         Ok(Self {
             alibi,
             layers,
@@ -1,11 +1,14 @@
 use super::with_tracing::{linear_no_bias as linear, Linear};
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
-use candle_nn::{Embedding, Module, VarBuilder};
+use candle_nn::rotary_emb::RopeScaling;
+use candle_nn::{Embedding, KvCache, Module, VarBuilder};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
 pub const MAX_SEQ_LEN: usize = 4096;
+// The initial capacity of the kv caches, these grow as needed.
+const KV_CACHE_CAPACITY: usize = 512;
 
 #[derive(Deserialize)]
 pub struct LlamaConfig {
@@ -18,12 +21,20 @@ pub struct LlamaConfig {
     pub rms_norm_eps: f64,
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
+    #[serde(default = "default_max_position_embeddings")]
+    pub max_position_embeddings: usize,
+    #[serde(default)]
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 fn default_rope() -> f32 {
     10_000.0
 }
 
+fn default_max_position_embeddings() -> usize {
+    MAX_SEQ_LEN
+}
+
 impl LlamaConfig {
     pub fn into_config(self, use_flash_attn: bool) -> Config {
         Config {
@@ -35,7 +46,10 @@ impl LlamaConfig {
             num_key_value_heads: self.num_key_value_heads.unwrap_or(self.num_attention_heads),
             rms_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
+            max_position_embeddings: self.max_position_embeddings,
+            rope_scaling: self.rope_scaling,
             use_flash_attn,
+            use_sdpa: false,
         }
     }
 }
@@ -48,8 +62,15 @@ pub struct Config {
     pub num_attention_heads: usize,
     pub num_key_value_heads: usize,
     pub use_flash_attn: bool,
+    /// Use the fused [`candle_nn::ops::sdpa`] attention rather than materializing the attention
+    /// matrix.
+    pub use_sdpa: bool,
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
+    pub max_position_embeddings: usize,
+    /// Extends the context length past the one the model was trained with, the positions are
+    /// limited to `max_position_embeddings`.
+    pub rope_scaling: Option<RopeScaling>,
 }
 
 impl Config {
@@ -62,8 +83,11 @@ impl Config {
             num_attention_heads: 32,
             num_key_value_heads: 32,
             use_flash_attn,
+            use_sdpa: false,
             rms_norm_eps: 1e-6,
             rope_theta: 10_000.0,
+            max_position_embeddings: MAX_SEQ_LEN,
+            rope_scaling: None,
         }
     }
 
@@ -76,8 +100,11 @@ impl Config {
             num_attention_heads: 32,
             num_key_value_heads: 32,
             use_flash_attn,
+            use_sdpa: false,
             rms_norm_eps: 1e-5,
             rope_theta: 10_000.0,
+            max_position_embeddings: MAX_SEQ_LEN,
+            rope_scaling: None,
         }
     }
 }
@@ -86,8 +113,7 @@ impl Config {
 pub struct Cache {
     masks: Arc<Mutex<HashMap<usize, Tensor>>>,
     pub use_kv_cache: bool,
-    #[allow(clippy::type_complexity)]
-    kvs: Arc<Mutex<Vec<Option<(Tensor, Tensor)>>>>,
+    kvs: Arc<Mutex<Vec<KvCache>>>,
     cos: Tensor,
     sin: Tensor,
     device: Device,
@@ -97,24 +123,22 @@ impl Cache {
     pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
         // precompute freqs_cis
         let n_elem = config.hidden_size / config.num_attention_heads;
-        let theta: Vec<_> = (0..n_elem)
-            .step_by(2)
-            .map(|i| 1f32 / config.rope_theta.powf(i as f32 / n_elem as f32))
-            .collect();
-        let theta = Tensor::new(theta.as_slice(), device)?;
-        let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((MAX_SEQ_LEN, 1))?
-            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        // This is different from the paper, see:
-        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
-        let idx_theta = Tensor::cat(&[&idx_theta, &idx_theta], D::Minus1)?;
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+        let (cos, sin) = candle_nn::rotary_emb::rope_tables(
+            n_elem,
+            config.max_position_embeddings,
+            config.rope_theta as f64,
+            config.rope_scaling.as_ref(),
+            dtype,
+            device,
+        )?;
         Ok(Self {
             masks: Arc::new(Mutex::new(HashMap::new())),
             use_kv_cache,
-            kvs: Arc::new(Mutex::new(vec![None; config.num_hidden_layers])),
+            kvs: Arc::new(Mutex::new(
+                (0..config.num_hidden_layers)
+                    .map(|_| KvCache::new(2, KV_CACHE_CAPACITY))
+                    .collect(),
+            )),
             device: device.clone(),
             cos,
             sin,
@@ -134,6 +158,20 @@ impl Cache {
             Ok(mask)
         }
     }
+
+    pub fn clear_kv_cache(&self) {
+        let mut kvs = self.kvs.lock().unwrap();
+        kvs.iter_mut().for_each(|kv| kv.reset())
+    }
+
+    /// Discards the kv cache entries past the first `seq_len` positions.
+    pub fn rollback_kv_cache(&self, seq_len: usize) -> Result<()> {
+        let mut kvs = self.kvs.lock().unwrap();
+        for kv in kvs.iter_mut() {
+            kv.rollback(seq_len)?
+        }
+        Ok(())
+    }
 }
 
 fn embedding(cfg: &Config, vb: VarBuilder) -> Result<Embedding> {
@@ -169,6 +207,7 @@ struct CausalSelfAttention {
     head_dim: usize,
     cache: Cache,
     use_flash_attn: bool,
+    use_sdpa: bool,
     span: tracing::Span,
     span_rot: tracing::Span,
 }
@@ -192,16 +231,10 @@ fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Ten
 impl CausalSelfAttention {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
-        let (b_sz, _, seq_len, hidden_size) = x.dims4()?;
+        let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
         let cos = self.cache.cos.narrow(0, index_pos, seq_len)?;
         let sin = self.cache.sin.narrow(0, index_pos, seq_len)?;
-        let cos = cos.broadcast_as((b_sz, 1, seq_len, hidden_size))?;
-        let sin = sin.broadcast_as((b_sz, 1, seq_len, hidden_size))?;
-        let x1 = x.narrow(D::Minus1, 0, hidden_size / 2)?;
-        let x2 = x.narrow(D::Minus1, hidden_size / 2, hidden_size / 2)?;
-        let rotate_x = Tensor::cat(&[&x2.neg()?, &x1], D::Minus1)?;
-        let rope = (x.broadcast_mul(&cos)? + rotate_x.broadcast_mul(&sin)?)?;
-        Ok(rope)
+        candle_nn::rotary_emb::rope(x, &cos, &sin)
     }
 
     fn forward(&self, x: &Tensor, index_pos: usize, block_idx: usize) -> Result<Tensor> {
@@ -226,46 +259,47 @@ impl CausalSelfAttention {
 
         if self.cache.use_kv_cache {
             let mut cache = self.cache.kvs.lock().unwrap();
-            if let Some((cache_k, cache_v)) = &cache[block_idx] {
-                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
-                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
-                let k_seq_len = k.dims()[1];
-                if k_seq_len > MAX_SEQ_LEN {
-                    k = k
-                        .narrow(D::Minus1, k_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                        .contiguous()?
-                }
-                let v_seq_len = v.dims()[1];
-                if v_seq_len > 2 * MAX_SEQ_LEN {
-                    v = v
-                        .narrow(D::Minus1, v_seq_len - MAX_SEQ_LEN, MAX_SEQ_LEN)?
-                        .contiguous()?
-                }
+            let cache = &mut cache[block_idx];
+            // Processing a position that has already been cached discards the subsequent ones.
+            if index_pos < cache.current_seq_len() {
+                cache.rollback(index_pos)?
             }
-            cache[block_idx] = Some((k.clone(), v.clone()))
+            (k, v) = cache.append(&k, &v)?;
         }
 
-        let k = self.repeat_kv(k)?;
-        let v = self.repeat_kv(v)?;
-
-        let y = if self.use_flash_attn {
-            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
-            let q = q.transpose(1, 2)?;
-            let k = k.transpose(1, 2)?;
-            let v = v.transpose(1, 2)?;
+        let y = if self.use_sdpa {
+            // sdpa handles the grouped key/value heads without repeating them.
             let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
+            candle_nn::ops::sdpa(&q, &k, &v, None, softmax_scale, true)?
         } else {
-            let in_dtype = q.dtype();
-            let q = q.to_dtype(DType::F32)?;
-            let k = k.to_dtype(DType::F32)?;
-            let v = v.to_dtype(DType::F32)?;
-            let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-            let mask = self.cache.mask(seq_len)?.broadcast_as(att.shape())?;
-            let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
-            let att = candle_nn::ops::softmax(&att, D::Minus1)?;
-            // Convert to contiguous as matmul doesn't support strided vs for now.
-            att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+            let k = self.repeat_kv(k)?;
+            let v = self.repeat_kv(v)?;
+
+            if self.use_flash_attn {
+                // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+                let q = q.transpose(1, 2)?;
+                let k = k.transpose(1, 2)?;
+                let v = v.transpose(1, 2)?;
+                let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+                flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
+            } else {
+                let in_dtype = q.dtype();
+                let q = q.to_dtype(DType::F32)?;
+                let k = k.to_dtype(DType::F32)?;
+                let v = v.to_dtype(DType::F32)?;
+                let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+                // The cached positions can be attended by all the new positions.
+                let kv_len = att.dim(D::Minus1)?;
+                let mask = self
+                    .cache
+                    .mask(seq_len)?
+                    .pad_with_zeros(D::Minus1, kv_len - seq_len, 0)?
+                    .broadcast_as(att.shape())?;
+                let att = att.masked_fill(&mask, f64::NEG_INFINITY)?;
+                let att = candle_nn::ops::softmax(&att, D::Minus1)?;
+                // Convert to contiguous as matmul doesn't support strided vs for now.
+                att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+            }
         };
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
         let y = self.o_proj.forward(&y)?;
@@ -306,19 +340,13 @@ impl CausalSelfAttention {
             head_dim: cfg.hidden_size / cfg.num_attention_heads,
             cache: cache.clone(),
             use_flash_attn: cfg.use_flash_attn,
+            use_sdpa: cfg.use_sdpa,
             span,
             span_rot,
         })
     }
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 struct Mlp {
     c_fc1: Linear,
     c_fc2: Linear,
@@ -393,6 +421,7 @@ pub struct Llama {
     blocks: Vec<Block>,
     ln_f: RmsNorm,
     lm_head: Linear,
+    cache: Cache,
 }
 
 impl Llama {
@@ -408,6 +437,27 @@ impl Llama {
         logits.to_dtype(DType::F32)
     }
 
+    /// Similar to `forward` but returns the logits for all the positions, with shape
+    /// `(b_sz, seq_len, vocab_size)`.
+    pub fn forward_all(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut x = self.wte.forward(x)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx)?;
+        }
+        let x = self.ln_f.forward(&x)?;
+        let logits = self.lm_head.forward(&x)?;
+        logits.to_dtype(DType::F32)
+    }
+
+    pub fn clear_kv_cache(&self) {
+        self.cache.clear_kv_cache()
+    }
+
+    /// Discards the kv cache entries past the first `seq_len` positions.
+    pub fn rollback_kv_cache(&self, seq_len: usize) -> Result<()> {
+        self.cache.rollback_kv_cache(seq_len)
+    }
+
     pub fn load(vb: VarBuilder, cache: &Cache, cfg: &Config) -> Result<Self> {
         let wte = embedding(cfg, vb.pp("model.embed_tokens"))?;
         let lm_head = linear(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
@@ -421,6 +471,7 @@ impl Llama {
             blocks,
             ln_f,
             lm_head,
+            cache: cache.clone(),
         })
     }
 }
@@ -1,6 +1,6 @@
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
 use candle_nn::linear_no_bias as linear;
-use candle_nn::{embedding, rms_norm, Embedding, Linear, Module, RmsNorm, VarBuilder};
+use candle_nn::{embedding, rms_norm, Embedding, KvCache, Linear, Module, RmsNorm, VarBuilder};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -74,8 +74,7 @@ impl Config {
 pub struct Cache {
     masks: Arc<Mutex<HashMap<usize, Tensor>>>,
     pub use_kv_cache: bool,
-    #[allow(clippy::type_complexity)]
-    pub kvs: Arc<Mutex<Vec<Option<(Tensor, Tensor)>>>>,
+    pub kvs: Arc<Mutex<Vec<KvCache>>>,
     pub cos: Tensor,
     pub sin: Tensor,
     device: Device,
@@ -107,13 +106,31 @@ impl Cache {
         Ok(Self {
             masks: Arc::new(Mutex::new(HashMap::new())),
             use_kv_cache,
-            kvs: Arc::new(Mutex::new(vec![None; cfg.n_layers])),
+            kvs: Arc::new(Mutex::new(
+                (0..cfg.n_layers)
+                    .map(|_| KvCache::new(1, cfg.seq_len))
+                    .collect(),
+            )),
             cos,
             sin,
             device: vb.device().clone(),
         })
     }
 
+    pub fn clear_kv_cache(&self) {
+        let mut kvs = self.kvs.lock().unwrap();
+        kvs.iter_mut().for_each(|kv| kv.reset())
+    }
+
+    /// Discards the kv cache entries past the first `seq_len` positions.
+    pub fn rollback_kv_cache(&self, seq_len: usize) -> Result<()> {
+        let mut kvs = self.kvs.lock().unwrap();
+        for kv in kvs.iter_mut() {
+            kv.rollback(seq_len)?
+        }
+        Ok(())
+    }
+
     pub fn mask(&self, t: usize) -> Result<Tensor> {
         let mut masks = self.masks.lock().unwrap();
         if let Some(mask) = masks.get(&t) {
@@ -146,19 +163,20 @@ struct CausalSelfAttention {
 
 impl CausalSelfAttention {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
-        let (b_sz, seq_len, h, n_embd) = x.dims4()?;
-        let cos = self.cache.cos.i(index_pos..index_pos + seq_len)?;
-        let sin = self.cache.sin.i(index_pos..index_pos + seq_len)?;
-        let cos = cos.unsqueeze(1)?;
-        let sin = sin.unsqueeze(1)?;
-        let cos = cos.broadcast_as((b_sz, seq_len, 1, n_embd / 2, 1))?;
-        let sin = sin.broadcast_as((b_sz, seq_len, 1, n_embd / 2, 1))?;
-        let x = x.reshape((b_sz, seq_len, h, n_embd / 2, 2))?;
-        let x0 = x.narrow(D::Minus1, 0, 1)?;
-        let x1 = x.narrow(D::Minus1, 1, 1)?;
-        let dst0 = (x0.broadcast_mul(&cos)? - x1.broadcast_mul(&sin)?)?;
-        let dst1 = (x0.broadcast_mul(&sin)? + x1.broadcast_mul(&cos)?)?;
-        let rope = Tensor::cat(&[&dst0, &dst1], D::Minus1)?.reshape((b_sz, seq_len, h, n_embd))?;
+        let (_b_sz, seq_len, _h, _n_embd) = x.dims4()?;
+        let cos = self
+            .cache
+            .cos
+            .i(index_pos..index_pos + seq_len)?
+            .squeeze(2)?;
+        let sin = self
+            .cache
+            .sin
+            .i(index_pos..index_pos + seq_len)?
+            .squeeze(2)?;
+        // The rotary embeddings apply to tensors of shape (b_sz, h, seq_len, n_embd).
+        let rope = candle_nn::rotary_emb::rope_interleaved(&x.transpose(1, 2)?, &cos, &sin)?
+            .transpose(1, 2)?;
         Ok(rope)
     }
 
@@ -177,11 +195,12 @@ impl CausalSelfAttention {
 
         if self.cache.use_kv_cache {
             let mut cache = self.cache.kvs.lock().unwrap();
-            if let Some((cache_k, cache_v)) = &cache[block_idx] {
-                k = Tensor::cat(&[cache_k, &k], 1)?.contiguous()?;
-                v = Tensor::cat(&[cache_v, &v], 1)?.contiguous()?;
+            let cache = &mut cache[block_idx];
+            // Processing a position that has already been cached discards the subsequent ones.
+            if index_pos < cache.current_seq_len() {
+                cache.rollback(index_pos)?
             }
-            cache[block_idx] = Some((k.clone(), v.clone()))
+            (k, v) = cache.append(&k, &v)?;
         }
 
         let k = self.repeat_kv(k)?;
@@ -192,8 +211,14 @@ impl CausalSelfAttention {
         let v = v.transpose(1, 2)?.contiguous()?;
 
         let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-        let mask = self.cache.mask(seq_len)?.broadcast_as(att.shape())?;
-        let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
+        // The cached positions can be attended by all the new positions.
+        let kv_len = att.dim(D::Minus1)?;
+        let mask = self
+            .cache
+            .mask(seq_len)?
+            .pad_with_zeros(D::Minus1, kv_len - seq_len, 0)?
+            .broadcast_as(att.shape())?;
+        let att = att.masked_fill(&mask, f64::NEG_INFINITY)?;
         let att = candle_nn::ops::softmax(&att, D::Minus1)?;
         // Convert to contiguous as matmul doesn't support strided vs for now.
         let y = att.matmul(&v.contiguous()?)?;
@@ -237,13 +262,6 @@ impl CausalSelfAttention {
     }
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 struct Mlp {
     c_fc1: Linear,
     c_fc2: Linear,
@@ -320,6 +338,7 @@ pub struct Llama {
     blocks: Vec<Block>,
     ln_f: RmsNorm,
     lm_head: Linear,
+    cache: Cache,
     pub config: Config,
 }
 
@@ -335,6 +354,15 @@ impl Llama {
         logits.to_dtype(DType::F32)
     }
 
+    pub fn clear_kv_cache(&self) {
+        self.cache.clear_kv_cache()
+    }
+
+    /// Discards the kv cache entries past the first `seq_len` positions.
+    pub fn rollback_kv_cache(&self, seq_len: usize) -> Result<()> {
+        self.cache.rollback_kv_cache(seq_len)
+    }
+
     pub fn load(vb: VarBuilder, cache: &Cache, cfg: Config) -> Result<Self> {
         let wte = embedding(cfg.vocab_size, cfg.dim, vb.pp("model.embed_tokens"))?;
         let lm_head = linear(cfg.dim, cfg.vocab_size, vb.pp("lm_head"))?;
@@ -347,6 +375,7 @@ impl Llama {
             blocks,
             ln_f,
             lm_head,
+            cache: cache.clone(),
             config: cfg,
         })
     }
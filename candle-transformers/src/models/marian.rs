@@ -217,6 +217,15 @@ impl Attention {
     fn reset_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            let k = k.contiguous()?.index_select(beam_indices, 0)?;
+            let v = v.contiguous()?.index_select(beam_indices, 0)?;
+            self.kv_cache = Some((k, v))
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -327,6 +336,11 @@ impl DecoderLayer {
         self.self_attn.reset_kv_cache();
         self.encoder_attn.reset_kv_cache()
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.self_attn.reorder_kv_cache(beam_indices)?;
+        self.encoder_attn.reorder_kv_cache(beam_indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -441,6 +455,13 @@ impl Decoder {
             layer.reset_kv_cache()
         }
     }
+
+    pub fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.reorder_kv_cache(beam_indices)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -517,4 +538,10 @@ impl MTModel {
     pub fn reset_kv_cache(&mut self) {
         self.model.reset_kv_cache();
     }
+
+    /// Selects the decoder kv cache entries along the batch dimension, this is used to follow
+    /// the beams during beam search.
+    pub fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.model.decoder.reorder_kv_cache(beam_indices)
+    }
 }
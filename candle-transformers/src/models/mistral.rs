@@ -1,23 +1,33 @@
 use crate::models::with_tracing::{linear_no_bias, Linear};
+use crate::paged_attention::{paged_attention, PagedAttentionMetadata};
 /// Mistral LLM, https://github.com/mistralai/mistral-src
-use candle::{DType, Device, Module, Result, Tensor, D};
-use candle_nn::{Activation, VarBuilder};
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::paged_kv_cache::PagedKvCache;
+use candle_nn::rotary_emb::RopeScaling;
+use candle_nn::{Activation, KvCache, VarBuilder};
 use std::sync::Arc;
 
+// The initial capacity of the kv caches, these grow as needed.
+const KV_CACHE_CAPACITY: usize = 512;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Config {
-    pub(crate) vocab_size: usize,
-    pub(crate) hidden_size: usize,
-    pub(crate) intermediate_size: usize,
-    pub(crate) num_hidden_layers: usize,
-    pub(crate) num_attention_heads: usize,
-    pub(crate) num_key_value_heads: usize,
-    pub(crate) hidden_act: Activation,
-    pub(crate) max_position_embeddings: usize,
-    pub(crate) rms_norm_eps: f64,
-    pub(crate) rope_theta: f64,
-    pub(crate) sliding_window: usize,
-    pub(crate) use_flash_attn: bool,
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub num_key_value_heads: usize,
+    pub hidden_act: Activation,
+    pub max_position_embeddings: usize,
+    pub rms_norm_eps: f64,
+    pub rope_theta: f64,
+    pub rope_scaling: Option<RopeScaling>,
+    pub sliding_window: usize,
+    pub use_flash_attn: bool,
+    /// Use the fused [`candle_nn::ops::sdpa`] attention rather than materializing the attention
+    /// matrix.
+    pub use_sdpa: bool,
 }
 
 impl Config {
@@ -33,8 +43,10 @@ impl Config {
             max_position_embeddings: 32768,
             rms_norm_eps: 1e-5,
             rope_theta: 10_000.,
+            rope_scaling: None,
             sliding_window: 4096,
             use_flash_attn,
+            use_sdpa: false,
         }
     }
 }
@@ -66,47 +78,47 @@ struct RotaryEmbedding {
     cos: Tensor,
 }
 
-fn rotate_half(xs: &Tensor) -> Result<Tensor> {
-    let last_dim = xs.dim(D::Minus1)?;
-    let xs1 = xs.narrow(D::Minus1, 0, last_dim / 2)?;
-    let xs2 = xs.narrow(D::Minus1, last_dim / 2, last_dim - last_dim / 2)?;
-    Tensor::cat(&[&xs2.neg()?, &xs1], D::Minus1)
-}
-
 impl RotaryEmbedding {
     fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
         let dim = cfg.hidden_size / cfg.num_attention_heads;
-        let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / 10000f32.powf(i as f32 / dim as f32))
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(dtype)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
+        let (cos, sin) = candle_nn::rotary_emb::rope_tables(
+            dim,
+            cfg.max_position_embeddings,
+            cfg.rope_theta,
+            cfg.rope_scaling.as_ref(),
+            dtype,
+            dev,
+        )?;
+        Ok(Self { sin, cos })
     }
 
+    // `positions` optionally holds the position of each token with shape (b_sz, seq_len),
+    // otherwise the positions start at `seqlen_offset` for all the batch elements.
     fn apply_rotary_emb_qkv(
         &self,
         q: &Tensor,
         k: &Tensor,
         seqlen_offset: usize,
+        positions: Option<&Tensor>,
     ) -> Result<(Tensor, Tensor)> {
-        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
-        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
-        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-        let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
-        let k_embed = (k.broadcast_mul(&cos)? + rotate_half(k)?.broadcast_mul(&sin))?;
+        let (b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let (cos, sin) = match positions {
+            None => {
+                let cos = self.cos.narrow(0, seqlen_offset, seq_len)?; // (seq_len, dim / 2)
+                let sin = self.sin.narrow(0, seqlen_offset, seq_len)?; // (seq_len, dim / 2)
+                (cos, sin)
+            }
+            Some(positions) => {
+                let positions = positions.flatten_all()?;
+                let cos = self.cos.index_select(&positions, 0)?;
+                let sin = self.sin.index_select(&positions, 0)?;
+                let cos = cos.reshape((b_sz, seq_len, ()))?; // (b_sz, seq_len, dim / 2)
+                let sin = sin.reshape((b_sz, seq_len, ()))?; // (b_sz, seq_len, dim / 2)
+                (cos, sin)
+            }
+        };
+        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
         Ok((q_embed, k_embed))
     }
 }
@@ -172,8 +184,11 @@ struct Attention {
     head_dim: usize,
     hidden_size: usize,
     rotary_emb: Arc<RotaryEmbedding>,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
+    paged_kv_cache: Option<PagedKvCache>,
     use_flash_attn: bool,
+    use_sdpa: bool,
+    sliding_window: usize,
 }
 
 impl Attention {
@@ -198,8 +213,11 @@ impl Attention {
             head_dim,
             hidden_size: hidden_sz,
             rotary_emb,
-            kv_cache: None,
+            kv_cache: KvCache::new(2, KV_CACHE_CAPACITY).with_sliding_window(cfg.sliding_window),
+            paged_kv_cache: None,
             use_flash_attn: cfg.use_flash_attn,
+            use_sdpa: cfg.use_sdpa,
+            sliding_window: cfg.sliding_window,
         })
     }
 
@@ -220,6 +238,7 @@ impl Attention {
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        positions: Option<&Tensor>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -237,46 +256,113 @@ impl Attention {
             .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
             .transpose(1, 2)?;
 
-        let (query_states, key_states) =
-            self.rotary_emb
-                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
-
-        let (key_states, value_states) = match &self.kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        let (query_states, key_states) = self.rotary_emb.apply_rotary_emb_qkv(
+            &query_states,
+            &key_states,
+            seqlen_offset,
+            positions,
+        )?;
 
-        let key_states = self.repeat_kv(key_states)?;
-        let value_states = self.repeat_kv(value_states)?;
+        // Processing a position that has already been cached discards the subsequent ones.
+        if seqlen_offset < self.kv_cache.current_seq_len() {
+            self.kv_cache.rollback(seqlen_offset)?
+        }
+        let (key_states, value_states) = self.kv_cache.append(&key_states, &value_states)?;
 
-        let attn_output = if self.use_flash_attn {
-            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
-            let q = query_states.transpose(1, 2)?;
-            let k = key_states.transpose(1, 2)?;
-            let v = value_states.transpose(1, 2)?;
+        let attn_output = if self.use_sdpa {
+            // sdpa handles the grouped key/value heads and the causal sliding window without
+            // materializing them, the mask is only set for padded batches.
             let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
-            flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+            candle_nn::ops::sdpa_sliding_window(
+                &query_states,
+                &key_states,
+                &value_states,
+                attention_mask,
+                softmax_scale,
+                self.sliding_window,
+            )?
         } else {
-            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
-            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
-
-            let attn_weights = match attention_mask {
-                None => attn_weights,
-                Some(mask) => attn_weights.broadcast_add(mask)?,
-            };
-            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
-            attn_weights.matmul(&value_states)?
+            let key_states = self.repeat_kv(key_states)?;
+            let value_states = self.repeat_kv(value_states)?;
+
+            if self.use_flash_attn {
+                // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+                let q = query_states.transpose(1, 2)?;
+                let k = key_states.transpose(1, 2)?;
+                let v = value_states.transpose(1, 2)?;
+                let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+                flash_attn(&q, &k, &v, softmax_scale, q_len > 1)?.transpose(1, 2)?
+            } else {
+                let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+                let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+
+                let attn_weights = match attention_mask {
+                    None => attn_weights,
+                    Some(mask) => attn_weights.broadcast_add(mask)?,
+                };
+                let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+                attn_weights.matmul(&value_states)?
+            }
         };
         attn_output
             .transpose(1, 2)?
             .reshape((b_sz, q_len, self.hidden_size))?
             .apply(&self.o_proj)
     }
+
+    // Same as `forward` for a single row of concatenated sequences using the paged kv cache.
+    fn forward_paged(
+        &mut self,
+        xs: &Tensor,
+        metadata: &PagedAttentionMetadata,
+        masks: &[Tensor],
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+        let value_states = value_states.reshape((q_len, self.num_kv_heads, self.head_dim))?;
+
+        let positions = metadata.positions().unsqueeze(0)?;
+        let (query_states, key_states) = self.rotary_emb.apply_rotary_emb_qkv(
+            &query_states,
+            &key_states,
+            0,
+            Some(&positions),
+        )?;
+        let query_states = query_states.squeeze(0)?.transpose(0, 1)?;
+        let key_states = key_states.squeeze(0)?.transpose(0, 1)?;
+
+        let cache = match &self.paged_kv_cache {
+            None => candle::bail!("the paged kv cache has not been allocated"),
+            Some(cache) => cache,
+        };
+        let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+        let attn_output = paged_attention(
+            &query_states,
+            &key_states,
+            &value_states,
+            cache,
+            metadata,
+            masks,
+            scale,
+        )?;
+        attn_output
+            .reshape((b_sz, q_len, self.hidden_size))?
+            .apply(&self.o_proj)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -311,15 +397,37 @@ impl DecoderLayer {
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        positions: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self
+            .self_attn
+            .forward(&xs, attention_mask, seqlen_offset, positions)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        residual + xs
+    }
+
+    fn forward_paged(
+        &mut self,
+        xs: &Tensor,
+        metadata: &PagedAttentionMetadata,
+        masks: &[Tensor],
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
-        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = self.self_attn.forward_paged(&xs, metadata, masks)?;
         let xs = (xs + residual)?;
         let residual = &xs;
         let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
         residual + xs
     }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -329,6 +437,8 @@ pub struct Model {
     norm: RmsNorm,
     lm_head: Linear,
     sliding_window: usize,
+    use_flash_attn: bool,
+    use_sdpa: bool,
     device: Device,
     dtype: DType,
 }
@@ -353,6 +463,8 @@ impl Model {
             norm,
             lm_head,
             sliding_window: cfg.sliding_window,
+            use_flash_attn: cfg.use_flash_attn,
+            use_sdpa: cfg.use_sdpa,
             device: vb.device().clone(),
             dtype: vb.dtype(),
         })
@@ -364,11 +476,13 @@ impl Model {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        // Sliding window mask?
+        // The kv cache only keeps the last `sliding_window` positions, the mask covers these
+        // followed by the new positions.
+        let cached_len = usize::min(seqlen_offset, self.sliding_window);
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + self.sliding_window < i {
+                (0..cached_len + tgt_len).map(move |j| {
+                    if i + cached_len < j || j + self.sliding_window < i + cached_len {
                         f32::NEG_INFINITY
                     } else {
                         0.
@@ -376,20 +490,15 @@ impl Model {
                 })
             })
             .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+        let mask = Tensor::from_slice(&mask, (tgt_len, cached_len + tgt_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, cached_len + tgt_len))?
             .to_dtype(self.dtype)
     }
 
     pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
         let (b_size, seq_len) = input_ids.dims2()?;
-        let attention_mask = if seq_len <= 1 {
+        // The sdpa kernel applies the causal sliding window mask itself.
+        let attention_mask = if seq_len <= 1 || self.use_sdpa {
             None
         } else {
             let mask = self.prepare_decoder_attention_mask(b_size, seq_len, seqlen_offset)?;
@@ -397,10 +506,99 @@ impl Model {
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
         for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset, None)?
         }
         xs.narrow(1, seq_len - 1, 1)?
             .apply(&self.norm)?
             .apply(&self.lm_head)
     }
+
+    /// Runs a batch of left-padded sequences of different lengths, `pad_lens` holds the number
+    /// of padding tokens at the beginning of each sequence and `seqlen_offset` is the number of
+    /// columns that have already been processed. The kv cache of sequence `i` then holds
+    /// `seqlen_offset + seq_len - pad_lens[i]` entries, up to the sliding window size. Returns
+    /// the logits for the last column.
+    ///
+    /// The sequences can be padded with [`crate::utils::left_pad`].
+    pub fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+        pad_lens: &[usize],
+    ) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        if pad_lens.len() != b_size {
+            candle::bail!("unexpected number of padding lengths {pad_lens:?} for batch {b_size}")
+        }
+        if self.use_flash_attn {
+            candle::bail!("padded batches are not supported with flash-attn")
+        }
+        let kv_len = usize::min(seqlen_offset, self.sliding_window) + seq_len;
+        let mask = crate::utils::padded_causal_mask(
+            pad_lens,
+            seqlen_offset,
+            seq_len,
+            kv_len,
+            Some(self.sliding_window),
+            &self.device,
+        )?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, &self.device)?.broadcast_as(mask.shape())?;
+        let attention_mask = mask
+            .where_cond(&neg_inf, &neg_inf.zeros_like()?)?
+            .to_dtype(self.dtype)?;
+        let positions =
+            crate::utils::padded_positions(pad_lens, seqlen_offset, seq_len, &self.device)?;
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, Some(&attention_mask), seqlen_offset, Some(&positions))?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Allocates a paged kv cache with `num_blocks` blocks of `block_size` positions for each
+    /// layer, this is required before calling `forward_paged`.
+    pub fn allocate_paged_kv_cache(&mut self, num_blocks: usize, block_size: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            let attn = &mut layer.self_attn;
+            let cache = PagedKvCache::new(
+                num_blocks,
+                block_size,
+                attn.num_kv_heads,
+                attn.head_dim,
+                self.dtype,
+                &self.device,
+            )?;
+            attn.paged_kv_cache = Some(cache)
+        }
+        Ok(())
+    }
+
+    /// Runs a batch of sequences using the paged kv cache, `input_ids` is a one dimensional
+    /// tensor holding the new tokens of all the sequences concatenated as described by
+    /// `metadata`. Returns the logits for the last token of each sequence, with shape
+    /// `(num_sequences, vocab_size)`.
+    ///
+    /// The sliding window is applied through the attention mask, the positions that are out of
+    /// the window are not evicted from the paged cache.
+    pub fn forward_paged(
+        &mut self,
+        input_ids: &Tensor,
+        metadata: &PagedAttentionMetadata,
+    ) -> Result<Tensor> {
+        let masks = metadata.masks(Some(self.sliding_window))?;
+        let mut xs = self.embed_tokens.forward(&input_ids.unsqueeze(0)?)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward_paged(&xs, metadata, &masks)?
+        }
+        xs.squeeze(0)?
+            .index_select(metadata.last_token_indexes(), 0)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.layers.iter_mut().for_each(|l| l.clear_kv_cache())
+    }
 }
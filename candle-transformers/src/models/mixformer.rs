@@ -117,13 +117,6 @@ fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
     Tensor::from_slice(&mask, (size, size), device)
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug, Clone)]
 struct RotaryEmbedding {
     sin: Tensor,
@@ -132,20 +125,9 @@ struct RotaryEmbedding {
 
 impl RotaryEmbedding {
     fn new(dim: usize, max_seq_len: usize, dev: &Device) -> Result<Self> {
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / 10000f32.powf(i as f32 / dim as f32))
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
+        let (cos, sin) =
+            candle_nn::rotary_emb::rope_tables(dim, max_seq_len, 10_000., None, DType::F32, dev)?;
+        Ok(Self { sin, cos })
     }
 
     fn apply_rotary_emb_qkv(
@@ -163,26 +145,14 @@ impl RotaryEmbedding {
         let q_pass = qkv.i((.., .., 0, .., rotary_dim..))?;
         let k_rot = qkv.i((.., .., 1, .., ..rotary_dim))?;
         let k_pass = qkv.i((.., .., 1, .., rotary_dim..))?;
-        let q12 = q_rot.chunk(2, D::Minus1)?;
-        let k12 = k_rot.chunk(2, D::Minus1)?;
-        let (q1, q2) = (&q12[0], &q12[1]);
-        let (k1, k2) = (&k12[0], &k12[1]);
-        let c = self.cos.narrow(0, seqlen_offset, seqlen)?.unsqueeze(1)?;
-        let s = self.sin.narrow(0, seqlen_offset, seqlen)?.unsqueeze(1)?;
-        let q_rot = Tensor::cat(
-            &[
-                (q1.broadcast_mul(&c)? - q2.broadcast_mul(&s)?)?,
-                (q1.broadcast_mul(&s)? + q2.broadcast_mul(&c)?)?,
-            ],
-            D::Minus1,
-        )?;
-        let k_rot = Tensor::cat(
-            &[
-                (k1.broadcast_mul(&c)? - k2.broadcast_mul(&s)?)?,
-                (k1.broadcast_mul(&s)? + k2.broadcast_mul(&c)?)?,
-            ],
-            D::Minus1,
-        )?;
+        let c = self.cos.narrow(0, seqlen_offset, seqlen)?;
+        let s = self.sin.narrow(0, seqlen_offset, seqlen)?;
+        // The rotary embeddings apply to tensors of shape (b_size, num_heads, seqlen, dim).
+        let rope = |xs: &Tensor| -> Result<Tensor> {
+            candle_nn::rotary_emb::rope(&xs.transpose(1, 2)?, &c, &s)?.transpose(1, 2)
+        };
+        let q_rot = rope(&q_rot)?;
+        let k_rot = rope(&k_rot)?;
         let q = Tensor::cat(&[&q_rot, &q_pass], D::Minus1)?;
         let k = Tensor::cat(&[&k_rot, &k_pass], D::Minus1)?;
         let v = qkv.i((.., .., 2))?;
@@ -304,10 +274,9 @@ impl MHA {
         // scores = scores + causal_mask.to(dtype=scores.dtype)
         let attn_weights = match mask {
             None => attn_weights,
-            Some(mask) => masked_fill(
-                &attn_weights,
+            Some(mask) => attn_weights.masked_fill(
                 &mask.broadcast_left(b_size * self.n_head)?,
-                f32::NEG_INFINITY,
+                f64::NEG_INFINITY,
             )?,
         };
         let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
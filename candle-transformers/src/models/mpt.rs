@@ -1,7 +1,7 @@
 use crate::models::with_tracing::{linear_no_bias, Embedding, Linear};
 /// MPT model used by replit-code-v1_5-3b
 /// https://huggingface.co/replit/replit-code-v1_5-3b/blob/main/modeling_mpt.py
-use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
+use candle::{Device, IndexOp, Module, Result, Tensor, D};
 use candle_nn::{layer_norm, LayerNorm, VarBuilder};
 
 // https://huggingface.co/replit/replit-code-v1_5-3b/blob/main/configuration_mpt.py
@@ -61,7 +61,7 @@ impl GroupedQueryAttention {
         let wqkv = linear_no_bias(cfg.d_model, wqkv_size, vb.pp("Wqkv"))?;
         let softmax_scale = 1f64 / (head_dim as f64).sqrt();
         let out_proj = linear_no_bias(cfg.d_model, cfg.d_model, vb.pp("out_proj"))?;
-        let attn_bias = build_alibi_bias(cfg)?.to_device(vb.device())?;
+        let attn_bias = build_alibi_bias(cfg, vb.device())?;
         Ok(Self {
             wqkv,
             out_proj,
@@ -118,11 +118,8 @@ impl GroupedQueryAttention {
         let attn_weights = attn_weights.broadcast_add(&attn_bias)?;
         let attn_weights = match mask {
             None => attn_weights,
-            Some(mask) => masked_fill(
-                &attn_weights,
-                &mask.broadcast_as(attn_weights.shape())?,
-                f32::NEG_INFINITY,
-            )?,
+            Some(mask) => attn_weights
+                .masked_fill(&mask.broadcast_as(attn_weights.shape())?, f64::NEG_INFINITY)?,
         };
         let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
         let attn_output = attn_weights
@@ -206,40 +203,6 @@ impl MPTBlock {
     }
 }
 
-pub(crate) fn build_alibi_bias(cfg: &Config) -> Result<Tensor> {
-    let full = !cfg.is_causal();
-    let seq_len = cfg.max_seq_len;
-    let alibi_bias = Tensor::arange(1 - seq_len as i64, 1, &Device::Cpu)?;
-    let alibi_bias = if full {
-        let a1 = alibi_bias.reshape((1, 1, 1, seq_len))?;
-        let a2 = alibi_bias.reshape((1, 1, seq_len, 1))?;
-        a1.broadcast_sub(&a2)?.abs()?.neg()?
-    } else {
-        alibi_bias.reshape((1, 1, 1, seq_len))?
-    };
-    let mut n_heads2 = 1;
-    while n_heads2 < cfg.n_heads {
-        n_heads2 *= 2
-    }
-    let slopes = (1..=n_heads2)
-        .map(|v| 1f32 / 2f32.powf((v * cfg.attn_alibi_bias_max) as f32 / n_heads2 as f32))
-        .collect::<Vec<_>>();
-    let slopes = if n_heads2 == cfg.n_heads {
-        slopes
-    } else {
-        slopes
-            .iter()
-            .skip(1)
-            .step_by(2)
-            .chain(slopes.iter().step_by(2))
-            .take(cfg.n_heads)
-            .cloned()
-            .collect::<Vec<f32>>()
-    };
-    let slopes = Tensor::new(slopes, &Device::Cpu)?.reshape((1, (), 1, 1))?;
-    alibi_bias.to_dtype(DType::F32)?.broadcast_mul(&slopes)
-}
-
 #[derive(Debug, Clone)]
 pub struct Model {
     wte: Embedding,
@@ -289,16 +252,24 @@ impl Model {
     }
 }
 
+/// Returns the alibi bias for `cfg.max_seq_len` positions, to be narrowed to the actual
+/// sequence lengths. With a causal mask, the full bias only differs from its last row by a per
+/// query constant that the softmax cancels out so a single row is kept.
+pub(crate) fn build_alibi_bias(cfg: &Config, device: &Device) -> Result<Tensor> {
+    let seq_len_q = if cfg.is_causal() { 1 } else { cfg.max_seq_len };
+    candle_nn::rotary_emb::alibi_bias(
+        cfg.n_heads,
+        seq_len_q,
+        cfg.max_seq_len,
+        cfg.attn_alibi_bias_max as f64,
+        device,
+    )?
+    .unsqueeze(0)
+}
+
 pub(crate) fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
     let mask: Vec<_> = (0..size)
         .flat_map(|i| (0..size).map(move |j| u8::from(j > i)))
         .collect();
     Tensor::from_slice(&mask, (size, size), device)
 }
-
-pub(crate) fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 
+use crate::paged_attention::{paged_attention, PagedAttentionMetadata};
 use candle::quantized::QTensor;
 use candle::quantized::{ggml_file, gguf_file};
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
-use candle_nn::{Embedding, Module};
+use candle_nn::paged_kv_cache::PagedKvCache;
+use candle_nn::rotary_emb::RopeScaling;
+use candle_nn::{Embedding, KvCache, Module};
 
 pub const MAX_SEQ_LEN: usize = 4096;
+// The initial capacity of the kv caches, these grow as needed.
+const KV_CACHE_CAPACITY: usize = 512;
 
 #[derive(Debug, Clone)]
 struct RmsNorm {
@@ -63,50 +68,53 @@ struct LayerWeights {
     head_dim: usize,
     cos: Tensor,
     sin: Tensor,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
+    paged_kv_cache: Option<PagedKvCache>,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 impl LayerWeights {
-    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    // `positions` optionally holds the position of each token with shape (b_sz, seq_len),
+    // otherwise the positions start at `index_pos` for all the batch elements.
+    fn apply_rotary_emb(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        positions: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
-        let (b_sz, n_head, seq_len, n_embd) = x.dims4()?;
-        let cos = self
-            .cos
-            .narrow(0, index_pos, seq_len)?
-            .reshape((seq_len, n_embd / 2, 1))?;
-        let sin = self
-            .sin
-            .narrow(0, index_pos, seq_len)?
-            .reshape((seq_len, n_embd / 2, 1))?;
-        let cos = cos.broadcast_as((b_sz, 1, seq_len, n_embd / 2, 1))?;
-        let sin = sin.broadcast_as((b_sz, 1, seq_len, n_embd / 2, 1))?;
-        // This mimics the llama.cpp behavior.
+        let (b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let (cos, sin) = match positions {
+            None => {
+                let cos = self.cos.narrow(0, index_pos, seq_len)?;
+                let sin = self.sin.narrow(0, index_pos, seq_len)?;
+                (cos, sin)
+            }
+            Some(positions) => {
+                let positions = positions.flatten_all()?;
+                let cos = self.cos.index_select(&positions, 0)?;
+                let sin = self.sin.index_select(&positions, 0)?;
+                (
+                    cos.reshape((b_sz, seq_len, ()))?,
+                    sin.reshape((b_sz, seq_len, ()))?,
+                )
+            }
+        };
+        // This mimics the llama.cpp behavior where the rotated values are interleaved on the
+        // head dimension.
         // https://github.com/ggerganov/llama.cpp/blob/1f0bccb27929e261744c979bc75114955da49e98/ggml.c#L12104-L12105
-        // The x0 and x1 value are interleaved on the n_embd (= head_dim) dimension.
-        // The resulting y0 and y1 are also interleaved with:
-        //   y0 = x0*cos - x1*sin
-        //   y1 = x0*sin + x1*cos
-        let x = x.reshape((b_sz, n_head, seq_len, n_embd / 2, 2))?;
-        let x0 = x.narrow(D::Minus1, 0, 1)?;
-        let x1 = x.narrow(D::Minus1, 1, 1)?;
-        let y0 = (x0.broadcast_mul(&cos)? - x1.broadcast_mul(&sin)?)?;
-        let y1 = (x0.broadcast_mul(&sin)? + x1.broadcast_mul(&cos)?)?;
-        let rope = Tensor::cat(&[y0, y1], D::Minus1)?;
-        let rope = rope.flatten_from(D::Minus2)?;
-        Ok(rope)
+        candle_nn::rotary_emb::rope_interleaved(x, &cos, &sin)
     }
 
-    fn forward_attn(&mut self, x: &Tensor, mask: &Tensor, index_pos: usize) -> Result<Tensor> {
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: &Tensor,
+        index_pos: usize,
+        positions: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let _enter = self.span_attn.enter();
         let (b_sz, seq_len, n_embd) = x.dims3()?;
         let q = self.attention_wq.forward(x)?;
@@ -123,30 +131,26 @@ impl LayerWeights {
             .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
             .transpose(1, 2)?;
 
-        let q = self.apply_rotary_emb(&q, index_pos)?;
-        let k = self.apply_rotary_emb(&k, index_pos)?;
-
-        let (k, v) = match &self.kv_cache {
-            None => (k, v),
-            Some((k_cache, v_cache)) => {
-                if index_pos == 0 {
-                    (k, v)
-                } else {
-                    let k = Tensor::cat(&[k_cache, &k], 2)?.contiguous()?;
-                    let v = Tensor::cat(&[v_cache, &v], 2)?.contiguous()?;
-                    (k, v)
-                }
-            }
-        };
-        self.kv_cache = Some((k.clone(), v.clone()));
+        let q = self.apply_rotary_emb(&q, index_pos, positions)?;
+        let k = self.apply_rotary_emb(&k, index_pos, positions)?;
+
+        // Processing a position that has already been cached discards the subsequent ones.
+        if index_pos < self.kv_cache.current_seq_len() {
+            self.kv_cache.rollback(index_pos)?
+        }
+        let (k, v) = self.kv_cache.append(&k, &v)?;
 
         // Support for MQA, useful for 70B models.
         let k = self.repeat_kv(k)?;
         let v = self.repeat_kv(v)?;
 
         let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
-        let mask = mask.broadcast_as(att.shape())?;
-        let att = masked_fill(&att, &mask, f32::NEG_INFINITY)?;
+        // The cached positions can be attended by all the new positions.
+        let kv_len = att.dim(D::Minus1)?;
+        let mask = mask
+            .pad_with_zeros(D::Minus1, kv_len - mask.dim(D::Minus1)?, 0)?
+            .broadcast_as(att.shape())?;
+        let att = att.masked_fill(&mask, f64::NEG_INFINITY)?;
         let att = candle_nn::ops::softmax_last_dim(&att)?;
         // Convert to contiguous as matmul doesn't support strided vs for now.
         let y = att.matmul(&v.contiguous()?)?;
@@ -155,6 +159,55 @@ impl LayerWeights {
         Ok(y)
     }
 
+    // Same as `forward_attn` for a single row of concatenated sequences using the paged kv
+    // cache.
+    fn forward_paged_attn(
+        &mut self,
+        x: &Tensor,
+        metadata: &PagedAttentionMetadata,
+        masks: &[Tensor],
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+        let q = self.attention_wq.forward(x)?;
+        let k = self.attention_wk.forward(x)?;
+        let v = self.attention_wv.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = k
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let positions = metadata.positions().unsqueeze(0)?;
+        let q = self.apply_rotary_emb(&q, 0, Some(&positions))?;
+        let k = self.apply_rotary_emb(&k, 0, Some(&positions))?;
+        let q = q.squeeze(0)?.transpose(0, 1)?;
+        let k = k.squeeze(0)?.transpose(0, 1)?;
+        let v = v.reshape((seq_len, self.n_kv_head, self.head_dim))?;
+
+        let cache = match &self.paged_kv_cache {
+            None => candle::bail!("the paged kv cache has not been allocated"),
+            Some(cache) => cache,
+        };
+        let scale = 1. / (self.head_dim as f64).sqrt();
+        let y = paged_attention(&q, &k, &v, cache, metadata, masks, scale)?;
+        let y = y.reshape((b_sz, seq_len, n_embd))?;
+        self.attention_wo.forward(&y)
+    }
+
+    fn forward_mlp(&self, x: &Tensor) -> Result<Tensor> {
+        let _enter = self.span_mlp.enter();
+        let residual = x;
+        let x = self.ffn_norm.forward(x)?;
+        let w1 = self.feed_forward_w1.forward(&x)?;
+        let w3 = self.feed_forward_w3.forward(&x)?;
+        let mlp = self
+            .feed_forward_w2
+            .forward(&(candle_nn::ops::silu(&w1)? * w3)?)?;
+        mlp + residual
+    }
+
     fn repeat_kv(&self, x: Tensor) -> Result<Tensor> {
         let n_rep = self.n_head / self.n_kv_head;
         if n_rep == 1 {
@@ -181,26 +234,26 @@ pub struct ModelWeights {
     span_output: tracing::Span,
 }
 
-fn precomput_freqs_cis(head_dim: usize, freq_base: f32) -> Result<(Tensor, Tensor)> {
-    let theta: Vec<_> = (0..head_dim)
-        .step_by(2)
-        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
-        .collect();
-    let theta = Tensor::new(theta.as_slice(), &Device::Cpu)?;
-    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, &Device::Cpu)?
-        .to_dtype(DType::F32)?
-        .reshape((MAX_SEQ_LEN, 1))?
-        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-    let cos = idx_theta.cos()?;
-    let sin = idx_theta.sin()?;
-    Ok((cos, sin))
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    scaling: Option<&RopeScaling>,
+) -> Result<(Tensor, Tensor)> {
+    candle_nn::rotary_emb::rope_tables(
+        head_dim,
+        MAX_SEQ_LEN,
+        freq_base as f64,
+        scaling,
+        DType::F32,
+        &Device::Cpu,
+    )
 }
 
 impl ModelWeights {
     pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
         let cpu = &Device::Cpu;
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let (cos, sin) = precomput_freqs_cis(head_dim, 10000.)?;
+        let (cos, sin) = precomput_freqs_cis(head_dim, 10000., None)?;
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(cpu)?;
         let norm = RmsNorm::new(ct.remove("norm.weight")?, 1e-5)?;
@@ -235,7 +288,8 @@ impl ModelWeights {
                 head_dim: (ct.hparams.n_embd / ct.hparams.n_head) as usize,
                 cos: cos.clone(),
                 sin: sin.clone(),
-                kv_cache: None,
+                kv_cache: KvCache::new(2, KV_CACHE_CAPACITY),
+                paged_kv_cache: None,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -276,7 +330,24 @@ impl ModelWeights {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
-        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base)?;
+        let rope_scaling_factor = md_get("llama.rope.scaling.factor").and_then(|m| m.to_f32());
+        let rope_scaling = match md_get("llama.rope.scaling.type").and_then(|m| m.to_string()) {
+            Ok(t) if t == "linear" => Some(RopeScaling::Linear {
+                factor: rope_scaling_factor? as f64,
+            }),
+            Ok(t) if t == "yarn" => Some(RopeScaling::Yarn {
+                factor: rope_scaling_factor? as f64,
+                original_max_position_embeddings: md_get(
+                    "llama.rope.scaling.original_context_length",
+                )?
+                .to_u32()? as usize,
+                beta_fast: 32.,
+                beta_slow: 1.,
+            }),
+            Ok(t) if t != "none" => candle::bail!("unsupported rope scaling type {t}"),
+            _ => None,
+        };
+        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, rope_scaling.as_ref())?;
 
         let tok_embeddings = ct.tensor(reader, "token_embd.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(cpu)?;
@@ -312,7 +383,8 @@ impl ModelWeights {
                 head_dim: embedding_length / head_count,
                 cos: cos.clone(),
                 sin: sin.clone(),
-                kv_cache: None,
+                kv_cache: KvCache::new(2, KV_CACHE_CAPACITY),
+                paged_kv_cache: None,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -344,32 +416,133 @@ impl ModelWeights {
         }
     }
 
-    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+    // Returns the normalized hidden states for all the positions, `pad_lens` holds the number
+    // of left-padding tokens of each sequence for batches of sequences with different lengths.
+    fn forward_hidden(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        pad_lens: Option<&[usize]>,
+    ) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
-        let mask = self.mask(seq_len)?;
+        let (mask, positions) = match pad_lens {
+            None => (self.mask(seq_len)?, None),
+            Some(pad_lens) => {
+                let device = x.device();
+                let kv_len = index_pos + seq_len;
+                let mask = crate::utils::padded_causal_mask(
+                    pad_lens, index_pos, seq_len, kv_len, None, device,
+                )?;
+                let positions =
+                    crate::utils::padded_positions(pad_lens, index_pos, seq_len, device)?;
+                (mask, Some(positions))
+            }
+        };
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
         for layer in self.layers.iter_mut() {
             let x = layer_in;
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
-            let attn = layer.forward_attn(&x, &mask, index_pos)?;
+            let attn = layer.forward_attn(&x, &mask, index_pos, positions.as_ref())?;
             let x = (attn + residual)?;
+            layer_in = layer.forward_mlp(&x)?;
+        }
+        self.norm.forward(&layer_in)
+    }
 
-            // MLP
-            let _enter = layer.span_mlp.enter();
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.forward_hidden(x, index_pos, None)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+
+    /// Runs a batch of left-padded sequences of different lengths, `pad_lens` holds the number
+    /// of padding tokens at the beginning of each sequence and `index_pos` is the number of
+    /// columns that have already been processed. The kv cache of sequence `i` then holds
+    /// `index_pos + seq_len - pad_lens[i]` entries. Returns the logits for the last column.
+    ///
+    /// The sequences can be padded with [`crate::utils::left_pad`].
+    pub fn forward_padded(
+        &mut self,
+        x: &Tensor,
+        index_pos: usize,
+        pad_lens: &[usize],
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        if pad_lens.len() != b_sz {
+            candle::bail!("unexpected number of padding lengths {pad_lens:?} for batch {b_sz}")
+        }
+        let x = self.forward_hidden(x, index_pos, Some(pad_lens))?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+
+    /// Similar to `forward` but returns the logits for all the positions, with shape
+    /// `(b_sz, seq_len, vocab_size)`.
+    pub fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let x = self.forward_hidden(x, index_pos, None)?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+
+    /// Allocates a paged kv cache with `num_blocks` blocks of `block_size` positions for each
+    /// layer, this is required before calling `forward_paged`.
+    pub fn allocate_paged_kv_cache(&mut self, num_blocks: usize, block_size: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            let cache = PagedKvCache::new(
+                num_blocks,
+                block_size,
+                layer.n_kv_head,
+                layer.head_dim,
+                DType::F32,
+                layer.cos.device(),
+            )?;
+            layer.paged_kv_cache = Some(cache)
+        }
+        Ok(())
+    }
+
+    /// Runs a batch of sequences using the paged kv cache, `x` is a one dimensional tensor
+    /// holding the new tokens of all the sequences concatenated as described by `metadata`.
+    /// Returns the logits for the last token of each sequence, with shape `(num_sequences,
+    /// vocab_size)`.
+    pub fn forward_paged(
+        &mut self,
+        x: &Tensor,
+        metadata: &PagedAttentionMetadata,
+    ) -> Result<Tensor> {
+        let masks = metadata.masks(None)?;
+        let _enter = self.span.enter();
+        let mut layer_in = self.tok_embeddings.forward(&x.unsqueeze(0)?)?;
+        for layer in self.layers.iter_mut() {
+            let x = layer_in;
             let residual = &x;
-            let x = layer.ffn_norm.forward(&x)?;
-            let w1 = layer.feed_forward_w1.forward(&x)?;
-            let w3 = layer.feed_forward_w3.forward(&x)?;
-            let mlp = layer
-                .feed_forward_w2
-                .forward(&(candle_nn::ops::silu(&w1)? * w3)?)?;
-            layer_in = (mlp + residual)?;
+            let x = layer.attention_norm.forward(&x)?;
+            let attn = layer.forward_paged_attn(&x, metadata, &masks)?;
+            let x = (attn + residual)?;
+            layer_in = layer.forward_mlp(&x)?;
         }
         let x = self.norm.forward(&layer_in)?;
-        let x = x.i((.., seq_len - 1, ..))?;
+        let x = x
+            .squeeze(0)?
+            .index_select(metadata.last_token_indexes(), 0)?;
         let _enter = self.span_output.enter();
         self.output.forward(&x)
     }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.layers.iter_mut().for_each(|l| l.kv_cache.reset())
+    }
+
+    /// Discards the kv cache entries past the first `seq_len` positions.
+    pub fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.kv_cache.rollback(seq_len)?
+        }
+        Ok(())
+    }
 }
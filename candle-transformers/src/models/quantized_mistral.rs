@@ -1,9 +1,12 @@
 use crate::quantized_nn::{linear_no_bias, Embedding, Linear, RmsNorm};
 pub use crate::quantized_var_builder::VarBuilder;
-use candle::{DType, Device, Module, Result, Tensor, D};
-use candle_nn::Activation;
+use candle::{DType, Device, Module, Result, Tensor};
+use candle_nn::{Activation, KvCache};
 use std::sync::Arc;
 
+// The initial capacity of the kv caches, these grow as needed.
+const KV_CACHE_CAPACITY: usize = 512;
+
 pub use crate::models::mistral::Config;
 
 #[derive(Debug, Clone)]
@@ -12,47 +15,47 @@ struct RotaryEmbedding {
     cos: Tensor,
 }
 
-fn rotate_half(xs: &Tensor) -> Result<Tensor> {
-    let last_dim = xs.dim(D::Minus1)?;
-    let xs1 = xs.narrow(D::Minus1, 0, last_dim / 2)?;
-    let xs2 = xs.narrow(D::Minus1, last_dim / 2, last_dim - last_dim / 2)?;
-    Tensor::cat(&[&xs2.neg()?, &xs1], D::Minus1)
-}
-
 impl RotaryEmbedding {
     fn new(cfg: &Config, dev: &Device) -> Result<Self> {
         let dim = cfg.hidden_size / cfg.num_attention_heads;
-        let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / 10000f32.powf(i as f32 / dim as f32))
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        let freqs = Tensor::cat(&[&freqs, &freqs], D::Minus1)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
+        let (cos, sin) = candle_nn::rotary_emb::rope_tables(
+            dim,
+            cfg.max_position_embeddings,
+            cfg.rope_theta,
+            cfg.rope_scaling.as_ref(),
+            DType::F32,
+            dev,
+        )?;
+        Ok(Self { sin, cos })
     }
 
+    // `positions` optionally holds the position of each token with shape (b_sz, seq_len),
+    // otherwise the positions start at `seqlen_offset` for all the batch elements.
     fn apply_rotary_emb_qkv(
         &self,
         q: &Tensor,
         k: &Tensor,
         seqlen_offset: usize,
+        positions: Option<&Tensor>,
     ) -> Result<(Tensor, Tensor)> {
-        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
-        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
-        let cos = cos.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-        let sin = sin.unsqueeze(0)?.unsqueeze(0)?; // (1, 1, seq_len, dim)
-        let q_embed = (q.broadcast_mul(&cos)? + rotate_half(q)?.broadcast_mul(&sin))?;
-        let k_embed = (k.broadcast_mul(&cos)? + rotate_half(k)?.broadcast_mul(&sin))?;
+        let (b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let (cos, sin) = match positions {
+            None => {
+                let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
+                let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+                (cos, sin)
+            }
+            Some(positions) => {
+                let positions = positions.flatten_all()?;
+                let cos = self.cos.index_select(&positions, 0)?;
+                let sin = self.sin.index_select(&positions, 0)?;
+                let cos = cos.reshape((b_sz, seq_len, ()))?; // (b_sz, seq_len, dim / 2)
+                let sin = sin.reshape((b_sz, seq_len, ()))?; // (b_sz, seq_len, dim / 2)
+                (cos, sin)
+            }
+        };
+        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
         Ok((q_embed, k_embed))
     }
 }
@@ -102,7 +105,7 @@ struct Attention {
     head_dim: usize,
     hidden_size: usize,
     rotary_emb: Arc<RotaryEmbedding>,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
 }
 
 impl Attention {
@@ -127,7 +130,7 @@ impl Attention {
             head_dim,
             hidden_size: hidden_sz,
             rotary_emb,
-            kv_cache: None,
+            kv_cache: KvCache::new(2, KV_CACHE_CAPACITY).with_sliding_window(cfg.sliding_window),
         })
     }
 
@@ -148,6 +151,7 @@ impl Attention {
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        positions: Option<&Tensor>,
     ) -> Result<Tensor> {
         let (b_sz, q_len, _) = xs.dims3()?;
 
@@ -165,19 +169,18 @@ impl Attention {
             .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
             .transpose(1, 2)?;
 
-        let (query_states, key_states) =
-            self.rotary_emb
-                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
+        let (query_states, key_states) = self.rotary_emb.apply_rotary_emb_qkv(
+            &query_states,
+            &key_states,
+            seqlen_offset,
+            positions,
+        )?;
 
-        let (key_states, value_states) = match &self.kv_cache {
-            None => (key_states, value_states),
-            Some((prev_k, prev_v)) => {
-                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
-                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
-                (key_states, value_states)
-            }
-        };
-        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        // Processing a position that has already been cached discards the subsequent ones.
+        if seqlen_offset < self.kv_cache.current_seq_len() {
+            self.kv_cache.rollback(seqlen_offset)?
+        }
+        let (key_states, value_states) = self.kv_cache.append(&key_states, &value_states)?;
 
         let key_states = self.repeat_kv(key_states)?;
         let value_states = self.repeat_kv(value_states)?;
@@ -198,6 +201,10 @@ impl Attention {
             .reshape((b_sz, q_len, self.hidden_size))?
             .apply(&self.o_proj)
     }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache.reset()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -232,15 +239,22 @@ impl DecoderLayer {
         xs: &Tensor,
         attention_mask: Option<&Tensor>,
         seqlen_offset: usize,
+        positions: Option<&Tensor>,
     ) -> Result<Tensor> {
         let residual = xs;
         let xs = self.input_layernorm.forward(xs)?;
-        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = self
+            .self_attn
+            .forward(&xs, attention_mask, seqlen_offset, positions)?;
         let xs = (xs + residual)?;
         let residual = &xs;
         let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
         residual + xs
     }
+
+    fn clear_kv_cache(&mut self) {
+        self.self_attn.clear_kv_cache()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -283,11 +297,13 @@ impl Model {
         tgt_len: usize,
         seqlen_offset: usize,
     ) -> Result<Tensor> {
-        // Sliding window mask?
+        // The kv cache only keeps the last `sliding_window` positions, the mask covers these
+        // followed by the new positions.
+        let cached_len = usize::min(seqlen_offset, self.sliding_window);
         let mask: Vec<_> = (0..tgt_len)
             .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + self.sliding_window < i {
+                (0..cached_len + tgt_len).map(move |j| {
+                    if i + cached_len < j || j + self.sliding_window < i + cached_len {
                         f32::NEG_INFINITY
                     } else {
                         0.
@@ -295,14 +311,8 @@ impl Model {
                 })
             })
             .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((b_size, 1, tgt_len, tgt_len + seqlen_offset))?
+        let mask = Tensor::from_slice(&mask, (tgt_len, cached_len + tgt_len), &self.device)?;
+        mask.expand((b_size, 1, tgt_len, cached_len + tgt_len))?
             .to_dtype(DType::F32)
     }
 
@@ -316,10 +326,55 @@ impl Model {
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
         for layer in self.layers.iter_mut() {
-            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset, None)?
+        }
+        xs.narrow(1, seq_len - 1, 1)?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)
+    }
+
+    /// Runs a batch of left-padded sequences of different lengths, `pad_lens` holds the number
+    /// of padding tokens at the beginning of each sequence and `seqlen_offset` is the number of
+    /// columns that have already been processed. The kv cache of sequence `i` then holds
+    /// `seqlen_offset + seq_len - pad_lens[i]` entries, up to the sliding window size. Returns
+    /// the logits for the last column.
+    ///
+    /// The sequences can be padded with [`crate::utils::left_pad`].
+    pub fn forward_padded(
+        &mut self,
+        input_ids: &Tensor,
+        seqlen_offset: usize,
+        pad_lens: &[usize],
+    ) -> Result<Tensor> {
+        let (b_size, seq_len) = input_ids.dims2()?;
+        if pad_lens.len() != b_size {
+            candle::bail!("unexpected number of padding lengths {pad_lens:?} for batch {b_size}")
+        }
+        let kv_len = usize::min(seqlen_offset, self.sliding_window) + seq_len;
+        let mask = crate::utils::padded_causal_mask(
+            pad_lens,
+            seqlen_offset,
+            seq_len,
+            kv_len,
+            Some(self.sliding_window),
+            &self.device,
+        )?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, &self.device)?.broadcast_as(mask.shape())?;
+        let attention_mask = mask
+            .where_cond(&neg_inf, &neg_inf.zeros_like()?)?
+            .to_dtype(DType::F32)?;
+        let positions =
+            crate::utils::padded_positions(pad_lens, seqlen_offset, seq_len, &self.device)?;
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, Some(&attention_mask), seqlen_offset, Some(&positions))?
         }
         xs.narrow(1, seq_len - 1, 1)?
             .apply(&self.norm)?
             .apply(&self.lm_head)
     }
+
+    pub fn clear_kv_cache(&mut self) {
+        self.layers.iter_mut().for_each(|l| l.clear_kv_cache())
+    }
 }
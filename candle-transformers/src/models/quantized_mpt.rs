@@ -28,7 +28,7 @@ impl GroupedQueryAttention {
         let wqkv = linear_no_bias(cfg.d_model, wqkv_size, vb.pp("Wqkv"))?;
         let softmax_scale = 1f64 / (head_dim as f64).sqrt();
         let out_proj = linear_no_bias(cfg.d_model, cfg.d_model, vb.pp("out_proj"))?;
-        let attn_bias = super::mpt::build_alibi_bias(cfg)?.to_device(vb.device())?;
+        let attn_bias = super::mpt::build_alibi_bias(cfg, vb.device())?;
         Ok(Self {
             wqkv,
             out_proj,
@@ -85,11 +85,8 @@ impl GroupedQueryAttention {
         let attn_weights = attn_weights.broadcast_add(&attn_bias)?;
         let attn_weights = match mask {
             None => attn_weights,
-            Some(mask) => super::mpt::masked_fill(
-                &attn_weights,
-                &mask.broadcast_as(attn_weights.shape())?,
-                f32::NEG_INFINITY,
-            )?,
+            Some(mask) => attn_weights
+                .masked_fill(&mask.broadcast_as(attn_weights.shape())?, f64::NEG_INFINITY)?,
         };
         let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
         let attn_output = attn_weights
@@ -33,13 +33,6 @@ fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
     Tensor::from_slice(&mask, (size, size), device)
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Config {
     vocab_size: usize,
@@ -351,13 +344,12 @@ impl T5Attention {
         };
         let scores = match mask {
             None => scores,
-            Some(mask) => masked_fill(
-                &scores,
+            Some(mask) => scores.masked_fill(
                 &mask
                     .unsqueeze(0)?
                     .unsqueeze(0)?
                     .repeat((b_sz, self.n_heads))?,
-                f32::NEG_INFINITY,
+                f64::NEG_INFINITY,
             )?,
         };
 
@@ -144,7 +144,7 @@ impl Sam {
             multimask_output,
         )?;
         let mask = low_res_mask
-            .upsample_nearest2d(IMAGE_SIZE, IMAGE_SIZE)?
+            .upsample_bilinear2d(IMAGE_SIZE, IMAGE_SIZE, false)?
             .get(0)?
             .i((.., ..original_h, ..original_w))?;
         Ok((mask, iou))
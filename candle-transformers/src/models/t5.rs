@@ -30,13 +30,6 @@ fn get_mask(size: usize, device: &Device) -> Result<Tensor> {
     Tensor::from_slice(&mask, (size, size), device)
 }
 
-fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: f32) -> Result<Tensor> {
-    let shape = mask.shape();
-    let on_true = Tensor::new(on_true, on_false.device())?.broadcast_as(shape.dims())?;
-    let m = mask.where_cond(&on_true, on_false)?;
-    Ok(m)
-}
-
 #[derive(Debug, Deserialize, Default, Clone, PartialEq)]
 pub struct ActivationWithOptionalGating {
     pub gated: bool,
@@ -410,13 +403,12 @@ impl T5Attention {
         };
         let scores = match mask {
             None => scores,
-            Some(mask) => masked_fill(
-                &scores,
+            Some(mask) => scores.masked_fill(
                 &mask
                     .unsqueeze(0)?
                     .unsqueeze(0)?
                     .repeat((b_sz, self.n_heads))?,
-                f32::NEG_INFINITY,
+                f64::NEG_INFINITY,
             )?,
         };
 
@@ -494,6 +486,15 @@ impl T5Attention {
     fn clear_kv_cache(&mut self) {
         self.kv_cache = None
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            let k = k.contiguous()?.index_select(beam_indices, 0)?;
+            let v = v.contiguous()?.index_select(beam_indices, 0)?;
+            self.kv_cache = Some((k, v))
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -533,6 +534,10 @@ impl T5LayerSelfAttention {
     fn clear_kv_cache(&mut self) {
         self.self_attention.clear_kv_cache()
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.self_attention.reorder_kv_cache(beam_indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -575,6 +580,10 @@ impl T5LayerCrossAttention {
     fn clear_kv_cache(&mut self) {
         self.cross_attention.clear_kv_cache()
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.cross_attention.reorder_kv_cache(beam_indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -646,6 +655,14 @@ impl T5Block {
         self.self_attn.clear_kv_cache();
         self.cross_attn.iter_mut().for_each(|c| c.clear_kv_cache());
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.self_attn.reorder_kv_cache(beam_indices)?;
+        if let Some(cross_attn) = &mut self.cross_attn {
+            cross_attn.reorder_kv_cache(beam_indices)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -696,6 +713,13 @@ impl T5Stack {
     fn clear_kv_cache(&mut self) {
         self.block.iter_mut().for_each(|b| b.clear_kv_cache())
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        for block in self.block.iter_mut() {
+            block.reorder_kv_cache(beam_indices)?
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -845,4 +869,10 @@ impl T5ForConditionalGeneration {
         self.encoder.clear_kv_cache();
         self.decoder.clear_kv_cache();
     }
+
+    /// Selects the decoder kv cache entries along the batch dimension, this is used to follow
+    /// the beams during beam search.
+    pub fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(beam_indices)
+    }
 }
@@ -142,6 +142,15 @@ impl TrOCRAttention {
         self.kv_cache = None
     }
 
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            let k = k.contiguous()?.index_select(beam_indices, 0)?;
+            let v = v.contiguous()?.index_select(beam_indices, 0)?;
+            self.kv_cache = Some((k, v))
+        }
+        Ok(())
+    }
+
     fn _shape(&self, tensor: &Tensor, bsz: usize) -> Result<Tensor> {
         tensor
             .reshape((bsz, (), self.num_heads, self.head_dim))?
@@ -247,6 +256,10 @@ impl TrOCRDecoderLayer {
         self.self_attn.reset_kv_cache();
     }
 
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.self_attn.reorder_kv_cache(beam_indices)
+    }
+
     fn forward(
         &mut self,
         xs: &Tensor,
@@ -319,6 +332,13 @@ impl TrOCRDecoder {
         self.layers.iter_mut().for_each(|l| l.reset_kv_cache())
     }
 
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        for layer in self.layers.iter_mut() {
+            layer.reorder_kv_cache(beam_indices)?
+        }
+        Ok(())
+    }
+
     pub fn forward(
         &mut self,
         xs: &Tensor,
@@ -409,6 +429,10 @@ impl TrOCRForCausalLM {
     fn reset_kv_cache(&mut self) {
         self.decoder.reset_kv_cache();
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(beam_indices)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -451,4 +475,10 @@ impl TrOCRModel {
     pub fn reset_kv_cache(&mut self) {
         self.decoder.reset_kv_cache();
     }
+
+    /// Selects the decoder kv cache entries along the batch dimension, this is used to follow
+    /// the beams during beam search.
+    pub fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.decoder.reorder_kv_cache(beam_indices)
+    }
 }
@@ -97,6 +97,7 @@ pub struct Embeddings {
     patch_embeddings: PatchEmbeddings,
     position_embeddings: Tensor,
     hidden_size: usize,
+    patch_size: usize,
 }
 
 impl Embeddings {
@@ -118,6 +119,7 @@ impl Embeddings {
             patch_embeddings,
             position_embeddings,
             hidden_size,
+            patch_size: cfg.patch_size,
         })
     }
 
@@ -127,7 +129,23 @@ impl Embeddings {
         height: usize,
         width: usize,
     ) -> Result<Tensor> {
-        todo!()
+        let num_patches = embeddings.dim(1)? - 1;
+        let num_positions = self.position_embeddings.dim(1)? - 1;
+        if num_patches == num_positions && height == width {
+            return Ok(self.position_embeddings.clone());
+        }
+        let class_pos_embed = self.position_embeddings.i((.., ..1))?;
+        let patch_pos_embed = self.position_embeddings.i((.., 1..))?;
+        let dim = self.hidden_size;
+        let sqrt_n = (num_positions as f64).sqrt() as usize;
+        let (new_h, new_w) = (height / self.patch_size, width / self.patch_size);
+        let patch_pos_embed = patch_pos_embed
+            .reshape((1, sqrt_n, sqrt_n, dim))?
+            .permute((0, 3, 1, 2))?
+            .upsample_bicubic2d(new_h, new_w, false)?
+            .permute((0, 2, 3, 1))?
+            .reshape((1, new_h * new_w, dim))?;
+        Tensor::cat(&[&class_pos_embed, &patch_pos_embed], 1)
     }
 
     pub fn forward(
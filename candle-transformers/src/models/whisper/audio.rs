@@ -211,3 +211,57 @@ pub fn pcm_to_mel<T: Float + std::fmt::Display>(
         false,
     )
 }
+
+/// Same as `pcm_to_mel` but expressed with tensor ops so that it can run on any device and on
+/// batches of samples. `samples` has shape `(n_samples,)` or `(batch, n_samples)` and `filters`
+/// has shape `(num_mel_bins, N_FFT / 2 + 1)`, the result has shape `(num_mel_bins, n_len)` or
+/// `(batch, num_mel_bins, n_len)`.
+pub fn pcm_to_mel_tensor(
+    cfg: &super::Config,
+    samples: &candle::Tensor,
+    filters: &candle::Tensor,
+) -> candle::Result<candle::Tensor> {
+    use candle::{Tensor, D};
+
+    let (fft_size, fft_step) = (super::N_FFT, super::HOP_LENGTH);
+    let n_bins = fft_size / 2 + 1;
+    let (num_mel_bins, filters_bins) = filters.dims2()?;
+    if num_mel_bins != cfg.num_mel_bins || filters_bins != n_bins {
+        candle::bail!(
+            "unexpected filters shape {:?}, expected ({}, {n_bins})",
+            filters.shape(),
+            cfg.num_mel_bins
+        )
+    }
+    let n_samples = samples.dim(D::Minus1)?;
+    let n_len = n_samples / fft_step;
+    // pad audio with at least one extra chunk of zeros
+    let pad = 100 * super::CHUNK_LENGTH / 2;
+    let n_len = if n_len % pad != 0 {
+        (n_len / pad + 1) * pad
+    } else {
+        n_len
+    };
+    let n_len = n_len + pad;
+    // The windows that go past the end of the padded audio are zero filled.
+    let samples = samples.pad_with_zeros(D::Minus1, 0, n_len * fft_step + fft_size - n_samples)?;
+    let window = Tensor::hann_window(fft_size, samples.dtype(), samples.device())?;
+    let spec = samples
+        .stft(fft_size, fft_step, Some(&window), false)?
+        .narrow(D::Minus2, 0, n_len)?;
+    // The power of the bins that have a mirrored negative frequency is counted twice.
+    let scale: Vec<f32> = (0..n_bins)
+        .map(|j| if j == 0 || j == fft_size / 2 { 1. } else { 2. })
+        .collect();
+    let scale =
+        Tensor::from_vec(scale, (n_bins, 1), samples.device())?.to_dtype(samples.dtype())?;
+    let power = spec.sqr()?.sum(D::Minus1)?.broadcast_mul(&scale)?;
+    let mel = filters.broadcast_matmul(&power)?;
+    let mel = (mel.maximum(1e-10)?.log()? / std::f64::consts::LN_10)?;
+    let mmax = (mel
+        .flatten_from(D::Minus2)?
+        .max_keepdim(D::Minus1)?
+        .unsqueeze(D::Minus1)?
+        - 8.)?;
+    mel.broadcast_maximum(&mmax)?.affine(0.25, 1.)
+}
@@ -96,6 +96,15 @@ impl MultiHeadAttention {
         Ok(out)
     }
 
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        if let Some((k, v)) = &self.kv_cache {
+            let k = k.index_select(beam_indices, 0)?;
+            let v = v.index_select(beam_indices, 0)?;
+            self.kv_cache = Some((k, v))
+        }
+        Ok(())
+    }
+
     fn reshape_head(&self, x: &Tensor) -> Result<Tensor> {
         let (n_batch, n_ctx, n_state) = x.dims3()?;
         let target_dims = &[n_batch, n_ctx, self.n_head, n_state / self.n_head];
@@ -198,6 +207,14 @@ impl ResidualAttentionBlock {
         )?;
         x + mlp
     }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        self.attn.reorder_kv_cache(beam_indices)?;
+        if let Some((attn, _)) = &mut self.cross_attn {
+            attn.reorder_kv_cache(beam_indices)?
+        }
+        Ok(())
+    }
 }
 
 fn sinusoids(length: usize, channels: usize) -> Result<Tensor> {
@@ -346,6 +363,15 @@ impl TextDecoder {
         self.ln.forward(&x)
     }
 
+    /// Selects the cached cross-attention keys and values along the batch dimension, this is
+    /// used to follow the beams during beam search.
+    pub fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        for block in self.blocks.iter_mut() {
+            block.reorder_kv_cache(beam_indices)?
+        }
+        Ok(())
+    }
+
     pub fn final_linear(&self, x: &Tensor) -> Result<Tensor> {
         let b_size = x.dim(0)?;
         let w = self.token_embedding.embeddings().broadcast_left(b_size)?;
@@ -2,7 +2,7 @@ use super::Config;
 use crate::quantized_nn::{layer_norm, linear, linear_no_bias, Embedding, Linear};
 pub use crate::quantized_var_builder::VarBuilder;
 use candle::{Device, IndexOp, Result, Tensor, D};
-use candle_nn::{Conv1d, Conv1dConfig, LayerNorm, Module};
+use candle_nn::{Conv1d, Conv1dConfig, KvCache, LayerNorm, Module};
 
 fn conv1d(
     in_channels: usize,
@@ -29,7 +29,7 @@ struct MultiHeadAttention {
     span: tracing::Span,
     softmax_span: tracing::Span,
     matmul_span: tracing::Span,
-    kv_cache: Option<(Tensor, Tensor)>,
+    kv_cache: KvCache,
 }
 
 impl MultiHeadAttention {
@@ -50,7 +50,7 @@ impl MultiHeadAttention {
             span,
             softmax_span,
             matmul_span,
-            kv_cache: None,
+            kv_cache: KvCache::new(1, 1),
         })
     }
 
@@ -71,15 +71,15 @@ impl MultiHeadAttention {
             }
             Some(x) => {
                 if flush_cache {
-                    self.kv_cache = None;
+                    self.kv_cache.reset();
                 }
-                if let Some((k, v)) = &self.kv_cache {
-                    (k.clone(), v.clone())
-                } else {
-                    let k = self.key.forward(x)?;
-                    let v = self.value.forward(x)?;
-                    self.kv_cache = Some((k.clone(), v.clone()));
-                    (k, v)
+                match (self.kv_cache.k()?, self.kv_cache.v()?) {
+                    (Some(k), Some(v)) => (k, v),
+                    _ => {
+                        let k = self.key.forward(x)?;
+                        let v = self.value.forward(x)?;
+                        self.kv_cache.append(&k, &v)?
+                    }
                 }
             }
         };
@@ -0,0 +1,165 @@
+//! Attention over a paged kv cache, see [`candle_nn::paged_kv_cache`].
+//!
+//! The new tokens of all the sequences in a batch are concatenated along a single dimension
+//! rather than padded, so sequences of different lengths and in different stages, e.g. some
+//! processing their prompt while others generate a single token, can be mixed in a batch.
+use candle::{Device, Result, Tensor};
+use candle_nn::paged_kv_cache::{BlockTable, PagedKvCache};
+
+/// Describes a batch of sequences for a forward pass over a paged kv cache.
+#[derive(Debug, Clone)]
+pub struct PagedAttentionMetadata {
+    num_tokens: Vec<usize>,
+    context_lens: Vec<usize>,
+    // The slots where the keys and values of the new tokens are written.
+    write_slots: Vec<u32>,
+    // The slots of all the positions of each sequence, including the new ones.
+    slots: Vec<Tensor>,
+    positions: Tensor,
+    last_token_indexes: Tensor,
+}
+
+impl PagedAttentionMetadata {
+    /// Creates the metadata for a batch where sequence `i` processes `num_tokens[i]` new tokens.
+    /// The block tables must already contain slots for the new tokens, these being the last
+    /// positions of each table.
+    pub fn new(
+        block_tables: &[&BlockTable],
+        num_tokens: &[usize],
+        device: &Device,
+    ) -> Result<Self> {
+        if block_tables.len() != num_tokens.len() {
+            candle::bail!(
+                "got {} block tables for {} sequences",
+                block_tables.len(),
+                num_tokens.len()
+            )
+        }
+        let mut context_lens = Vec::with_capacity(num_tokens.len());
+        let mut write_slots = vec![];
+        let mut slots = Vec::with_capacity(num_tokens.len());
+        let mut positions = vec![];
+        let mut last_token_indexes = Vec::with_capacity(num_tokens.len());
+        for (table, &num_tokens) in block_tables.iter().zip(num_tokens.iter()) {
+            if num_tokens == 0 || num_tokens > table.len() {
+                candle::bail!(
+                    "invalid number of tokens {num_tokens} for {} slots",
+                    table.len()
+                )
+            }
+            let context_len = table.len() - num_tokens;
+            let table_slots = table.slots();
+            write_slots.extend_from_slice(&table_slots[context_len..]);
+            slots.push(Tensor::new(table_slots, device)?);
+            positions.extend((context_len..table.len()).map(|p| p as u32));
+            last_token_indexes.push(positions.len() as u32 - 1);
+            context_lens.push(context_len);
+        }
+        Ok(Self {
+            num_tokens: num_tokens.to_vec(),
+            context_lens,
+            write_slots,
+            slots,
+            positions: Tensor::new(positions, device)?,
+            last_token_indexes: Tensor::new(last_token_indexes, device)?,
+        })
+    }
+
+    pub fn num_sequences(&self) -> usize {
+        self.num_tokens.len()
+    }
+
+    /// The number of new tokens of each sequence.
+    pub fn num_tokens(&self) -> &[usize] {
+        &self.num_tokens
+    }
+
+    /// The number of cached positions of each sequence before the new tokens.
+    pub fn context_lens(&self) -> &[usize] {
+        &self.context_lens
+    }
+
+    /// The position of each new token, as a one dimensional u32 tensor.
+    pub fn positions(&self) -> &Tensor {
+        &self.positions
+    }
+
+    /// The index of the last new token of each sequence in the concatenated tokens.
+    pub fn last_token_indexes(&self) -> &Tensor {
+        &self.last_token_indexes
+    }
+
+    /// Returns the attention mask of each sequence, with shape `(num_tokens, context_len +
+    /// num_tokens)` and where masked entries are set to 1.
+    pub fn masks(&self, sliding_window: Option<usize>) -> Result<Vec<Tensor>> {
+        self.num_tokens
+            .iter()
+            .zip(self.context_lens.iter())
+            .map(|(&num_tokens, &context_len)| {
+                let seq_len = context_len + num_tokens;
+                let mask = crate::utils::padded_causal_mask(
+                    &[0],
+                    context_len,
+                    num_tokens,
+                    seq_len,
+                    sliding_window,
+                    self.positions.device(),
+                )?;
+                mask.reshape((num_tokens, seq_len))
+            })
+            .collect()
+    }
+}
+
+fn repeat_kv(xs: Tensor, n_rep: usize) -> Result<Tensor> {
+    if n_rep == 1 {
+        xs.contiguous()
+    } else {
+        let (num_kv_heads, seq_len, head_dim) = xs.dims3()?;
+        xs.unsqueeze(1)?
+            .expand((num_kv_heads, n_rep, seq_len, head_dim))?
+            .reshape((num_kv_heads * n_rep, seq_len, head_dim))
+    }
+}
+
+/// Writes the keys and values of the new tokens to the cache and computes the attention of
+/// each sequence over all its cached positions.
+///
+/// `q` has shape `(num_tokens, num_heads, head_dim)` and `k`, `v` have shape `(num_tokens,
+/// num_kv_heads, head_dim)` where `num_tokens` is the total number of new tokens in the batch.
+/// The masks are the ones returned by [`PagedAttentionMetadata::masks`]. Returns a tensor with
+/// the same shape as `q`.
+pub fn paged_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    cache: &PagedKvCache,
+    metadata: &PagedAttentionMetadata,
+    masks: &[Tensor],
+    scale: f64,
+) -> Result<Tensor> {
+    let (_, num_heads, _) = q.dims3()?;
+    let (_, num_kv_heads, _) = k.dims3()?;
+    cache.write(&metadata.write_slots, k, v)?;
+    let mut ys = Vec::with_capacity(metadata.num_sequences());
+    let mut start = 0;
+    for (i, &num_tokens) in metadata.num_tokens.iter().enumerate() {
+        let q = q
+            .narrow(0, start, num_tokens)?
+            .transpose(0, 1)?
+            .contiguous()?;
+        let (k, v) = cache.gather(&metadata.slots[i])?;
+        let k = repeat_kv(k.transpose(0, 1)?, num_heads / num_kv_heads)?;
+        let v = repeat_kv(v.transpose(0, 1)?, num_heads / num_kv_heads)?;
+        let att = (q.matmul(&k.t()?)? * scale)?;
+        let mask = masks[i].broadcast_as(att.shape())?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, att.device())?
+            .to_dtype(att.dtype())?
+            .broadcast_as(att.shape())?;
+        let att = mask.where_cond(&neg_inf, &att)?;
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        ys.push(att.matmul(&v)?.transpose(0, 1)?);
+        start += num_tokens
+    }
+    Tensor::cat(&ys, 0)
+}
@@ -1 +1,357 @@
+//! Text generation pipeline.
+//!
+//! This wraps the prompt -> tokenize -> forward -> sample -> detokenize loop that is shared by
+//! the text generation examples so that it can be reused with any causal language model.
+use crate::generation::{LogitsProcessor, Sampling};
+use candle::{DType, Device, Result, Tensor};
 
+/// A decoder-only language model that can be driven token by token.
+pub trait CausalLM {
+    /// Runs the model on `input_ids`, a `(batch, seq_len)` tensor of token ids starting at
+    /// position `index_pos`, and returns the logits for the last position with shape
+    /// `(batch, vocab_size)`.
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor>;
+
+    /// Drops the key-value cache so that the next call to `forward` starts a new sequence.
+    fn clear_kv_cache(&mut self);
+}
+
+impl CausalLM for crate::models::quantized_llama::ModelWeights {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids, index_pos)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+impl CausalLM for crate::models::llama::Llama {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        crate::models::llama::Llama::forward(self, input_ids, index_pos)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        crate::models::llama::Llama::clear_kv_cache(self)
+    }
+}
+
+impl CausalLM for crate::models::mistral::Model {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids, index_pos)?.squeeze(1)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+impl CausalLM for crate::models::quantized_mistral::Model {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids, index_pos)?.squeeze(1)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+// The mixformer models track the sequence position through their own kv cache.
+impl CausalLM for crate::models::mixformer::MixFormerSequentialForCausalLM {
+    fn forward(&mut self, input_ids: &Tensor, _index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+impl CausalLM for crate::models::quantized_mixformer::MixFormerSequentialForCausalLM {
+    fn forward(&mut self, input_ids: &Tensor, _index_pos: usize) -> Result<Tensor> {
+        self.forward(input_ids)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.clear_kv_cache()
+    }
+}
+
+/// The subset of tokenizer functionality required by the pipeline.
+pub trait Tokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<u32>>;
+    fn decode(&self, tokens: &[u32]) -> Result<String>;
+    fn token_to_id(&self, token: &str) -> Option<u32>;
+}
+
+// The tokenizers methods are reached through `Deref` as they would otherwise resolve to the
+// trait methods.
+#[cfg(feature = "tokenizers")]
+impl Tokenizer for tokenizers::Tokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        match std::ops::Deref::deref(self).encode(text, true) {
+            Ok(encoding) => Ok(encoding.get_ids().to_vec()),
+            Err(err) => candle::bail!("cannot encode: {err}"),
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        match std::ops::Deref::deref(self).decode(tokens, true) {
+            Ok(str) => Ok(str),
+            Err(err) => candle::bail!("cannot decode: {err}"),
+        }
+    }
+
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        std::ops::Deref::deref(self).token_to_id(token)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextGenerationConfig {
+    pub seed: u64,
+    pub sampling: Sampling,
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    pub repeat_penalty: f32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    /// The context size to consider for the penalties.
+    pub repeat_last_n: usize,
+    /// The maximum number of tokens to generate.
+    pub max_new_tokens: usize,
+    /// Generation stops when one of these tokens is sampled, e.g. `</s>`.
+    pub eos_tokens: Vec<String>,
+    /// Generation stops when the generated text contains one of these strings, the stop
+    /// sequence itself is not returned.
+    pub stop_sequences: Vec<String>,
+}
+
+impl Default for TextGenerationConfig {
+    fn default() -> Self {
+        Self {
+            seed: 299792458,
+            sampling: Sampling::ArgMax,
+            repeat_penalty: 1.,
+            frequency_penalty: 0.,
+            presence_penalty: 0.,
+            repeat_last_n: 64,
+            max_new_tokens: 100,
+            eos_tokens: vec![],
+            stop_sequences: vec![],
+        }
+    }
+}
+
+pub struct TextGenerationPipeline<M: CausalLM, T: Tokenizer> {
+    model: M,
+    tokenizer: T,
+    config: TextGenerationConfig,
+    device: Device,
+}
+
+impl<M: CausalLM, T: Tokenizer> TextGenerationPipeline<M, T> {
+    pub fn new(model: M, tokenizer: T, config: TextGenerationConfig, device: &Device) -> Self {
+        Self {
+            model,
+            tokenizer,
+            config,
+            device: device.clone(),
+        }
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    pub fn tokenizer(&self) -> &T {
+        &self.tokenizer
+    }
+
+    pub fn config(&self) -> &TextGenerationConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut TextGenerationConfig {
+        &mut self.config
+    }
+
+    pub fn into_inner(self) -> (M, T) {
+        (self.model, self.tokenizer)
+    }
+
+    /// Starts generating a continuation of `prompt`, the returned iterator yields the generated
+    /// text in chunks as soon as they can be decoded.
+    pub fn generate(&mut self, prompt: &str) -> Result<TextGeneration<'_, M, T>> {
+        let tokens = self.tokenizer.encode(prompt)?;
+        if tokens.is_empty() {
+            candle::bail!("empty prompt")
+        }
+        let mut eos_tokens = Vec::with_capacity(self.config.eos_tokens.len());
+        for token in self.config.eos_tokens.iter() {
+            match self.tokenizer.token_to_id(token) {
+                Some(token) => eos_tokens.push(token),
+                None => candle::bail!("cannot find the {token} token"),
+            }
+        }
+        let config = &self.config;
+        let logits_processor = LogitsProcessor::from_sampling(config.seed, config.sampling.clone())
+            .with_repeat_penalty(config.repeat_penalty)
+            .with_frequency_penalty(config.frequency_penalty)
+            .with_presence_penalty(config.presence_penalty)
+            .with_penalty_last_n(config.repeat_last_n);
+        self.model.clear_kv_cache();
+        let prompt_len = tokens.len();
+        Ok(TextGeneration {
+            pipeline: self,
+            logits_processor,
+            eos_tokens,
+            tokens,
+            prompt_len,
+            // Keep the last prompt token as context for decoding, e.g. so that the leading
+            // space of the first generated token is preserved.
+            prev_index: prompt_len - 1,
+            current_index: prompt_len,
+            pending: String::new(),
+            done: false,
+        })
+    }
+
+    /// Generates a continuation of `prompt` and returns it as a single string.
+    pub fn run(&mut self, prompt: &str) -> Result<String> {
+        self.generate(prompt)?.collect()
+    }
+}
+
+/// Streaming iterator over the text produced by [`TextGenerationPipeline::generate`].
+pub struct TextGeneration<'a, M: CausalLM, T: Tokenizer> {
+    pipeline: &'a mut TextGenerationPipeline<M, T>,
+    logits_processor: LogitsProcessor,
+    eos_tokens: Vec<u32>,
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    // Tokens in prev_index..current_index have already been decoded and are only kept around
+    // so that the tokenizer gets some context when decoding the following ones.
+    prev_index: usize,
+    current_index: usize,
+    // Decoded text that has not been returned yet as it could be the start of a stop sequence.
+    pending: String,
+    done: bool,
+}
+
+impl<'a, M: CausalLM, T: Tokenizer> TextGeneration<'a, M, T> {
+    /// The token ids generated so far, excluding the prompt.
+    pub fn generated_tokens(&self) -> &[u32] {
+        &self.tokens[self.prompt_len..]
+    }
+
+    fn sample_next_token(&mut self) -> Result<u32> {
+        let pipeline = &mut *self.pipeline;
+        let context_size = if self.tokens.len() > self.prompt_len {
+            1
+        } else {
+            self.tokens.len()
+        };
+        let start_pos = self.tokens.len() - context_size;
+        let input = Tensor::new(&self.tokens[start_pos..], &pipeline.device)?.unsqueeze(0)?;
+        let logits = pipeline.model.forward(&input, start_pos)?;
+        let logits = logits.squeeze(0)?.to_dtype(DType::F32)?;
+        self.logits_processor
+            .sample_with_context(&logits, &self.tokens)
+    }
+
+    // Same approach as text-generation-inference, only return the new text once it ends with
+    // an ascii character so as not to split multi-byte characters.
+    // https://github.com/huggingface/text-generation-inference/blob/5ba53d44a18983a4de32d122f4cb46f4a17d9ef6/server/text_generation_server/models/model.py#L68
+    fn decode_next(&mut self, flush: bool) -> Result<()> {
+        let tokenizer = &self.pipeline.tokenizer;
+        let prev_text = tokenizer.decode(&self.tokens[self.prev_index..self.current_index])?;
+        let text = tokenizer.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len()
+            && (flush || text.chars().last().is_some_and(|c| c.is_ascii()))
+        {
+            self.pending.push_str(&text[prev_text.len()..]);
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+        }
+        Ok(())
+    }
+
+    // Splits the pending text into the part that can be returned and the part that has to be
+    // held back as it may be the prefix of a stop sequence. Returns true if a stop sequence has
+    // been found, in which case the stop sequence and the text after it are discarded.
+    fn take_pending(&mut self) -> (String, bool) {
+        let stop_sequences = &self.pipeline.config.stop_sequences;
+        let stop_at = stop_sequences
+            .iter()
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| self.pending.find(s.as_str()))
+            .min();
+        if let Some(stop_at) = stop_at {
+            self.pending.truncate(stop_at);
+            return (std::mem::take(&mut self.pending), true);
+        }
+        let mut keep_from = self.pending.len();
+        for stop_sequence in stop_sequences.iter() {
+            for (idx, _) in self.pending.char_indices() {
+                if idx < keep_from && stop_sequence.starts_with(&self.pending[idx..]) {
+                    keep_from = idx;
+                    break;
+                }
+            }
+        }
+        let rest = self.pending.split_off(keep_from);
+        (std::mem::replace(&mut self.pending, rest), false)
+    }
+
+    fn step(&mut self) -> Result<Option<String>> {
+        loop {
+            if self.generated_tokens().len() >= self.pipeline.config.max_new_tokens {
+                self.done = true;
+            } else {
+                let next_token = self.sample_next_token()?;
+                if self.eos_tokens.contains(&next_token) {
+                    self.done = true;
+                } else {
+                    self.tokens.push(next_token);
+                }
+            }
+            self.decode_next(self.done)?;
+            let (text, stop) = self.take_pending();
+            if stop {
+                self.done = true;
+            }
+            if self.done {
+                let mut text = text;
+                if !stop {
+                    text.push_str(&self.pending)
+                }
+                self.pending.clear();
+                return Ok(if text.is_empty() { None } else { Some(text) });
+            }
+            if !text.is_empty() {
+                return Ok(Some(text));
+            }
+        }
+    }
+}
+
+impl<'a, M: CausalLM, T: Tokenizer> Iterator for TextGeneration<'a, M, T> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.step() {
+            Ok(text) => text.map(Ok),
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
@@ -1,18 +1,100 @@
-use candle::{Result, Tensor};
+use candle::{Device, Result, Tensor};
 
-pub fn apply_repeat_penalty(logits: &Tensor, penalty: f32, context: &[u32]) -> Result<Tensor> {
-    let device = logits.device();
-    let mut logits = logits.to_vec1::<f32>()?;
-    let context: std::collections::HashSet<_> = context.iter().collect();
-    for (token_id, logit) in logits.iter_mut().enumerate() {
-        if context.contains(&(token_id as u32)) {
-            if *logit >= 0. {
-                *logit /= penalty
-            } else {
-                *logit *= penalty
-            }
+/// Returns a one dimensional `f32` tensor of size `vocab_size` holding the number of occurrences
+/// of each token in `context`. Tokens outside of the vocabulary are ignored.
+pub fn token_counts(context: &[u32], vocab_size: usize, device: &Device) -> Result<Tensor> {
+    let mut counts = vec![0f32; vocab_size];
+    for &token_id in context.iter() {
+        if let Some(count) = counts.get_mut(token_id as usize) {
+            *count += 1.
         }
     }
-    let logits_len = logits.len();
-    Tensor::from_vec(logits, logits_len, device)
+    Tensor::from_vec(counts, vocab_size, device)
+}
+
+/// Applies the repeat penalty to the logits of the tokens that have a non-zero count, `counts`
+/// is typically obtained via [`token_counts`].
+pub fn apply_repeat_penalty_counts(
+    logits: &Tensor,
+    penalty: f32,
+    counts: &Tensor,
+) -> Result<Tensor> {
+    let penalty = penalty as f64;
+    let penalized = logits
+        .ge(0f32)?
+        .where_cond(&(logits / penalty)?, &(logits * penalty)?)?;
+    counts.gt(0f32)?.where_cond(&penalized, logits)
+}
+
+pub fn apply_repeat_penalty(logits: &Tensor, penalty: f32, context: &[u32]) -> Result<Tensor> {
+    let counts = token_counts(context, logits.dim(0)?, logits.device())?;
+    apply_repeat_penalty_counts(logits, penalty, &counts)
+}
+
+/// Left-pads some token sequences with `pad_id` so that they can be processed as a single batch.
+/// Returns the `(b_size, max_seq_len)` tensor of tokens and the number of padding tokens at the
+/// beginning of each sequence.
+pub fn left_pad(
+    sequences: &[Vec<u32>],
+    pad_id: u32,
+    device: &Device,
+) -> Result<(Tensor, Vec<usize>)> {
+    let max_seq_len = sequences.iter().map(|s| s.len()).max().unwrap_or(0);
+    let mut tokens = Vec::with_capacity(sequences.len() * max_seq_len);
+    let mut pad_lens = Vec::with_capacity(sequences.len());
+    for sequence in sequences.iter() {
+        let pad_len = max_seq_len - sequence.len();
+        tokens.extend(std::iter::repeat_n(pad_id, pad_len));
+        tokens.extend_from_slice(sequence);
+        pad_lens.push(pad_len)
+    }
+    let tokens = Tensor::from_vec(tokens, (sequences.len(), max_seq_len), device)?;
+    Ok((tokens, pad_lens))
+}
+
+/// Returns the `(b_size, seq_len)` tensor of positions for a batch of left-padded sequences
+/// where `pad_lens` holds the number of padding tokens of each sequence and `index_pos` is the
+/// index of the first column being processed. Padding tokens get position 0.
+pub fn padded_positions(
+    pad_lens: &[usize],
+    index_pos: usize,
+    seq_len: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    let positions: Vec<u32> = pad_lens
+        .iter()
+        .flat_map(|&pad_len| {
+            (index_pos..index_pos + seq_len).map(move |col| col.saturating_sub(pad_len) as u32)
+        })
+        .collect();
+    Tensor::from_vec(positions, (pad_lens.len(), seq_len), device)
+}
+
+/// Returns a `(b_size, 1, tgt_len, kv_len)` attention mask for a batch of left-padded sequences,
+/// masked entries are set to 1. The queries are the `tgt_len` columns starting at `index_pos`
+/// and the keys are the last `kv_len` columns up to the last query. Padding tokens are masked
+/// except for the query at the same column, so that no row is fully masked. With a sliding
+/// window, the keys that are more than `sliding_window` columns before the query are masked.
+pub fn padded_causal_mask(
+    pad_lens: &[usize],
+    index_pos: usize,
+    tgt_len: usize,
+    kv_len: usize,
+    sliding_window: Option<usize>,
+    device: &Device,
+) -> Result<Tensor> {
+    let kv_start = index_pos + tgt_len - kv_len;
+    let sliding_window = sliding_window.unwrap_or(usize::MAX);
+    let mask: Vec<u8> = pad_lens
+        .iter()
+        .flat_map(|&pad_len| {
+            (index_pos..index_pos + tgt_len).flat_map(move |q| {
+                (kv_start..kv_start + kv_len).map(move |k| {
+                    let masked = k > q || q - k > sliding_window || (k < pad_len && k != q);
+                    u8::from(masked)
+                })
+            })
+        })
+        .collect();
+    Tensor::from_vec(mask, (pad_lens.len(), 1, tgt_len, kv_len), device)
 }
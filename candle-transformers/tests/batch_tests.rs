@@ -0,0 +1,311 @@
+use candle::quantized::{gguf_file, QTensor};
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::paged_kv_cache::{BlockAllocator, BlockTable};
+use candle_nn::{Activation, VarBuilder, VarMap};
+use candle_transformers::generation::{ContinuousBatching, LogitsProcessor, SchedulerConfig};
+use candle_transformers::models::{mistral, quantized_llama};
+use candle_transformers::paged_attention::PagedAttentionMetadata;
+use candle_transformers::utils::left_pad;
+
+const VOCAB_SIZE: usize = 16;
+
+fn prompts() -> Vec<Vec<u32>> {
+    vec![vec![3, 1, 4], vec![1, 5, 9, 2, 6, 5], vec![3]]
+}
+
+fn argmax(logits: &Tensor) -> Result<u32> {
+    logits.flatten_all()?.argmax(0)?.to_scalar::<u32>()
+}
+
+// Greedily decodes `steps` tokens for a single prompt using a `(model, tokens, index_pos)`
+// forward function, returning the generated tokens and the logits for the last step.
+fn decode_single<F>(prompt: &[u32], steps: usize, mut forward: F) -> Result<(Vec<u32>, Tensor)>
+where
+    F: FnMut(&Tensor, usize) -> Result<Tensor>,
+{
+    let input = Tensor::new(prompt, &Device::Cpu)?.unsqueeze(0)?;
+    let mut logits = forward(&input, 0)?;
+    let mut tokens = vec![];
+    for step in 0..steps {
+        let token = argmax(&logits)?;
+        tokens.push(token);
+        let input = Tensor::new(&[token], &Device::Cpu)?.unsqueeze(0)?;
+        logits = forward(&input, prompt.len() + step)?;
+    }
+    Ok((tokens, logits.flatten_all()?))
+}
+
+// Same as `decode_single` for a batch of prompts, the forward function also gets the padding
+// lengths.
+fn decode_batch<F>(
+    prompts: &[Vec<u32>],
+    steps: usize,
+    mut forward: F,
+) -> Result<(Vec<Vec<u32>>, Tensor)>
+where
+    F: FnMut(&Tensor, usize, &[usize]) -> Result<Tensor>,
+{
+    let (input, pad_lens) = left_pad(prompts, 0, &Device::Cpu)?;
+    let seq_len = input.dim(1)?;
+    let mut logits = forward(&input, 0, &pad_lens)?;
+    let mut tokens = vec![vec![]; prompts.len()];
+    for step in 0..steps {
+        let mut next_tokens = vec![];
+        for (i, tokens) in tokens.iter_mut().enumerate() {
+            let token = argmax(&logits.i(i)?)?;
+            tokens.push(token);
+            next_tokens.push(token)
+        }
+        let input = Tensor::new(next_tokens, &Device::Cpu)?.unsqueeze(1)?;
+        logits = forward(&input, seq_len + step, &pad_lens)?;
+    }
+    Ok((tokens, logits))
+}
+
+fn assert_close(lhs: &Tensor, rhs: &Tensor) -> Result<()> {
+    let diff = (lhs.flatten_all()? - rhs.flatten_all()?)?
+        .abs()?
+        .max_keepdim(0)?
+        .to_vec1::<f32>()?[0];
+    assert!(diff < 1e-4, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn left_pad_prompts() -> Result<()> {
+    let (tokens, pad_lens) = left_pad(&prompts(), 0, &Device::Cpu)?;
+    assert_eq!(pad_lens, [3, 0, 5]);
+    assert_eq!(
+        tokens.to_vec2::<u32>()?,
+        [[0, 0, 0, 3, 1, 4], [1, 5, 9, 2, 6, 5], [0, 0, 0, 0, 0, 3]]
+    );
+    Ok(())
+}
+
+fn mistral_config() -> mistral::Config {
+    mistral::Config {
+        vocab_size: VOCAB_SIZE,
+        hidden_size: 16,
+        intermediate_size: 32,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        hidden_act: Activation::Silu,
+        max_position_embeddings: 64,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        rope_scaling: None,
+        // Small enough for the sliding window to evict some positions.
+        sliding_window: 5,
+        use_flash_attn: false,
+        use_sdpa: false,
+    }
+}
+
+fn mistral_model() -> Result<mistral::Model> {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    mistral::Model::new(&mistral_config(), vb)
+}
+
+#[test]
+fn mistral_padded_batch() -> Result<()> {
+    let mut model = mistral_model()?;
+    let steps = 5;
+    let (batch_tokens, batch_logits) = decode_batch(&prompts(), steps, |xs, offset, pad_lens| {
+        model.forward_padded(xs, offset, pad_lens)
+    })?;
+    for (i, prompt) in prompts().iter().enumerate() {
+        model.clear_kv_cache();
+        let (tokens, logits) =
+            decode_single(prompt, steps, |xs, offset| model.forward(xs, offset))?;
+        assert_eq!(tokens, batch_tokens[i]);
+        assert_close(&logits, &batch_logits.i(i)?)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn mistral_sdpa() -> Result<()> {
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let mut model = mistral::Model::new(&mistral_config(), vb.clone())?;
+    let cfg = mistral::Config {
+        use_sdpa: true,
+        ..mistral_config()
+    };
+    let mut sdpa_model = mistral::Model::new(&cfg, vb)?;
+    let steps = 5;
+    let (tokens, logits) = decode_batch(&prompts(), steps, |xs, offset, pad_lens| {
+        model.forward_padded(xs, offset, pad_lens)
+    })?;
+    let (sdpa_tokens, sdpa_logits) = decode_batch(&prompts(), steps, |xs, offset, pad_lens| {
+        sdpa_model.forward_padded(xs, offset, pad_lens)
+    })?;
+    assert_eq!(tokens, sdpa_tokens);
+    assert_close(&logits, &sdpa_logits)?;
+
+    // Without padding the sliding window is only applied by the sdpa kernel.
+    for prompt in prompts().iter() {
+        model.clear_kv_cache();
+        sdpa_model.clear_kv_cache();
+        let (tokens, logits) =
+            decode_single(prompt, steps, |xs, offset| model.forward(xs, offset))?;
+        let (sdpa_tokens, sdpa_logits) =
+            decode_single(prompt, steps, |xs, offset| sdpa_model.forward(xs, offset))?;
+        assert_eq!(tokens, sdpa_tokens);
+        assert_close(&logits, &sdpa_logits)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn mistral_continuous_batching() -> Result<()> {
+    let mut model = mistral_model()?;
+    let steps = 6;
+    let mut expected = vec![];
+    for prompt in prompts().iter() {
+        model.clear_kv_cache();
+        let (mut tokens, logits) =
+            decode_single(prompt, steps - 1, |xs, offset| model.forward(xs, offset))?;
+        tokens.push(argmax(&logits)?);
+        expected.push(tokens)
+    }
+    // The cache is too small for all the sequences at once so some get evicted and recomputed.
+    let config = SchedulerConfig {
+        num_blocks: 5,
+        block_size: 3,
+        max_num_seqs: 3,
+        max_num_batched_tokens: 16,
+    };
+    let mut engine = ContinuousBatching::new(model, config, &Device::Cpu)?;
+    for prompt in prompts().iter() {
+        engine.add_request(prompt, steps, None, LogitsProcessor::new(0, None, None))?;
+    }
+    let mut finished = engine.run()?;
+    assert!(engine.scheduler().num_preemptions() > 0);
+    assert_eq!(engine.scheduler().num_free_blocks(), 5);
+    finished.sort();
+    let finished = finished.into_iter().map(|(_, t)| t).collect::<Vec<_>>();
+    assert_eq!(finished, expected);
+    Ok(())
+}
+
+fn quantized_llama_model() -> Result<quantized_llama::ModelWeights> {
+    let (dim, hidden_dim, n_head, n_kv_head, n_layer) = (16, 32, 4, 2, 2);
+    let kv_dim = dim / n_head * n_kv_head;
+    let mut tensors = vec![];
+    let mut add = |name: String, shape: &[usize]| -> Result<()> {
+        let t = Tensor::randn(0f32, 0.5, shape, &Device::Cpu)?;
+        tensors.push((name, QTensor::quantize::<f32>(&t)?));
+        Ok(())
+    };
+    add("token_embd.weight".to_string(), &[VOCAB_SIZE, dim])?;
+    add("output_norm.weight".to_string(), &[dim])?;
+    add("output.weight".to_string(), &[VOCAB_SIZE, dim])?;
+    for i in 0..n_layer {
+        add(format!("blk.{i}.attn_q.weight"), &[dim, dim])?;
+        add(format!("blk.{i}.attn_k.weight"), &[kv_dim, dim])?;
+        add(format!("blk.{i}.attn_v.weight"), &[kv_dim, dim])?;
+        add(format!("blk.{i}.attn_output.weight"), &[dim, dim])?;
+        add(format!("blk.{i}.ffn_gate.weight"), &[hidden_dim, dim])?;
+        add(format!("blk.{i}.ffn_down.weight"), &[dim, hidden_dim])?;
+        add(format!("blk.{i}.ffn_up.weight"), &[hidden_dim, dim])?;
+        add(format!("blk.{i}.attn_norm.weight"), &[dim])?;
+        add(format!("blk.{i}.ffn_norm.weight"), &[dim])?;
+    }
+    let metadata = [
+        (
+            "llama.attention.head_count",
+            gguf_file::Value::U32(n_head as u32),
+        ),
+        (
+            "llama.attention.head_count_kv",
+            gguf_file::Value::U32(n_kv_head as u32),
+        ),
+        ("llama.block_count", gguf_file::Value::U32(n_layer as u32)),
+        ("llama.embedding_length", gguf_file::Value::U32(dim as u32)),
+        (
+            "llama.rope.dimension_count",
+            gguf_file::Value::U32((dim / n_head) as u32),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            gguf_file::Value::F32(1e-5),
+        ),
+    ];
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let mut buffer = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut buffer, &metadata, &tensors)?;
+    buffer.set_position(0);
+    let content = gguf_file::Content::read(&mut buffer)?;
+    quantized_llama::ModelWeights::from_gguf(content, &mut buffer)
+}
+
+#[test]
+fn quantized_llama_padded_batch() -> Result<()> {
+    let mut model = quantized_llama_model()?;
+    let steps = 4;
+    let (batch_tokens, batch_logits) = decode_batch(&prompts(), steps, |xs, offset, pad_lens| {
+        model.forward_padded(xs, offset, pad_lens)
+    })?;
+    for (i, prompt) in prompts().iter().enumerate() {
+        model.clear_kv_cache();
+        let (tokens, logits) =
+            decode_single(prompt, steps, |xs, offset| model.forward(xs, offset))?;
+        assert_eq!(tokens, batch_tokens[i]);
+        assert_close(&logits, &batch_logits.i(i)?)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn quantized_llama_paged_attention() -> Result<()> {
+    let mut model = quantized_llama_model()?;
+    let prompts = prompts();
+    let steps = 4;
+    let mut expected = vec![];
+    for prompt in prompts.iter() {
+        model.clear_kv_cache();
+        expected.push(decode_single(prompt, steps, |xs, offset| {
+            model.forward(xs, offset)
+        })?);
+    }
+
+    let device = &Device::Cpu;
+    let mut allocator = BlockAllocator::new(16, 2);
+    let mut tables = vec![BlockTable::new(2); prompts.len()];
+    model.allocate_paged_kv_cache(16, 2)?;
+    // Process the prompts with different lengths in a single batch.
+    let mut num_tokens = vec![];
+    for (table, prompt) in tables.iter_mut().zip(prompts.iter()) {
+        allocator.append_slots(table, prompt.len())?;
+        num_tokens.push(prompt.len())
+    }
+    let input = Tensor::new(prompts.concat(), device)?;
+    let tables_ref = tables.iter().collect::<Vec<_>>();
+    let metadata = PagedAttentionMetadata::new(&tables_ref, &num_tokens, device)?;
+    let mut logits = model.forward_paged(&input, &metadata)?;
+    let mut tokens = vec![vec![]; prompts.len()];
+    for _step in 0..steps {
+        let mut next_tokens = vec![];
+        for (i, (tokens, table)) in tokens.iter_mut().zip(tables.iter_mut()).enumerate() {
+            let token = argmax(&logits.i(i)?)?;
+            tokens.push(token);
+            next_tokens.push(token);
+            allocator.append_slots(table, 1)?;
+        }
+        let tables_ref = tables.iter().collect::<Vec<_>>();
+        let metadata = PagedAttentionMetadata::new(&tables_ref, &vec![1; prompts.len()], device)?;
+        logits = model.forward_paged(&Tensor::new(next_tokens, device)?, &metadata)?;
+    }
+    for (i, (expected_tokens, expected_logits)) in expected.iter().enumerate() {
+        assert_eq!(&tokens[i], expected_tokens);
+        assert_close(expected_logits, &logits.i(i)?)?;
+    }
+    Ok(())
+}
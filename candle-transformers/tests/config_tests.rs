@@ -0,0 +1,92 @@
+use candle_nn::rotary_emb::RopeScaling;
+use candle_transformers::models::llama::LlamaConfig;
+
+// https://huggingface.co/meta-llama/Llama-3.1-8B-Instruct/blob/main/config.json
+const LLAMA_3_1_8B: &str = r#"{
+  "architectures": [
+    "LlamaForCausalLM"
+  ],
+  "attention_bias": false,
+  "attention_dropout": 0.0,
+  "bos_token_id": 128000,
+  "eos_token_id": [
+    128001,
+    128008,
+    128009
+  ],
+  "hidden_act": "silu",
+  "hidden_size": 4096,
+  "initializer_range": 0.02,
+  "intermediate_size": 14336,
+  "max_position_embeddings": 131072,
+  "mlp_bias": false,
+  "model_type": "llama",
+  "num_attention_heads": 32,
+  "num_hidden_layers": 32,
+  "num_key_value_heads": 8,
+  "pretraining_tp": 1,
+  "rms_norm_eps": 1e-05,
+  "rope_scaling": {
+    "factor": 8.0,
+    "low_freq_factor": 1.0,
+    "high_freq_factor": 4.0,
+    "original_max_position_embeddings": 8192,
+    "rope_type": "llama3"
+  },
+  "rope_theta": 500000.0,
+  "tie_word_embeddings": false,
+  "torch_dtype": "bfloat16",
+  "transformers_version": "4.43.0.dev0",
+  "use_cache": true,
+  "vocab_size": 128256
+}"#;
+
+#[test]
+fn llama_3_1_config() -> serde_json::Result<()> {
+    let config: LlamaConfig = serde_json::from_str(LLAMA_3_1_8B)?;
+    assert_eq!(config.max_position_embeddings, 131072);
+    assert_eq!(
+        config.rope_scaling,
+        Some(RopeScaling::Llama3 {
+            factor: 8.,
+            low_freq_factor: 1.,
+            high_freq_factor: 4.,
+            original_max_position_embeddings: 8192,
+        })
+    );
+    let config = config.into_config(false);
+    assert_eq!(config.num_key_value_heads, 8);
+    Ok(())
+}
+
+#[test]
+fn rope_scaling_config() -> serde_json::Result<()> {
+    let scaling = |json: &str| serde_json::from_str::<RopeScaling>(json);
+    assert_eq!(
+        scaling(r#"{"type": "linear", "factor": 2.0}"#)?,
+        RopeScaling::Linear { factor: 2. }
+    );
+    assert_eq!(
+        scaling(r#"{"type": "dynamic", "factor": 4.0}"#)?,
+        RopeScaling::Dynamic { factor: 4. }
+    );
+    assert_eq!(
+        scaling(
+            r#"{"rope_type": "yarn", "factor": 4.0, "original_max_position_embeddings": 32768}"#
+        )?,
+        RopeScaling::Yarn {
+            factor: 4.,
+            original_max_position_embeddings: 32768,
+            beta_fast: 32.,
+            beta_slow: 1.,
+        }
+    );
+    // Some configs have both keys, `rope_type` takes precedence.
+    assert_eq!(
+        scaling(r#"{"type": "mrope", "rope_type": "default", "mrope_section": [16, 24, 24]}"#)?,
+        RopeScaling::Other("default".to_string())
+    );
+    assert!(scaling(r#"{"type": "linear"}"#).is_err());
+    assert!(scaling(r#"{"factor": 2.0}"#).is_err());
+    Ok(())
+}
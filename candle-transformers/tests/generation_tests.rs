@@ -1,5 +1,10 @@
-use candle::{Device, Result, Tensor};
-use candle_transformers::generation::LogitsProcessor;
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::generation::{
+    json_schema::json_schema_to_grammar, BeamSearch, BeamSearchConfig, Constraint, EncoderDecoder,
+    Grammar, GrammarConstraint, Hypothesis, LogitsProcessor, Sampling, Scheduler, SchedulerConfig,
+    SpeculativeDecoding, SpeculativeModel, TokenVocabulary,
+};
+use std::sync::Arc;
 
 #[test]
 fn sample_with_zero_temperature() -> Result<()> {
@@ -27,3 +32,526 @@ fn sample_with_top_p() -> Result<()> {
     assert_eq!(token, 2);
     Ok(())
 }
+
+#[test]
+fn sample_with_top_k() -> Result<()> {
+    let logits = Tensor::new(&[0.1, 0.2, 0.3, 0.4], &Device::Cpu)?;
+    let sampling = Sampling::TopK {
+        k: 1,
+        temperature: 1.0,
+    };
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    let token = logits_process.sample(&logits)?;
+    assert_eq!(token, 3);
+    let sampling = Sampling::TopK {
+        k: 2,
+        temperature: 1.0,
+    };
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    let tokens = (0..8)
+        .map(|_| logits_process.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(tokens, [2, 3, 2, 3, 3, 3, 3, 2]);
+    Ok(())
+}
+
+#[test]
+fn sample_with_top_k_then_top_p() -> Result<()> {
+    let logits = Tensor::new(&[0.1, 0.2, 0.3, 4.0], &Device::Cpu)?;
+    let sampling = Sampling::TopKThenTopP {
+        k: 3,
+        p: 0.5,
+        temperature: 1.0,
+    };
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    let tokens = (0..8)
+        .map(|_| logits_process.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(tokens, [3, 3, 3, 3, 3, 3, 3, 3]);
+    Ok(())
+}
+
+#[test]
+fn sample_with_min_p() -> Result<()> {
+    // Probabilities are proportional to 1, 2, 4, 8.
+    let logits = Tensor::new(&[0f32, 1., 2., 3.], &Device::Cpu)?.affine(2f64.ln(), 0.)?;
+    let sampling = Sampling::MinP {
+        p: 0.4,
+        temperature: 1.0,
+    };
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    let tokens = (0..8)
+        .map(|_| logits_process.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(tokens, [2, 3, 2, 3, 3, 3, 3, 3]);
+    Ok(())
+}
+
+#[test]
+fn sample_with_typical() -> Result<()> {
+    let logits = Tensor::new(&[0f32, 1., 2., 3.], &Device::Cpu)?;
+    // The information content of token 2 is the closest to the entropy of the distribution so
+    // it is the only one to be kept with a small enough p.
+    let sampling = Sampling::Typical {
+        p: 0.2,
+        temperature: 1.0,
+    };
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    let tokens = (0..8)
+        .map(|_| logits_process.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(tokens, [2, 2, 2, 2, 2, 2, 2, 2]);
+    Ok(())
+}
+
+#[test]
+fn sample_with_penalties() -> Result<()> {
+    let logits = Tensor::new(&[1f32, 2., -1., 3.], &Device::Cpu)?;
+    let logits_process = LogitsProcessor::from_sampling(42, Sampling::ArgMax)
+        .with_repeat_penalty(2.)
+        .with_frequency_penalty(0.5)
+        .with_presence_penalty(0.25);
+    let penalized = logits_process.apply_penalties(&logits, &[3, 3, 2])?;
+    // token 2: -1 * 2 - 0.5 - 0.25, token 3: 3 / 2 - 2 * 0.5 - 0.25
+    assert_eq!(penalized.to_vec1::<f32>()?, [1., 2., -2.75, 0.25]);
+    // Only the last token is taken into account.
+    let logits_process = logits_process.with_penalty_last_n(1);
+    let penalized = logits_process.apply_penalties(&logits, &[3, 3, 2])?;
+    assert_eq!(penalized.to_vec1::<f32>()?, [1., 2., -2.75, 3.]);
+    let mut logits_process = logits_process.with_penalty_last_n(64);
+    let token = logits_process.sample_with_context(&logits, &[3, 3, 2])?;
+    assert_eq!(token, 1);
+    Ok(())
+}
+
+#[test]
+fn sample_with_logit_bias_and_banned_tokens() -> Result<()> {
+    let logits = Tensor::new(&[0.1, 0.2, 0.3, 0.4], &Device::Cpu)?;
+    let logit_bias = [(0, 1.0)].into_iter().collect();
+    let mut logits_process = LogitsProcessor::from_sampling(42, Sampling::ArgMax)
+        .with_logit_bias(logit_bias)
+        .with_banned_tokens(vec![0]);
+    let token = logits_process.sample(&logits)?;
+    assert_eq!(token, 3);
+    let mut logits_process = logits_process.with_banned_tokens(vec![]);
+    let token = logits_process.sample(&logits)?;
+    assert_eq!(token, 0);
+    let sampling = Sampling::All { temperature: 1.0 };
+    let mut logits_process =
+        LogitsProcessor::from_sampling(42, sampling).with_banned_tokens(vec![1, 2, 3]);
+    let tokens = (0..4)
+        .map(|_| logits_process.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(tokens, [0, 0, 0, 0]);
+    Ok(())
+}
+
+#[test]
+fn repeat_penalty() -> Result<()> {
+    let logits = Tensor::new(&[1f32, -2., 3., 4.], &Device::Cpu)?;
+    let logits = candle_transformers::utils::apply_repeat_penalty(&logits, 2., &[0, 1, 1, 7])?;
+    assert_eq!(logits.to_vec1::<f32>()?, [0.5, -4., 3., 4.]);
+    Ok(())
+}
+
+// A decoder whose next token distribution only depends on the previous token, the previous
+// tokens are kept in a fake kv cache to check that it gets reordered properly.
+struct MockDecoder {
+    kv_cache: Option<Tensor>,
+}
+
+impl EncoderDecoder for MockDecoder {
+    fn decode_step(
+        &mut self,
+        tokens: &Tensor,
+        _encoder_xs: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let seq_len = tokens.dim(1)?;
+        let new_tokens = tokens.narrow(1, index_pos, seq_len - index_pos)?;
+        let kv_cache = match &self.kv_cache {
+            None => new_tokens,
+            Some(kv_cache) => Tensor::cat(&[kv_cache, &new_tokens], 1)?,
+        };
+        self.kv_cache = Some(kv_cache.clone());
+        // Token 0 is the start token and token 1 the end of sequence.
+        let last_tokens = kv_cache.narrow(1, seq_len - 1, 1)?.flatten_all()?;
+        let prs = last_tokens
+            .to_vec1::<u32>()?
+            .into_iter()
+            .flat_map(|last| match last {
+                0 => [0f32, 0.1, 0.5, 0.4],
+                2 => [0f32, 0.25, 0.4, 0.35],
+                _ => [0f32, 0.9, 0.05, 0.05],
+            })
+            .collect::<Vec<_>>();
+        Tensor::from_vec(prs, (last_tokens.dim(0)?, 4), &Device::Cpu)?.log()
+    }
+
+    fn reorder_kv_cache(&mut self, beam_indices: &Tensor) -> Result<()> {
+        if let Some(kv_cache) = &self.kv_cache {
+            self.kv_cache = Some(kv_cache.contiguous()?.index_select(beam_indices, 0)?)
+        }
+        Ok(())
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.kv_cache = None
+    }
+}
+
+fn beam_search(beam_search: &mut BeamSearch) -> Result<Vec<Hypothesis>> {
+    let mut model = MockDecoder { kv_cache: None };
+    let encoder_xs = Tensor::zeros((1, 3, 2), DType::F32, &Device::Cpu)?;
+    beam_search.generate(&mut model, &encoder_xs, &[0])
+}
+
+fn hyp_tokens(hyps: &[Hypothesis]) -> Vec<Vec<u32>> {
+    hyps.iter().map(|h| h.tokens.clone()).collect()
+}
+
+#[test]
+fn beam_search_n_best() -> Result<()> {
+    // A single beam is the same as greedy decoding.
+    let config = BeamSearchConfig {
+        max_new_tokens: 4,
+        ..BeamSearchConfig::new(1, 1)
+    };
+    let hyps = beam_search(&mut BeamSearch::new(config))?;
+    assert_eq!(hyp_tokens(&hyps), [[2, 2, 2, 2]]);
+
+    let config = BeamSearchConfig {
+        length_penalty: 0.,
+        num_return_sequences: 2,
+        ..BeamSearchConfig::new(2, 1)
+    };
+    let hyps = beam_search(&mut BeamSearch::new(config))?;
+    assert_eq!(hyp_tokens(&hyps), [vec![3, 1], vec![2, 3, 1]]);
+    let score = 0.4f64.ln() + 0.9f64.ln();
+    assert!((hyps[0].score - score).abs() < 1e-5);
+    Ok(())
+}
+
+#[test]
+fn beam_search_constraints() -> Result<()> {
+    let config = BeamSearchConfig {
+        length_penalty: 0.,
+        ..BeamSearchConfig::new(2, 1)
+    };
+    let mut search = BeamSearch::new(config.clone()).with_forced_prefix(vec![2, 2]);
+    let hyps = beam_search(&mut search)?;
+    assert_eq!(hyp_tokens(&hyps), [[2, 2, 3, 1]]);
+
+    // Only allow the end of sequence token after at least three tokens.
+    let mut search = BeamSearch::new(config).with_allowed_tokens(|tokens| {
+        if tokens.len() < 3 {
+            Some(vec![2, 3])
+        } else {
+            None
+        }
+    });
+    let hyps = beam_search(&mut search)?;
+    assert_eq!(hyp_tokens(&hyps), [[2, 2, 3, 1]]);
+    Ok(())
+}
+
+// A language model where the logits only depend on the previous token, the kv cache length is
+// tracked to check that the cache is rolled back properly.
+struct MockLM {
+    logits: fn(u32) -> Vec<f32>,
+    cache_len: usize,
+}
+
+impl MockLM {
+    fn new(logits: fn(u32) -> Vec<f32>) -> Self {
+        Self {
+            logits,
+            cache_len: 0,
+        }
+    }
+}
+
+impl SpeculativeModel for MockLM {
+    fn forward_all(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        assert_eq!(index_pos, self.cache_len);
+        let input_ids = input_ids.squeeze(0)?.to_vec1::<u32>()?;
+        self.cache_len += input_ids.len();
+        let logits = input_ids
+            .iter()
+            .flat_map(|&t| (self.logits)(t))
+            .collect::<Vec<_>>();
+        Tensor::from_vec(logits, (input_ids.len(), 4), &Device::Cpu)
+    }
+
+    fn rollback_kv_cache(&mut self, seq_len: usize) -> Result<()> {
+        assert!(seq_len <= self.cache_len);
+        self.cache_len = seq_len;
+        Ok(())
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.cache_len = 0
+    }
+}
+
+fn one_hot(t: u32) -> Vec<f32> {
+    (0..4).map(|i| if i == t { 10. } else { 0. }).collect()
+}
+
+#[test]
+fn speculative_decoding_greedy() -> Result<()> {
+    // The target model predicts the next token, the draft model gets it wrong after a 2.
+    let target = MockLM::new(|t| one_hot((t + 1) % 4));
+    let draft = MockLM::new(|t| one_hot(if t == 2 { 0 } else { (t + 1) % 4 }));
+    let logits_processor = LogitsProcessor::from_sampling(42, Sampling::ArgMax);
+    let mut decoding =
+        SpeculativeDecoding::new(target, draft, logits_processor, 3, 42, &Device::Cpu);
+    let tokens = decoding.generate(&[1], 10, None)?;
+    assert_eq!(tokens, [2, 3, 0, 1, 2, 3, 0, 1, 2, 3]);
+    assert!(decoding.acceptance_rate() > 0.5 && decoding.acceptance_rate() < 1.);
+    let tokens = decoding.generate(&[0, 1], 5, Some(0))?;
+    assert_eq!(tokens, [2, 3, 0]);
+    Ok(())
+}
+
+#[test]
+fn speculative_decoding_distribution() -> Result<()> {
+    // The generated tokens follow the target distribution whatever the draft distribution.
+    let target_prs = [0.5f32, 0.3, 0.15, 0.05];
+    let target = MockLM::new(|_| [0.5f32, 0.3, 0.15, 0.05].iter().map(|p| p.ln()).collect());
+    let draft = MockLM::new(|_| [0.1f32, 0.2, 0.3, 0.4].iter().map(|p| p.ln()).collect());
+    let logits_processor = LogitsProcessor::from_sampling(42, Sampling::All { temperature: 1. });
+    let mut decoding =
+        SpeculativeDecoding::new(target, draft, logits_processor, 4, 1337, &Device::Cpu);
+    let n = 20000;
+    let tokens = decoding.generate(&[0], n, None)?;
+    assert_eq!(tokens.len(), n);
+    for (token, &p) in target_prs.iter().enumerate() {
+        let freq = tokens.iter().filter(|&&t| t == token as u32).count() as f32 / n as f32;
+        assert!((freq - p).abs() < 0.015, "{token} {freq} {p}");
+    }
+    Ok(())
+}
+
+#[test]
+fn scheduler_admission_and_preemption() -> Result<()> {
+    let device = &Device::Cpu;
+    let config = SchedulerConfig {
+        num_blocks: 3,
+        block_size: 2,
+        max_num_seqs: 2,
+        max_num_batched_tokens: 5,
+    };
+    let mut scheduler = Scheduler::new(config);
+    assert!(scheduler
+        .add_sequence(&[1, 2, 3, 4, 5, 6], 4, None)
+        .is_err());
+    let s0 = scheduler.add_sequence(&[1, 2], 3, None)?;
+    let s1 = scheduler.add_sequence(&[4, 5], 3, Some(0))?;
+    let s2 = scheduler.add_sequence(&[6], 1, None)?;
+
+    // The third sequence exceeds the number of sequences per batch.
+    let batch = scheduler.schedule(device)?.unwrap();
+    assert_eq!(batch.ids, [s0, s1]);
+    assert_eq!(batch.input_ids.to_vec1::<u32>()?, [1, 2, 4, 5]);
+    assert_eq!(batch.metadata.context_lens(), [0, 0]);
+    assert_eq!(scheduler.num_free_blocks(), 1);
+    assert_eq!(scheduler.append_token(s0, 7)?, None);
+    assert_eq!(scheduler.append_token(s1, 8)?, None);
+
+    // Both sequences need a new block, the last admitted one gets evicted.
+    let batch = scheduler.schedule(device)?.unwrap();
+    assert_eq!(batch.ids, [s0]);
+    assert_eq!(batch.input_ids.to_vec1::<u32>()?, [7]);
+    assert_eq!(batch.metadata.context_lens(), [2]);
+    assert_eq!(scheduler.num_preemptions(), 1);
+    assert_eq!(scheduler.num_waiting(), 2);
+    assert_eq!(scheduler.append_token(s0, 9)?, None);
+
+    // There is no room to readmit the evicted sequence yet.
+    let batch = scheduler.schedule(device)?.unwrap();
+    assert_eq!(batch.ids, [s0]);
+    assert_eq!(scheduler.append_token(s0, 10)?, Some(vec![7, 9, 10]));
+    assert_eq!(scheduler.num_free_blocks(), 3);
+
+    // The evicted sequence is recomputed from its prompt and generated tokens.
+    let batch = scheduler.schedule(device)?.unwrap();
+    assert_eq!(batch.ids, [s1, s2]);
+    assert_eq!(batch.input_ids.to_vec1::<u32>()?, [4, 5, 8, 6]);
+    assert_eq!(batch.metadata.num_tokens(), [3, 1]);
+    assert_eq!(scheduler.append_token(s1, 0)?, Some(vec![8, 0]));
+    assert!(scheduler.abort(s2));
+    assert!(!scheduler.abort(s2));
+    assert!(scheduler.is_empty());
+    assert_eq!(scheduler.num_free_blocks(), 3);
+    assert!(scheduler.schedule(device)?.is_none());
+    Ok(())
+}
+
+#[test]
+fn grammar_matching() -> Result<()> {
+    let grammar = Grammar::parse(
+        r#"
+        # A comma separated list of numbers or words.
+        root ::= item ("," " "? item)* "."?
+        item ::= [0-9]{1,3} | word
+               | "\"" [^"]+ "\""
+        word ::= ([a-z] | "\u00e9")+
+        "#,
+    )?;
+    for text in ["1", "12, abc,\"x y\".", "café,999", "a,b,c"] {
+        assert!(grammar.matches(text)?, "{text}");
+    }
+    for text in ["", "1234", "1,,2", "A", "a.b", "\"\""] {
+        assert!(!grammar.matches(text)?, "{text}");
+    }
+    assert!(grammar.matches_prefix("12, ab")?);
+    assert!(grammar.matches_prefix("\"x")?);
+    assert!(!grammar.matches_prefix("12;")?);
+
+    assert!(Grammar::parse("item ::= \"a\"").is_err());
+    assert!(Grammar::parse("root ::= item").is_err());
+    assert!(Grammar::parse("root ::= [a").is_err());
+    let left_recursive = Grammar::parse("root ::= root \"a\" | \"a\"")?;
+    assert!(left_recursive.matches("aa").is_err());
+    Ok(())
+}
+
+#[test]
+fn json_schema_matching() -> Result<()> {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "maxLength": 8 },
+            "age": { "type": "integer" },
+            "tags": { "type": "array", "items": { "enum": ["a", "b", 1] }, "maxItems": 2 },
+            "tree": { "$ref": "#/definitions/tree" },
+        },
+        "required": ["name"],
+        "definitions": {
+            "tree": {
+                "anyOf": [
+                    { "type": "null" },
+                    { "type": "array", "items": { "$ref": "#/definitions/tree" } },
+                ]
+            }
+        }
+    });
+    let grammar = Grammar::parse(&json_schema_to_grammar(&schema)?)?;
+    let valid = [
+        r#"{"name": "bob"}"#,
+        r#"{"name":"bob","age":-12}"#,
+        r#"{ "name": "a\"b", "tags": ["b", 1] }"#,
+        r#"{"name": "", "age": 3, "tags": [], "tree": [null, [[], null]]}"#,
+        "{\n  \"name\": \"x\",\n  \"tree\": null\n}",
+    ];
+    for text in valid {
+        assert!(grammar.matches(text)?, "{text}");
+    }
+    let invalid = [
+        r#"{}"#,
+        r#"{"age": 12}"#,
+        r#"{"age": 12, "name": "bob"}"#,
+        r#"{"name": "too long name"}"#,
+        r#"{"name": "bob", "age": 1.5}"#,
+        r#"{"name": "bob", "tags": ["c"]}"#,
+        r#"{"name": "bob", "tags": ["a", "a", "a"]}"#,
+        r#"{"name": "bob", "tree": [1]}"#,
+        r#"{"name": "bob", "other": 1}"#,
+    ];
+    for text in invalid {
+        assert!(!grammar.matches(text)?, "{text}");
+    }
+    assert!(json_schema_to_grammar(&serde_json::json!({ "allOf": [] })).is_err());
+    assert!(json_schema_to_grammar(&serde_json::json!({ "$ref": "#/definitions/x" })).is_err());
+    Ok(())
+}
+
+// A vocabulary with all the ascii characters, some multi-character tokens, the two bytes of
+// "é" as separate tokens and an end of sequence token.
+fn toy_vocabulary() -> (TokenVocabulary, u32) {
+    let mut tokens = (0..128u8).map(|b| vec![b]).collect::<Vec<_>>();
+    for token in ["true", "false", "{\"", "\": ", "é!"] {
+        tokens.push(token.as_bytes().to_vec())
+    }
+    tokens.push(vec![0xC3]);
+    tokens.push(vec![0xA9]);
+    tokens.push(vec![]);
+    let eos_token = tokens.len() as u32 - 1;
+    (TokenVocabulary::new(tokens), eos_token)
+}
+
+// Samples tokens with random logits until the end of sequence token, returning the text.
+fn sample_constrained(grammar: Grammar, seed: u64) -> Result<String> {
+    let (vocabulary, eos_token) = toy_vocabulary();
+    let vocabulary = Arc::new(vocabulary);
+    let constraint = GrammarConstraint::new(Arc::new(grammar), vocabulary.clone(), &[eos_token])?;
+    let mut logits_process =
+        LogitsProcessor::from_sampling(seed, Sampling::All { temperature: 1.0 })
+            .with_constraint(constraint);
+    let mut bytes = vec![];
+    for _ in 0..200 {
+        let logits = Tensor::randn(0f32, 3., vocabulary.len(), &Device::Cpu)?;
+        let token = logits_process.sample(&logits)?;
+        if token == eos_token {
+            assert!(logits_process.constraint().unwrap().is_complete());
+            return String::from_utf8(bytes).map_err(candle::Error::wrap);
+        }
+        bytes.extend_from_slice(vocabulary.token_bytes(token).unwrap());
+    }
+    candle::bail!("no end of sequence token")
+}
+
+#[test]
+fn grammar_constrained_sampling() -> Result<()> {
+    let grammar = Grammar::parse(r#"root ::= "caf" [\u00e9] "!" | "no""#)?;
+    for seed in 0..4 {
+        let text = sample_constrained(grammar.clone(), seed)?;
+        assert!(text == "café!" || text == "no", "{text}");
+    }
+
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string", "maxLength": 4 },
+            "ok": { "type": "boolean" },
+            "kind": { "enum": ["a", "é"] },
+        },
+        "required": ["kind", "name", "ok"],
+    });
+    let grammar = Grammar::from_json_schema(&schema)?;
+    for seed in 0..4 {
+        let text = sample_constrained(grammar.clone(), seed)?;
+        let value: serde_json::Value = serde_json::from_str(&text).map_err(candle::Error::wrap)?;
+        assert!(value["ok"].is_boolean(), "{text}");
+        assert!(
+            value["name"].as_str().unwrap().chars().count() <= 4,
+            "{text}"
+        );
+        assert!(grammar.matches(&text)?, "{text}");
+    }
+
+    // The end of sequence token is only allowed once the grammar is complete.
+    let (vocabulary, eos_token) = toy_vocabulary();
+    let grammar = Arc::new(Grammar::parse(r#"root ::= "ab""#)?);
+    let mut constraint = GrammarConstraint::new(grammar, Arc::new(vocabulary), &[eos_token])?;
+    let allowed = constraint.allowed_tokens()?;
+    assert_eq!(
+        allowed
+            .iter()
+            .enumerate()
+            .filter(|(_, &a)| a)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>(),
+        [b'a' as usize]
+    );
+    assert!(constraint.advance(b'b' as u32).is_err());
+    constraint.advance(b'a' as u32)?;
+    assert!(!constraint.allowed_tokens()?[eos_token as usize]);
+    constraint.advance(b'b' as u32)?;
+    assert!(constraint.is_complete());
+    assert!(constraint.allowed_tokens()?[eos_token as usize]);
+    constraint.reset();
+    assert!(!constraint.is_complete());
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::pipelines::text_generation::{
+    CausalLM, TextGenerationConfig, TextGenerationPipeline, Tokenizer,
+};
+
+// A model that always predicts the token following the last input token in the vocabulary.
+struct NextTokenModel {
+    vocab_size: usize,
+    cache_len: usize,
+}
+
+impl CausalLM for NextTokenModel {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        assert_eq!(index_pos, self.cache_len);
+        self.cache_len += seq_len;
+        let last = input_ids.to_vec2::<u32>()?[0][seq_len - 1] as usize;
+        let logits: Vec<f32> = (0..self.vocab_size)
+            .map(|i| {
+                if i == (last + 1) % self.vocab_size {
+                    1.
+                } else {
+                    0.
+                }
+            })
+            .collect();
+        Tensor::new(logits.as_slice(), &Device::Cpu)?.unsqueeze(0)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.cache_len = 0
+    }
+}
+
+// One token per lowercase ascii letter.
+struct CharTokenizer;
+
+impl Tokenizer for CharTokenizer {
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        Ok(text.bytes().map(|b| (b - b'a') as u32).collect())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        Ok(tokens.iter().map(|&t| (b'a' + t as u8) as char).collect())
+    }
+
+    fn token_to_id(&self, token: &str) -> Option<u32> {
+        match token.as_bytes() {
+            [b] => Some((b - b'a') as u32),
+            _ => None,
+        }
+    }
+}
+
+fn pipeline(config: TextGenerationConfig) -> TextGenerationPipeline<NextTokenModel, CharTokenizer> {
+    let model = NextTokenModel {
+        vocab_size: 26,
+        cache_len: 0,
+    };
+    TextGenerationPipeline::new(model, CharTokenizer, config, &Device::Cpu)
+}
+
+#[test]
+fn text_generation_max_new_tokens() -> Result<()> {
+    let config = TextGenerationConfig {
+        max_new_tokens: 5,
+        ..Default::default()
+    };
+    let mut pipeline = pipeline(config);
+    let chunks = pipeline.generate("abc")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(chunks, ["d", "e", "f", "g", "h"]);
+    // Running a second time resets the kv cache.
+    assert_eq!(pipeline.run("xy")?, "zabcd");
+    Ok(())
+}
+
+#[test]
+fn text_generation_eos_token() -> Result<()> {
+    let config = TextGenerationConfig {
+        eos_tokens: vec!["g".to_string()],
+        ..Default::default()
+    };
+    let mut pipeline = pipeline(config);
+    let mut generation = pipeline.generate("abc")?;
+    let text = generation.by_ref().collect::<Result<String>>()?;
+    assert_eq!(text, "def");
+    assert_eq!(generation.generated_tokens(), [3, 4, 5]);
+    Ok(())
+}
+
+#[test]
+fn text_generation_stop_sequences() -> Result<()> {
+    let config = TextGenerationConfig {
+        stop_sequences: vec!["fgz".to_string(), "hi".to_string()],
+        ..Default::default()
+    };
+    let mut pipeline = pipeline(config);
+    // "fg" is held back until it is clear that it does not start "fgz".
+    let chunks = pipeline.generate("abc")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(chunks, ["d", "e", "fg"]);
+    Ok(())
+}
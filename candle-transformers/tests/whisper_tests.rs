@@ -0,0 +1,56 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::models::whisper::{self, audio};
+
+fn config() -> whisper::Config {
+    whisper::Config {
+        num_mel_bins: 8,
+        max_source_positions: 1500,
+        d_model: 16,
+        encoder_attention_heads: 2,
+        encoder_layers: 1,
+        vocab_size: 16,
+        max_target_positions: 16,
+        decoder_attention_heads: 2,
+        decoder_layers: 1,
+        suppress_tokens: vec![],
+    }
+}
+
+#[test]
+fn pcm_to_mel_tensor() -> Result<()> {
+    let cfg = config();
+    let dev = &Device::Cpu;
+    let n_bins = whisper::N_FFT / 2 + 1;
+    let filters: Vec<f32> = (0..cfg.num_mel_bins * n_bins)
+        .map(|i| ((i * 7) % 13) as f32 / 13.)
+        .collect();
+    let samples: Vec<Vec<f32>> = [440f32, 1000.]
+        .iter()
+        .map(|freq| {
+            (0..whisper::SAMPLE_RATE / 2)
+                .map(|i| {
+                    let t = i as f32 / whisper::SAMPLE_RATE as f32;
+                    (2. * std::f32::consts::PI * freq * t).sin() * 0.5
+                })
+                .collect()
+        })
+        .collect();
+
+    let filters_t = Tensor::from_slice(&filters, (cfg.num_mel_bins, n_bins), dev)?;
+    let batch = Tensor::new(samples.clone(), dev)?;
+    let mels = audio::pcm_to_mel_tensor(&cfg, &batch, &filters_t)?;
+    for (i, samples) in samples.iter().enumerate() {
+        let expected = audio::pcm_to_mel(&cfg, samples, &filters);
+        let n_len = expected.len() / cfg.num_mel_bins;
+        let expected = Tensor::from_vec(expected, (cfg.num_mel_bins, n_len), dev)?;
+        let mel = mels.get(i)?;
+        assert_eq!(mel.dims(), expected.dims());
+        let diff = (mel - expected)?
+            .abs()?
+            .flatten_all()?
+            .max(0)?
+            .to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "{diff}");
+    }
+    Ok(())
+}